@@ -17,7 +17,76 @@
 
 use core::{hint, panic};
 
-fn main() {}
+use drv_fs::{blk::BlockDevice, ipc::Client};
+
+/// Endpoint of the block device to mount, the first capability granted.
+const DEVICE: sys::Endpoint = sys::Endpoint(0);
+
+/// Bytes transferred per request, shared with the block device's driver.
+const BUFFER_SIZE: usize = 64 << 10;
+
+/// Boot sector signature, of the MBR as well.
+const SIGNATURE: [u8; 2] = [0x55, 0xAA];
+
+/// Offset of the first partition entry's starting sector in the MBR.
+const MBR_PARTITION_START: usize = 446 + 8;
+/// Bytes of the sectors the MBR counts in, regardless of the block size.
+const MBR_SECTOR_SIZE: u64 = 512;
+
+sys::main!(main);
+
+fn main(_argument: &[u8]) {
+    sys::Logger::init(log::LevelFilter::Info);
+
+    let mut device = match Client::connect(DEVICE, BUFFER_SIZE) {
+        Ok(device) => device,
+        Err(error) => {
+            log::error!("Block device not connected: {:?}", error);
+            return;
+        }
+    };
+    let mut block = [0; 4096];
+    let Some(block) = block
+        .get_mut(..device.block_size())
+        .filter(|block| block.len() >= 512)
+    else {
+        log::error!("Block size {} not supported", device.block_size());
+        return;
+    };
+
+    // the volume is either the whole disk, or its first partition
+    let mut start = 0;
+    loop {
+        if let Err(error) = device.read(start, block) {
+            log::error!("Block {} not read: {:?}", start, error);
+            return;
+        }
+        if block[510..512] != SIGNATURE {
+            break;
+        }
+        if block[54..57] == *b"FAT" || block[82..87] == *b"FAT32" {
+            log::info!("FAT volume found at block {}", start);
+            return;
+        }
+        let partition = &block[MBR_PARTITION_START..MBR_PARTITION_START + 4];
+        let partition = u32::from_le_bytes(partition.try_into().unwrap()) as u64;
+        if start != 0 || partition == 0 {
+            break;
+        }
+        let offset = partition * MBR_SECTOR_SIZE;
+        let block_size = block.len() as u64;
+        if !offset.is_multiple_of(block_size) {
+            log::error!(
+                "Partition at sector {} not aligned to blocks of {} bytes",
+                partition,
+                block_size
+            );
+            return;
+        }
+        start = offset / block_size;
+    }
+    log::info!("No FAT volume");
+}
 
 #[panic_handler]
 fn panic(_info: &panic::PanicInfo) -> ! {
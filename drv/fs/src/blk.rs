@@ -0,0 +1,220 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::slice;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Error {
+    /// The request is beyond the last block, not a multiple of the block
+    /// size, or the buffer is misaligned.
+    InvalidRequest,
+    /// The device reported an error.
+    Device,
+    /// The device didn't respond in time.
+    Timeout,
+    /// The device or medium can't be written, e.g. a CD-ROM.
+    ReadOnly,
+    /// The device doesn't support the operation.
+    Unsupported,
+    /// The queue is full, completions have to be taken first.
+    Busy,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Operation {
+    Read,
+    Write,
+    Flush,
+    Discard,
+}
+
+/// Request submitted to a block device, which completes it asynchronously.
+#[derive(Clone, Copy, Debug)]
+pub struct Request {
+    pub operation: Operation,
+    pub block: u64,
+    /// Blocks read, written or discarded.
+    pub count: u64,
+    /// Memory of the blocks read or written, the device owns it until the
+    /// request completed.
+    pub buffer: *mut u8,
+    /// Chosen by the submitter, to tell the completions apart.
+    pub tag: u32,
+}
+
+impl Request {
+    /// Returns the bytes of the blocks read or written.
+    pub fn length(&self, block_size: usize) -> Option<usize> {
+        usize::try_from(self.count).ok()?.checked_mul(block_size)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Completion {
+    pub tag: u32,
+    pub result: Result<(), Error>,
+}
+
+/// Health reported by the device, e.g. from SMART.
+#[derive(Clone, Copy, Debug)]
+pub struct Health {
+    /// Whether the device predicts its failure.
+    pub failing: bool,
+    /// Temperature in °C.
+    pub temperature: Option<u8>,
+    pub power_on_hours: Option<u32>,
+    /// Blocks remapped after errors.
+    pub reallocated: Option<u32>,
+}
+
+/// Device addressed in fixed-size blocks, e.g. a disk.
+pub trait BlockDevice {
+    /// Bytes per block.
+    fn block_size(&self) -> usize;
+
+    fn block_count(&self) -> u64;
+
+    /// Whether writes are rejected, e.g. by a CD/DVD drive.
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    /// Reads consecutive blocks, starting at the given one, into the buffer,
+    /// which has to be a multiple of the block size.
+    fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<(), Error>;
+
+    /// Writes the buffer, which has to be a multiple of the block size, to
+    /// consecutive blocks, starting at the given one.
+    fn write(&mut self, block: u64, buffer: &[u8]) -> Result<(), Error>;
+
+    /// Writes cached data to the medium, blocks written before are persistent
+    /// afterwards.
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Marks blocks as unused, their contents are undefined afterwards.
+    fn discard(&mut self, _block: u64, _count: u64) -> Result<(), Error> {
+        Err(Error::Unsupported)
+    }
+
+    fn health(&mut self) -> Result<Health, Error> {
+        Err(Error::Unsupported)
+    }
+
+    /// Requests the device works on at once.
+    fn queue_depth(&self) -> usize {
+        1
+    }
+
+    /// Starts the request, and returns its completion if the device finished
+    /// it right away, otherwise [`poll`](Self::poll) returns it later.
+    /// Devices without a queue execute it synchronously.
+    ///
+    /// # Safety
+    ///
+    /// The buffer has to be valid for the blocks of the request, and must not
+    /// be accessed until it completed.
+    unsafe fn submit(&mut self, request: Request) -> Option<Completion> {
+        Some(Completion {
+            tag: request.tag,
+            result: unsafe { execute(self, &request) },
+        })
+    }
+
+    /// Waits for one of the requests submitted, returns `None` if there are
+    /// none outstanding.
+    fn poll(&mut self) -> Option<Completion> {
+        None
+    }
+}
+
+impl<T: BlockDevice + ?Sized> BlockDevice for &mut T {
+    fn block_size(&self) -> usize {
+        (**self).block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        (**self).block_count()
+    }
+
+    fn read_only(&self) -> bool {
+        (**self).read_only()
+    }
+
+    fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        (**self).read(block, buffer)
+    }
+
+    fn write(&mut self, block: u64, buffer: &[u8]) -> Result<(), Error> {
+        (**self).write(block, buffer)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        (**self).flush()
+    }
+
+    fn discard(&mut self, block: u64, count: u64) -> Result<(), Error> {
+        (**self).discard(block, count)
+    }
+
+    fn health(&mut self) -> Result<Health, Error> {
+        (**self).health()
+    }
+
+    fn queue_depth(&self) -> usize {
+        (**self).queue_depth()
+    }
+
+    unsafe fn submit(&mut self, request: Request) -> Option<Completion> {
+        unsafe { (**self).submit(request) }
+    }
+
+    fn poll(&mut self) -> Option<Completion> {
+        (**self).poll()
+    }
+}
+
+/// Executes the request with the synchronous operations of the device.
+///
+/// # Safety
+///
+/// The buffer has to be valid for the blocks of the request.
+pub unsafe fn execute<D: BlockDevice + ?Sized>(
+    device: &mut D,
+    request: &Request,
+) -> Result<(), Error> {
+    let buffer = || {
+        request
+            .length(device.block_size())
+            .filter(|_| !request.buffer.is_null())
+            .ok_or(Error::InvalidRequest)
+    };
+    match request.operation {
+        Operation::Read => {
+            let length = buffer()?;
+            device.read(request.block, unsafe {
+                slice::from_raw_parts_mut(request.buffer, length)
+            })
+        }
+        Operation::Write => {
+            let length = buffer()?;
+            device.write(request.block, unsafe {
+                slice::from_raw_parts(request.buffer, length)
+            })
+        }
+        Operation::Flush => device.flush(),
+        Operation::Discard => device.discard(request.block, request.count),
+    }
+}
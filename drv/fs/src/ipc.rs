@@ -0,0 +1,445 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::slice;
+
+use crate::{
+    blk::{BlockDevice, Error, Operation, Request},
+    queue::{Queue, QUEUE_LENGTH},
+};
+
+/// Returns the block size, block count and whether the device is read-only.
+const INFO: usize = 0;
+/// Shares the client's buffer, granted as physical memory, with its address
+/// and size in the words.
+const BUFFER: usize = 1;
+/// Reads blocks into the buffer, with the first block and count in the words.
+const READ: usize = 2;
+/// Writes blocks from the buffer, like [`READ`].
+const WRITE: usize = 3;
+const FLUSH: usize = 4;
+
+/// Label of a successful reply, others carry an [`Error`].
+const STATUS_OK: usize = 0;
+
+/// Serves a block device to another task, e.g. a USB stick's disk to the file
+/// system driver mounting it. Data is transferred through a buffer the client
+/// shares once.
+#[derive(Default)]
+pub struct Server {
+    /// Buffer shared by the client, mapped on its first request.
+    buffer: Option<(*mut u8, usize)>,
+}
+
+impl Server {
+    pub const fn new() -> Self {
+        Self { buffer: None }
+    }
+
+    /// Handles the client's request, and returns the reply.
+    pub fn handle<D: BlockDevice + ?Sized>(
+        &mut self,
+        device: &mut D,
+        request: &sys::Message,
+    ) -> sys::Message {
+        let [a, b, c, ..] = request.words;
+        let mut words = [0; sys::MESSAGE_WORDS];
+        let result = match request.label {
+            INFO => {
+                let [low, high] = split(device.block_count());
+                words[..4].copy_from_slice(&[
+                    device.block_size(),
+                    low,
+                    high,
+                    device.read_only() as usize,
+                ]);
+                Ok(())
+            }
+            BUFFER => self.share(join([a, b]), c),
+            READ => self
+                .buffer(device, join([a, b]), c)
+                .and_then(|(block, buffer)| device.read(block, buffer)),
+            WRITE => self
+                .buffer(device, join([a, b]), c)
+                .and_then(|(block, buffer)| device.write(block, buffer)),
+            FLUSH => device.flush(),
+            _ => Err(Error::Unsupported),
+        };
+        sys::Message::new(status(result), words)
+    }
+
+    /// Handles the requests waiting on the endpoint. Reads and writes are
+    /// submitted to the queue, so that they are dispatched together, and
+    /// replied to once the queue completed them.
+    pub fn serve<D: BlockDevice>(
+        &mut self,
+        queue: &mut Queue<D>,
+        endpoint: sys::Endpoint,
+    ) -> Result<(), sys::Error> {
+        let mut result = Ok(());
+        let mut replies = [const { None }; QUEUE_LENGTH];
+        while let Some(tag) = replies.iter().position(Option::is_none) {
+            let (request, reply) = match sys::try_receive(endpoint) {
+                Ok(Some(received)) => received,
+                Ok(None) => break,
+                Err(error) => {
+                    result = Err(error);
+                    break;
+                }
+            };
+            match self.submit(queue, &request, tag as u32) {
+                Some(message) => result = result.and(sys::reply(reply, &message)),
+                None => replies[tag] = Some(reply),
+            }
+        }
+        while let Some(completion) = queue.poll() {
+            if let Some(reply) = replies
+                .get_mut(completion.tag as usize)
+                .and_then(Option::take)
+            {
+                let message = sys::Message::new(status(completion.result), [0; sys::MESSAGE_WORDS]);
+                result = result.and(sys::reply(reply, &message));
+            }
+        }
+        result
+    }
+
+    /// Submits a read or write to the queue, tagged with the given tag, and
+    /// handles other requests right away. Returns the reply, unless the
+    /// request was queued.
+    fn submit<D: BlockDevice>(
+        &mut self,
+        queue: &mut Queue<D>,
+        request: &sys::Message,
+        tag: u32,
+    ) -> Option<sys::Message> {
+        let operation = match request.label {
+            READ => Operation::Read,
+            WRITE => Operation::Write,
+            _ => return Some(self.handle(queue, request)),
+        };
+        let [a, b, c, ..] = request.words;
+        let (block, buffer) = match self.buffer(queue, join([a, b]), c) {
+            Ok(transfer) => transfer,
+            Err(error) => return Some(failure(error)),
+        };
+        let request = Request {
+            operation,
+            block,
+            count: c as u64,
+            buffer: buffer.as_mut_ptr(),
+            tag,
+        };
+        // the shared buffer stays mapped, and the client waits for the reply
+        let completion = unsafe { queue.submit(request) }?;
+        Some(sys::Message::new(
+            status(completion.result),
+            [0; sys::MESSAGE_WORDS],
+        ))
+    }
+
+    /// Maps the client's buffer, which can only be shared once.
+    fn share(&mut self, phys_addr: u64, size: usize) -> Result<(), Error> {
+        if self.buffer.is_some() || size == 0 {
+            return Err(Error::InvalidRequest);
+        }
+        let phys_addr = usize::try_from(phys_addr).map_err(|_| Error::InvalidRequest)?;
+        let buffer = sys::memory_map(phys_addr, size).map_err(|_| Error::InvalidRequest)?;
+        self.buffer = Some((buffer, size));
+        Ok(())
+    }
+
+    /// Returns the part of the shared buffer for the blocks transferred.
+    fn buffer<D: BlockDevice + ?Sized>(
+        &mut self,
+        device: &D,
+        block: u64,
+        count: usize,
+    ) -> Result<(u64, &mut [u8]), Error> {
+        let (buffer, size) = self.buffer.ok_or(Error::InvalidRequest)?;
+        let length = count
+            .checked_mul(device.block_size())
+            .filter(|length| *length <= size)
+            .ok_or(Error::InvalidRequest)?;
+        Ok((block, unsafe { slice::from_raw_parts_mut(buffer, length) }))
+    }
+}
+
+/// Block device served by another task, see [`Server`].
+pub struct Client {
+    endpoint: sys::Endpoint,
+    buffer: *mut u8,
+    size: usize,
+    block_size: usize,
+    block_count: u64,
+    read_only: bool,
+}
+
+impl Client {
+    /// Connects to the block device served on the endpoint, and shares a
+    /// buffer of the given size for the data transferred, which limits the
+    /// blocks per request.
+    pub fn connect(endpoint: sys::Endpoint, size: usize) -> Result<Self, Error> {
+        let buffer = sys::dma_allocate(size).map_err(|_| Error::Device)?;
+        let phys_addr = sys::physical_address(buffer).map_err(|_| Error::Device)? as u64;
+        let [low, high] = split(phys_addr);
+        let mut request = message(BUFFER, [low, high, size]);
+        request
+            .grant(sys::Grant::memory(phys_addr..phys_addr + size as u64))
+            .map_err(|_| Error::Device)?;
+        call(endpoint, request)?;
+
+        let words = call(endpoint, message(INFO, [0; 3]))?;
+        let block_size = words[0];
+        if block_size == 0 || size < block_size {
+            return Err(Error::Unsupported);
+        }
+        Ok(Self {
+            endpoint,
+            buffer,
+            size,
+            block_size,
+            block_count: join([words[1], words[2]]),
+            read_only: words[3] != 0,
+        })
+    }
+
+    /// Transfers the blocks in requests of at most the buffer's size.
+    fn transfer(
+        &mut self,
+        block: u64,
+        length: usize,
+        label: usize,
+        mut f: impl FnMut(&mut [u8], usize),
+    ) -> Result<(), Error> {
+        if !length.is_multiple_of(self.block_size) {
+            return Err(Error::InvalidRequest);
+        }
+        let per_request = self.size / self.block_size;
+        let count = length / self.block_size;
+        let mut done = 0;
+        while done < count {
+            let chunk = (count - done).min(per_request);
+            let [low, high] = split(block + done as u64);
+            let buffer = unsafe { slice::from_raw_parts_mut(self.buffer, chunk * self.block_size) };
+            if label == WRITE {
+                f(buffer, done * self.block_size);
+            }
+            call(self.endpoint, message(label, [low, high, chunk]))?;
+            if label == READ {
+                f(buffer, done * self.block_size);
+            }
+            done += chunk;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for Client {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        self.transfer(block, buffer.len(), READ, |shared, offset| {
+            buffer[offset..offset + shared.len()].copy_from_slice(shared)
+        })
+    }
+
+    fn write(&mut self, block: u64, buffer: &[u8]) -> Result<(), Error> {
+        self.transfer(block, buffer.len(), WRITE, |shared, offset| {
+            shared.copy_from_slice(&buffer[offset..offset + shared.len()])
+        })
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        call(self.endpoint, message(FLUSH, [0; 3])).map(|_| ())
+    }
+}
+
+/// Returns the reply of a request which failed before reaching the device,
+/// e.g. as it has gone.
+pub fn failure(error: Error) -> sys::Message {
+    sys::Message::new(status(Err(error)), [0; sys::MESSAGE_WORDS])
+}
+
+fn message(label: usize, words: [usize; 3]) -> sys::Message {
+    let mut message = sys::Message::new(label, [0; sys::MESSAGE_WORDS]);
+    message.words[..3].copy_from_slice(&words);
+    message
+}
+
+/// Calls the server, and returns the words of its reply.
+fn call(
+    endpoint: sys::Endpoint,
+    mut message: sys::Message,
+) -> Result<[usize; sys::MESSAGE_WORDS], Error> {
+    sys::call(endpoint, &mut message).map_err(|_| Error::Device)?;
+    result(message.label)?;
+    Ok(message.words)
+}
+
+fn status(result: Result<(), Error>) -> usize {
+    match result {
+        Ok(()) => STATUS_OK,
+        Err(Error::InvalidRequest) => 1,
+        Err(Error::Device) => 2,
+        Err(Error::Timeout) => 3,
+        Err(Error::ReadOnly) => 4,
+        Err(Error::Unsupported) => 5,
+        Err(Error::Busy) => 6,
+    }
+}
+
+fn result(status: usize) -> Result<(), Error> {
+    Err(match status {
+        STATUS_OK => return Ok(()),
+        1 => Error::InvalidRequest,
+        3 => Error::Timeout,
+        4 => Error::ReadOnly,
+        5 => Error::Unsupported,
+        6 => Error::Busy,
+        _ => Error::Device,
+    })
+}
+
+/// Splits the value into 32-bit words, as `usize` may be 32 bits.
+fn split(value: u64) -> [usize; 2] {
+    [value as u32 as usize, (value >> 32) as usize]
+}
+
+fn join([low, high]: [usize; 2]) -> u64 {
+    (low as u32 as u64) | (high as u64) << 32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::Scheduler;
+
+    #[test]
+    fn status() {
+        for error in [
+            Error::InvalidRequest,
+            Error::Device,
+            Error::Timeout,
+            Error::ReadOnly,
+            Error::Unsupported,
+            Error::Busy,
+        ] {
+            assert_eq!(result(super::status(Err(error))), Err(error));
+        }
+        assert_eq!(result(super::status(Ok(()))), Ok(()));
+    }
+
+    #[test]
+    fn split_join() {
+        for value in [0, 1, u32::MAX as u64, 1 << 32, u64::MAX] {
+            assert_eq!(join(split(value)), value);
+        }
+    }
+
+    #[test]
+    fn info_and_unshared_buffer() {
+        struct Disk;
+
+        impl BlockDevice for Disk {
+            fn block_size(&self) -> usize {
+                512
+            }
+
+            fn block_count(&self) -> u64 {
+                1 << 33
+            }
+
+            fn read(&mut self, _block: u64, _buffer: &mut [u8]) -> Result<(), Error> {
+                unreachable!()
+            }
+
+            fn write(&mut self, _block: u64, _buffer: &[u8]) -> Result<(), Error> {
+                unreachable!()
+            }
+        }
+
+        let mut server = Server::new();
+        let reply = server.handle(&mut Disk, &message(INFO, [0; 3]));
+        assert_eq!(result(reply.label), Ok(()));
+        assert_eq!(reply.words[0], 512);
+        assert_eq!(join([reply.words[1], reply.words[2]]), 1 << 33);
+        let reply = server.handle(&mut Disk, &message(READ, [0, 0, 1]));
+        assert_eq!(result(reply.label), Err(Error::InvalidRequest));
+    }
+
+    #[test]
+    fn queued_transfer() {
+        struct Disk(std::vec::Vec<u8>);
+
+        impl BlockDevice for Disk {
+            fn block_size(&self) -> usize {
+                512
+            }
+
+            fn block_count(&self) -> u64 {
+                (self.0.len() / 512) as u64
+            }
+
+            fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<(), Error> {
+                let start = block as usize * 512;
+                buffer.copy_from_slice(&self.0[start..start + buffer.len()]);
+                Ok(())
+            }
+
+            fn write(&mut self, block: u64, buffer: &[u8]) -> Result<(), Error> {
+                let start = block as usize * 512;
+                self.0[start..start + buffer.len()].copy_from_slice(buffer);
+                Ok(())
+            }
+        }
+
+        let mut shared = [0; 2 * 512];
+        let mut server = Server::new();
+        server.buffer = Some((shared.as_mut_ptr(), shared.len()));
+        let mut queue = Queue::new(Disk(std::vec![1; 4 * 512]), Scheduler::Fifo);
+
+        assert!(server
+            .submit(&mut queue, &message(READ, [1, 0, 2]), 3)
+            .is_none());
+        let completion = queue.poll().unwrap();
+        assert_eq!((completion.tag, completion.result), (3, Ok(())));
+        assert!(queue.poll().is_none());
+        assert_eq!(shared, [1; 2 * 512]);
+
+        // beyond the shared buffer, or the disk
+        let reply = server.submit(&mut queue, &message(WRITE, [0, 0, 3]), 0);
+        assert_eq!(
+            reply.map(|reply| result(reply.label)),
+            Some(Err(Error::InvalidRequest))
+        );
+        let reply = server.submit(&mut queue, &message(WRITE, [3, 0, 2]), 0);
+        assert_eq!(
+            reply.map(|reply| result(reply.label)),
+            Some(Err(Error::InvalidRequest))
+        );
+        let reply = server.submit(&mut queue, &message(INFO, [0; 3]), 0);
+        assert_eq!(reply.map(|reply| reply.words[0]), Some(512));
+    }
+}
@@ -0,0 +1,584 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::blk::{BlockDevice, Completion, Error, Health, Operation, Request};
+
+/// Requests queued at once, including the completed ones not taken yet.
+pub const QUEUE_LENGTH: usize = 32;
+
+/// Requests submitted after the oldest one waiting, before it's dispatched
+/// regardless of the elevator's position.
+const MAX_DEFER: u64 = 64;
+
+/// Order in which waiting requests are dispatched.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Scheduler {
+    /// In order of submission, for devices without seek times.
+    Fifo,
+    /// In ascending block order from the last request dispatched, wrapping
+    /// around at the end (C-LOOK).
+    Elevator,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Waiting,
+    /// Part of the device request tagged with the index of its first entry.
+    Dispatched(u32),
+    Completed(Result<(), Error>),
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    request: Request,
+    state: State,
+    /// Order of submission.
+    sequence: u64,
+    /// Submitted by the synchronous operations of the queue itself.
+    internal: bool,
+}
+
+/// Request queue on top of a block device. Adjacent requests are merged, and
+/// dispatched in the order of the scheduler once the queue is polled.
+/// Requests overlapping earlier ones, and flushes, aren't reordered.
+pub struct Queue<D: BlockDevice> {
+    device: D,
+    scheduler: Scheduler,
+    entries: [Option<Entry>; QUEUE_LENGTH],
+    /// Device requests outstanding.
+    dispatched: usize,
+    /// Block after the last request dispatched.
+    position: u64,
+    sequence: u64,
+}
+
+impl<D: BlockDevice> Queue<D> {
+    pub fn new(device: D, scheduler: Scheduler) -> Self {
+        Self {
+            device,
+            scheduler,
+            entries: [None; QUEUE_LENGTH],
+            dispatched: 0,
+            position: 0,
+            sequence: 0,
+        }
+    }
+
+    /// Validates the request, and adds it to the waiting ones. Returns its
+    /// index.
+    fn enqueue(&mut self, request: Request, internal: bool) -> Result<usize, Error> {
+        let valid = match request.operation {
+            Operation::Read | Operation::Write => {
+                request.length(self.device.block_size()).is_some() && !request.buffer.is_null()
+            }
+            Operation::Flush | Operation::Discard => true,
+        };
+        let end = request.block.checked_add(request.count);
+        if !valid || end.is_none_or(|end| end > self.device.block_count()) {
+            return Err(Error::InvalidRequest);
+        }
+        if matches!(request.operation, Operation::Write | Operation::Discard)
+            && self.device.read_only()
+        {
+            return Err(Error::ReadOnly);
+        }
+
+        let index = self
+            .entries
+            .iter()
+            .position(Option::is_none)
+            .ok_or(Error::Busy)?;
+        self.entries[index] = Some(Entry {
+            request,
+            state: State::Waiting,
+            sequence: self.sequence,
+            internal,
+        });
+        self.sequence += 1;
+        Ok(index)
+    }
+
+    /// Whether the waiting entry can be dispatched. It waits for earlier
+    /// requests it conflicts with, as the device may reorder requests
+    /// outstanding.
+    fn ready(&self, entry: &Entry) -> bool {
+        entry.state == State::Waiting
+            && self.entries.iter().flatten().all(|other| {
+                other.sequence >= entry.sequence
+                    || matches!(other.state, State::Completed(_))
+                    || !conflicts(&other.request, &entry.request)
+            })
+    }
+
+    /// Picks the next request to dispatch, by the scheduler, but the oldest
+    /// one if it waited too long.
+    fn next(&self) -> Option<usize> {
+        let ready = || {
+            self.entries
+                .iter()
+                .enumerate()
+                .filter_map(|(index, entry)| Some((index, entry.as_ref()?)))
+                .filter(|(_, entry)| self.ready(entry))
+        };
+        let (oldest, entry) = ready().min_by_key(|(_, entry)| entry.sequence)?;
+        if self.scheduler == Scheduler::Fifo || self.sequence - entry.sequence > MAX_DEFER {
+            return Some(oldest);
+        }
+        ready()
+            .min_by_key(|(_, entry)| (entry.request.block < self.position, entry.request.block))
+            .map(|(index, _)| index)
+    }
+
+    /// Merges the waiting requests adjacent to the first into a single device
+    /// request, reads and writes only if their buffers are contiguous too.
+    fn merge(&mut self, first: usize) -> Request {
+        let tag = first as u32;
+        let block_size = self.device.block_size();
+        let Some(entry) = &mut self.entries[first] else {
+            unreachable!()
+        };
+        entry.state = State::Dispatched(tag);
+        let mut request = Request {
+            tag,
+            ..entry.request
+        };
+        if request.operation == Operation::Flush {
+            return request;
+        }
+
+        loop {
+            let bytes = |request: &Request| match request.operation {
+                Operation::Read | Operation::Write => request.length(block_size).unwrap_or(0),
+                _ => 0,
+            };
+            let adjacent = |entry: &Entry| {
+                let other = &entry.request;
+                if other.operation != request.operation || !self.ready(entry) {
+                    return None;
+                }
+                // merged in front, or behind
+                if other.block.checked_add(other.count) == Some(request.block)
+                    && other.buffer.wrapping_add(bytes(other)) == request.buffer
+                {
+                    Some(true)
+                } else if request.block.checked_add(request.count) == Some(other.block)
+                    && request.buffer.wrapping_add(bytes(&request)) == other.buffer
+                {
+                    Some(false)
+                } else {
+                    None
+                }
+            };
+            let Some((index, front)) = self
+                .entries
+                .iter()
+                .enumerate()
+                .find_map(|(index, entry)| Some((index, adjacent(entry.as_ref()?)?)))
+            else {
+                break;
+            };
+            let Some(entry) = &mut self.entries[index] else {
+                break;
+            };
+            entry.state = State::Dispatched(tag);
+            if front {
+                request.block = entry.request.block;
+                request.buffer = entry.request.buffer;
+            }
+            request.count += entry.request.count;
+        }
+        request
+    }
+
+    /// Dispatches waiting requests, as long as the device accepts more.
+    /// Returns whether any were.
+    fn dispatch(&mut self) -> bool {
+        let mut progress = false;
+        while self.dispatched < self.device.queue_depth().max(1) {
+            let Some(first) = self.next() else {
+                break;
+            };
+            let request = self.merge(first);
+            if request.operation != Operation::Flush {
+                self.position = request.block + request.count;
+            }
+            self.dispatched += 1;
+            progress = true;
+            // the submitter guarantees the buffers are valid until completion
+            if let Some(completion) = unsafe { self.device.submit(request) } {
+                self.complete(completion);
+            }
+        }
+        progress
+    }
+
+    /// Completes the entries merged into the device request.
+    fn complete(&mut self, completion: Completion) {
+        self.dispatched = self.dispatched.saturating_sub(1);
+        for entry in self.entries.iter_mut().flatten() {
+            if entry.state == State::Dispatched(completion.tag) {
+                entry.state = State::Completed(completion.result);
+            }
+        }
+    }
+
+    /// Dispatches waiting requests, and waits for one of the device. Returns
+    /// `false` if there was nothing to do, devices completing synchronously
+    /// have none outstanding afterwards.
+    fn advance(&mut self) -> bool {
+        let progress = self.dispatch();
+        if self.dispatched == 0 {
+            return progress;
+        }
+        match self.device.poll() {
+            Some(completion) => self.complete(completion),
+            // the device lost track of them
+            None => {
+                log::warn!("Device requests lost");
+                self.dispatched = 0;
+                for entry in self.entries.iter_mut().flatten() {
+                    if matches!(entry.state, State::Dispatched(_)) {
+                        entry.state = State::Completed(Err(Error::Device));
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    /// Submits the request, and waits for its completion.
+    fn execute(
+        &mut self,
+        operation: Operation,
+        block: u64,
+        count: u64,
+        buffer: *mut u8,
+    ) -> Result<(), Error> {
+        let index = self.enqueue(
+            Request {
+                operation,
+                block,
+                count,
+                buffer,
+                tag: 0,
+            },
+            true,
+        )?;
+        loop {
+            let progress = self.advance();
+            if let Some(Entry {
+                state: State::Completed(result),
+                ..
+            }) = self.entries[index]
+            {
+                self.entries[index] = None;
+                return result;
+            }
+            if !progress {
+                self.entries[index] = None;
+                return Err(Error::Device);
+            }
+        }
+    }
+
+    /// Returns the blocks covered by the buffer.
+    fn count(&self, buffer: &[u8]) -> Result<u64, Error> {
+        let block_size = self.device.block_size();
+        let count = buffer.len() / block_size.max(1);
+        if block_size == 0 || count * block_size != buffer.len() {
+            return Err(Error::InvalidRequest);
+        }
+        Ok(count as u64)
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for Queue<D> {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn read_only(&self) -> bool {
+        self.device.read_only()
+    }
+
+    fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        let count = self.count(buffer)?;
+        self.execute(Operation::Read, block, count, buffer.as_mut_ptr())
+    }
+
+    fn write(&mut self, block: u64, buffer: &[u8]) -> Result<(), Error> {
+        let count = self.count(buffer)?;
+        self.execute(Operation::Write, block, count, buffer.as_ptr() as *mut u8)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.execute(Operation::Flush, 0, 0, core::ptr::null_mut())
+    }
+
+    fn discard(&mut self, block: u64, count: u64) -> Result<(), Error> {
+        self.execute(Operation::Discard, block, count, core::ptr::null_mut())
+    }
+
+    fn health(&mut self) -> Result<Health, Error> {
+        self.device.health()
+    }
+
+    fn queue_depth(&self) -> usize {
+        QUEUE_LENGTH
+    }
+
+    /// Queues the request, it's dispatched once the queue is polled, so that
+    /// the requests submitted meanwhile can be merged.
+    unsafe fn submit(&mut self, request: Request) -> Option<Completion> {
+        match self.enqueue(request, false) {
+            Ok(_) => None,
+            Err(error) => Some(Completion {
+                tag: request.tag,
+                result: Err(error),
+            }),
+        }
+    }
+
+    fn poll(&mut self) -> Option<Completion> {
+        loop {
+            let completed = self
+                .entries
+                .iter()
+                .enumerate()
+                .filter_map(|(index, entry)| Some((index, entry.as_ref()?)))
+                .filter(|(_, entry)| !entry.internal)
+                .filter_map(|(index, entry)| match entry.state {
+                    State::Completed(result) => {
+                        Some((index, entry.sequence, entry.request.tag, result))
+                    }
+                    _ => None,
+                })
+                .min_by_key(|(_, sequence, _, _)| *sequence);
+            if let Some((index, _, tag, result)) = completed {
+                self.entries[index] = None;
+                return Some(Completion { tag, result });
+            }
+            if !self.advance() {
+                return None;
+            }
+        }
+    }
+}
+
+/// Whether the requests have to be executed in order: flushes, and
+/// overlapping requests unless both read.
+fn conflicts(a: &Request, b: &Request) -> bool {
+    if a.operation == Operation::Flush || b.operation == Operation::Flush {
+        return true;
+    }
+    if a.operation == Operation::Read && b.operation == Operation::Read {
+        return false;
+    }
+    a.block < b.block.saturating_add(b.count) && b.block < a.block.saturating_add(a.count)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, vec, vec::Vec};
+
+    use super::*;
+    use crate::blk;
+
+    const BLOCK_SIZE: usize = 512;
+
+    /// Memory-backed device, which records the requests submitted. Requests
+    /// are executed right away, but only completed when polled if it has a
+    /// queue.
+    struct Disk {
+        data: Vec<u8>,
+        depth: usize,
+        submitted: Vec<Request>,
+        completions: VecDeque<Completion>,
+    }
+
+    impl Disk {
+        fn new(depth: usize) -> Self {
+            Self {
+                data: vec![0; 64 * BLOCK_SIZE],
+                depth,
+                submitted: Vec::new(),
+                completions: VecDeque::new(),
+            }
+        }
+
+        fn blocks(&self) -> Vec<(Operation, u64, u64)> {
+            self.submitted
+                .iter()
+                .map(|request| (request.operation, request.block, request.count))
+                .collect()
+        }
+    }
+
+    impl BlockDevice for Disk {
+        fn block_size(&self) -> usize {
+            BLOCK_SIZE
+        }
+
+        fn block_count(&self) -> u64 {
+            (self.data.len() / BLOCK_SIZE) as u64
+        }
+
+        fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<(), Error> {
+            let start = block as usize * BLOCK_SIZE;
+            buffer.copy_from_slice(&self.data[start..start + buffer.len()]);
+            Ok(())
+        }
+
+        fn write(&mut self, block: u64, buffer: &[u8]) -> Result<(), Error> {
+            let start = block as usize * BLOCK_SIZE;
+            self.data[start..start + buffer.len()].copy_from_slice(buffer);
+            Ok(())
+        }
+
+        fn queue_depth(&self) -> usize {
+            self.depth
+        }
+
+        unsafe fn submit(&mut self, request: Request) -> Option<Completion> {
+            self.submitted.push(request);
+            let completion = Completion {
+                tag: request.tag,
+                result: unsafe { blk::execute(self, &request) },
+            };
+            if self.depth == 1 {
+                return Some(completion);
+            }
+            self.completions.push_back(completion);
+            None
+        }
+
+        fn poll(&mut self) -> Option<Completion> {
+            self.completions.pop_front()
+        }
+    }
+
+    fn read(block: u64, buffer: &mut [u8], tag: u32) -> Request {
+        Request {
+            operation: Operation::Read,
+            block,
+            count: (buffer.len() / BLOCK_SIZE) as u64,
+            buffer: buffer.as_mut_ptr(),
+            tag,
+        }
+    }
+
+    /// Polls the queue until it's empty, returns the tags completed.
+    fn drain<D: BlockDevice>(queue: &mut Queue<D>) -> Vec<u32> {
+        let mut tags = Vec::new();
+        while let Some(completion) = queue.poll() {
+            assert_eq!(completion.result, Ok(()));
+            tags.push(completion.tag);
+        }
+        tags
+    }
+
+    #[test]
+    fn synchronous_device() {
+        let mut queue = Queue::new(Disk::new(1), Scheduler::Elevator);
+        // more than fit into the queue, entries have to be released
+        for block in 0..(QUEUE_LENGTH as u64 * 2) {
+            let buffer = [block as u8; BLOCK_SIZE];
+            assert_eq!(queue.write(block % 64, &buffer), Ok(()));
+            let mut buffer = [0; BLOCK_SIZE];
+            assert_eq!(queue.read(block % 64, &mut buffer), Ok(()));
+            assert_eq!(buffer, [block as u8; BLOCK_SIZE]);
+        }
+        assert_eq!(queue.flush(), Ok(()));
+    }
+
+    #[test]
+    fn merge() {
+        let mut queue = Queue::new(Disk::new(4), Scheduler::Elevator);
+        queue.device.data[..3 * BLOCK_SIZE].fill(1);
+        let mut buffer = [0; 3 * BLOCK_SIZE];
+        let (first, rest) = buffer.split_at_mut(BLOCK_SIZE);
+        let (second, third) = rest.split_at_mut(BLOCK_SIZE);
+
+        unsafe {
+            assert!(queue.submit(read(1, second, 1)).is_none());
+            assert!(queue.submit(read(2, third, 2)).is_none());
+            assert!(queue.submit(read(0, first, 0)).is_none());
+        }
+        let mut tags = drain(&mut queue);
+        tags.sort();
+        assert_eq!(tags, [0, 1, 2]);
+
+        assert_eq!(queue.device.blocks(), [(Operation::Read, 0, 3)]);
+        assert_eq!(buffer, [1; 3 * BLOCK_SIZE]);
+    }
+
+    #[test]
+    fn elevator() {
+        let mut queue = Queue::new(Disk::new(1), Scheduler::Elevator);
+        let mut buffers = [[0; BLOCK_SIZE]; 5];
+        let [a, b, c, d, e] = &mut buffers;
+        unsafe {
+            assert!(queue.submit(read(30, a, 30)).is_none());
+            assert_eq!(drain(&mut queue), [30]);
+            // ascending from the last one, then wrapping around
+            assert!(queue.submit(read(10, b, 10)).is_none());
+            assert!(queue.submit(read(50, c, 50)).is_none());
+            assert!(queue.submit(read(20, d, 20)).is_none());
+            assert!(queue.submit(read(40, e, 40)).is_none());
+        }
+        drain(&mut queue);
+
+        let blocks: Vec<_> = queue
+            .device
+            .blocks()
+            .iter()
+            .map(|&(_, block, _)| block)
+            .collect();
+        assert_eq!(blocks, [30, 40, 50, 10, 20]);
+    }
+
+    #[test]
+    fn flush() {
+        let mut queue = Queue::new(Disk::new(4), Scheduler::Elevator);
+        let mut buffers = [[0; BLOCK_SIZE]; 2];
+        let [a, b] = &mut buffers;
+        let flush = Request {
+            operation: Operation::Flush,
+            block: 0,
+            count: 0,
+            buffer: core::ptr::null_mut(),
+            tag: 1,
+        };
+        unsafe {
+            assert!(queue.submit(read(5, a, 0)).is_none());
+            assert!(queue.submit(flush).is_none());
+            // before the first one in block order, but not across the flush
+            assert!(queue.submit(read(1, b, 2)).is_none());
+        }
+        assert_eq!(drain(&mut queue), [0, 1, 2]);
+
+        assert_eq!(
+            queue.device.blocks(),
+            [
+                (Operation::Read, 5, 1),
+                (Operation::Flush, 0, 0),
+                (Operation::Read, 1, 1),
+            ]
+        );
+    }
+}
@@ -0,0 +1,252 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![no_std]
+
+use bitflags::bitflags;
+
+/// Events kept until they are consumed.
+pub const QUEUE_SIZE: usize = 64;
+
+/// Bytes of an encoded event, see [`Event::encode`].
+pub const EVENT_SIZE: usize = 8;
+
+bitflags! {
+    /// Modifier keys, in the order of their usage IDs and bits in boot
+    /// keyboard reports.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct Modifiers: u8 {
+        const LEFT_CTRL = 1 << 0;
+        const LEFT_SHIFT = 1 << 1;
+        const LEFT_ALT = 1 << 2;
+        const LEFT_GUI = 1 << 3;
+        const RIGHT_CTRL = 1 << 4;
+        const RIGHT_SHIFT = 1 << 5;
+        const RIGHT_ALT = 1 << 6;
+        const RIGHT_GUI = 1 << 7;
+    }
+
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct Buttons: u8 {
+        const LEFT = 1 << 0;
+        const RIGHT = 1 << 1;
+        const MIDDLE = 1 << 2;
+        const BACK = 1 << 3;
+        const FORWARD = 1 << 4;
+    }
+}
+
+impl Modifiers {
+    pub fn shift(self) -> bool {
+        self.intersects(Self::LEFT_SHIFT | Self::RIGHT_SHIFT)
+    }
+
+    pub fn ctrl(self) -> bool {
+        self.intersects(Self::LEFT_CTRL | Self::RIGHT_CTRL)
+    }
+
+    pub fn alt(self) -> bool {
+        self.intersects(Self::LEFT_ALT | Self::RIGHT_ALT)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Event {
+    /// A key went down or up, the scancode is its usage ID on the HID
+    /// keyboard page, modifiers are those held afterwards.
+    Key {
+        scancode: u8,
+        pressed: bool,
+        modifiers: Modifiers,
+    },
+    /// Relative motion, with y growing downwards, and wheel detents.
+    Motion {
+        x: i16,
+        y: i16,
+        wheel: i8,
+    },
+    /// Absolute position, scaled to the whole range.
+    Position {
+        x: u16,
+        y: u16,
+    },
+    Button {
+        button: Buttons,
+        pressed: bool,
+    },
+}
+
+impl Event {
+    const KEY: u8 = 1;
+    const MOTION: u8 = 2;
+    const POSITION: u8 = 3;
+    const BUTTON: u8 = 4;
+
+    /// Encodes the event as passed from drivers to the console, the kind
+    /// followed by its fields, little-endian.
+    pub fn encode(&self) -> [u8; EVENT_SIZE] {
+        let mut bytes = [0; EVENT_SIZE];
+        match *self {
+            Self::Key {
+                scancode,
+                pressed,
+                modifiers,
+            } => {
+                bytes[..4].copy_from_slice(&[Self::KEY, scancode, pressed as u8, modifiers.bits()])
+            }
+            Self::Motion { x, y, wheel } => {
+                bytes[0] = Self::MOTION;
+                bytes[1..3].copy_from_slice(&x.to_le_bytes());
+                bytes[3..5].copy_from_slice(&y.to_le_bytes());
+                bytes[5] = wheel as u8;
+            }
+            Self::Position { x, y } => {
+                bytes[0] = Self::POSITION;
+                bytes[1..3].copy_from_slice(&x.to_le_bytes());
+                bytes[3..5].copy_from_slice(&y.to_le_bytes());
+            }
+            Self::Button { button, pressed } => {
+                bytes[..3].copy_from_slice(&[Self::BUTTON, button.bits(), pressed as u8])
+            }
+        }
+        bytes
+    }
+
+    /// Decodes an event encoded by [`Event::encode`].
+    pub fn decode(bytes: [u8; EVENT_SIZE]) -> Option<Self> {
+        let word = |offset: usize| [bytes[offset], bytes[offset + 1]];
+        Some(match bytes[0] {
+            Self::KEY => Self::Key {
+                scancode: bytes[1],
+                pressed: bytes[2] != 0,
+                modifiers: Modifiers::from_bits_retain(bytes[3]),
+            },
+            Self::MOTION => Self::Motion {
+                x: i16::from_le_bytes(word(1)),
+                y: i16::from_le_bytes(word(3)),
+                wheel: bytes[5] as i8,
+            },
+            Self::POSITION => Self::Position {
+                x: u16::from_le_bytes(word(1)),
+                y: u16::from_le_bytes(word(3)),
+            },
+            Self::BUTTON => Self::Button {
+                button: Buttons::from_bits(bytes[1])?,
+                pressed: bytes[2] != 0,
+            },
+            _ => return None,
+        })
+    }
+}
+
+/// Returns the character typed by the key, with the US layout.
+pub fn character(scancode: u8, modifiers: Modifiers) -> Option<char> {
+    const DIGITS: &[u8; 10] = b"1234567890";
+    const DIGITS_SHIFTED: &[u8; 10] = b"!@#$%^&*()";
+    // 0x32 is the non-US hash key
+    const SYMBOLS: &[u8; 11] = b"-=[]\\\0;'`,.";
+    const SYMBOLS_SHIFTED: &[u8; 11] = b"_+{}|\0:\"~<>";
+    let shift = modifiers.shift();
+    let character = match scancode {
+        0x04..=0x1D => {
+            let letter = b'a' + (scancode - 0x04);
+            if shift {
+                letter.to_ascii_uppercase()
+            } else {
+                letter
+            }
+        }
+        0x1E..=0x27 => {
+            let index = (scancode - 0x1E) as usize;
+            if shift {
+                DIGITS_SHIFTED[index]
+            } else {
+                DIGITS[index]
+            }
+        }
+        0x28 => b'\n',
+        0x29 => 0x1B,
+        0x2A => 0x08,
+        0x2B => b'\t',
+        0x2C => b' ',
+        0x2D..=0x37 => {
+            let index = (scancode - 0x2D) as usize;
+            if shift {
+                SYMBOLS_SHIFTED[index]
+            } else {
+                SYMBOLS[index]
+            }
+        }
+        0x38 => {
+            if shift {
+                b'?'
+            } else {
+                b'/'
+            }
+        }
+        _ => return None,
+    };
+    (character != 0).then_some(character as char)
+}
+
+/// Events in the order they happened, the oldest are dropped once it's full.
+pub struct Queue {
+    events: [Option<Event>; QUEUE_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl Queue {
+    pub const fn new() -> Self {
+        Self {
+            events: [None; QUEUE_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, event: Event) {
+        let tail = (self.head + self.len) % QUEUE_SIZE;
+        self.events[tail] = Some(event);
+        if self.len == QUEUE_SIZE {
+            self.head = (self.head + 1) % QUEUE_SIZE;
+        } else {
+            self.len += 1;
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<Event> {
+        if self.len == 0 {
+            return None;
+        }
+        let event = self.events[self.head].take();
+        self.head = (self.head + 1) % QUEUE_SIZE;
+        self.len -= 1;
+        event
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+impl Default for Queue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,188 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::str;
+
+pub const DATA_SET_MANAGEMENT: u8 = 0x06;
+pub const READ_DMA_EXT: u8 = 0x25;
+pub const WRITE_DMA_EXT: u8 = 0x35;
+pub const READ_FPDMA_QUEUED: u8 = 0x60;
+pub const WRITE_FPDMA_QUEUED: u8 = 0x61;
+pub const READ_LOG_EXT: u8 = 0x2F;
+pub const PACKET: u8 = 0xA0;
+pub const IDENTIFY_PACKET_DEVICE: u8 = 0xA1;
+pub const SMART: u8 = 0xB0;
+pub const FLUSH_CACHE: u8 = 0xE7;
+pub const FLUSH_CACHE_EXT: u8 = 0xEA;
+pub const IDENTIFY_DEVICE: u8 = 0xEC;
+pub const SET_FEATURES: u8 = 0xEF;
+
+/// SET FEATURES subcommand, and the Serial ATA feature it enables in the count
+pub const FEATURE_ENABLE_SATA: u8 = 0x10;
+pub const SATA_FEATURE_DEVSLEEP: u16 = 0x09;
+
+/// DATA SET MANAGEMENT function, and its LBA range entries
+pub const DSM_TRIM: u8 = 0x01;
+pub const DSM_RANGE_MAX_LEN: u64 = 0xFFFF;
+
+/// SMART subcommands, which have to be issued with the signature in the LBA
+pub const SMART_READ_DATA: u8 = 0xD0;
+pub const SMART_ENABLE_OPERATIONS: u8 = 0xD8;
+pub const SMART_RETURN_STATUS: u8 = 0xDA;
+pub const SMART_SIGNATURE: u64 = 0xC24F00;
+/// SMART RETURN STATUS: LBA when a threshold is exceeded
+pub const SMART_THRESHOLD_EXCEEDED: u64 = 0x2CF400;
+
+/// SMART attributes
+pub const ATTRIBUTE_REALLOCATED_SECTORS: u8 = 5;
+pub const ATTRIBUTE_POWER_ON_HOURS: u8 = 9;
+pub const ATTRIBUTE_TEMPERATURE: u8 = 194;
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// Log address of the NCQ Command Error log.
+pub const LOG_NCQ_COMMAND_ERROR: u64 = 0x10;
+
+/// Queue depth: maximum queue depth - 1
+const WORD_75_QUEUE_DEPTH: u16 = (1 << 5) - 1;
+/// Serial ATA capabilities: supports the NCQ feature set
+const WORD_76_NCQ: u16 = 1 << 8;
+/// Serial ATA capabilities: supports host-initiated power management
+const WORD_76_HIPM: u16 = 1 << 9;
+/// Serial ATA features supported: device sleep
+const WORD_78_DEVSLEEP: u16 = 1 << 8;
+/// Commands and feature sets supported: SMART feature set
+const WORD_82_SMART: u16 = 1 << 0;
+/// Commands and feature sets supported: 48-bit Address feature set
+const WORD_83_LBA48: u16 = 1 << 10;
+/// Commands and feature sets supported: FLUSH CACHE EXT
+const WORD_83_FLUSH_CACHE_EXT: u16 = 1 << 13;
+/// Commands and feature sets enabled: SMART feature set
+const WORD_85_SMART: u16 = 1 << 0;
+/// Physical/logical sector size: valid if bit 14 is set and bit 15 clear
+const WORD_106_VALID: u16 = 3 << 14;
+const WORD_106_VALID_VALUE: u16 = 1 << 14;
+/// Physical/logical sector size: logical sector longer than 256 words
+const WORD_106_LOGICAL_SECTOR_SIZE: u16 = 1 << 12;
+/// Data Set Management: TRIM supported
+const WORD_169_TRIM: u16 = 1 << 0;
+
+/// Data returned by IDENTIFY (PACKET) DEVICE.
+pub struct Identity {
+    serial: [u8; 20],
+    firmware: [u8; 8],
+    model: [u8; 40],
+    /// Number of addressable logical sectors.
+    pub sectors: u64,
+    /// Bytes per logical sector.
+    pub sector_size: u32,
+    /// Whether 48-bit commands, e.g. READ DMA EXT, are supported.
+    pub lba48: bool,
+    /// Commands the device can queue, 0 without native command queuing.
+    pub queue_depth: u8,
+    /// Whether the host may put the link into partial or slumber.
+    pub hipm: bool,
+    /// Whether the device supports device sleep.
+    pub devsleep: bool,
+    pub flush_cache_ext: bool,
+    pub trim: bool,
+    pub smart: bool,
+    pub smart_enabled: bool,
+}
+
+impl Identity {
+    pub fn parse(data: &[u8; SECTOR_SIZE]) -> Self {
+        let word = |index: usize| u16::from_le_bytes([data[index * 2], data[index * 2 + 1]]);
+        let dword = |index: usize| word(index) as u32 | (word(index + 1) as u32) << 16;
+
+        let lba48 = word(83) & WORD_83_LBA48 != 0;
+        let sectors = if lba48 {
+            dword(100) as u64 | (dword(102) as u64) << 32
+        } else {
+            dword(60) as u64
+        };
+        let sector_size = if word(106) & WORD_106_VALID == WORD_106_VALID_VALUE
+            && word(106) & WORD_106_LOGICAL_SECTOR_SIZE != 0
+        {
+            dword(117) * 2
+        } else {
+            SECTOR_SIZE as u32
+        };
+        // the words are reserved for PATA devices
+        let sata = word(76) != 0 && word(76) != 0xFFFF;
+        let queue_depth = if sata && word(76) & WORD_76_NCQ != 0 {
+            (word(75) & WORD_75_QUEUE_DEPTH) as u8 + 1
+        } else {
+            0
+        };
+        Self {
+            serial: string(data, 10),
+            firmware: string(data, 23),
+            model: string(data, 27),
+            sectors,
+            sector_size,
+            lba48,
+            queue_depth,
+            hipm: sata && word(76) & WORD_76_HIPM != 0,
+            devsleep: sata && word(78) != 0xFFFF && word(78) & WORD_78_DEVSLEEP != 0,
+            flush_cache_ext: word(83) & WORD_83_FLUSH_CACHE_EXT != 0,
+            trim: word(169) & WORD_169_TRIM != 0,
+            smart: word(82) & WORD_82_SMART != 0,
+            smart_enabled: word(85) & WORD_85_SMART != 0,
+        }
+    }
+
+    pub fn serial(&self) -> &str {
+        text(&self.serial)
+    }
+
+    pub fn firmware(&self) -> &str {
+        text(&self.firmware)
+    }
+
+    pub fn model(&self) -> &str {
+        text(&self.model)
+    }
+}
+
+/// Returns the raw value of the attribute in SMART READ DATA.
+pub fn smart_attribute(data: &[u8; SECTOR_SIZE], id: u8) -> Option<u64> {
+    // 30 entries of 12 bytes follow the revision
+    data[2..2 + 30 * 12]
+        .chunks_exact(12)
+        .find(|attribute| attribute[0] == id)
+        .map(|attribute| {
+            let mut raw = [0; 8];
+            raw[..6].copy_from_slice(&attribute[5..11]);
+            u64::from_le_bytes(raw)
+        })
+}
+
+/// Reads a string starting at the given word, the bytes of each word are
+/// swapped.
+fn string<const N: usize>(data: &[u8; SECTOR_SIZE], word: usize) -> [u8; N] {
+    let mut string = [0; N];
+    for (chunk, bytes) in string
+        .chunks_exact_mut(2)
+        .zip(data[word * 2..].chunks_exact(2))
+    {
+        chunk.copy_from_slice(&[bytes[1], bytes[0]]);
+    }
+    string
+}
+
+/// Strings are padded with spaces.
+fn text(string: &[u8]) -> &str {
+    str::from_utf8(string).unwrap_or("?").trim()
+}
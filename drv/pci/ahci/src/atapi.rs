@@ -0,0 +1,136 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::str;
+
+use crate::port::{Error, Port};
+
+const INQUIRY: u8 = 0x12;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const READ_12: u8 = 0xA8;
+
+/// Bytes of the standard INQUIRY data up to the product revision level.
+const INQUIRY_LENGTH: usize = 36;
+const READ_CAPACITY_LENGTH: usize = 8;
+
+/// Peripheral device type of CD/DVD devices
+const TYPE_MMC: u8 = 5;
+
+/// Sense keys, reported in the upper nibble of the error register
+const SENSE_NOT_READY: u8 = 0x2;
+pub const SENSE_UNIT_ATTENTION: u8 = 0x6;
+
+/// Block size of CD/DVD media, used until the capacity is read.
+pub const BLOCK_SIZE: usize = 2048;
+
+/// Capacity of the medium, returned by READ CAPACITY.
+#[derive(Clone, Copy)]
+pub struct Capacity {
+    pub blocks: u64,
+    pub block_size: u32,
+}
+
+impl Port {
+    /// Logs the vendor, product and type of the device.
+    pub fn inquiry(&mut self) -> Result<(), Error> {
+        let (buffer, region) = self.buffer(INQUIRY_LENGTH);
+        self.packet(&[INQUIRY, 0, 0, 0, INQUIRY_LENGTH as u8, 0], &[region])?;
+
+        let data = unsafe { &*buffer };
+        let text = |range| str::from_utf8(&data[range]).unwrap_or("?").trim();
+        log::info!(
+            "Port {}: {} {} {}{}",
+            self.number,
+            text(8..16),
+            text(16..32),
+            text(32..36),
+            if data[0] & 0x1F == TYPE_MMC {
+                " (CD/DVD)"
+            } else {
+                ""
+            }
+        );
+        Ok(())
+    }
+
+    /// Reads the capacity of the medium, which is `None` without one, or if
+    /// its block size isn't a power of two.
+    pub fn read_capacity(&mut self) -> Result<(), Error> {
+        self.capacity = None;
+        let (buffer, region) = self.buffer(READ_CAPACITY_LENGTH);
+        let mut cdb = [0; 10];
+        cdb[0] = READ_CAPACITY_10;
+        // the first command after a medium change reports it
+        let mut result = self.packet(&cdb, &[region]);
+        if sense_key(&result) == Some(SENSE_UNIT_ATTENTION) {
+            result = self.packet(&cdb, &[region]);
+        }
+        match result {
+            Ok(()) => {}
+            Err(_) if sense_key(&result) == Some(SENSE_NOT_READY) => {
+                log::info!("Port {}: no medium", self.number);
+                return Ok(());
+            }
+            Err(error) => return Err(error),
+        }
+
+        let data = unsafe { &*buffer };
+        let last = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+        let block_size = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+        if !block_size.is_power_of_two() {
+            return Err(Error::BlockSize(block_size));
+        }
+        let capacity = Capacity {
+            blocks: last as u64 + 1,
+            block_size,
+        };
+        log::info!(
+            "Port {}: medium of {} blocks of {} bytes, {} MiB",
+            self.number,
+            capacity.blocks,
+            capacity.block_size,
+            (capacity.blocks * capacity.block_size as u64) >> 20
+        );
+        self.capacity = Some(capacity);
+        Ok(())
+    }
+}
+
+/// Returns READ (10), or READ (12) if the count doesn't fit.
+pub fn read(block: u32, count: u32) -> [u8; 12] {
+    let block = block.to_be_bytes();
+    match u16::try_from(count) {
+        Ok(count) => {
+            let count = count.to_be_bytes();
+            [
+                READ_10, 0, block[0], block[1], block[2], block[3], 0, count[0], count[1], 0, 0, 0,
+            ]
+        }
+        Err(_) => {
+            let count = count.to_be_bytes();
+            [
+                READ_12, 0, block[0], block[1], block[2], block[3], count[0], count[1], count[2],
+                count[3], 0, 0,
+            ]
+        }
+    }
+}
+
+pub fn sense_key(result: &Result<(), Error>) -> Option<u8> {
+    match result {
+        Err(Error::Device { error, .. }) => Some(error >> 4),
+        _ => None,
+    }
+}
@@ -0,0 +1,418 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use drv_fs::blk::{self, BlockDevice};
+
+use crate::{
+    ata, atapi,
+    port::{self, Port, Region, PRDT_LENGTH},
+    H2DRegisterFIS,
+};
+
+const BYTES_PER_PAGE: usize = 4096;
+
+/// Bytes a single physical region descriptor can transfer.
+const PRD_MAX_LEN: u32 = 4 << 20;
+
+/// Sectors of a 48-bit command, a count of 0 stands for 65536.
+const MAX_SECTORS: usize = 1 << 16;
+
+impl Port {
+    /// Transfers the buffer with as few commands as possible, each covers as
+    /// many sectors as its scatter-gather list can describe. With native
+    /// command queuing, up to the queue depth of them are outstanding, ATAPI
+    /// PACKET commands are issued one at a time.
+    fn transfer(
+        &mut self,
+        lba: u64,
+        buffer: *mut u8,
+        len: usize,
+        write: bool,
+    ) -> Result<(), blk::Error> {
+        let sector_size = self.block_size();
+        let sectors = len / sector_size;
+        if sectors * sector_size != len
+            || buffer.align_offset(2) != 0
+            || lba + sectors as u64 > self.block_count()
+        {
+            return Err(blk::Error::InvalidRequest);
+        }
+        let (command, name) = match (self.ncq, write) {
+            _ if self.atapi => (ata::PACKET, "READ"),
+            (false, false) => (ata::READ_DMA_EXT, "READ DMA EXT"),
+            (false, true) => (ata::WRITE_DMA_EXT, "WRITE DMA EXT"),
+            (true, false) => (ata::READ_FPDMA_QUEUED, "READ FPDMA QUEUED"),
+            (true, true) => (ata::WRITE_FPDMA_QUEUED, "WRITE FPDMA QUEUED"),
+        };
+
+        let mut outstanding = 0;
+        let mut result = Ok(());
+        let mut offset = 0;
+        while offset < len && result.is_ok() {
+            let Some(slot) = self.free_slot() else {
+                result = self.complete(&mut outstanding, name);
+                continue;
+            };
+            let max_len = if self.atapi {
+                len - offset
+            } else {
+                (len - offset).min(MAX_SECTORS * sector_size)
+            };
+            let Some((regions, count, command_len)) =
+                scatter(buffer as usize + offset, max_len, sector_size)
+            else {
+                result = Err(blk::Error::InvalidRequest);
+                break;
+            };
+
+            let sector = lba + (offset / sector_size) as u64;
+            if self.atapi {
+                let cdb = atapi::read(sector as u32, (command_len / sector_size) as u32);
+                let packet = self.packet(&cdb, &regions[..count]);
+                // the medium changed, the request is failed as it may address
+                // the previous one
+                if atapi::sense_key(&packet) == Some(atapi::SENSE_UNIT_ATTENTION) {
+                    log::info!("Port {}: medium changed", self.number);
+                    if let Err(error) = self.read_capacity() {
+                        failure(self.number, "READ CAPACITY", error);
+                    }
+                }
+                result = packet.map_err(|error| failure(self.number, name, error));
+                offset += command_len;
+                continue;
+            }
+            let sector_count = (command_len / sector_size) as u16;
+            let fis = if self.ncq {
+                H2DRegisterFIS::queued(command, sector, sector_count, slot)
+            } else {
+                H2DRegisterFIS::command(command, sector, sector_count)
+            };
+            self.issue(slot, fis, &regions[..count], write);
+            outstanding |= 1 << slot;
+            offset += command_len;
+        }
+        // the commands issued access the buffer until they completed
+        while outstanding != 0 {
+            result = result.and(self.complete(&mut outstanding, name));
+        }
+        result
+    }
+
+    /// Issues the read or write as a single queued command, tagged with its
+    /// slot. Returns `false` if it isn't described by one, e.g. if it's too
+    /// long or invalid, or no slot is free.
+    fn queue(&mut self, request: &blk::Request) -> bool {
+        let sector_size = self.block_size();
+        let Some(len) = request.length(sector_size) else {
+            return false;
+        };
+        let Some(slot) = self.free_slot() else {
+            return false;
+        };
+        if len == 0
+            || len > MAX_SECTORS * sector_size
+            || request.buffer.is_null()
+            || request.buffer.align_offset(2) != 0
+            || request
+                .block
+                .checked_add(request.count)
+                .is_none_or(|end| end > self.block_count())
+        {
+            return false;
+        }
+        let Some((regions, count, command_len)) =
+            scatter(request.buffer as usize, len, sector_size)
+        else {
+            return false;
+        };
+        if command_len != len {
+            return false;
+        }
+
+        let write = request.operation == blk::Operation::Write;
+        let command = if write {
+            ata::WRITE_FPDMA_QUEUED
+        } else {
+            ata::READ_FPDMA_QUEUED
+        };
+        let fis = H2DRegisterFIS::queued(command, request.block, request.count as u16, slot);
+        self.issue(slot, fis, &regions[..count], write);
+        self.requests[slot as usize] = Some(*request);
+        true
+    }
+
+    fn smart(&mut self, subcommand: u8, regions: &[Region]) -> Result<(), blk::Error> {
+        let fis = H2DRegisterFIS::features(ata::SMART, subcommand, ata::SMART_SIGNATURE, 0);
+        self.execute(fis, regions, false)
+            .map_err(|error| failure(self.number, "SMART", error))
+    }
+
+    /// Waits for some of the outstanding commands, and takes their results.
+    fn complete(&mut self, outstanding: &mut u32, name: &str) -> Result<(), blk::Error> {
+        let completed = self.wait(*outstanding);
+        *outstanding &= !completed;
+        let mut result = Ok(());
+        for slot in 0..32 {
+            if completed & 1 << slot == 0 {
+                continue;
+            }
+            if let Err(error) = self.result(slot) {
+                result = result.and(Err(failure(self.number, name, error)));
+            }
+        }
+        result
+    }
+}
+
+/// Logs the failed command, and maps its error.
+fn failure(number: u8, name: &str, error: port::Error) -> blk::Error {
+    log::warn!("Port {}: {} failed: {}", number, name, error);
+    match error {
+        port::Error::Timeout => blk::Error::Timeout,
+        _ => blk::Error::Device,
+    }
+}
+
+/// Describes up to `max_len` bytes of the buffer at the virtual address with
+/// a scatter-gather list, and returns it along with the whole sectors covered.
+fn scatter(
+    virt_addr: usize,
+    max_len: usize,
+    sector_size: usize,
+) -> Option<([Region; PRDT_LENGTH], usize, usize)> {
+    let mut regions = [Region {
+        phys_addr: 0,
+        len: 0,
+    }; PRDT_LENGTH];
+    let mut count = 0;
+    let mut len = 0;
+    while len < max_len {
+        let chunk_addr = virt_addr + len;
+        let chunk_len = (BYTES_PER_PAGE - chunk_addr % BYTES_PER_PAGE).min(max_len - len);
+        let phys_addr = sys::physical_address(chunk_addr as *const u8).ok()? as u64;
+        match regions[..count].last_mut() {
+            // physically contiguous pages share a descriptor
+            Some(region)
+                if region.phys_addr + region.len as u64 == phys_addr
+                    && region.len + chunk_len as u32 <= PRD_MAX_LEN =>
+            {
+                region.len += chunk_len as u32
+            }
+            _ if count < PRDT_LENGTH => {
+                regions[count] = Region {
+                    phys_addr,
+                    len: chunk_len as u32,
+                };
+                count += 1;
+            }
+            _ => break,
+        }
+        len += chunk_len;
+    }
+
+    // the rest of a partially described sector goes to the next command
+    let mut excess = len % sector_size;
+    len -= excess;
+    while excess != 0 {
+        let region = &mut regions[count - 1];
+        let cut = excess.min(region.len as usize);
+        region.len -= cut as u32;
+        excess -= cut;
+        if region.len == 0 {
+            count -= 1;
+        }
+    }
+    Some((regions, count, len))
+}
+
+impl BlockDevice for Port {
+    fn block_size(&self) -> usize {
+        if self.atapi {
+            return self
+                .capacity
+                .map_or(atapi::BLOCK_SIZE, |capacity| capacity.block_size as usize);
+        }
+        self.identity
+            .as_ref()
+            .map_or(ata::SECTOR_SIZE, |identity| identity.sector_size as usize)
+    }
+
+    fn block_count(&self) -> u64 {
+        if self.atapi {
+            return self.capacity.map_or(0, |capacity| capacity.blocks);
+        }
+        self.identity
+            .as_ref()
+            .filter(|identity| identity.lba48)
+            .map_or(0, |identity| identity.sectors)
+    }
+
+    fn read_only(&self) -> bool {
+        self.atapi
+    }
+
+    fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<(), blk::Error> {
+        self.transfer(block, buffer.as_mut_ptr(), buffer.len(), false)
+    }
+
+    fn write(&mut self, block: u64, buffer: &[u8]) -> Result<(), blk::Error> {
+        if self.atapi {
+            return Err(blk::Error::ReadOnly);
+        }
+        self.transfer(block, buffer.as_ptr() as *mut u8, buffer.len(), true)
+    }
+
+    fn flush(&mut self) -> Result<(), blk::Error> {
+        let Some(identity) = &self.identity else {
+            return Ok(());
+        };
+        if self.atapi {
+            return Ok(());
+        }
+        let (command, name) = if identity.flush_cache_ext {
+            (ata::FLUSH_CACHE_EXT, "FLUSH CACHE EXT")
+        } else {
+            (ata::FLUSH_CACHE, "FLUSH CACHE")
+        };
+        self.execute(H2DRegisterFIS::command(command, 0, 0), &[], false)
+            .map_err(|error| failure(self.number, name, error))
+    }
+
+    /// Issues DATA SET MANAGEMENT, with as many ranges as fit into a sector.
+    fn discard(&mut self, mut block: u64, mut count: u64) -> Result<(), blk::Error> {
+        if self.atapi {
+            return Err(blk::Error::ReadOnly);
+        }
+        if !self.identity.as_ref().is_some_and(|identity| identity.trim) {
+            return Err(blk::Error::Unsupported);
+        }
+        if block + count > self.block_count() {
+            return Err(blk::Error::InvalidRequest);
+        }
+
+        let (buffer, region) = self.buffer(ata::SECTOR_SIZE);
+        while count != 0 {
+            // entries of 0 blocks are ignored
+            let ranges = unsafe { &mut *buffer };
+            ranges.fill(0);
+            for range in ranges.chunks_exact_mut(8) {
+                let len = count.min(ata::DSM_RANGE_MAX_LEN);
+                range.copy_from_slice(&(block | len << 48).to_le_bytes());
+                block += len;
+                count -= len;
+                if count == 0 {
+                    break;
+                }
+            }
+            let fis = H2DRegisterFIS::features(ata::DATA_SET_MANAGEMENT, ata::DSM_TRIM, 0, 1);
+            self.execute(fis, &[region], true)
+                .map_err(|error| failure(self.number, "DATA SET MANAGEMENT", error))?;
+        }
+        Ok(())
+    }
+
+    fn queue_depth(&self) -> usize {
+        if self.ncq {
+            self.depth as usize
+        } else {
+            1
+        }
+    }
+
+    /// Queues reads and writes with native command queuing, if a single
+    /// command describes them. Others are executed synchronously, which
+    /// waits for the queued ones if they can't be mixed, but leaves their
+    /// results to [`poll`](Self::poll).
+    unsafe fn submit(&mut self, request: blk::Request) -> Option<blk::Completion> {
+        if self.ncq
+            && matches!(
+                request.operation,
+                blk::Operation::Read | blk::Operation::Write
+            )
+        {
+            if self.queue(&request) {
+                return None;
+            }
+            // the synchronous transfer needs a slot as well
+            if self.free_slot().is_none() {
+                return Some(blk::Completion {
+                    tag: request.tag,
+                    result: Err(blk::Error::Busy),
+                });
+            }
+        }
+        Some(blk::Completion {
+            tag: request.tag,
+            result: unsafe { blk::execute(self, &request) },
+        })
+    }
+
+    /// Waits for one of the queued commands, their slots map to the requests.
+    fn poll(&mut self) -> Option<blk::Completion> {
+        let mut pending = 0;
+        for (slot, request) in self.requests.iter().enumerate() {
+            if request.is_some() {
+                pending |= 1 << slot;
+            }
+        }
+        let completed = self.wait(pending);
+        if completed == 0 {
+            return None;
+        }
+        let slot = completed.trailing_zeros() as u8;
+        let request = self.requests[slot as usize].take()?;
+        let name = match request.operation {
+            blk::Operation::Write => "WRITE FPDMA QUEUED",
+            _ => "READ FPDMA QUEUED",
+        };
+        Some(blk::Completion {
+            tag: request.tag,
+            result: self
+                .result(slot)
+                .map_err(|error| failure(self.number, name, error)),
+        })
+    }
+
+    /// Reads the SMART attributes, and whether a threshold is exceeded.
+    fn health(&mut self) -> Result<blk::Health, blk::Error> {
+        let Some(identity) = &self.identity else {
+            return Err(blk::Error::Unsupported);
+        };
+        if self.atapi || !identity.smart {
+            return Err(blk::Error::Unsupported);
+        }
+        if !identity.smart_enabled {
+            self.smart(ata::SMART_ENABLE_OPERATIONS, &[])?;
+            if let Some(identity) = &mut self.identity {
+                identity.smart_enabled = true;
+            }
+        }
+
+        let (buffer, region) = self.buffer(ata::SECTOR_SIZE);
+        self.smart(ata::SMART_READ_DATA, &[region])?;
+        let data = unsafe { &*buffer };
+        let attribute = |id| ata::smart_attribute(data, id);
+        let temperature = attribute(ata::ATTRIBUTE_TEMPERATURE).map(|raw| raw as u8);
+        let power_on_hours = attribute(ata::ATTRIBUTE_POWER_ON_HOURS).map(|raw| raw as u32);
+        let reallocated = attribute(ata::ATTRIBUTE_REALLOCATED_SECTORS).map(|raw| raw as u32);
+
+        self.smart(ata::SMART_RETURN_STATUS, &[])?;
+        Ok(blk::Health {
+            failing: self.result_lba() & 0xFFFF00 == ata::SMART_THRESHOLD_EXCEEDED,
+            temperature,
+            power_on_hours,
+            reallocated,
+        })
+    }
+}
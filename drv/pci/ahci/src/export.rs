@@ -0,0 +1,100 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use drv_fs::{
+    blk::BlockDevice,
+    ipc::Server,
+    queue::{Queue, Scheduler},
+};
+
+use crate::port::Port;
+
+/// File system driver spawned for each disk.
+const FILE_SYSTEM_MODULE: &str = "drv_fs_fat";
+
+/// Disk of a port served to the file system driver spawned for it.
+pub struct Export {
+    endpoint: sys::Endpoint,
+    task: sys::Task,
+    server: Server,
+}
+
+/// Serves the disk attached to the port to a file system driver spawned for
+/// it, which finds the endpoint as its first capability.
+pub fn export(exports: &mut [Option<Export>; 32], port: &Port) {
+    if !port.present || port.block_count() == 0 {
+        return;
+    }
+    match spawn() {
+        Ok((endpoint, task)) => {
+            exports[port.number as usize] = Some(Export {
+                endpoint,
+                task,
+                server: Server::new(),
+            })
+        }
+        Err(error) => log::warn!(
+            "Port {}: {} not spawned: {:?}",
+            port.number,
+            FILE_SYSTEM_MODULE,
+            error
+        ),
+    }
+}
+
+/// Stops serving the disk of the port, and kills its file system driver.
+pub fn unexport(exports: &mut [Option<Export>; 32], number: u8) {
+    let Some(Export { endpoint, task, .. }) = exports[number as usize].take() else {
+        return;
+    };
+    if let Err(error) = sys::kill(task).and_then(|_| sys::close(endpoint)) {
+        log::warn!(
+            "Port {}: {} not killed: {:?}",
+            number,
+            FILE_SYSTEM_MODULE,
+            error
+        );
+    }
+}
+
+/// Handles the requests of the file system drivers waiting, through a queue
+/// in front of the disk, which orders them by block.
+pub fn serve(exports: &mut [Option<Export>; 32], ports: &mut [Option<Port>; 32]) {
+    for (export, port) in exports.iter_mut().zip(ports.iter_mut()) {
+        let (Some(export), Some(port)) = (export, port) else {
+            continue;
+        };
+        let number = port.number;
+        let mut queue = Queue::new(port, Scheduler::Elevator);
+        if let Err(error) = export.server.serve(&mut queue, export.endpoint) {
+            log::warn!("Port {}: not served: {:?}", number, error);
+        }
+    }
+}
+
+/// Spawns a file system driver, which may call the endpoint created for it.
+fn spawn() -> Result<(sys::Endpoint, sys::Task), sys::Error> {
+    let endpoint = sys::endpoint_create()?;
+    match sys::spawn(
+        FILE_SYSTEM_MODULE,
+        &[],
+        &[sys::Grant::endpoint(endpoint), sys::Grant::dma()],
+    ) {
+        Ok(task) => Ok((endpoint, task)),
+        Err(error) => {
+            let _ = sys::close(endpoint);
+            Err(error)
+        }
+    }
+}
@@ -0,0 +1,93 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::{hint, mem};
+
+use crate::{
+    export::{self, Export},
+    port::{LinkPower, LinkState, Port},
+    probe, HBA,
+};
+
+/// Attaches and detaches devices as they arrive and leave, serves their disks,
+/// and logs link power state changes. Blocks until a port's hot plug events
+/// interrupt while no disk is served, polls the HBA between requests
+/// otherwise, and only returns if the interrupt can't be waited on.
+pub fn run(
+    hba: *mut HBA,
+    irq: sys::Irq,
+    ports: &mut [Option<Port>; 32],
+    buffer: &mut [u8],
+    link_power: LinkPower,
+) {
+    let mut states = [LinkState::Down; 32];
+    let mut exports = [const { None::<Export> }; 32];
+    let is = read_volatile!(hba, is);
+    for (port, state) in ports.iter_mut().zip(&mut states) {
+        if let Some(port) = port {
+            port.connected();
+            *state = port.link_state();
+            export::export(&mut exports, port);
+        }
+    }
+    write_volatile!(hba, is, is);
+
+    loop {
+        if exports.iter().all(Option::is_none) {
+            if let Err(error) = sys::irq_wait(irq.vector) {
+                log::error!("IRQ {} not waitable: {:?}", irq.vector, error);
+                return;
+            }
+        } else {
+            export::serve(&mut exports, ports);
+            if read_volatile!(hba, is) == 0 {
+                hint::spin_loop();
+                continue;
+            }
+        }
+        // the ports' status is cleared before the HBA's, which interrupts
+        // again for events in between
+        let is = read_volatile!(hba, is);
+        for (port, state) in ports.iter_mut().zip(&mut states) {
+            let Some(port) = port else {
+                continue;
+            };
+            let connected = port.connected();
+            let link_state = port.link_state();
+            let previous = mem::replace(state, link_state);
+            if link_state != previous {
+                log::info!("Port {}: link {:?}", port.number, link_state);
+            }
+
+            // a device connected while one is attached replaced it
+            if port.present && (link_state == LinkState::Down || connected) {
+                log::info!("Port {}: device removed", port.number);
+                export::unexport(&mut exports, port.number);
+                port.detach();
+            }
+            // a device which failed to attach is retried once reconnected
+            if !port.present
+                && link_state != LinkState::Down
+                && (connected || previous == LinkState::Down)
+            {
+                log::info!("Port {}: device arrived", port.number);
+                probe(port, buffer, link_power);
+                port.connected();
+                *state = port.link_state();
+                export::export(&mut exports, port);
+            }
+        }
+        write_volatile!(hba, is, is);
+    }
+}
@@ -15,12 +15,232 @@
 #![no_std]
 #![no_main]
 
-use core::{hint, panic};
+use core::{hint, mem, panic, slice};
 
 use bitflags::bitflags;
-use drv_pci::Device;
+use drv_fs::blk::{self, BlockDevice};
+use drv_pci::{Device, Resource};
 
-fn main(_device: Device) {}
+use crate::port::{LinkPower, Port};
+
+/// Reads a register of a memory-mapped structure.
+macro_rules! read_volatile {
+    ($base:expr, $field:ident) => {{
+        let base = $base;
+        unsafe { core::ptr::addr_of!((*base).$field).read_volatile() }
+    }};
+}
+
+/// Writes a register of a memory-mapped structure.
+macro_rules! write_volatile {
+    ($base:expr, $field:ident, $value:expr) => {{
+        let (base, value) = ($base, $value);
+        unsafe { core::ptr::addr_of_mut!((*base).$field).write_volatile(value) }
+    }};
+}
+
+mod ata;
+mod atapi;
+mod disk;
+mod export;
+mod hp;
+mod port;
+
+/// AHCI Base Address, the HBA's registers.
+const RESOURCE_ABAR: usize = 5;
+
+/// Buffer for the first block of each disk.
+const BUFFER_SIZE: usize = 4096;
+
+/// Link power management of the devices, unless the driver's arguments name
+/// one with `link_power=`. The HBA or device may only support less.
+const LINK_POWER: LinkPower = LinkPower::Slumber;
+
+/// Block of the first volume descriptor, after the system area.
+const ISO9660_VOLUME_DESCRIPTOR: u64 = 16;
+
+/// Polls of a register until the HBA or device has to respond.
+const ATTEMPTS: usize = 10_000_000;
+
+drv_pci::main!(main);
+
+fn main(device: Device, arguments: &str) {
+    sys::Logger::init(log::LevelFilter::Info);
+
+    let link_power = link_power(arguments);
+
+    let Resource::Mem32(abar) = &device.resource[RESOURCE_ABAR] else {
+        log::error!("ABAR not assigned");
+        return;
+    };
+    let Ok(hba) = sys::memory_map(abar.start as usize, abar.len()) else {
+        log::error!("ABAR not mappable");
+        return;
+    };
+    let hba = hba as *mut HBA;
+    if !init(hba) {
+        return;
+    }
+
+    let Some(irq) = device.irq else {
+        log::error!("No interrupt assigned");
+        return;
+    };
+
+    let mut ports = [const { None }; 32];
+    let implemented = read_volatile!(hba, pi);
+    // ports beyond the ABAR are not accessible
+    let count = abar.len().saturating_sub(mem::offset_of!(HBA, port)) / size_of::<HBAPort>();
+    for (number, port) in ports.iter_mut().enumerate().take(count) {
+        if implemented & 1 << number == 0 {
+            continue;
+        }
+        match Port::new(hba, number as u8) {
+            Ok(new_port) => *port = Some(new_port),
+            Err(error) => log::warn!("Port {}: {}", number, error),
+        }
+    }
+
+    let Ok(buffer) = sys::dma_allocate(BUFFER_SIZE) else {
+        return;
+    };
+    let buffer = unsafe { slice::from_raw_parts_mut(buffer, BUFFER_SIZE) };
+    // ports only interrupt for hot plug events
+    let ghc = read_volatile!(hba, ghc);
+    write_volatile!(hba, ghc, ghc | HBAGlobalControl::IE);
+    for port in ports.iter_mut().flatten() {
+        probe(port, buffer, link_power);
+    }
+    hp::run(hba, irq, &mut ports, buffer, link_power);
+}
+
+/// Returns the link power management policy in the driver's arguments,
+/// given as `link_power=<policy>`.
+fn link_power(arguments: &str) -> LinkPower {
+    let Some(name) = arguments
+        .split_whitespace()
+        .find_map(|argument| argument.strip_prefix("link_power="))
+    else {
+        return LINK_POWER;
+    };
+    LinkPower::from_name(name).unwrap_or_else(|| {
+        log::warn!("Unknown link power policy {}", name);
+        LINK_POWER
+    })
+}
+
+/// Attaches the device on the port, if any, and reads its partition table or
+/// volume descriptor, to see whether it is usable.
+fn probe(port: &mut Port, buffer: &mut [u8], link_power: LinkPower) {
+    match port.attach() {
+        Ok(()) => {}
+        Err(port::Error::NoDevice) => return,
+        Err(error) => {
+            log::warn!("Port {}: {}", port.number, error);
+            return;
+        }
+    }
+    if let Err(error) = port.identify() {
+        log::warn!("Port {}: IDENTIFY failed: {}", port.number, error);
+    }
+    match port.set_link_power(link_power) {
+        Ok(policy) if policy != link_power => {
+            log::info!("Port {}: link power {:?}", port.number, policy)
+        }
+        Ok(_) => {}
+        Err(error) => log::warn!("Port {}: link power not set: {}", port.number, error),
+    }
+    match port.health() {
+        Ok(health) => log::info!(
+            "Port {}: {}, {} °C, {} hours",
+            port.number,
+            if health.failing { "failing" } else { "healthy" },
+            health.temperature.unwrap_or(0),
+            health.power_on_hours.unwrap_or(0)
+        ),
+        Err(blk::Error::Unsupported) => {}
+        Err(error) => log::warn!("Port {}: SMART failed: {:?}", port.number, error),
+    }
+    if port.atapi {
+        if let Err(error) = port.inquiry().and_then(|_| port.read_capacity()) {
+            log::warn!("Port {}: INQUIRY failed: {}", port.number, error);
+        }
+    }
+
+    let block_size = port.block_size();
+    if port.block_count() == 0 || block_size > buffer.len() {
+        return;
+    }
+    let block = &mut buffer[..block_size];
+    if port.atapi {
+        if port.read(ISO9660_VOLUME_DESCRIPTOR, block).is_ok() {
+            log::info!(
+                "Port {}: {}",
+                port.number,
+                if block[1..6] == *b"CD001" {
+                    "ISO 9660 volume found"
+                } else {
+                    "no ISO 9660 volume"
+                }
+            );
+        }
+    } else if port.read(0, block).is_ok() {
+        log::info!(
+            "Port {}: {}",
+            port.number,
+            if block[510..512] == [0x55, 0xAA] {
+                "partition table found"
+            } else {
+                "no partition table"
+            }
+        );
+    }
+}
+
+/// Takes the HBA over from the BIOS, and resets it into AHCI mode.
+fn init(hba: *mut HBA) -> bool {
+    if read_volatile!(hba, cap2).contains(HBACapabilitiesExtended::BOH) {
+        let bohc = read_volatile!(hba, bohc);
+        write_volatile!(hba, bohc, bohc | HBABIOSOSHandoffControl::OOS);
+        // the BIOS has to release it within 25 ms, or 2 s when busy
+        if !poll(|| {
+            !read_volatile!(hba, bohc)
+                .intersects(HBABIOSOSHandoffControl::BOS | HBABIOSOSHandoffControl::BB)
+        }) {
+            log::warn!("BIOS did not release the HBA");
+        }
+    }
+
+    let ghc = read_volatile!(hba, ghc);
+    write_volatile!(hba, ghc, ghc | HBAGlobalControl::AE);
+    write_volatile!(hba, ghc, ghc | HBAGlobalControl::AE | HBAGlobalControl::HR);
+    if !poll(|| !read_volatile!(hba, ghc).contains(HBAGlobalControl::HR)) {
+        log::error!("HBA reset timed out");
+        return false;
+    }
+    // the reset clears AE, unless the HBA only supports AHCI mode
+    let ghc = read_volatile!(hba, ghc);
+    write_volatile!(hba, ghc, ghc | HBAGlobalControl::AE);
+
+    let cap = read_volatile!(hba, cap);
+    log::info!(
+        "AHCI {}.{}, {} ports, {} command slots",
+        read_volatile!(hba, vs_mjr),
+        read_volatile!(hba, vs_mnr),
+        (cap & HBACapabilities::NP).bits() + 1,
+        ((cap & HBACapabilities::NCS).bits() >> 8) + 1
+    );
+    true
+}
+
+/// Polls until the condition holds, returns `false` if it never did.
+fn poll(mut condition: impl FnMut() -> bool) -> bool {
+    (0..ATTEMPTS).any(|_| {
+        let holds = condition();
+        hint::spin_loop();
+        holds
+    })
+}
 
 #[panic_handler]
 fn panic(_info: &panic::PanicInfo) -> ! {
@@ -52,9 +272,9 @@ struct HBA {
     /// Command Completion Coalescing Ports
     ccc_ports: u32,
     /// Enclosure Management Location: Buffer Size
-    em_loc_sz: u32,
+    em_loc_sz: u16,
     /// Enclosure Management Location: Offset
-    em_loc_ofst: u32,
+    em_loc_ofst: u16,
     /// Enclosure Management Control
     em_ctl: HBAEnclosureManagementControl,
     /// Host Capabilities Extended
@@ -71,6 +291,7 @@ struct HBA {
 }
 
 bitflags! {
+    #[derive(Clone, Copy)]
     struct HBACapabilities: u32 {
         /// Number of Ports
         const NP = (1 << 4) - 1;
@@ -81,7 +302,7 @@ bitflags! {
         /// Command Completion Coalescing Supported
         const CCCS = 1 << 7;
         /// Number of Command Slots
-        const NCS = ((1 << 4) - 1) << 8;
+        const NCS = ((1 << 5) - 1) << 8;
         /// Partial State Capable
         const PSC = 1 << 13;
         /// Slumber State Capable
@@ -118,6 +339,7 @@ bitflags! {
         const S64A = 1 << 31;
     }
 
+    #[derive(Clone, Copy)]
     struct HBACapabilitiesExtended: u32 {
         /// BIOS/OS Handoff
         const BOH = 1 << 0;
@@ -133,6 +355,7 @@ bitflags! {
         const DESO = 1 << 5;
     }
 
+    #[derive(Clone, Copy)]
     struct HBAGlobalControl: u32 {
         /// HBA Reset
         const HR = 1 << 0;
@@ -144,6 +367,7 @@ bitflags! {
         const AE = 1 << 31;
     }
 
+    #[derive(Clone, Copy)]
     struct HBAEnclosureManagementControl: u32 {
         /// Message Received
         const STS_RM = 1 << 0;
@@ -169,6 +393,7 @@ bitflags! {
         const ATTR_PM = 1 << 27;
     }
 
+    #[derive(Clone, Copy)]
     struct HBABIOSOSHandoffControl: u32 {
         /// BIOS Owned Semaphore
         const BOS = 1 << 0;
@@ -252,6 +477,7 @@ struct HBAPort {
 }
 
 bitflags! {
+    #[derive(Clone, Copy)]
     struct HBAPortInterrupt: u32 {
         /// Device to Host Register FIS Interrupt
         const DHR = 1 << 0;
@@ -289,6 +515,7 @@ bitflags! {
         const CPD = 1 << 31;
     }
 
+    #[derive(Clone, Copy)]
     struct HBAPortCommand: u32 {
         /// Start
         const ST = 1 << 0;
@@ -336,6 +563,7 @@ bitflags! {
         const ICC = ((1 << 4) - 1) << 28;
     }
 
+    #[derive(Clone, Copy)]
     struct HBAPortSerialATAError: u16 {
         /// Recovered Data Integrity Error
         const I = 1 << 0;
@@ -351,6 +579,7 @@ bitflags! {
         const E = 1 << 11;
     }
 
+    #[derive(Clone, Copy)]
     struct HBAPortSerialATAErrorDiagnostics: u16 {
         /// PhyRdy Change
         const N = 1 << 0;
@@ -411,7 +640,7 @@ struct CommandTable {
     cfis: H2DRegisterFIS,
     _cfis_remaining: [u32; 11],
     /// ATAPI Command
-    acmd: [u32; 4],
+    acmd: [u8; 16],
     _reserved: [u32; 12],
     /// Physical Region Descriptor Table
     prdt: [PhysicalRegionDescriptor; 0],
@@ -490,6 +719,7 @@ struct PIOSetupFIS {
 }
 
 #[repr(C)]
+#[derive(Clone, Copy)]
 struct H2DRegisterFIS {
     fis_type: u8,
     flags: u8,
@@ -516,6 +746,69 @@ struct H2DRegisterFIS {
     _reserved: [u8; 2],
 }
 
+impl H2DRegisterFIS {
+    const TYPE: u8 = 0x27;
+    /// Command, as opposed to Device Control
+    const FLAGS_C: u8 = 1 << 7;
+    /// LBA addressing, must be set for 48-bit commands
+    const DEVICE_LBA: u8 = 1 << 6;
+    /// Data transferred by DMA, for PACKET
+    const FEATURES_DMA: u8 = 1 << 0;
+
+    fn command(command: u8, lba: u64, count: u16) -> Self {
+        Self {
+            fis_type: Self::TYPE,
+            flags: Self::FLAGS_C,
+            command,
+            features_0_7: 0,
+            lba_0_7: lba as u8,
+            lba_8_15: (lba >> 8) as u8,
+            lba_16_32: (lba >> 16) as u8,
+            device: Self::DEVICE_LBA,
+            lba_24_31: (lba >> 24) as u8,
+            lba_32_39: (lba >> 32) as u8,
+            lba_40_47: (lba >> 40) as u8,
+            features_8_15: 0,
+            count_0_7: count as u8,
+            count_8_15: (count >> 8) as u8,
+            icc: 0,
+            control: 0,
+            auxiliary_0_7: 0,
+            auxiliary_8_15: 0,
+            _reserved: [0; 2],
+        }
+    }
+
+    /// Commands with subcommands or options in the features register.
+    fn features(command: u8, features: u8, lba: u64, count: u16) -> Self {
+        Self {
+            features_0_7: features,
+            ..Self::command(command, lba, count)
+        }
+    }
+
+    /// Data of ATAPI PACKET commands is transferred by DMA, the SCSI command
+    /// is in the command table.
+    fn packet() -> Self {
+        Self {
+            features_0_7: Self::FEATURES_DMA,
+            device: 0,
+            ..Self::command(ata::PACKET, 0, 0)
+        }
+    }
+
+    /// Native command queuing commands carry the count in the features
+    /// register, and the tag in the count register.
+    fn queued(command: u8, lba: u64, count: u16, tag: u8) -> Self {
+        Self {
+            features_0_7: count as u8,
+            features_8_15: (count >> 8) as u8,
+            count_0_7: tag << 3,
+            ..Self::command(command, lba, 0)
+        }
+    }
+}
+
 #[repr(C)]
 struct D2HRegisterFIS {
     fis_type: u8,
@@ -545,5 +838,6 @@ struct SetDeviceBitsFIS {
     status: u8,
     error: u8,
 
-    _unknown: u32,
+    /// Tags of the queued commands completed by the device
+    sactive: u32,
 }
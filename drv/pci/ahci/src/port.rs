@@ -0,0 +1,873 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::{
+    fmt, hint, mem, ptr,
+    sync::atomic::{self, Ordering},
+};
+
+use drv_fs::blk;
+
+use crate::{
+    ata, atapi, poll, CommandList, CommandTable, H2DRegisterFIS, HBACapabilities,
+    HBACapabilitiesExtended, HBAPort, HBAPortCommand, HBAPortInterrupt, HBAPortSerialATAError,
+    HBAPortSerialATAErrorDiagnostics, PhysicalRegionDescriptor, ReceivedFIS, HBA,
+};
+
+/// Physical region descriptors per command, fills the command table up to
+/// 1 KiB.
+pub const PRDT_LENGTH: usize = 56;
+
+const SIGNATURE_ATAPI: u32 = 0xEB140101;
+
+/// Device presence detected and Phy communication established
+const SSTS_DET_PRESENT: u8 = 3;
+
+const TFD_STS_ERR: u8 = 1 << 0;
+const TFD_STS_DRQ: u8 = 1 << 3;
+const TFD_STS_BSY: u8 = 1 << 7;
+
+/// Serial ATA Status: interface power management state
+const SSTS_IPM_ACTIVE: u8 = 1;
+const SSTS_IPM_PARTIAL: u8 = 2;
+const SSTS_IPM_SLUMBER: u8 = 6;
+const SSTS_IPM_DEVSLEEP: u8 = 8;
+
+/// Serial ATA Control: perform interface communication initialization
+const SCTL_DET_INIT: u8 = 1;
+/// Serial ATA Control: interface power management transitions not allowed
+const SCTL_IPM_NO_PARTIAL: u8 = 1 << 0;
+const SCTL_IPM_NO_SLUMBER: u8 = 1 << 1;
+const SCTL_IPM_NO_DEVSLEEP: u8 = 1 << 2;
+
+/// Device Sleep: aggressive device sleep enable
+const DEVSLP_ADSE: u32 = 1 << 0;
+/// Device Sleep: device sleep present
+const DEVSLP_DSP: u32 = 1 << 1;
+const DEVSLP_DETO_SHIFT: u32 = 2;
+const DEVSLP_MDAT_SHIFT: u32 = 10;
+const DEVSLP_DITO_SHIFT: u32 = 15;
+/// Enable, exit timeout, minimum assertion time, idle timeout and its
+/// multiplier.
+const DEVSLP_CONFIGURATION: u32 = DEVSLP_ADSE | ((1 << 27) - 1) << DEVSLP_DETO_SHIFT;
+/// Device sleep exit timeout and minimum assertion time in ms, the defaults of
+/// the Serial ATA specification.
+const DEVSLP_DETO: u32 = 20;
+const DEVSLP_MDAT: u32 = 10;
+/// Idle time in ms before the HBA asserts device sleep.
+const DEVSLP_DITO: u32 = 1000;
+
+/// COMRESET has to be held for at least 1 ms, without a timer it is held for
+/// a number of spins instead.
+const COMRESET_SPINS: usize = 1_000_000;
+
+/// Command header flags, besides the command FIS length.
+const COMMAND_HEADER_ATAPI: u8 = 1 << 5;
+const COMMAND_HEADER_WRITE: u8 = 1 << 6;
+
+/// NCQ Command Error log: the failed command was not queued
+const NCQ_LOG_NQ: u8 = 1 << 7;
+const NCQ_LOG_TAG: u8 = (1 << 5) - 1;
+
+/// Interrupt status bits of devices arriving and leaving, the only ones
+/// enabled to interrupt.
+const HOT_PLUG: HBAPortInterrupt = HBAPortInterrupt::PC
+    .union(HBAPortInterrupt::PRC)
+    .union(HBAPortInterrupt::CPD)
+    .union(HBAPortInterrupt::DMP);
+
+/// Interrupt status bits which stop the port.
+const ERRORS: HBAPortInterrupt = HBAPortInterrupt::TFE
+    .union(HBAPortInterrupt::HBF)
+    .union(HBAPortInterrupt::HBD)
+    .union(HBAPortInterrupt::IF);
+
+/// Times a command is retried, when the failed one couldn't be determined.
+const RETRIES: u8 = 3;
+
+#[repr(C, align(128))]
+struct CommandSlot {
+    table: CommandTable,
+    prdt: [PhysicalRegionDescriptor; PRDT_LENGTH],
+}
+
+/// DMA memory of a port.
+#[repr(C)]
+struct Memory {
+    command_list: CommandList,
+    received_fis: ReceivedFIS,
+    slots: [CommandSlot; 32],
+    /// Data of commands issued by the driver itself, e.g. IDENTIFY DEVICE.
+    buffer: [u8; 512],
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Error {
+    /// No device is attached, or it didn't become ready.
+    NoDevice,
+    /// The HBA didn't complete the command in time.
+    Timeout,
+    /// The memory for the command list and received FISes is not accessible
+    /// by the HBA.
+    Memory,
+    /// The device aborted the command, with the task file and SATA errors.
+    Device { status: u8, error: u8, serr: u16 },
+    /// The host bus or link failed, with the SATA errors.
+    Interface { serr: u16 },
+    /// The device reported a block size which isn't a power of two.
+    BlockSize(u32),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoDevice => write!(f, "no device"),
+            Self::Timeout => write!(f, "timeout"),
+            Self::Memory => write!(f, "memory not accessible"),
+            Self::Device {
+                status,
+                error,
+                serr,
+            } if status & TFD_STS_ERR != 0 => write!(
+                f,
+                "device error {:#04X}, status {:#04X}, SError {:#06X}",
+                error, status, serr
+            ),
+            Self::Device { status, serr, .. } => write!(
+                f,
+                "device failed, status {:#04X}, SError {:#06X}",
+                status, serr
+            ),
+            Self::Interface { serr } => write!(f, "interface error, SError {:#06X}", serr),
+            Self::BlockSize(block_size) => write!(f, "block size {}", block_size),
+        }
+    }
+}
+
+/// Link power management policy, each saves more power than the previous one,
+/// at the cost of a longer wake-up.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LinkPower {
+    MaxPerformance,
+    Partial,
+    Slumber,
+    DevSleep,
+}
+
+impl LinkPower {
+    /// Parses the policy's name, as given in the driver's arguments.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "max_performance" => Some(Self::MaxPerformance),
+            "partial" => Some(Self::Partial),
+            "slumber" => Some(Self::Slumber),
+            "devsleep" => Some(Self::DevSleep),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LinkState {
+    Down,
+    Active,
+    Partial,
+    Slumber,
+    DevSleep,
+}
+
+/// Physically contiguous range of memory transferred by a command.
+#[derive(Clone, Copy)]
+pub struct Region {
+    pub phys_addr: u64,
+    pub len: u32,
+}
+
+pub struct Port {
+    pub number: u8,
+    registers: *mut HBAPort,
+    memory: *mut Memory,
+    phys_addr: u64,
+    cap: HBACapabilities,
+    cap2: HBACapabilitiesExtended,
+    /// Command slots implemented by the HBA.
+    slots: u8,
+    /// Whether reads and writes are queued, with up to `depth` outstanding.
+    pub ncq: bool,
+    pub depth: u8,
+    /// Slots of the commands issued, whose result wasn't taken yet.
+    busy: u32,
+    /// Slots of the commands the HBA is processing.
+    active: u32,
+    /// Slots of the active commands which are queued.
+    queued: u32,
+    retries: [u8; 32],
+    errors: [Option<Error>; 32],
+    /// Block requests submitted, by the slot of their queued command.
+    pub requests: [Option<blk::Request>; 32],
+    /// Whether a device is attached, and the port started.
+    pub present: bool,
+    pub atapi: bool,
+    pub identity: Option<ata::Identity>,
+    /// Medium of an ATAPI device.
+    pub capacity: Option<atapi::Capacity>,
+}
+
+impl Port {
+    /// Sets up the command list and FIS receive area, the device is attached
+    /// separately.
+    pub fn new(hba: *mut HBA, number: u8) -> Result<Self, Error> {
+        let cap = read_volatile!(hba, cap);
+        let cap2 = read_volatile!(hba, cap2);
+        let registers = unsafe { ptr::addr_of_mut!((*hba).port[number as usize]) };
+        stop(registers)?;
+
+        let memory = sys::dma_allocate(mem::size_of::<Memory>()).map_err(|_| Error::Memory)?;
+        let phys_addr = sys::physical_address(memory).map_err(|_| Error::Memory)? as u64;
+        if !cap.contains(HBACapabilities::S64A)
+            && phys_addr + mem::size_of::<Memory>() as u64 > u32::MAX as u64
+        {
+            return Err(Error::Memory);
+        }
+        let port = Self {
+            number,
+            registers,
+            memory: memory as *mut Memory,
+            phys_addr,
+            cap,
+            cap2,
+            slots: ((cap & HBACapabilities::NCS).bits() >> 8) as u8 + 1,
+            ncq: false,
+            depth: 1,
+            busy: 0,
+            active: 0,
+            queued: 0,
+            retries: [0; 32],
+            errors: [None; 32],
+            requests: [None; 32],
+            present: false,
+            atapi: false,
+            identity: None,
+            capacity: None,
+        };
+
+        let command_list = port.phys_addr_of(unsafe { ptr::addr_of!((*port.memory).command_list) });
+        let received_fis = port.phys_addr_of(unsafe { ptr::addr_of!((*port.memory).received_fis) });
+        write_volatile!(registers, clb, command_list as u32);
+        write_volatile!(registers, clbu, (command_list >> 32) as u32);
+        write_volatile!(registers, fb, received_fis as u32);
+        write_volatile!(registers, fbu, (received_fis >> 32) as u32);
+        for slot in 0..32 {
+            let table = port.phys_addr_of(unsafe { ptr::addr_of!((*port.memory).slots[slot]) });
+            let header = unsafe { &mut (*port.memory).command_list.0[slot] };
+            header.ctba = table as u32;
+            header.ctbau = (table >> 32) as u32;
+        }
+
+        // commands are polled, only hot plug events interrupt
+        write_volatile!(registers, ie, HOT_PLUG);
+        port.spin_up();
+        Ok(port)
+    }
+
+    /// Waits for the device to become ready, and starts the port.
+    pub fn attach(&mut self) -> Result<(), Error> {
+        let registers = self.registers;
+        if !poll(|| read_volatile!(registers, ssts_detspd) & 0xF == SSTS_DET_PRESENT) {
+            return Err(Error::NoDevice);
+        }
+        if !poll(|| read_volatile!(registers, tfd_sts) & (TFD_STS_BSY | TFD_STS_DRQ) == 0) {
+            return Err(Error::NoDevice);
+        }
+        self.clear_errors();
+
+        self.atapi = read_volatile!(registers, sig) == SIGNATURE_ATAPI;
+        let mut cmd = read_volatile!(registers, cmd) - HBAPortCommand::ATAPI;
+        if self.atapi {
+            cmd |= HBAPortCommand::ATAPI;
+        }
+        write_volatile!(registers, cmd, cmd);
+        self.start()?;
+        self.present = true;
+        Ok(())
+    }
+
+    /// Stops the port after the device left, its outstanding commands fail.
+    pub fn detach(&mut self) {
+        if let Err(error) = stop(self.registers) {
+            log::warn!("Port {}: not stopped: {}", self.number, error);
+        }
+        for slot in 0..32 {
+            if self.active & 1 << slot != 0 {
+                self.errors[slot] = Some(Error::NoDevice);
+            }
+        }
+        self.active = 0;
+        self.queued = 0;
+        self.ncq = false;
+        self.depth = 1;
+        self.present = false;
+        self.atapi = false;
+        self.identity = None;
+        self.capacity = None;
+        self.clear_errors();
+        self.spin_up();
+    }
+
+    /// Returns whether a device was connected or powered on since the last
+    /// call, which also reset an attached one.
+    pub fn connected(&mut self) -> bool {
+        let registers = self.registers;
+        let is = read_volatile!(registers, is);
+        let events = is & HOT_PLUG;
+        if events.is_empty() {
+            return false;
+        }
+        // port connect change is cleared along with the exchanged bit
+        write_volatile!(
+            registers,
+            serr_diag,
+            HBAPortSerialATAErrorDiagnostics::X | HBAPortSerialATAErrorDiagnostics::N
+        );
+        write_volatile!(registers, is, events);
+
+        // power the device according to its cold presence
+        let cmd = read_volatile!(registers, cmd);
+        if events.contains(HBAPortInterrupt::CPD) && cmd.contains(HBAPortCommand::CPD) {
+            let cmd = if cmd.contains(HBAPortCommand::CPS) {
+                cmd | HBAPortCommand::POD
+            } else {
+                cmd - HBAPortCommand::POD
+            };
+            write_volatile!(registers, cmd, cmd);
+        }
+        events.intersects(HBAPortInterrupt::PC | HBAPortInterrupt::CPD)
+    }
+
+    /// Returns the interface power management state of the link.
+    pub fn link_state(&self) -> LinkState {
+        let registers = self.registers;
+        match read_volatile!(registers, ssts_ipm) & 0xF {
+            SSTS_IPM_ACTIVE if read_volatile!(registers, ssts_detspd) & 0xF == SSTS_DET_PRESENT => {
+                LinkState::Active
+            }
+            SSTS_IPM_PARTIAL => LinkState::Partial,
+            SSTS_IPM_SLUMBER => LinkState::Slumber,
+            SSTS_IPM_DEVSLEEP => LinkState::DevSleep,
+            _ => LinkState::Down,
+        }
+    }
+
+    /// Lets the HBA put the link into low power states when idle, as far as
+    /// the HBA and device support the policy.
+    pub fn set_link_power(&mut self, policy: LinkPower) -> Result<LinkPower, Error> {
+        let Some(identity) = &self.identity else {
+            return Ok(LinkPower::MaxPerformance);
+        };
+        let registers = self.registers;
+        let alpm = self.cap.contains(HBACapabilities::SALP) && identity.hipm;
+        let devsleep = self
+            .cap2
+            .contains(HBACapabilitiesExtended::SDS | HBACapabilitiesExtended::SADM)
+            && read_volatile!(registers, devslp) & DEVSLP_DSP != 0
+            && identity.devsleep;
+        let policy = match policy {
+            LinkPower::DevSleep if alpm && devsleep => LinkPower::DevSleep,
+            LinkPower::DevSleep | LinkPower::Slumber
+                if alpm && self.cap.contains(HBACapabilities::SSC) =>
+            {
+                LinkPower::Slumber
+            }
+            LinkPower::DevSleep | LinkPower::Slumber | LinkPower::Partial
+                if alpm && self.cap.contains(HBACapabilities::PSC) =>
+            {
+                LinkPower::Partial
+            }
+            _ => LinkPower::MaxPerformance,
+        };
+        if policy == LinkPower::DevSleep {
+            let fis = H2DRegisterFIS::features(
+                ata::SET_FEATURES,
+                ata::FEATURE_ENABLE_SATA,
+                0,
+                ata::SATA_FEATURE_DEVSLEEP,
+            );
+            self.execute(fis, &[], false)?;
+        }
+
+        // aggressive link power management and device sleep are configured
+        // while the port is stopped
+        stop(registers)?;
+        let mut cmd = read_volatile!(registers, cmd)
+            - (HBAPortCommand::ALPE | HBAPortCommand::ASP | HBAPortCommand::APSTE);
+        match policy {
+            LinkPower::MaxPerformance => {}
+            LinkPower::Partial => cmd |= HBAPortCommand::ALPE,
+            // partial is entered sooner, and left faster
+            LinkPower::Slumber | LinkPower::DevSleep
+                if self.cap2.contains(HBACapabilitiesExtended::APST) =>
+            {
+                cmd |= HBAPortCommand::ALPE | HBAPortCommand::APSTE
+            }
+            LinkPower::Slumber | LinkPower::DevSleep => {
+                cmd |= HBAPortCommand::ALPE | HBAPortCommand::ASP
+            }
+        }
+        write_volatile!(registers, cmd, cmd);
+        let mut devslp = read_volatile!(registers, devslp) & !DEVSLP_CONFIGURATION;
+        if policy == LinkPower::DevSleep {
+            devslp |= DEVSLP_ADSE
+                | DEVSLP_DETO << DEVSLP_DETO_SHIFT
+                | DEVSLP_MDAT << DEVSLP_MDAT_SHIFT
+                | DEVSLP_DITO << DEVSLP_DITO_SHIFT;
+        }
+        if self.cap2.contains(HBACapabilitiesExtended::SDS) {
+            write_volatile!(registers, devslp, devslp);
+        }
+        let ipm = match policy {
+            LinkPower::MaxPerformance => {
+                SCTL_IPM_NO_PARTIAL | SCTL_IPM_NO_SLUMBER | SCTL_IPM_NO_DEVSLEEP
+            }
+            LinkPower::Partial => SCTL_IPM_NO_SLUMBER | SCTL_IPM_NO_DEVSLEEP,
+            LinkPower::Slumber => SCTL_IPM_NO_DEVSLEEP,
+            LinkPower::DevSleep => 0,
+        };
+        let sctl = read_volatile!(registers, sctl_ipmspm) & !0xF;
+        write_volatile!(registers, sctl_ipmspm, sctl | ipm);
+        self.spin_up();
+        self.start()?;
+        Ok(policy)
+    }
+
+    /// Identifies the device, and logs its model, serial number and capacity.
+    pub fn identify(&mut self) -> Result<(), Error> {
+        let command = if self.atapi {
+            ata::IDENTIFY_PACKET_DEVICE
+        } else {
+            ata::IDENTIFY_DEVICE
+        };
+        let (buffer, region) = self.buffer(ata::SECTOR_SIZE);
+        self.execute(H2DRegisterFIS::command(command, 0, 0), &[region], false)?;
+
+        let identity = ata::Identity::parse(unsafe { &*buffer });
+        if !self.atapi && !identity.sector_size.is_power_of_two() {
+            return Err(Error::BlockSize(identity.sector_size));
+        }
+        if self.atapi {
+            log::info!(
+                "Port {}: {} {} {} (ATAPI)",
+                self.number,
+                identity.model(),
+                identity.serial(),
+                identity.firmware()
+            );
+        } else {
+            if !identity.lba48 {
+                log::warn!("Port {}: no 48-bit addressing", self.number);
+            }
+            log::info!(
+                "Port {}: {} {} {} ({} sectors of {} bytes, {} MiB)",
+                self.number,
+                identity.model(),
+                identity.serial(),
+                identity.firmware(),
+                identity.sectors,
+                identity.sector_size,
+                (identity.sectors * identity.sector_size as u64) >> 20
+            );
+            // the last slot is kept for commands issued during error recovery
+            if self.cap.contains(HBACapabilities::SNCQ)
+                && self.slots > 1
+                && identity.lba48
+                && identity.queue_depth != 0
+            {
+                self.ncq = true;
+                self.depth = (self.slots - 1).min(identity.queue_depth);
+                log::info!("Port {}: queue depth {}", self.number, self.depth);
+            }
+        }
+        self.identity = Some(identity);
+        Ok(())
+    }
+
+    /// Issues a command transferring the regions, and waits for its
+    /// completion.
+    pub fn execute(
+        &mut self,
+        fis: H2DRegisterFIS,
+        regions: &[Region],
+        write: bool,
+    ) -> Result<(), Error> {
+        // queued and non-queued commands must not be mixed
+        while self.active != 0 {
+            self.wait(self.active);
+        }
+        let slot = self.slots - 1;
+        self.issue(slot, fis, regions, write);
+        self.wait(1 << slot);
+        self.result(slot)
+    }
+
+    /// Issues an ATAPI PACKET command with the SCSI command, and waits for its
+    /// completion.
+    pub fn packet(&mut self, cdb: &[u8], regions: &[Region]) -> Result<(), Error> {
+        let slot = self.slots as usize - 1;
+        unsafe {
+            let acmd = &mut (*self.memory).slots[slot].table.acmd;
+            acmd.fill(0);
+            acmd[..cdb.len()].copy_from_slice(cdb);
+        }
+        self.execute(H2DRegisterFIS::packet(), regions, false)
+    }
+
+    /// Returns the buffer of the commands issued by the driver itself, and
+    /// its first bytes as region.
+    pub fn buffer(&self, len: usize) -> (*mut [u8; 512], Region) {
+        let buffer = unsafe { ptr::addr_of_mut!((*self.memory).buffer) };
+        let region = Region {
+            phys_addr: self.phys_addr_of(buffer),
+            len: len as u32,
+        };
+        (buffer, region)
+    }
+
+    /// Returns the LBA of the last Device to Host Register FIS, some commands
+    /// return their result in it.
+    pub fn result_lba(&self) -> u64 {
+        let fis = unsafe { ptr::read_volatile(ptr::addr_of!((*self.memory).received_fis.rfis)) };
+        u64::from_le_bytes([
+            fis.lba_0_7,
+            fis.lba_8_15,
+            fis.lba_16_32,
+            fis.lba_24_31,
+            fis.lba_32_39,
+            fis.lba_40_47,
+            0,
+            0,
+        ])
+    }
+
+    /// Returns a slot for the next command, if fewer than the queue depth are
+    /// outstanding. Queued commands are tagged with their slot.
+    pub fn free_slot(&self) -> Option<u8> {
+        let slot = (!self.busy).trailing_zeros() as u8;
+        (slot < self.depth).then_some(slot)
+    }
+
+    /// Issues a command transferring the regions, without waiting for its
+    /// completion.
+    pub fn issue(&mut self, slot: u8, fis: H2DRegisterFIS, regions: &[Region], write: bool) {
+        assert!(self.busy & 1 << slot == 0 && regions.len() <= PRDT_LENGTH);
+        self.prepare(slot, fis, regions, write);
+        self.busy |= 1 << slot;
+        self.retries[slot as usize] = 0;
+        self.launch(slot);
+    }
+
+    /// Waits until at least one of the commands in the slots completed, and
+    /// returns the slots of the completed ones. Errors are recovered from
+    /// here, their commands complete with the error.
+    pub fn wait(&mut self, slots: u32) -> u32 {
+        let slots = slots & self.busy;
+        loop {
+            let completed = slots & !self.active;
+            if completed != 0 || slots == 0 {
+                return completed;
+            }
+
+            let registers = self.registers;
+            let active = self.active;
+            let mut completed = 0;
+            let responded = poll(|| {
+                completed =
+                    active & !(read_volatile!(registers, sact) | read_volatile!(registers, ci));
+                completed != 0 || read_volatile!(registers, is).intersects(ERRORS)
+            });
+            let is = read_volatile!(registers, is);
+            if responded && !is.intersects(ERRORS) {
+                write_volatile!(
+                    registers,
+                    is,
+                    HBAPortInterrupt::DHR | HBAPortInterrupt::SDB | HBAPortInterrupt::INF
+                );
+                self.active &= !completed;
+                self.queued &= !completed;
+            } else {
+                self.recover(is, responded);
+            }
+        }
+    }
+
+    /// Frees the slot of a completed command, and returns its result.
+    pub fn result(&mut self, slot: u8) -> Result<(), Error> {
+        assert!((self.busy & !self.active) & 1 << slot != 0);
+        self.busy &= !(1 << slot);
+        self.errors[slot as usize].take().map_or(Ok(()), Err)
+    }
+
+    /// Fills the command table and header of the slot.
+    fn prepare(&mut self, slot: u8, fis: H2DRegisterFIS, regions: &[Region], write: bool) {
+        unsafe {
+            let command_slot = &mut (*self.memory).slots[slot as usize];
+            command_slot.table.cfis = fis;
+            for (prd, region) in command_slot.prdt.iter_mut().zip(regions) {
+                *prd = PhysicalRegionDescriptor {
+                    dba: region.phys_addr as u32,
+                    dbau: (region.phys_addr >> 32) as u32,
+                    _rsvd: 0,
+                    dbci: region.len - 1,
+                };
+            }
+
+            let header = &mut (*self.memory).command_list.0[slot as usize];
+            let mut flags = (mem::size_of::<H2DRegisterFIS>() / 4) as u8;
+            if fis.command == ata::PACKET {
+                flags |= COMMAND_HEADER_ATAPI;
+            }
+            if write {
+                flags |= COMMAND_HEADER_WRITE;
+            }
+            header.cflawp = flags;
+            header.prdtl = regions.len() as u16;
+        }
+    }
+
+    /// Hands the prepared command in the slot to the HBA, also when it is
+    /// retried.
+    fn launch(&mut self, slot: u8) {
+        let queued = unsafe {
+            (*self.memory).command_list.0[slot as usize].prdbc = 0;
+            matches!(
+                (*self.memory).slots[slot as usize].table.cfis.command,
+                ata::READ_FPDMA_QUEUED | ata::WRITE_FPDMA_QUEUED
+            )
+        };
+        // the HBA must see the command before it is issued
+        atomic::fence(Ordering::SeqCst);
+        // the device clears the tag with a Set Device Bits FIS, once it
+        // completed the queued command
+        if queued {
+            write_volatile!(self.registers, sact, 1 << slot);
+            self.queued |= 1 << slot;
+        }
+        write_volatile!(self.registers, ci, 1 << slot);
+        self.active |= 1 << slot;
+    }
+
+    /// Recovers from an error which stopped the port, or from the port not
+    /// responding. The failed command completes with the error, the commands
+    /// aborted along with it are retried.
+    fn recover(&mut self, is: HBAPortInterrupt, responded: bool) {
+        let registers = self.registers;
+        let serr = read_volatile!(registers, serr_err).bits();
+        let error = if !responded {
+            Error::Timeout
+        } else if is.contains(HBAPortInterrupt::TFE) {
+            Error::Device {
+                status: read_volatile!(registers, tfd_sts),
+                error: read_volatile!(registers, tfd_err),
+                serr,
+            }
+        } else {
+            Error::Interface { serr }
+        };
+
+        // commands completed before the error are done
+        let outstanding = read_volatile!(registers, sact) | read_volatile!(registers, ci);
+        self.active &= outstanding;
+        self.queued &= outstanding;
+        // the HBA stops at the failed non-queued command
+        let failed = match error {
+            Error::Device { .. } if self.queued == 0 => {
+                Some(((read_volatile!(registers, cmd) & HBAPortCommand::CCS).bits() >> 8) as u8)
+            }
+            _ => None,
+        };
+
+        let active = self.active;
+        let queued = self.queued;
+        self.active = 0;
+        self.queued = 0;
+        // the device aborted all queued commands, and won't accept new ones
+        // until its error log is read
+        let restarted = self
+            .restart(!matches!(error, Error::Device { .. }))
+            .and_then(|_| match error {
+                Error::Device { .. } if queued != 0 => self.ncq_error(),
+                _ => Ok(failed),
+            });
+        log::warn!(
+            "Port {}: {}, {} commands outstanding",
+            self.number,
+            error,
+            active.count_ones()
+        );
+        let failed = match restarted {
+            Ok(failed) => failed,
+            Err(restart_error) => {
+                log::error!("Port {}: recovery failed: {}", self.number, restart_error);
+                for slot in 0..32 {
+                    if active & 1 << slot != 0 {
+                        self.errors[slot] = Some(restart_error);
+                    }
+                }
+                return;
+            }
+        };
+
+        for slot in 0..32 {
+            if active & 1 << slot == 0 {
+                continue;
+            }
+            if failed == Some(slot as u8) || failed.is_none() && self.retries[slot] >= RETRIES {
+                self.errors[slot] = Some(error);
+                continue;
+            }
+            if failed.is_none() {
+                self.retries[slot] += 1;
+            }
+            self.launch(slot as u8);
+        }
+    }
+
+    /// Reads the NCQ Command Error log, and returns the tag of the failed
+    /// queued command. The device is reset if the log can't be read.
+    fn ncq_error(&mut self) -> Result<Option<u8>, Error> {
+        let slot = self.slots - 1;
+        let (buffer, region) = self.buffer(ata::SECTOR_SIZE);
+        let fis = H2DRegisterFIS::command(ata::READ_LOG_EXT, ata::LOG_NCQ_COMMAND_ERROR, 1);
+        self.prepare(slot, fis, &[region], false);
+        atomic::fence(Ordering::SeqCst);
+        write_volatile!(self.registers, ci, 1 << slot);
+
+        let registers = self.registers;
+        if !poll(|| {
+            read_volatile!(registers, ci) & 1 << slot == 0
+                || read_volatile!(registers, is).intersects(ERRORS)
+        }) || read_volatile!(registers, is).intersects(ERRORS)
+        {
+            return self.restart(true).map(|_| None);
+        }
+        let log = unsafe { &*buffer };
+        Ok((log[0] & NCQ_LOG_NQ == 0).then_some(log[0] & NCQ_LOG_TAG))
+    }
+
+    /// Restarts the port after an error. The device is reset when it is
+    /// still busy, and the command list can't be overridden.
+    fn restart(&mut self, mut reset: bool) -> Result<(), Error> {
+        let registers = self.registers;
+        let cmd = read_volatile!(registers, cmd);
+        write_volatile!(registers, cmd, cmd - HBAPortCommand::ST);
+        if !poll(|| !read_volatile!(registers, cmd).contains(HBAPortCommand::CR)) {
+            reset = true;
+        }
+        self.clear_errors();
+        if !reset && read_volatile!(registers, tfd_sts) & (TFD_STS_BSY | TFD_STS_DRQ) != 0 {
+            reset = !self.override_command_list();
+        }
+        if reset {
+            self.reset()?;
+        }
+        self.start()
+    }
+
+    /// Clears BSY and DRQ, so that the next command can be issued.
+    fn override_command_list(&self) -> bool {
+        let registers = self.registers;
+        if !self.cap.contains(HBACapabilities::SCLO) {
+            return false;
+        }
+        let cmd = read_volatile!(registers, cmd);
+        write_volatile!(registers, cmd, cmd | HBAPortCommand::CLO);
+        poll(|| !read_volatile!(registers, cmd).contains(HBAPortCommand::CLO))
+            && read_volatile!(registers, tfd_sts) & (TFD_STS_BSY | TFD_STS_DRQ) == 0
+    }
+
+    /// Resets the device with a COMRESET, and waits until it is ready.
+    fn reset(&self) -> Result<(), Error> {
+        let registers = self.registers;
+        let sctl = read_volatile!(registers, sctl_detspd) & !0xF;
+        write_volatile!(registers, sctl_detspd, sctl | SCTL_DET_INIT);
+        for _ in 0..COMRESET_SPINS {
+            hint::spin_loop();
+        }
+        write_volatile!(registers, sctl_detspd, sctl);
+
+        if !poll(|| read_volatile!(registers, ssts_detspd) & 0xF == SSTS_DET_PRESENT) {
+            return Err(Error::NoDevice);
+        }
+        // the initialization sets the exchanged bit
+        self.clear_errors();
+        if !poll(|| read_volatile!(registers, tfd_sts) & (TFD_STS_BSY | TFD_STS_DRQ) == 0) {
+            return Err(Error::Timeout);
+        }
+        Ok(())
+    }
+
+    /// Enables receiving FISes, and spins up the device with staggered
+    /// spin-up.
+    fn spin_up(&self) {
+        let mut cmd = read_volatile!(self.registers, cmd) | HBAPortCommand::FRE;
+        if self.cap.contains(HBACapabilities::SSS) {
+            cmd |= HBAPortCommand::SUD;
+        }
+        write_volatile!(self.registers, cmd, cmd);
+    }
+
+    fn start(&self) -> Result<(), Error> {
+        let registers = self.registers;
+        if !poll(|| !read_volatile!(registers, cmd).contains(HBAPortCommand::CR)) {
+            return Err(Error::Timeout);
+        }
+        let cmd = read_volatile!(registers, cmd) | HBAPortCommand::FRE;
+        write_volatile!(registers, cmd, cmd);
+        write_volatile!(registers, cmd, cmd | HBAPortCommand::ST);
+        Ok(())
+    }
+
+    /// Clears the interrupt status and SATA errors, by writing ones.
+    fn clear_errors(&self) {
+        write_volatile!(
+            self.registers,
+            serr_err,
+            HBAPortSerialATAError::from_bits_retain(!0)
+        );
+        write_volatile!(
+            self.registers,
+            serr_diag,
+            HBAPortSerialATAErrorDiagnostics::from_bits_retain(!0)
+        );
+        write_volatile!(self.registers, is, HBAPortInterrupt::from_bits_retain(!0));
+    }
+
+    fn phys_addr_of<T>(&self, field: *const T) -> u64 {
+        self.phys_addr + (field as usize - self.memory as usize) as u64
+    }
+}
+
+/// Stops processing the command list, the port has to be idle before the
+/// command list or FIS receive area are changed.
+fn stop(registers: *mut HBAPort) -> Result<(), Error> {
+    let cmd = read_volatile!(registers, cmd);
+    write_volatile!(registers, cmd, cmd - HBAPortCommand::ST);
+    if !poll(|| !read_volatile!(registers, cmd).contains(HBAPortCommand::CR)) {
+        return Err(Error::Timeout);
+    }
+    let cmd = read_volatile!(registers, cmd);
+    write_volatile!(registers, cmd, cmd - HBAPortCommand::FRE);
+    if !poll(|| !read_volatile!(registers, cmd).contains(HBAPortCommand::FR)) {
+        return Err(Error::Timeout);
+    }
+    Ok(())
+}
@@ -0,0 +1,172 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::{mem, ops::RangeInclusive, ptr, slice};
+
+/// Real mode segment of the Extended BIOS Data Area.
+const EBDA_SEGMENT: usize = 0x40E;
+const BIOS_AREA: usize = 0xE0000;
+const BIOS_AREA_SIZE: usize = 0x20000;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct RootSystemDescriptionPointer {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+
+    // ACPI 2.0+
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    _reserved: [u8; 3],
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct SystemDescriptionTableHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct McfgAllocation {
+    base_address: u64,
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+    _reserved: u32,
+}
+
+/// ECAM window of a PCI segment group.
+pub struct EcamWindow {
+    pub segment: u16,
+    pub buses: RangeInclusive<u8>,
+    /// Physical address of the first bus.
+    pub base_address: u64,
+}
+
+/// Returns the ECAM windows listed in the MCFG table. The mappings of the
+/// tables are kept, as they are only read once.
+pub fn mcfg() -> impl Iterator<Item = EcamWindow> {
+    let allocations = table(b"MCFG")
+        .and_then(|mcfg| mcfg.get(mem::size_of::<SystemDescriptionTableHeader>() + 8..))
+        .unwrap_or_default();
+    allocations
+        .chunks_exact(mem::size_of::<McfgAllocation>())
+        .map(|allocation| {
+            let allocation =
+                unsafe { ptr::read_unaligned(allocation.as_ptr() as *const McfgAllocation) };
+            EcamWindow {
+                segment: allocation.segment,
+                buses: allocation.start_bus..=allocation.end_bus,
+                base_address: allocation.base_address + ((allocation.start_bus as u64) << 20),
+            }
+        })
+}
+
+/// Finds a table through the XSDT, or the RSDT before ACPI 2.0.
+fn table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    let rsdp = rsdp()?;
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (rsdp.xsdt_address, 8)
+    } else {
+        (rsdp.rsdt_address as u64, 4)
+    };
+    let root = map_table(root, header(root)?)?;
+    // only the headers are mapped to compare the signatures, as the mappings
+    // are kept
+    root[mem::size_of::<SystemDescriptionTableHeader>()..]
+        .chunks_exact(entry_size)
+        .find_map(|entry| {
+            let mut address = [0; 8];
+            address[..entry_size].copy_from_slice(entry);
+            let address = u64::from_le_bytes(address);
+            let header = header(address)?;
+            (&header.signature == signature)
+                .then(|| map_table(address, header))
+                .flatten()
+        })
+}
+
+/// Searches the first KiB of the EBDA and the BIOS area for the RSDP, which is
+/// aligned to 16 bytes.
+fn rsdp() -> Option<RootSystemDescriptionPointer> {
+    let ebda = unsafe { map(EBDA_SEGMENT, 2)?.cast::<u16>().read_unaligned() } as usize * 16;
+    [(ebda, 1024), (BIOS_AREA, BIOS_AREA_SIZE)]
+        .into_iter()
+        .find_map(|(address, size)| {
+            let area = unsafe { slice::from_raw_parts(map(address, size)?, size) };
+            (0..size)
+                .step_by(16)
+                .find_map(|offset| parse_rsdp(&area[offset..]))
+        })
+}
+
+fn parse_rsdp(bytes: &[u8]) -> Option<RootSystemDescriptionPointer> {
+    // the ACPI 1.0 part ends after the RSDT address
+    if !bytes.starts_with(b"RSD PTR ") || !bytes.get(..20).is_some_and(valid) {
+        return None;
+    }
+    let mut rsdp = [0; mem::size_of::<RootSystemDescriptionPointer>()];
+    let length = rsdp.len().min(bytes.len());
+    rsdp[..length].copy_from_slice(&bytes[..length]);
+    let rsdp = unsafe { ptr::read_unaligned(rsdp.as_ptr() as *const RootSystemDescriptionPointer) };
+    if rsdp.revision >= 2 && !bytes.get(..rsdp.length as usize).is_some_and(valid) {
+        return None;
+    }
+    Some(rsdp)
+}
+
+/// Maps and reads the header of a table.
+fn header(address: u64) -> Option<SystemDescriptionTableHeader> {
+    let address = usize::try_from(address).ok()?;
+    Some(unsafe {
+        map(address, mem::size_of::<SystemDescriptionTableHeader>())?
+            .cast::<SystemDescriptionTableHeader>()
+            .read_unaligned()
+    })
+}
+
+/// Maps the whole table, and verifies its checksum.
+fn map_table(address: u64, header: SystemDescriptionTableHeader) -> Option<&'static [u8]> {
+    let address = usize::try_from(address).ok()?;
+    let length = header.length as usize;
+    if length < mem::size_of::<SystemDescriptionTableHeader>() {
+        return None;
+    }
+    let table = unsafe { slice::from_raw_parts(map(address, length)?, length) };
+    valid(table).then_some(table)
+}
+
+fn map(address: usize, size: usize) -> Option<*const u8> {
+    sys::memory_map(address, size)
+        .map(|virt_addr| virt_addr as *const u8)
+        .ok()
+}
+
+/// Checksums are valid if all bytes sum up to zero.
+fn valid(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
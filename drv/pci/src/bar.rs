@@ -0,0 +1,256 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::ops::Range;
+
+use drv_pci::{
+    cfg::{ConfigurationAccessMechanism, ConfigurationSpaceHeaderCommand},
+    Device, Resource, RESOURCE_ROM,
+};
+
+const OFFSET_BASE_ADDRESS_REGISTER: u16 = 0x10;
+const OFFSET_EXPANSION_ROM_BASE_ADDRESS: u16 = 0x30;
+const OFFSET_BRIDGE_IO_BASE: u16 = 0x1C;
+const OFFSET_BRIDGE_MEMORY_BASE: u16 = 0x20;
+const OFFSET_BRIDGE_PREFETCHABLE_MEMORY_BASE: u16 = 0x24;
+const OFFSET_BRIDGE_PREFETCHABLE_MEMORY_BASE_UPPER: u16 = 0x28;
+const OFFSET_BRIDGE_PREFETCHABLE_MEMORY_LIMIT_UPPER: u16 = 0x2C;
+const OFFSET_BRIDGE_IO_BASE_UPPER: u16 = 0x30;
+const OFFSET_BRIDGE_EXPANSION_ROM_BASE_ADDRESS: u16 = 0x38;
+
+const BAR_IO: u32 = 1 << 0;
+const BAR_MEMORY_TYPE: u32 = 3 << 1;
+const BAR_MEMORY_TYPE_64: u32 = 2 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+const BAR_IO_ADDRESS: u32 = !0x3;
+const BAR_MEMORY_ADDRESS: u32 = !0xF;
+const EXPANSION_ROM_ADDRESS: u32 = !0x7FF;
+
+/// I/O, memory and prefetchable memory window of a PCI-to-PCI bridge.
+pub type Windows = [Resource; 3];
+
+/// Sizes the base address registers and the expansion ROM, type 1 headers
+/// only have two of the former.
+pub fn probe<AM: ConfigurationAccessMechanism>(am: &AM, device: &mut Device, bridge: bool) {
+    let location = device.location;
+
+    // the device must not decode while the registers are all-ones
+    let decode = am.command(location)
+        & (ConfigurationSpaceHeaderCommand::IOSE | ConfigurationSpaceHeaderCommand::MSE);
+    am.disable(location, decode);
+
+    let count = if bridge { 2 } else { 6 };
+    let mut index = 0;
+    while index < count {
+        let offset = OFFSET_BASE_ADDRESS_REGISTER + index as u16 * 4;
+        let (value, mask) = size(am, location, offset, !0);
+        if value & BAR_IO != 0 {
+            // the upper 16 bits may be hardwired to 0
+            let base = value & BAR_IO_ADDRESS;
+            let mask = mask & BAR_IO_ADDRESS;
+            if mask != 0 {
+                device.resource[index] =
+                    Resource::Pio(base as u16..end(base, mask | 0xFFFF0000) as u16);
+            }
+        } else if value & BAR_MEMORY_TYPE == BAR_MEMORY_TYPE_64 && index + 1 < count {
+            let (value_upper, mask_upper) = size(am, location, offset + 4, !0);
+            let base = (value_upper as u64) << 32 | (value & BAR_MEMORY_ADDRESS) as u64;
+            let mask = (mask_upper as u64) << 32 | (mask & BAR_MEMORY_ADDRESS) as u64;
+            if mask != 0 {
+                device.resource[index] = Resource::Mem64(base..base.saturating_add(!mask + 1));
+            }
+            if value & BAR_PREFETCHABLE != 0 {
+                device.prefetchable |= 1 << index;
+            }
+            index += 1;
+        } else {
+            // memory below 1 MiB is decoded like any other 32-bit memory
+            let base = value & BAR_MEMORY_ADDRESS;
+            let mask = mask & BAR_MEMORY_ADDRESS;
+            if mask != 0 {
+                device.resource[index] = Resource::Mem32(base..end(base, mask));
+            }
+            if value & BAR_PREFETCHABLE != 0 {
+                device.prefetchable |= 1 << index;
+            }
+        }
+        index += 1;
+    }
+
+    let offset = if bridge {
+        OFFSET_BRIDGE_EXPANSION_ROM_BASE_ADDRESS
+    } else {
+        OFFSET_EXPANSION_ROM_BASE_ADDRESS
+    };
+    let (value, mask) = size(am, location, offset, EXPANSION_ROM_ADDRESS);
+    let mask = mask & EXPANSION_ROM_ADDRESS;
+    if mask != 0 {
+        let base = value & EXPANSION_ROM_ADDRESS;
+        device.resource[RESOURCE_ROM] = Resource::Mem32(base..end(base, mask));
+    }
+
+    am.enable(location, decode);
+}
+
+/// Places the unassigned BARs of a function behind a bridge into the
+/// bridge's windows, and enables decoding. `next` holds the next free address
+/// of each window, and is advanced past the placed BARs.
+pub fn assign<AM: ConfigurationAccessMechanism>(
+    am: &AM,
+    device: &mut Device,
+    windows: &Windows,
+    next: &mut [u64; 3],
+) {
+    let location = device.location;
+    let mut decode = ConfigurationSpaceHeaderCommand::empty();
+    for index in 0..RESOURCE_ROM {
+        let prefetchable = device.prefetchable & 1 << index != 0;
+        // 32-bit BARs can't be placed in a 64-bit prefetchable window
+        let (window, size) = match &device.resource[index] {
+            Resource::Pio(range) if range.start == 0 => (0, range.end as u64),
+            Resource::Mem32(range) if range.start == 0 => (1, range.end as u64),
+            Resource::Mem64(range) if range.start == 0 => {
+                let window = match windows[2] {
+                    Resource::None => 1,
+                    _ if prefetchable => 2,
+                    _ => 1,
+                };
+                (window, range.end)
+            }
+            _ => continue,
+        };
+        let Some(range) = range(&windows[window]) else {
+            log::warn!("{:04X}: no window for BAR {}", location, index);
+            continue;
+        };
+        let base = next[window].max(range.start).next_multiple_of(size);
+        if base + size > range.end {
+            log::warn!("{:04X}: no space for BAR {}", location, index);
+            continue;
+        }
+        next[window] = base + size;
+
+        let offset = OFFSET_BASE_ADDRESS_REGISTER + index as u16 * 4;
+        let value = am.read32(location, offset);
+        match &mut device.resource[index] {
+            Resource::Pio(range) => {
+                am.write32(location, offset, value & !BAR_IO_ADDRESS | base as u32);
+                *range = base as u16..(base + size) as u16;
+                decode |= ConfigurationSpaceHeaderCommand::IOSE;
+            }
+            Resource::Mem32(range) => {
+                am.write32(location, offset, value & !BAR_MEMORY_ADDRESS | base as u32);
+                *range = base as u32..(base + size) as u32;
+                decode |= ConfigurationSpaceHeaderCommand::MSE;
+            }
+            Resource::Mem64(range) => {
+                am.write32(location, offset, value & !BAR_MEMORY_ADDRESS | base as u32);
+                am.write32(location, offset + 4, (base >> 32) as u32);
+                *range = base..base + size;
+                decode |= ConfigurationSpaceHeaderCommand::MSE;
+            }
+            _ => unreachable!(),
+        }
+    }
+    am.enable(location, decode);
+}
+
+/// Advances `next` past the ranges that already decode within the bridge's
+/// windows, e.g. BARs or windows the firmware assigned, see [`assign`].
+pub fn reserve(windows: &Windows, resources: &[Resource], next: &mut [u64; 3]) {
+    for resource in resources {
+        let Some(used) = range(resource).filter(|used| used.start != 0) else {
+            continue;
+        };
+        for (window, next) in windows.iter().zip(next.iter_mut()) {
+            let same_space =
+                matches!(window, Resource::Pio(_)) == matches!(resource, Resource::Pio(_));
+            if same_space && range(window).is_some_and(|window| window.contains(&used.start)) {
+                *next = (*next).max(used.end);
+            }
+        }
+    }
+}
+
+/// Reads the address ranges forwarded by a PCI-to-PCI bridge, windows whose
+/// limit is below their base are disabled.
+pub fn windows<AM: ConfigurationAccessMechanism>(am: &AM, location: u16) -> Windows {
+    let mut windows = [const { Resource::None }; 3];
+
+    let io = am.read32(location, OFFSET_BRIDGE_IO_BASE);
+    let io_upper = am.read32(location, OFFSET_BRIDGE_IO_BASE_UPPER);
+    let (io_base, io_limit) = (io & 0xFF, io >> 8 & 0xFF);
+    let mut base = (io_base & 0xF0) << 8;
+    let mut limit = (io_limit & 0xF0) << 8 | 0xFFF;
+    // 32-bit I/O addressing, which is of no use on x86
+    if io_base & 0xF == 1 {
+        base |= (io_upper & 0xFFFF) << 16;
+        limit |= (io_upper >> 16) << 16;
+    }
+    if base <= limit && limit <= u16::MAX as u32 {
+        windows[0] = Resource::Pio(base as u16..(limit as u16).saturating_add(1));
+    }
+
+    let memory = am.read32(location, OFFSET_BRIDGE_MEMORY_BASE);
+    let base = (memory & 0xFFF0) << 16;
+    let limit = (memory >> 16 & 0xFFF0) << 16 | 0xFFFFF;
+    if base <= limit {
+        windows[1] = Resource::Mem32(base..limit.saturating_add(1));
+    }
+
+    let prefetchable = am.read32(location, OFFSET_BRIDGE_PREFETCHABLE_MEMORY_BASE);
+    let mut base = ((prefetchable & 0xFFF0) as u64) << 16;
+    let mut limit = ((prefetchable >> 16 & 0xFFF0) as u64) << 16 | 0xFFFFF;
+    if prefetchable & 0xF == 1 {
+        base |= (am.read32(location, OFFSET_BRIDGE_PREFETCHABLE_MEMORY_BASE_UPPER) as u64) << 32;
+        limit |= (am.read32(location, OFFSET_BRIDGE_PREFETCHABLE_MEMORY_LIMIT_UPPER) as u64) << 32;
+        if base <= limit {
+            windows[2] = Resource::Mem64(base..limit.saturating_add(1));
+        }
+    } else if base <= limit {
+        windows[2] = Resource::Mem32(base as u32..(limit as u32).saturating_add(1));
+    }
+
+    windows
+}
+
+/// Address range of a window, widened to 64-bit.
+pub fn range(window: &Resource) -> Option<Range<u64>> {
+    match window {
+        Resource::Pio(range) => Some(range.start as u64..range.end as u64),
+        Resource::Mem32(range) => Some(range.start as u64..range.end as u64),
+        Resource::Mem64(range) => Some(range.clone()),
+        _ => None,
+    }
+}
+
+/// Writes all-ones to a register, reads back which bits are implemented and
+/// restores the original value.
+fn size<AM: ConfigurationAccessMechanism>(
+    am: &AM,
+    location: u16,
+    offset: u16,
+    ones: u32,
+) -> (u32, u32) {
+    let value = am.read32(location, offset);
+    am.write32(location, offset, ones);
+    let mask = am.read32(location, offset);
+    am.write32(location, offset, value);
+    (value, mask)
+}
+
+/// End of the range decoded at `base`, given the implemented address bits.
+fn end(base: u32, mask: u32) -> u32 {
+    base.saturating_add(!mask).saturating_add(1)
+}
@@ -0,0 +1,226 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use drv_pci::{cfg::ConfigurationAccessMechanism, Device, Driver, Resource};
+
+use crate::{bar, ConfigurationSpace, ConfigurationSpaceHeader, Segment};
+
+/// Maximum number of functions, further ones are ignored.
+pub const MAX_FUNCTIONS: usize = 64;
+
+const HEADER_TYPE_MULTI_FUNCTION: u8 = 1 << 7;
+const HEADER_TYPE_LAYOUT: u8 = (1 << 7) - 1;
+const HEADER_LAYOUT_BRIDGE: u8 = 1;
+
+pub struct Node {
+    pub device: Device,
+    /// Bridge this function is behind, `None` for functions on a root bus.
+    pub parent: Option<usize>,
+    /// Secondary bus, in case of a PCI-to-PCI bridge.
+    pub secondary_bus: Option<u8>,
+    /// Forwarded address ranges, in case of a PCI-to-PCI bridge.
+    pub windows: Option<bar::Windows>,
+    pub driver: Option<&'static Driver>,
+    /// Task of the driver, spawned when it was bound.
+    pub task: Option<sys::Task>,
+    /// Times the driver was bound again after its task exited.
+    pub restarts: usize,
+}
+
+/// Functions in the order they have been found, bridges are followed by the
+/// functions behind them. Hot-added functions take the place of removed ones,
+/// so indices stay valid until the function is removed.
+pub struct Tree {
+    nodes: [Option<Node>; MAX_FUNCTIONS],
+    len: usize,
+}
+
+impl Tree {
+    pub fn new() -> Self {
+        Self {
+            nodes: [const { None }; MAX_FUNCTIONS],
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Node> {
+        self.nodes.get(index)?.as_ref()
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut Node> {
+        self.nodes.get_mut(index)?.as_mut()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Node)> {
+        self.nodes[..self.len]
+            .iter()
+            .enumerate()
+            .filter_map(|(index, node)| Some((index, node.as_ref()?)))
+    }
+
+    /// Functions directly behind the given bridge, or on a root bus.
+    pub fn children(&self, parent: Option<usize>) -> impl Iterator<Item = (usize, &Node)> {
+        self.iter().filter(move |(_, node)| node.parent == parent)
+    }
+
+    /// Removes the function, functions behind it have to be removed first.
+    pub fn remove(&mut self, index: usize) -> Option<Node> {
+        self.nodes.get_mut(index)?.take()
+    }
+
+    fn push(&mut self, node: Node) -> Option<usize> {
+        let index = self.nodes.iter().position(Option::is_none)?;
+        self.nodes[index] = Some(node);
+        self.len = self.len.max(index + 1);
+        Some(index)
+    }
+
+    /// Walks all buses of the segment reachable from the host bridges,
+    /// descending through PCI-to-PCI bridges.
+    pub fn enumerate<AM: ConfigurationAccessMechanism>(&mut self, segment: &Segment<AM>) {
+        // a multi-function host bridge has one root bus per function
+        let location = (segment.first_bus as u16) << 8;
+        let header = segment.am.header(location);
+        if header.header_type & HEADER_TYPE_MULTI_FUNCTION == 0 {
+            self.scan_bus(segment, segment.first_bus, None);
+        } else {
+            for function in 0..8 {
+                if segment.am.header(location | function).vendor_id == 0xFFFF {
+                    continue;
+                }
+                self.scan_bus(
+                    segment,
+                    segment.first_bus.wrapping_add(function as u8),
+                    None,
+                );
+            }
+        }
+    }
+
+    /// Places the BARs of the segment's functions that the firmware left
+    /// unassigned, in the windows of the bridge they are behind. Those on a
+    /// root bus are left alone, as the apertures of host bridges are unknown.
+    pub fn assign<AM: ConfigurationAccessMechanism>(&mut self, segment: &Segment<AM>) {
+        for parent in 0..self.len {
+            let Some(windows) = self
+                .get(parent)
+                .filter(|node| node.device.segment == segment.number)
+                .and_then(|node| node.windows.clone())
+            else {
+                continue;
+            };
+            let mut next = [0; 3];
+            for (_, node) in self.children(Some(parent)) {
+                bar::reserve(&windows, &node.device.resource, &mut next);
+                // and of bridges behind it
+                if let Some(forwarded) = &node.windows {
+                    bar::reserve(&windows, forwarded, &mut next);
+                }
+            }
+            for index in 0..self.len {
+                let Some(node) = self.get_mut(index) else {
+                    continue;
+                };
+                if node.parent == Some(parent) {
+                    bar::assign(&segment.am, &mut node.device, &windows, &mut next);
+                }
+            }
+        }
+    }
+
+    /// Adds the functions on the bus, and behind it.
+    pub fn scan_bus<AM: ConfigurationAccessMechanism>(
+        &mut self,
+        segment: &Segment<AM>,
+        bus: u8,
+        parent: Option<usize>,
+    ) {
+        let am = &segment.am;
+        for device in 0..32 {
+            let location = (bus as u16) << 8 | device << 3;
+            let header = am.header(location);
+            if header.vendor_id == 0xFFFF {
+                continue;
+            }
+
+            let multi_function = header.header_type & HEADER_TYPE_MULTI_FUNCTION != 0;
+            self.scan_function(segment, location, header, parent);
+            if multi_function {
+                for function in 1..8 {
+                    let header = am.header(location | function);
+                    if header.vendor_id == 0xFFFF {
+                        continue;
+                    }
+                    self.scan_function(segment, location | function, header, parent);
+                }
+            }
+        }
+    }
+
+    fn scan_function<AM: ConfigurationAccessMechanism>(
+        &mut self,
+        segment: &Segment<AM>,
+        location: u16,
+        header: ConfigurationSpaceHeader<AM>,
+        parent: Option<usize>,
+    ) {
+        let bridge = header.header_type & HEADER_TYPE_LAYOUT == HEADER_LAYOUT_BRIDGE;
+        let secondary_bus = if bridge {
+            let secondary_bus = unsafe { header.type_specific.type_1 }.secondary_bus_number;
+            // skip unassigned bus numbers, and ones which would loop
+            (secondary_bus > (location >> 8) as u8).then_some(secondary_bus)
+        } else {
+            None
+        };
+        let mut device = Device {
+            segment: segment.number,
+            location,
+            class: [
+                header.class_code[2],
+                header.class_code[1],
+                header.class_code[0],
+                header.revision_id,
+            ],
+            class_vendor: [header.vendor_id, header.device_id],
+            resource: [const { Resource::None }; 7],
+            prefetchable: 0,
+            irq: None,
+        };
+        bar::probe(&segment.am, &mut device, bridge);
+        let Some(index) = self.push(Node {
+            device,
+            parent,
+            secondary_bus,
+            windows: bridge.then(|| bar::windows(&segment.am, location)),
+            driver: None,
+            task: None,
+            restarts: 0,
+        }) else {
+            log::warn!(
+                "Too many functions, ignoring {:04X}:{:04X}",
+                segment.number,
+                location
+            );
+            return;
+        };
+
+        if let Some(secondary_bus) = secondary_bus {
+            self.scan_bus(segment, secondary_bus, Some(index));
+        }
+    }
+}
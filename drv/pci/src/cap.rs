@@ -0,0 +1,382 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::cfg::{ConfigurationAccessMechanism, OFFSET_STATUS};
+
+const OFFSET_CAPABILITIES_POINTER: u16 = 0x34;
+const OFFSET_EXTENDED_CAPABILITIES: u16 = 0x100;
+
+/// Capabilities List
+const STATUS_CL: u16 = 1 << 4;
+
+pub const ID_POWER_MANAGEMENT: u16 = 0x01;
+pub const ID_MSI: u16 = 0x05;
+pub const ID_PCI_EXPRESS: u16 = 0x10;
+pub const ID_MSI_X: u16 = 0x11;
+
+pub const EXTENDED_ID_ADVANCED_ERROR_REPORTING: u16 = 0x0001;
+pub const EXTENDED_ID_DEVICE_SERIAL_NUMBER: u16 = 0x0003;
+
+/// Local APIC address range, the destination is placed in bits 12-19.
+const MSI_ADDRESS: u32 = 0xFEE00000;
+
+#[derive(Clone, Copy)]
+pub struct Capability {
+    pub id: u16,
+    /// Offset in the configuration space.
+    pub offset: u16,
+    /// Whether it is a PCI Express extended capability, with its own ID space.
+    pub extended: bool,
+}
+
+/// Walks the capability list, followed by the extended capabilities if they
+/// are accessible.
+pub struct Capabilities<'a, AM: ConfigurationAccessMechanism> {
+    am: &'a AM,
+    location: u16,
+    next: u16,
+    extended: bool,
+    /// Only PCI Express functions have extended capabilities.
+    pci_express: bool,
+    /// Bounds the walk, in case the list loops.
+    remaining: u16,
+}
+
+impl<'a, AM: ConfigurationAccessMechanism> Capabilities<'a, AM> {
+    pub fn new(am: &'a AM, location: u16) -> Self {
+        let next = if am.read16(location, OFFSET_STATUS) & STATUS_CL != 0 {
+            (am.read8(location, OFFSET_CAPABILITIES_POINTER) & 0xFC) as u16
+        } else {
+            0
+        };
+        Self {
+            am,
+            location,
+            next,
+            extended: false,
+            pci_express: false,
+            remaining: (4096 - 64) / 4,
+        }
+    }
+
+    pub fn find(am: &'a AM, location: u16, id: u16, extended: bool) -> Option<Capability> {
+        Self::new(am, location)
+            .find(|capability| capability.id == id && capability.extended == extended)
+    }
+}
+
+impl<AM: ConfigurationAccessMechanism> Iterator for Capabilities<'_, AM> {
+    type Item = Capability;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.remaining = self.remaining.checked_sub(1)?;
+        if !self.extended {
+            if self.next != 0 {
+                let offset = self.next;
+                let id = self.am.read8(self.location, offset) as u16;
+                self.next = (self.am.read8(self.location, offset + 1) & 0xFC) as u16;
+                self.pci_express |= id == ID_PCI_EXPRESS;
+                return Some(Capability {
+                    id,
+                    offset,
+                    extended: false,
+                });
+            }
+
+            if !AM::EXTENDED || !self.pci_express {
+                return None;
+            }
+            self.extended = true;
+            self.next = OFFSET_EXTENDED_CAPABILITIES;
+        }
+
+        if self.next < OFFSET_EXTENDED_CAPABILITIES {
+            return None;
+        }
+        let offset = self.next;
+        let header = self.am.read32(self.location, offset);
+        if header == 0 || header == !0 {
+            return None;
+        }
+        self.next = (header >> 20) as u16 & 0xFFC;
+        Some(Capability {
+            id: header as u16,
+            offset,
+            extended: true,
+        })
+    }
+}
+
+/// Message Signaled Interrupts, only a single message is used.
+pub struct Msi<'a, AM: ConfigurationAccessMechanism> {
+    am: &'a AM,
+    location: u16,
+    offset: u16,
+}
+
+impl<'a, AM: ConfigurationAccessMechanism> Msi<'a, AM> {
+    /// Enable
+    const CONTROL_ENABLE: u16 = 1 << 0;
+    /// Multiple Message Enable
+    const CONTROL_MME: u16 = 7 << 4;
+    /// 64-bit Address Capable
+    const CONTROL_64: u16 = 1 << 7;
+    /// Per-Vector Masking Capable
+    const CONTROL_PVM: u16 = 1 << 8;
+
+    pub fn new(am: &'a AM, location: u16) -> Option<Self> {
+        Some(Self {
+            am,
+            location,
+            offset: Capabilities::find(am, location, ID_MSI, false)?.offset,
+        })
+    }
+
+    fn control(&self) -> u16 {
+        self.am.read16(self.location, self.offset + 2)
+    }
+
+    /// Delivers interrupts as the given vector to the given processor, legacy
+    /// INTx should be disabled through the command register.
+    pub fn enable(&self, irq: sys::Irq) {
+        let control = self.control();
+        self.am.write32(
+            self.location,
+            self.offset + 4,
+            MSI_ADDRESS | (irq.apic_id & 0xFF) << 12,
+        );
+        let data = if control & Self::CONTROL_64 != 0 {
+            self.am.write32(self.location, self.offset + 8, 0);
+            self.offset + 12
+        } else {
+            self.offset + 8
+        };
+        // fixed delivery, edge triggered
+        self.am.write16(self.location, data, irq.vector as u16);
+        if control & Self::CONTROL_PVM != 0 {
+            self.am.write32(self.location, data + 4, 0);
+        }
+        self.am.write16(
+            self.location,
+            self.offset + 2,
+            control & !Self::CONTROL_MME | Self::CONTROL_ENABLE,
+        );
+    }
+
+    pub fn disable(&self) {
+        self.am.write16(
+            self.location,
+            self.offset + 2,
+            self.control() & !Self::CONTROL_ENABLE,
+        );
+    }
+}
+
+/// Extended Message Signaled Interrupts, the table itself is located in one of
+/// the memory BARs and has to be mapped by the driver.
+pub struct MsiX<'a, AM: ConfigurationAccessMechanism> {
+    am: &'a AM,
+    location: u16,
+    offset: u16,
+}
+
+impl<'a, AM: ConfigurationAccessMechanism> MsiX<'a, AM> {
+    /// Table Size
+    const CONTROL_TABLE_SIZE: u16 = (1 << 11) - 1;
+    /// Function Mask
+    const CONTROL_FUNCTION_MASK: u16 = 1 << 14;
+    /// Enable
+    const CONTROL_ENABLE: u16 = 1 << 15;
+
+    pub fn new(am: &'a AM, location: u16) -> Option<Self> {
+        Some(Self {
+            am,
+            location,
+            offset: Capabilities::find(am, location, ID_MSI_X, false)?.offset,
+        })
+    }
+
+    fn control(&self) -> u16 {
+        self.am.read16(self.location, self.offset + 2)
+    }
+
+    pub fn table_size(&self) -> usize {
+        (self.control() & Self::CONTROL_TABLE_SIZE) as usize + 1
+    }
+
+    /// Index of the BAR and offset in it of the table.
+    pub fn table(&self) -> (usize, u32) {
+        let table = self.am.read32(self.location, self.offset + 4);
+        ((table & 0x7) as usize, table & !0x7)
+    }
+
+    /// Index of the BAR and offset in it of the pending bit array.
+    pub fn pending_bit_array(&self) -> (usize, u32) {
+        let pending_bit_array = self.am.read32(self.location, self.offset + 8);
+        ((pending_bit_array & 0x7) as usize, pending_bit_array & !0x7)
+    }
+
+    /// Programs and unmasks an entry of the mapped table.
+    ///
+    /// # Safety
+    ///
+    /// `table` has to point to the mapped table, and `index` be within
+    /// [`Self::table_size`].
+    pub unsafe fn set_entry(&self, table: *mut u32, index: usize, irq: sys::Irq) {
+        let entry = table.add(index * 4);
+        entry.write_volatile(MSI_ADDRESS | (irq.apic_id & 0xFF) << 12);
+        entry.add(1).write_volatile(0);
+        entry.add(2).write_volatile(irq.vector as u32);
+        entry.add(3).write_volatile(0);
+    }
+
+    pub fn enable(&self) {
+        self.am.write16(
+            self.location,
+            self.offset + 2,
+            self.control() & !Self::CONTROL_FUNCTION_MASK | Self::CONTROL_ENABLE,
+        );
+    }
+
+    pub fn disable(&self) {
+        self.am.write16(
+            self.location,
+            self.offset + 2,
+            self.control() & !Self::CONTROL_ENABLE,
+        );
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum PowerState {
+    D0 = 0,
+    D1 = 1,
+    D2 = 2,
+    D3Hot = 3,
+}
+
+pub struct PowerManagement<'a, AM: ConfigurationAccessMechanism> {
+    am: &'a AM,
+    location: u16,
+    offset: u16,
+}
+
+impl<'a, AM: ConfigurationAccessMechanism> PowerManagement<'a, AM> {
+    /// Power State
+    const PMCSR_POWER_STATE: u16 = 3;
+
+    pub fn new(am: &'a AM, location: u16) -> Option<Self> {
+        Some(Self {
+            am,
+            location,
+            offset: Capabilities::find(am, location, ID_POWER_MANAGEMENT, false)?.offset,
+        })
+    }
+
+    /// Power Management Capabilities
+    pub fn capabilities(&self) -> u16 {
+        self.am.read16(self.location, self.offset + 2)
+    }
+
+    pub fn power_state(&self) -> PowerState {
+        match self.am.read16(self.location, self.offset + 4) & Self::PMCSR_POWER_STATE {
+            0 => PowerState::D0,
+            1 => PowerState::D1,
+            2 => PowerState::D2,
+            _ => PowerState::D3Hot,
+        }
+    }
+
+    /// Transitions the function, which takes up to 10 ms from or to D3hot.
+    pub fn set_power_state(&self, power_state: PowerState) {
+        let pmcsr = self.am.read16(self.location, self.offset + 4);
+        self.am.write16(
+            self.location,
+            self.offset + 4,
+            pmcsr & !Self::PMCSR_POWER_STATE | power_state as u16,
+        );
+    }
+}
+
+pub struct PciExpress<'a, AM: ConfigurationAccessMechanism> {
+    am: &'a AM,
+    location: u16,
+    offset: u16,
+}
+
+impl<'a, AM: ConfigurationAccessMechanism> PciExpress<'a, AM> {
+    pub const DEVICE_CAPABILITIES: u16 = 0x04;
+    pub const DEVICE_CONTROL: u16 = 0x08;
+    pub const DEVICE_STATUS: u16 = 0x0A;
+    pub const LINK_CAPABILITIES: u16 = 0x0C;
+    pub const LINK_CONTROL: u16 = 0x10;
+    pub const LINK_STATUS: u16 = 0x12;
+    pub const SLOT_CAPABILITIES: u16 = 0x14;
+    pub const SLOT_CONTROL: u16 = 0x18;
+    pub const SLOT_STATUS: u16 = 0x1A;
+
+    /// Slot Implemented
+    const CAPABILITIES_SI: u16 = 1 << 8;
+
+    pub fn new(am: &'a AM, location: u16) -> Option<Self> {
+        Some(Self {
+            am,
+            location,
+            offset: Capabilities::find(am, location, ID_PCI_EXPRESS, false)?.offset,
+        })
+    }
+
+    fn capabilities(&self) -> u16 {
+        self.am.read16(self.location, self.offset + 2)
+    }
+
+    pub fn version(&self) -> u8 {
+        (self.capabilities() & 0xF) as u8
+    }
+
+    /// Device/Port Type, e.g. 4 for a root port and 6 for a downstream port.
+    pub fn port_type(&self) -> u8 {
+        (self.capabilities() >> 4 & 0xF) as u8
+    }
+
+    /// Whether the port is connected to a slot.
+    pub fn slot_implemented(&self) -> bool {
+        self.capabilities() & Self::CAPABILITIES_SI != 0
+    }
+
+    /// Current link speed (as in 2.5, 5, 8, ... GT/s) and width.
+    pub fn link(&self) -> (u8, u8) {
+        let link_status = self.read16(Self::LINK_STATUS);
+        ((link_status & 0xF) as u8, (link_status >> 4 & 0x3F) as u8)
+    }
+
+    pub fn read16(&self, register: u16) -> u16 {
+        self.am.read16(self.location, self.offset + register)
+    }
+
+    pub fn read32(&self, register: u16) -> u32 {
+        self.am.read32(self.location, self.offset + register)
+    }
+
+    pub fn write16(&self, register: u16, value: u16) {
+        self.am
+            .write16(self.location, self.offset + register, value)
+    }
+
+    pub fn write32(&self, register: u16, value: u32) {
+        self.am
+            .write32(self.location, self.offset + register, value)
+    }
+}
@@ -0,0 +1,214 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::ops::RangeInclusive;
+
+use bitflags::bitflags;
+use pio::Port;
+
+pub const OFFSET_COMMAND: u16 = 0x04;
+pub const OFFSET_STATUS: u16 = 0x06;
+
+/// Access to the configuration space of all functions, which are addressed by
+/// their location and a byte offset. Offsets have to be aligned to the size of
+/// the access.
+pub trait ConfigurationAccessMechanism {
+    /// Whether the extended configuration space (offsets 256-4095) is
+    /// accessible.
+    const EXTENDED: bool;
+
+    fn read8(&self, location: u16, offset: u16) -> u8;
+
+    fn read16(&self, location: u16, offset: u16) -> u16;
+
+    fn read32(&self, location: u16, offset: u16) -> u32;
+
+    fn write8(&self, location: u16, offset: u16, value: u8);
+
+    fn write16(&self, location: u16, offset: u16, value: u16);
+
+    fn write32(&self, location: u16, offset: u16, value: u32);
+
+    fn command(&self, location: u16) -> ConfigurationSpaceHeaderCommand {
+        ConfigurationSpaceHeaderCommand::from_bits_retain(self.read16(location, OFFSET_COMMAND))
+    }
+
+    /// Sets bits in the command register, e.g. BME before the function may
+    /// initiate DMA.
+    fn enable(&self, location: u16, command: ConfigurationSpaceHeaderCommand) {
+        let command = self.command(location) | command;
+        self.write16(location, OFFSET_COMMAND, command.bits());
+    }
+
+    fn disable(&self, location: u16, command: ConfigurationSpaceHeaderCommand) {
+        let command = self.command(location) - command;
+        self.write16(location, OFFSET_COMMAND, command.bits());
+    }
+}
+
+/// Legacy configuration mechanism through CONFIG_ADDRESS and CONFIG_DATA,
+/// limited to the first 256 bytes. Reads beyond return all-ones, and writes
+/// are ignored.
+pub struct CAM(Port<u32>);
+
+impl CAM {
+    const CONFIG_ADDRESS: u16 = 0xCF8;
+    const CONFIG_DATA: u16 = 0xCFC;
+
+    /// # Safety
+    ///
+    /// Requires access to the I/O ports, which must not be used concurrently.
+    pub unsafe fn new() -> Self {
+        Self(Port::new(Self::CONFIG_ADDRESS))
+    }
+
+    /// Selects the register, and returns the data port for the access.
+    fn select<T: pio::PortType>(&self, location: u16, offset: u16) -> Option<Port<T>> {
+        if offset >= 0x100 {
+            return None;
+        }
+        self.0
+            .write(1 << 31 | (location as u32) << 8 | (offset & 0xFC) as u32);
+        Some(unsafe { Port::new(Self::CONFIG_DATA + (offset & 0x3)) })
+    }
+}
+
+impl ConfigurationAccessMechanism for CAM {
+    const EXTENDED: bool = false;
+
+    fn read8(&self, location: u16, offset: u16) -> u8 {
+        self.select(location, offset).map_or(!0, Port::read)
+    }
+
+    fn read16(&self, location: u16, offset: u16) -> u16 {
+        self.select(location, offset).map_or(!0, Port::read)
+    }
+
+    fn read32(&self, location: u16, offset: u16) -> u32 {
+        self.select(location, offset).map_or(!0, Port::read)
+    }
+
+    fn write8(&self, location: u16, offset: u16, value: u8) {
+        if let Some(port) = self.select(location, offset) {
+            port.write(value)
+        }
+    }
+
+    fn write16(&self, location: u16, offset: u16, value: u16) {
+        if let Some(port) = self.select(location, offset) {
+            port.write(value)
+        }
+    }
+
+    fn write32(&self, location: u16, offset: u16, value: u32) {
+        if let Some(port) = self.select(location, offset) {
+            port.write(value)
+        }
+    }
+}
+
+/// Enhanced configuration mechanism, the configuration space of every
+/// function is memory-mapped at `base + (location << 12)`. Buses outside of
+/// the mapped range read as all-ones, and writes to them are ignored.
+pub struct ECAM {
+    base: *mut u8,
+    buses: RangeInclusive<u8>,
+}
+
+impl ECAM {
+    /// # Safety
+    ///
+    /// `base` has to point to the uncached mapping of the configuration space
+    /// of the given buses, starting with the first one.
+    pub unsafe fn new(base: *mut u8, buses: RangeInclusive<u8>) -> Self {
+        Self { base, buses }
+    }
+
+    fn register<T>(&self, location: u16, offset: u16) -> Option<*mut T> {
+        let bus = (location >> 8) as u8;
+        if !self.buses.contains(&bus) {
+            return None;
+        }
+        let location = location - ((*self.buses.start() as u16) << 8);
+        Some(unsafe {
+            self.base
+                .add((location as usize) << 12 | (offset & 0xFFF) as usize) as *mut T
+        })
+    }
+}
+
+impl ConfigurationAccessMechanism for ECAM {
+    const EXTENDED: bool = true;
+
+    fn read8(&self, location: u16, offset: u16) -> u8 {
+        self.register::<u8>(location, offset)
+            .map_or(!0, |register| unsafe { register.read_volatile() })
+    }
+
+    fn read16(&self, location: u16, offset: u16) -> u16 {
+        self.register::<u16>(location, offset)
+            .map_or(!0, |register| unsafe { register.read_volatile() })
+    }
+
+    fn read32(&self, location: u16, offset: u16) -> u32 {
+        self.register::<u32>(location, offset)
+            .map_or(!0, |register| unsafe { register.read_volatile() })
+    }
+
+    fn write8(&self, location: u16, offset: u16, value: u8) {
+        if let Some(register) = self.register::<u8>(location, offset) {
+            unsafe { register.write_volatile(value) }
+        }
+    }
+
+    fn write16(&self, location: u16, offset: u16, value: u16) {
+        if let Some(register) = self.register::<u16>(location, offset) {
+            unsafe { register.write_volatile(value) }
+        }
+    }
+
+    fn write32(&self, location: u16, offset: u16, value: u32) {
+        if let Some(register) = self.register::<u32>(location, offset) {
+            unsafe { register.write_volatile(value) }
+        }
+    }
+}
+
+bitflags! {
+    #[derive(Clone, Copy)]
+    pub struct ConfigurationSpaceHeaderCommand: u16 {
+        /// I/O Space Enable
+        const IOSE = 1 << 0;
+        /// Memory Space Enable
+        const MSE = 1 << 1;
+        /// Bus Master Enable
+        const BME = 1 << 2;
+        /// Special Cycle Enable
+        const SCE = 1 << 3;
+        /// Memory Write and Invalidate
+        const MWI = 1 << 4;
+        /// VGA Palette Snoop
+        const VGAPS = 1 << 5;
+        /// Parity Error Response
+        const PER = 1 << 6;
+        /// IDSEL Stepping/Wait Cycle Control
+        const IDSEL = 1 << 7;
+        /// SERR# Enable
+        const SERRE = 1 << 8;
+        /// Fast Back-to-Back Transactions Enable
+        const FB2BTE = 1 << 9;
+        /// Interrupt Disable
+        const ID = 1 << 10;
+    }
+}
@@ -0,0 +1,299 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::hint;
+
+use drv_pci::{
+    cap::{Msi, PciExpress},
+    cfg::{ConfigurationAccessMechanism, ConfigurationSpaceHeaderCommand},
+    Device, Driver, Match, Resource,
+};
+
+use crate::{
+    bus::{Node, Tree},
+    Segment,
+};
+
+/// Polls of the device status until outstanding transactions have to complete.
+const PENDING_ATTEMPTS: usize = 1_000_000;
+/// Transactions Pending
+const DEVICE_STATUS_TP: u16 = 1 << 5;
+/// Times a driver is bound again after its task exited, e.g. as it failed to
+/// initialize the function.
+const MAX_RESTARTS: usize = 3;
+
+/// Drivers known to the bus driver, and the functions they support.
+static DRIVERS: &[Driver] = &[
+    Driver {
+        name: "ahci",
+        // mass storage, SATA, AHCI 1.0
+        matches: &[Match::class(0x01, 0x06, 0x01)],
+        module: "drv_pci_ahci",
+        arguments: "link_power=slumber",
+        input: false,
+    },
+    Driver {
+        name: "xhci",
+        // serial bus, USB, xHCI
+        matches: &[Match::class(0x0C, 0x03, 0x30)],
+        module: "drv_pci_xhci",
+        arguments: "",
+        input: true,
+    },
+];
+
+/// Returns the driver with the most specific match.
+fn best(device: &Device) -> Option<&'static Driver> {
+    let [class, subclass, prog_if, _] = device.class;
+    drv_pci::best(
+        DRIVERS,
+        |driver| driver.matches,
+        device.class_vendor,
+        [class, subclass, prog_if],
+    )
+}
+
+/// Binds the best driver to all functions without one.
+pub fn probe<AM: ConfigurationAccessMechanism>(tree: &mut Tree, segments: &[Option<Segment<AM>>]) {
+    for index in 0..tree.len() {
+        let Some(node) = tree.get(index) else {
+            continue;
+        };
+        if let Some(segment) = crate::segment(segments, node.device.segment) {
+            bind(tree, &segment.am, index);
+        }
+    }
+}
+
+/// Binds the best driver to the function, unless it already has one, and
+/// spawns its task. Drivers have no access to the configuration space, so
+/// decoding, bus mastering and MSI are enabled for them.
+pub fn bind<AM: ConfigurationAccessMechanism>(
+    tree: &mut Tree,
+    am: &AM,
+    index: usize,
+) -> Option<&'static Driver> {
+    let node = tree.get_mut(index)?;
+    if node.driver.is_none() {
+        let driver = best(&node.device)?;
+        am.enable(
+            node.device.location,
+            ConfigurationSpaceHeaderCommand::IOSE
+                | ConfigurationSpaceHeaderCommand::MSE
+                | ConfigurationSpaceHeaderCommand::BME,
+        );
+        match launch(am, node, driver) {
+            Ok(task) => {
+                node.driver = Some(driver);
+                node.task = Some(task);
+                log::info!(
+                    "{:04X}:{:04X} bound to {}",
+                    node.device.segment,
+                    node.device.location,
+                    driver.name
+                );
+            }
+            Err(error) => {
+                log::warn!(
+                    "{:04X}:{:04X} {} not spawned: {:?}",
+                    node.device.segment,
+                    node.device.location,
+                    driver.module,
+                    error
+                );
+                disable(am, node);
+            }
+        }
+    }
+    node.driver
+}
+
+/// Spawns the driver's task, which is granted the resources of the function,
+/// DMA, and its MSI if it supports one.
+fn launch<AM: ConfigurationAccessMechanism>(
+    am: &AM,
+    node: &mut Node,
+    driver: &Driver,
+) -> Result<sys::Task, sys::Error> {
+    let mut grants = [sys::Grant::dma(); 10];
+    let mut count = 1;
+    for resource in &node.device.resource {
+        grants[count] = match resource.clone() {
+            Resource::Pio(range) if range.start != 0 && range.start < range.end => {
+                sys::Grant::pio(range.start..=range.end - 1)
+            }
+            Resource::Mem16(range) if range.start != 0 => {
+                sys::Grant::memory(range.start as u64..range.end as u64)
+            }
+            Resource::Mem32(range) if range.start != 0 => {
+                sys::Grant::memory(range.start as u64..range.end as u64)
+            }
+            Resource::Mem64(range) if range.start != 0 => sys::Grant::memory(range),
+            _ => continue,
+        };
+        count += 1;
+    }
+    if let Some(msi) = Msi::new(am, node.device.location) {
+        // the vector is kept until the function is unbound, even if spawning failed
+        let irq = match node.device.irq {
+            Some(irq) => irq,
+            None => sys::irq_allocate()?,
+        };
+        msi.enable(irq);
+        am.enable(node.device.location, ConfigurationSpaceHeaderCommand::ID);
+        node.device.irq = Some(irq);
+        grants[count] = sys::Grant::irq(irq.vector);
+        count += 1;
+    }
+    if driver.input {
+        grants[count] = sys::Grant::input();
+        count += 1;
+    }
+
+    let mut argument = [0; Device::ARGUMENT_SIZE];
+    let argument = node
+        .device
+        .encode(driver.arguments, &mut argument)
+        .ok_or(sys::Error::InvalidArgument)?;
+    sys::spawn(driver.module, argument, &grants[..count])
+}
+
+/// Stops the function from decoding, initiating DMA and signaling MSIs, and
+/// waits for its outstanding transactions to complete.
+fn disable<AM: ConfigurationAccessMechanism>(am: &AM, node: &Node) {
+    if let Some(msi) = Msi::new(am, node.device.location) {
+        msi.disable();
+    }
+    am.disable(
+        node.device.location,
+        ConfigurationSpaceHeaderCommand::IOSE
+            | ConfigurationSpaceHeaderCommand::MSE
+            | ConfigurationSpaceHeaderCommand::BME,
+    );
+    if let Some(pci_express) = PciExpress::new(am, node.device.location) {
+        // all ones if the function is gone already
+        for _ in 0..PENDING_ATTEMPTS {
+            let status = pci_express.read16(PciExpress::<AM>::DEVICE_STATUS);
+            if status == u16::MAX || status & DEVICE_STATUS_TP == 0 {
+                break;
+            }
+            hint::spin_loop();
+        }
+    }
+}
+
+/// Stops the function like [`disable`], kills the driver's task only then, as
+/// its memory could still be the target of DMA, and frees the MSI vector.
+pub fn unbind<AM: ConfigurationAccessMechanism>(
+    tree: &mut Tree,
+    am: &AM,
+    index: usize,
+) -> Option<&'static Driver> {
+    let node = tree.get_mut(index)?;
+    disable(am, node);
+    let driver = node.driver.take();
+    if let (Some(driver), Some(task)) = (driver, node.task.take()) {
+        if let Err(error) = sys::kill(task) {
+            log::warn!(
+                "{:04X}:{:04X} {} not killed: {:?}",
+                node.device.segment,
+                node.device.location,
+                driver.module,
+                error
+            );
+        }
+    }
+    if let Some(irq) = node.device.irq.take() {
+        if let Err(error) = sys::irq_free(irq.vector) {
+            log::warn!(
+                "{:04X}:{:04X} vector {:#04X} not freed: {:?}",
+                node.device.segment,
+                node.device.location,
+                irq.vector,
+                error
+            );
+        }
+    }
+    let driver = driver?;
+    log::info!(
+        "{:04X}:{:04X} unbound from {}",
+        node.device.segment,
+        node.device.location,
+        driver.name
+    );
+    Some(driver)
+}
+
+/// Unbinds the current driver, and binds the best one again, e.g. after the
+/// driver failed.
+pub fn rebind<AM: ConfigurationAccessMechanism>(
+    tree: &mut Tree,
+    am: &AM,
+    index: usize,
+) -> Option<&'static Driver> {
+    unbind(tree, am, index);
+    bind(tree, am, index)
+}
+
+/// Binds drivers again whose tasks exited, and has all others signal the
+/// vector once they do. Drivers exiting too often are left unbound.
+pub fn watch<AM: ConfigurationAccessMechanism>(
+    tree: &mut Tree,
+    segments: &[Option<Segment<AM>>],
+    vector: u8,
+) {
+    for index in 0..tree.len() {
+        while let Some(node) = tree.get_mut(index) {
+            let (Some(driver), Some(task)) = (node.driver, node.task) else {
+                break;
+            };
+            let Some(segment) = crate::segment(segments, node.device.segment) else {
+                break;
+            };
+            match sys::watch(task, vector) {
+                Ok(false) => break,
+                Ok(true) if node.restarts < MAX_RESTARTS => {
+                    node.restarts += 1;
+                    log::warn!(
+                        "{:04X}:{:04X} {} exited, binding again",
+                        node.device.segment,
+                        node.device.location,
+                        driver.module
+                    );
+                    rebind(tree, &segment.am, index);
+                }
+                Ok(true) => {
+                    log::warn!(
+                        "{:04X}:{:04X} {} exited too often",
+                        node.device.segment,
+                        node.device.location,
+                        driver.module
+                    );
+                    unbind(tree, &segment.am, index);
+                    break;
+                }
+                Err(error) => {
+                    log::warn!(
+                        "{:04X}:{:04X} {} not watched: {:?}",
+                        node.device.segment,
+                        node.device.location,
+                        driver.module,
+                        error
+                    );
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,476 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::fmt;
+
+use drv_pci::{
+    cap::{self, Capabilities, Capability, PciExpress, PowerManagement},
+    cfg::{ConfigurationAccessMechanism, ConfigurationSpaceHeaderCommand},
+    Resource, RESOURCE_ROM,
+};
+
+use crate::{
+    bar,
+    bus::{Node, Tree},
+    ConfigurationSpace, ConfigurationSpaceHeaderStatus, Segment,
+};
+
+const OFFSET_EXPANSION_ROM_BASE_ADDRESS: u16 = 0x30;
+const OFFSET_BRIDGE_EXPANSION_ROM_BASE_ADDRESS: u16 = 0x38;
+const EXPANSION_ROM_ENABLE: u32 = 1 << 0;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    Normal,
+    /// `-v`
+    Verbose,
+    /// `-vv`
+    VeryVerbose,
+}
+
+impl Verbosity {
+    /// Parses the detail's name, as given in the driver's arguments.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "normal" => Some(Self::Normal),
+            "verbose" => Some(Self::Verbose),
+            "very_verbose" => Some(Self::VeryVerbose),
+            _ => None,
+        }
+    }
+}
+
+/// Logs every function of the tree like `lspci`, with the same field names,
+/// and the driver bound to it.
+pub fn dump<AM: ConfigurationAccessMechanism>(
+    tree: &Tree,
+    segments: &[Option<Segment<AM>>],
+    verbosity: Verbosity,
+) {
+    for (_, node) in tree.iter() {
+        if let Some(segment) = crate::segment(segments, node.device.segment) {
+            function(&segment.am, node, verbosity);
+        }
+    }
+}
+
+fn function<AM: ConfigurationAccessMechanism>(am: &AM, node: &Node, verbosity: Verbosity) {
+    let device = &node.device;
+    let location = device.location;
+    let [class, subclass, prog_if, revision] = device.class;
+    let [vendor_id, device_id] = device.class_vendor;
+    log::info!(
+        "{:04x}:{:02x}:{:02x}.{:x} {} [{:02x}{:02x}]: Device [{:04x}:{:04x}] (rev {:02x}) (prog-if {:02x})",
+        device.segment,
+        location >> 8,
+        location >> 3 & 0x1F,
+        location & 0x7,
+        class_name(class, subclass),
+        class,
+        subclass,
+        vendor_id,
+        device_id,
+        revision,
+        prog_if
+    );
+    if verbosity == Verbosity::Normal {
+        return;
+    }
+
+    let header = am.header(location);
+    let bridge = node.secondary_bus.is_some();
+    let (interrupt_pin, interrupt_line) = if bridge {
+        let type_1 = unsafe { header.type_specific.type_1 };
+        log::info!(
+            "\tBus: primary={:02x}, secondary={:02x}, subordinate={:02x}, sec-latency={}",
+            type_1.primary_bus_number,
+            type_1.secondary_bus_number,
+            type_1.subordinate_bus_number,
+            type_1.secondary_latency_timer
+        );
+        (type_1.interrupt_pin, type_1.interrupt_line)
+    } else {
+        let type_0 = unsafe { header.type_specific.type_0 };
+        log::info!(
+            "\tSubsystem: Device [{:04x}:{:04x}]",
+            type_0.subsystem_vendor_id,
+            type_0.subsystem_id
+        );
+        (type_0.interrupt_pin, type_0.interrupt_line)
+    };
+
+    let command = header.command;
+    let status = header.status;
+    let devsel = match (status & ConfigurationSpaceHeaderStatus::DEVSEL).bits() >> 9 {
+        0 => "fast",
+        1 => "medium",
+        2 => "slow",
+        _ => "??",
+    };
+    if verbosity == Verbosity::Verbose {
+        log::info!(
+            "\tFlags: {}{} devsel, latency {}{}",
+            if command.contains(ConfigurationSpaceHeaderCommand::BME) {
+                "bus master, "
+            } else {
+                ""
+            },
+            devsel,
+            header.latency_timer,
+            Irq(interrupt_pin, interrupt_line)
+        );
+    } else {
+        log::info!(
+            "\tControl: I/O{} Mem{} BusMaster{} SpecCycle{} MemWINV{} VGASnoop{} ParErr{} Stepping{} SERR{} FastB2B{} DisINTx{}",
+            flag(command.contains(ConfigurationSpaceHeaderCommand::IOSE)),
+            flag(command.contains(ConfigurationSpaceHeaderCommand::MSE)),
+            flag(command.contains(ConfigurationSpaceHeaderCommand::BME)),
+            flag(command.contains(ConfigurationSpaceHeaderCommand::SCE)),
+            flag(command.contains(ConfigurationSpaceHeaderCommand::MWI)),
+            flag(command.contains(ConfigurationSpaceHeaderCommand::VGAPS)),
+            flag(command.contains(ConfigurationSpaceHeaderCommand::PER)),
+            flag(command.contains(ConfigurationSpaceHeaderCommand::IDSEL)),
+            flag(command.contains(ConfigurationSpaceHeaderCommand::SERRE)),
+            flag(command.contains(ConfigurationSpaceHeaderCommand::FB2BTE)),
+            flag(command.contains(ConfigurationSpaceHeaderCommand::ID))
+        );
+        log::info!(
+            "\tStatus: Cap{} 66MHz{} FastB2B{} ParErr{} DEVSEL={} >TAbort{} <TAbort{} <MAbort{} >SERR{} <PERR{} INTx{}",
+            flag(status.contains(ConfigurationSpaceHeaderStatus::CL)),
+            flag(status.contains(ConfigurationSpaceHeaderStatus::_66C)),
+            flag(status.contains(ConfigurationSpaceHeaderStatus::FB2BTC)),
+            flag(status.contains(ConfigurationSpaceHeaderStatus::MDPE)),
+            devsel,
+            flag(status.contains(ConfigurationSpaceHeaderStatus::STA)),
+            flag(status.contains(ConfigurationSpaceHeaderStatus::RTA)),
+            flag(status.contains(ConfigurationSpaceHeaderStatus::RMA)),
+            flag(status.contains(ConfigurationSpaceHeaderStatus::SE)),
+            flag(status.contains(ConfigurationSpaceHeaderStatus::DPE)),
+            flag(status.contains(ConfigurationSpaceHeaderStatus::IS))
+        );
+        log::info!(
+            "\tLatency: {}, Cache Line Size: {} bytes",
+            header.latency_timer,
+            header.cache_line_size as u32 * 4
+        );
+        if interrupt_pin != 0 {
+            log::info!(
+                "\tInterrupt: pin {} routed to IRQ {}",
+                (b'A' + interrupt_pin - 1) as char,
+                interrupt_line
+            );
+        }
+    }
+
+    for (index, resource) in device.resource[..RESOURCE_ROM].iter().enumerate() {
+        let prefetchable = device.prefetchable & 1 << index != 0;
+        region(index, resource, prefetchable, verbosity);
+    }
+    if let Resource::Mem32(range) = &device.resource[RESOURCE_ROM] {
+        let offset = if bridge {
+            OFFSET_BRIDGE_EXPANSION_ROM_BASE_ADDRESS
+        } else {
+            OFFSET_EXPANSION_ROM_BASE_ADDRESS
+        };
+        let enabled = am.read32(location, offset) & EXPANSION_ROM_ENABLE != 0;
+        log::info!(
+            "\tExpansion ROM at {}{} [size={}]",
+            Address(range.start as u64),
+            if enabled { "" } else { " [disabled]" },
+            Size((range.end - range.start) as u64)
+        );
+    }
+    if let Some(windows) = &node.windows {
+        for (name, window) in ["I/O", "Memory", "Prefetchable memory"]
+            .into_iter()
+            .zip(windows)
+        {
+            let Some(range) = bar::range(window) else {
+                continue;
+            };
+            log::info!(
+                "\t{} behind bridge: {:08x}-{:08x} [size={}]",
+                name,
+                range.start,
+                range.end - 1,
+                Size(range.end - range.start)
+            );
+        }
+    }
+
+    for capability in Capabilities::new(am, location) {
+        self::capability(am, location, capability, verbosity);
+    }
+
+    if let Some(driver) = node.driver {
+        log::info!("\tKernel driver in use: {}", driver.name);
+    }
+}
+
+fn region(index: usize, resource: &Resource, prefetchable: bool, verbosity: Verbosity) {
+    let prefix = Region(index, verbosity);
+    let (start, size, width) = match resource {
+        Resource::Pio(range) => {
+            log::info!(
+                "\t{}I/O ports at {} [size={}]",
+                prefix,
+                Address(range.start as u64),
+                Size((range.end - range.start) as u64)
+            );
+            return;
+        }
+        Resource::Mem16(range) => (range.start as u64, (range.end - range.start) as u64, 16),
+        Resource::Mem32(range) => (range.start as u64, (range.end - range.start) as u64, 32),
+        Resource::Mem64(range) => (range.start, range.end - range.start, 64),
+        Resource::None => return,
+    };
+    log::info!(
+        "\t{}Memory at {} ({}-bit, {}prefetchable) [size={}]",
+        prefix,
+        Address(start),
+        width,
+        if prefetchable { "" } else { "non-" },
+        Size(size)
+    );
+}
+
+fn capability<AM: ConfigurationAccessMechanism>(
+    am: &AM,
+    location: u16,
+    capability: Capability,
+    verbosity: Verbosity,
+) {
+    let offset = capability.offset;
+    if capability.extended {
+        let version = am.read32(location, offset) >> 16 & 0xF;
+        let name = match capability.id {
+            cap::EXTENDED_ID_ADVANCED_ERROR_REPORTING => "Advanced Error Reporting",
+            cap::EXTENDED_ID_DEVICE_SERIAL_NUMBER => "Device Serial Number",
+            _ => "Extended Capability",
+        };
+        log::info!(
+            "\tCapabilities: [{:03x} v{}] {} <{:04x}>",
+            offset,
+            version,
+            name,
+            capability.id
+        );
+        return;
+    }
+
+    let control = am.read16(location, offset + 2);
+    match capability.id {
+        cap::ID_POWER_MANAGEMENT => {
+            log::info!(
+                "\tCapabilities: [{:02x}] Power Management version {}",
+                offset,
+                control & 0x7
+            );
+            if verbosity == Verbosity::VeryVerbose {
+                if let Some(power_management) = PowerManagement::new(am, location) {
+                    log::info!("\t\tStatus: {:?}", power_management.power_state());
+                }
+            }
+        }
+        cap::ID_MSI => log::info!(
+            "\tCapabilities: [{:02x}] MSI: Enable{} Count={}/{} Maskable{} 64bit{}",
+            offset,
+            flag(control & 1 << 0 != 0),
+            1 << (control >> 4 & 0x7),
+            1 << (control >> 1 & 0x7),
+            flag(control & 1 << 8 != 0),
+            flag(control & 1 << 7 != 0)
+        ),
+        cap::ID_MSI_X => log::info!(
+            "\tCapabilities: [{:02x}] MSI-X: Enable{} Count={} Masked{}",
+            offset,
+            flag(control & 1 << 15 != 0),
+            (control & 0x7FF) + 1,
+            flag(control & 1 << 14 != 0)
+        ),
+        cap::ID_PCI_EXPRESS => {
+            let Some(pci_express) = PciExpress::new(am, location) else {
+                return;
+            };
+            log::info!(
+                "\tCapabilities: [{:02x}] Express (v{}) {}",
+                offset,
+                pci_express.version(),
+                match pci_express.port_type() {
+                    0x0 => "Endpoint",
+                    0x1 => "Legacy Endpoint",
+                    0x4 => "Root Port",
+                    0x5 => "Upstream Port",
+                    0x6 => "Downstream Port",
+                    0x7 => "PCI-Express to PCI/PCI-X Bridge",
+                    0x8 => "PCI/PCI-X to PCI-Express Bridge",
+                    0x9 => "Root Complex Integrated Endpoint",
+                    0xA => "Root Complex Event Collector",
+                    _ => "Unknown type",
+                }
+            );
+            if verbosity == Verbosity::VeryVerbose {
+                let link_capabilities = pci_express.read32(PciExpress::<AM>::LINK_CAPABILITIES);
+                log::info!(
+                    "\t\tLnkCap: Port #{}, Speed {}, Width x{}",
+                    link_capabilities >> 24,
+                    Speed((link_capabilities & 0xF) as u8),
+                    link_capabilities >> 4 & 0x3F
+                );
+                let (speed, width) = pci_express.link();
+                log::info!("\t\tLnkSta: Speed {}, Width x{}", Speed(speed), width);
+            }
+        }
+        id => log::info!("\tCapabilities: [{:02x}] Capability <{:02x}>", offset, id),
+    }
+}
+
+/// Name of the class, or of the subclass if known.
+fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x00, 0x01) => "VGA compatible unclassified device",
+        (0x00, _) => "Unclassified device",
+        (0x01, 0x00) => "SCSI storage controller",
+        (0x01, 0x01) => "IDE interface",
+        (0x01, 0x02) => "Floppy disk controller",
+        (0x01, 0x04) => "RAID bus controller",
+        (0x01, 0x05) => "ATA controller",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x07) => "Serial Attached SCSI controller",
+        (0x01, 0x08) => "Non-Volatile memory controller",
+        (0x01, _) => "Mass storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "Network controller",
+        (0x03, 0x00) => "VGA compatible controller",
+        (0x03, 0x01) => "XGA compatible controller",
+        (0x03, 0x02) => "3D controller",
+        (0x03, _) => "Display controller",
+        (0x04, 0x00) => "Multimedia video controller",
+        (0x04, 0x01) => "Multimedia audio controller",
+        (0x04, 0x03) => "Audio device",
+        (0x04, _) => "Multimedia controller",
+        (0x05, 0x00) => "RAM memory",
+        (0x05, 0x01) => "FLASH memory",
+        (0x05, _) => "Memory controller",
+        (0x06, 0x00) => "Host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x02) => "EISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, 0x07) => "CardBus bridge",
+        (0x06, _) => "Bridge",
+        (0x07, 0x00) => "Serial controller",
+        (0x07, 0x01) => "Parallel controller",
+        (0x07, _) => "Communication controller",
+        (0x08, 0x00) => "PIC",
+        (0x08, 0x01) => "DMA controller",
+        (0x08, 0x02) => "Timer",
+        (0x08, 0x03) => "RTC",
+        (0x08, 0x05) => "SD Host controller",
+        (0x08, 0x06) => "IOMMU",
+        (0x08, _) => "System peripheral",
+        (0x09, 0x00) => "Keyboard controller",
+        (0x09, 0x02) => "Mouse controller",
+        (0x09, _) => "Input device controller",
+        (0x0A, _) => "Docking station",
+        (0x0B, _) => "Processor",
+        (0x0C, 0x03) => "USB controller",
+        (0x0C, 0x05) => "SMBus",
+        (0x0C, _) => "Serial bus controller",
+        (0x0D, _) => "Wireless controller",
+        (0x0E, _) => "Intelligent controller",
+        (0x0F, _) => "Satellite communications controller",
+        (0x10, _) => "Encryption controller",
+        (0x11, _) => "Signal processing controller",
+        (0x12, _) => "Processing accelerators",
+        (0x13, _) => "Non-Essential Instrumentation",
+        (0x40, _) => "Coprocessor",
+        _ => "Unassigned class",
+    }
+}
+
+fn flag(set: bool) -> char {
+    if set {
+        '+'
+    } else {
+        '-'
+    }
+}
+
+/// `, IRQ n` of the flags line, if the function has an interrupt pin.
+struct Irq(u8, u8);
+
+impl fmt::Display for Irq {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 == 0 {
+            return Ok(());
+        }
+        write!(f, ", IRQ {}", self.1)
+    }
+}
+
+/// `Region n: ` prefix, which is only shown at `-vv`.
+struct Region(usize, Verbosity);
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.1 != Verbosity::VeryVerbose {
+            return Ok(());
+        }
+        write!(f, "Region {}: ", self.0)
+    }
+}
+
+/// Base address, which is zero until assigned.
+struct Address(u64);
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 == 0 {
+            return f.write_str("<unassigned>");
+        }
+        write!(f, "{:x}", self.0)
+    }
+}
+
+/// Size in the largest unit it is a multiple of.
+struct Size(u64);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut size = self.0;
+        let mut units = ["", "K", "M", "G", "T"].into_iter();
+        while size != 0 && size.trailing_zeros() >= 10 && units.len() > 1 {
+            size /= 1024;
+            units.next();
+        }
+        write!(f, "{}{}", size, units.next().unwrap_or_default())
+    }
+}
+
+/// Link speed as encoded in the link registers.
+struct Speed(u8);
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            1 => f.write_str("2.5GT/s"),
+            2 => f.write_str("5GT/s"),
+            3 => f.write_str("8GT/s"),
+            4 => f.write_str("16GT/s"),
+            5 => f.write_str("32GT/s"),
+            6 => f.write_str("64GT/s"),
+            _ => f.write_str("unknown"),
+        }
+    }
+}
@@ -0,0 +1,381 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::hint;
+
+use bitflags::bitflags;
+use drv_pci::{
+    cap::{Msi, PciExpress},
+    cfg::{ConfigurationAccessMechanism, ConfigurationSpaceHeaderCommand},
+};
+
+use crate::{bar, bus::Tree, drv, Segment};
+
+/// Maximum number of hot-plug slots, further ones are ignored.
+const MAX_SLOTS: usize = 8;
+
+/// Polls of the vendor ID until a function on a new link has to respond.
+const READY_ATTEMPTS: usize = 1_000_000;
+/// Polls of the slot status until a slot control write has to complete.
+const COMMAND_ATTEMPTS: usize = 1_000_000;
+
+const PORT_TYPE_ROOT_PORT: u8 = 0x4;
+const PORT_TYPE_DOWNSTREAM_PORT: u8 = 0x6;
+
+/// Attention Button Present
+const SLOT_CAPABILITIES_ABP: u32 = 1 << 0;
+/// Power Controller Present
+const SLOT_CAPABILITIES_PCP: u32 = 1 << 1;
+/// Power Indicator Present
+const SLOT_CAPABILITIES_PIP: u32 = 1 << 4;
+/// Hot-Plug Capable
+const SLOT_CAPABILITIES_HPC: u32 = 1 << 6;
+/// No Command Completed Support
+const SLOT_CAPABILITIES_NCCS: u32 = 1 << 18;
+
+/// Data Link Layer Link Active Reporting Capable
+const LINK_CAPABILITIES_DLLLARC: u32 = 1 << 20;
+/// Data Link Layer Link Active
+const LINK_STATUS_DLLLA: u16 = 1 << 13;
+
+bitflags! {
+    #[derive(Clone, Copy)]
+    struct SlotControl: u16 {
+        /// Attention Button Pressed Enable
+        const ABPE = 1 << 0;
+        /// Presence Detect Changed Enable
+        const PDCE = 1 << 3;
+        /// Command Completed Interrupt Enable
+        const CCIE = 1 << 4;
+        /// Hot-Plug Interrupt Enable
+        const HPIE = 1 << 5;
+        /// Power Indicator Control
+        const PIC = 3 << 8;
+        const PIC_ON = 1 << 8;
+        const PIC_OFF = 3 << 8;
+        /// Power Controller Control, set if the power is off
+        const PCC = 1 << 10;
+        /// Data Link Layer State Changed Enable
+        const DLLSCE = 1 << 12;
+    }
+}
+
+bitflags! {
+    #[derive(Clone, Copy)]
+    struct SlotStatus: u16 {
+        /// Attention Button Pressed
+        const ABP = 1 << 0;
+        /// Power Fault Detected
+        const PFD = 1 << 1;
+        /// MRL Sensor Changed
+        const MRLSC = 1 << 2;
+        /// Presence Detect Changed
+        const PDC = 1 << 3;
+        /// Command Completed
+        const CC = 1 << 4;
+        /// Presence Detect State
+        const PDS = 1 << 6;
+        /// Data Link Layer State Changed
+        const DLLSC = 1 << 8;
+    }
+}
+
+impl SlotStatus {
+    /// Events which are cleared by writing ones.
+    const EVENTS: Self = Self::ABP
+        .union(Self::PFD)
+        .union(Self::MRLSC)
+        .union(Self::PDC)
+        .union(Self::DLLSC);
+}
+
+/// Hot-plug capable port.
+struct Slot {
+    /// Node of the port.
+    index: usize,
+    segment: u16,
+    location: u16,
+    capabilities: u32,
+    link_active_reporting: bool,
+}
+
+/// Services the PCI Express native hot-plug slots behind root and downstream
+/// ports, and binds drivers again which exited. All slots signal the vector,
+/// and are polled for their events whenever it fires. Bridges on hot-added
+/// cards are not configured, as bus numbers are only assigned by the firmware.
+pub fn run<AM: ConfigurationAccessMechanism>(
+    tree: &mut Tree,
+    segments: &[Option<Segment<AM>>],
+    irq: sys::Irq,
+) -> ! {
+    let mut slots = [const { None }; MAX_SLOTS];
+    let mut ports = tree.iter().filter_map(|(index, node)| {
+        let segment = crate::segment(segments, node.device.segment)?;
+        let location = node.device.location;
+        let pci_express = PciExpress::new(&segment.am, location)?;
+        if !matches!(
+            pci_express.port_type(),
+            PORT_TYPE_ROOT_PORT | PORT_TYPE_DOWNSTREAM_PORT
+        ) || !pci_express.slot_implemented()
+        {
+            return None;
+        }
+        let capabilities = pci_express.read32(PciExpress::<AM>::SLOT_CAPABILITIES);
+        if capabilities & SLOT_CAPABILITIES_HPC == 0 {
+            return None;
+        }
+        Some(Slot {
+            index,
+            segment: node.device.segment,
+            location,
+            capabilities,
+            link_active_reporting: pci_express.read32(PciExpress::<AM>::LINK_CAPABILITIES)
+                & LINK_CAPABILITIES_DLLLARC
+                != 0,
+        })
+    });
+    for slot in slots.iter_mut() {
+        *slot = ports.next();
+    }
+    if ports.next().is_some() {
+        log::warn!("Too many hot-plug slots, ignoring the rest");
+    }
+    drop(ports);
+    for slot in slots.iter_mut() {
+        let enabled = slot.as_ref().is_some_and(|slot| {
+            crate::segment(segments, slot.segment)
+                .is_some_and(|segment| enable(&segment.am, slot, irq))
+        });
+        if !enabled {
+            *slot = None;
+        }
+    }
+
+    // pick up changes which happened before the interrupts were enabled
+    for slot in slots.iter().flatten() {
+        if let Some(segment) = crate::segment(segments, slot.segment) {
+            service(tree, segment, slot, SlotStatus::PDC);
+        }
+    }
+    loop {
+        let _ = sys::irq_wait(irq.vector);
+        for slot in slots.iter().flatten() {
+            let Some(segment) = crate::segment(segments, slot.segment) else {
+                continue;
+            };
+            // the MSI is only sent again once all events have been cleared
+            loop {
+                let pci_express = PciExpress::new(&segment.am, slot.location).unwrap();
+                let status =
+                    SlotStatus::from_bits_retain(pci_express.read16(PciExpress::<AM>::SLOT_STATUS))
+                        & SlotStatus::EVENTS;
+                if status.is_empty() {
+                    break;
+                }
+                pci_express.write16(PciExpress::<AM>::SLOT_STATUS, status.bits());
+                service(tree, segment, slot, status);
+            }
+        }
+        drv::watch(tree, segments, irq.vector);
+    }
+}
+
+/// Routes the hot-plug interrupt of the port to the given vector.
+fn enable<AM: ConfigurationAccessMechanism>(am: &AM, slot: &Slot, irq: sys::Irq) -> bool {
+    let Some(msi) = Msi::new(am, slot.location) else {
+        log::warn!(
+            "{:04X}:{:04X} hot-plug disabled, no MSI",
+            slot.segment,
+            slot.location
+        );
+        return false;
+    };
+    let Some(pci_express) = PciExpress::new(am, slot.location) else {
+        return false;
+    };
+    // MSIs are memory writes, which the port only issues as bus master
+    am.enable(
+        slot.location,
+        ConfigurationSpaceHeaderCommand::BME | ConfigurationSpaceHeaderCommand::ID,
+    );
+    msi.enable(irq);
+
+    pci_express.write16(PciExpress::<AM>::SLOT_STATUS, SlotStatus::EVENTS.bits());
+    let mut enable = SlotControl::HPIE | SlotControl::PDCE;
+    if slot.capabilities & SLOT_CAPABILITIES_ABP != 0 {
+        enable |= SlotControl::ABPE;
+    }
+    if slot.link_active_reporting {
+        enable |= SlotControl::DLLSCE;
+    }
+    let control = (control(&pci_express) - SlotControl::CCIE) | enable;
+    set_control(&pci_express, slot, control);
+    true
+}
+
+/// Handles the events of a slot, by comparing whether a card is present
+/// with whether functions are behind the port.
+fn service<AM: ConfigurationAccessMechanism>(
+    tree: &mut Tree,
+    segment: &Segment<AM>,
+    slot: &Slot,
+    status: SlotStatus,
+) {
+    let am = &segment.am;
+    let Some(pci_express) = PciExpress::new(am, slot.location) else {
+        return;
+    };
+    let presence = SlotStatus::from_bits_retain(pci_express.read16(PciExpress::<AM>::SLOT_STATUS))
+        .contains(SlotStatus::PDS);
+    let powered = slot.capabilities & SLOT_CAPABILITIES_PCP == 0
+        || !control(&pci_express).contains(SlotControl::PCC);
+    let occupied = tree.children(Some(slot.index)).next().is_some();
+
+    // the button requests an orderly removal, or powers a present card on
+    if status.contains(SlotStatus::ABP) {
+        if occupied {
+            log::info!(
+                "{:04X}:{:04X} removal requested",
+                slot.segment,
+                slot.location
+            );
+            remove(tree, am, slot.index);
+            power(&pci_express, slot, false);
+        } else if presence {
+            power(&pci_express, slot, true);
+        }
+        return;
+    }
+    if status.contains(SlotStatus::PDC) && presence && !powered {
+        power(&pci_express, slot, true);
+    }
+
+    let link_active = !slot.link_active_reporting
+        || pci_express.read16(PciExpress::<AM>::LINK_STATUS) & LINK_STATUS_DLLLA != 0;
+    if presence && link_active && powered && !occupied {
+        log::info!("{:04X}:{:04X} card present", slot.segment, slot.location);
+        arrive(tree, segment, slot);
+    } else if !(presence && link_active) && occupied {
+        // surprise removal, the functions are already gone
+        log::info!("{:04X}:{:04X} card removed", slot.segment, slot.location);
+        remove(tree, am, slot.index);
+        power(&pci_express, slot, false);
+    }
+}
+
+/// Adds the functions behind the port, places their BARs and binds drivers.
+fn arrive<AM: ConfigurationAccessMechanism>(tree: &mut Tree, segment: &Segment<AM>, slot: &Slot) {
+    let am = &segment.am;
+    let Some(port) = tree.get(slot.index) else {
+        return;
+    };
+    let (Some(secondary_bus), Some(windows)) = (port.secondary_bus, port.windows.clone()) else {
+        log::warn!(
+            "{:04X}:{:04X} no bus behind the slot",
+            slot.segment,
+            slot.location
+        );
+        return;
+    };
+
+    // the function may request retries for a while after the link came up
+    let location = (secondary_bus as u16) << 8;
+    let ready = (0..READY_ATTEMPTS).any(|_| {
+        let vendor_id = am.read16(location, 0);
+        hint::spin_loop();
+        vendor_id != 0xFFFF && vendor_id != 0x0001
+    });
+    if !ready {
+        log::warn!(
+            "{:04X}:{:04X} card not responding",
+            slot.segment,
+            slot.location
+        );
+        return;
+    }
+
+    tree.scan_bus(segment, secondary_bus, Some(slot.index));
+    let mut next = [0; 3];
+    for index in 0..tree.len() {
+        let Some(node) = tree.get_mut(index) else {
+            continue;
+        };
+        if node.parent != Some(slot.index) {
+            continue;
+        }
+        bar::assign(am, &mut node.device, &windows, &mut next);
+        drv::bind(tree, am, index);
+    }
+}
+
+/// Unbinds and removes all functions behind the bridge, deepest first.
+/// Unbinding waits for outstanding DMA before the driver's memory is freed,
+/// and frees the vectors, so that they can be allocated for the next card.
+fn remove<AM: ConfigurationAccessMechanism>(tree: &mut Tree, am: &AM, parent: usize) {
+    loop {
+        let Some((index, _)) = tree.children(Some(parent)).next() else {
+            break;
+        };
+        remove(tree, am, index);
+        drv::unbind(tree, am, index);
+        tree.remove(index);
+    }
+}
+
+fn power<AM: ConfigurationAccessMechanism>(pci_express: &PciExpress<AM>, slot: &Slot, on: bool) {
+    let mut control = control(pci_express);
+    if slot.capabilities & SLOT_CAPABILITIES_PCP != 0 {
+        control.set(SlotControl::PCC, !on);
+    }
+    if slot.capabilities & SLOT_CAPABILITIES_PIP != 0 {
+        control.remove(SlotControl::PIC);
+        control |= if on {
+            SlotControl::PIC_ON
+        } else {
+            SlotControl::PIC_OFF
+        };
+    }
+    set_control(pci_express, slot, control);
+}
+
+fn control<AM: ConfigurationAccessMechanism>(pci_express: &PciExpress<AM>) -> SlotControl {
+    SlotControl::from_bits_retain(pci_express.read16(PciExpress::<AM>::SLOT_CONTROL))
+}
+
+/// Writes the slot control register, and waits for the port to complete the
+/// command, as it may ignore writes until then.
+fn set_control<AM: ConfigurationAccessMechanism>(
+    pci_express: &PciExpress<AM>,
+    slot: &Slot,
+    control: SlotControl,
+) {
+    pci_express.write16(PciExpress::<AM>::SLOT_CONTROL, control.bits());
+    if slot.capabilities & SLOT_CAPABILITIES_NCCS != 0 {
+        return;
+    }
+    let completed = (0..COMMAND_ATTEMPTS).any(|_| {
+        let status = pci_express.read16(PciExpress::<AM>::SLOT_STATUS);
+        hint::spin_loop();
+        status & SlotStatus::CC.bits() != 0
+    });
+    if completed {
+        pci_express.write16(PciExpress::<AM>::SLOT_STATUS, SlotStatus::CC.bits());
+    } else {
+        log::warn!(
+            "{:04X}:{:04X} slot command timed out",
+            slot.segment,
+            slot.location
+        );
+    }
+}
@@ -14,14 +14,84 @@
 
 #![no_std]
 
-use core::ops::Range;
+use core::{ops::Range, ptr, str};
 
+pub mod cap;
+pub mod cfg;
+
+/// Defines the entry point of a driver, which calls `main` with the function
+/// it's bound to, and the arguments of the driver, see [`Driver::arguments`].
+/// The driver exits once `main` returns.
+#[macro_export]
+macro_rules! main {
+    ($main:path) => {
+        #[no_mangle]
+        extern "C" fn _start(argument: *const u8, len: usize) -> ! {
+            let argument = unsafe { core::slice::from_raw_parts(argument, len) };
+            if let Some((device, arguments)) = unsafe { $crate::Device::decode(argument) } {
+                $main(device, arguments)
+            }
+            sys::exit()
+        }
+    };
+}
+
+#[derive(Clone)]
 pub struct Device {
+    /// PCI segment group, only segment 0 is reachable without ECAM.
+    pub segment: u16,
+    /// Bus, device and function number, as `bbbbbbbb_dddddfff`.
+    pub location: u16,
+    /// Class, subclass, programming interface and revision.
     pub class: [u8; 4],
+    /// Vendor and device ID.
     pub class_vendor: [u16; 2],
-    pub resource: [Resource; 6],
+    /// Base address registers, followed by the expansion ROM.
+    pub resource: [Resource; 7],
+    /// Bit `n` is set if resource `n` is prefetchable.
+    pub prefetchable: u8,
+    /// Interrupt signaled by MSI, set up by the bus driver for the driver.
+    pub irq: Option<sys::Irq>,
+}
+
+impl Device {
+    /// Bytes of the argument of a driver's task.
+    pub const ARGUMENT_SIZE: usize = 512;
+
+    /// Encodes the device followed by the arguments of its driver, as argument
+    /// of the driver's task.
+    pub fn encode<'a>(
+        &self,
+        arguments: &str,
+        buffer: &'a mut [u8; Self::ARGUMENT_SIZE],
+    ) -> Option<&'a [u8]> {
+        let size = size_of::<Self>();
+        let len = size.checked_add(arguments.len())?;
+        buffer
+            .get_mut(size..len)?
+            .copy_from_slice(arguments.as_bytes());
+        unsafe { ptr::write_unaligned(buffer.as_mut_ptr() as *mut Self, self.clone()) };
+        Some(&buffer[..len])
+    }
+
+    /// Decodes the device, and the arguments of its driver.
+    ///
+    /// # Safety
+    ///
+    /// The argument has to be encoded by [`Device::encode`] of the same build.
+    pub unsafe fn decode(argument: &[u8]) -> Option<(Self, &str)> {
+        let arguments = str::from_utf8(argument.get(size_of::<Self>()..)?).ok()?;
+        let device = unsafe { ptr::read_unaligned(argument.as_ptr() as *const Self) };
+        Some((device, arguments))
+    }
 }
 
+/// Index of the expansion ROM in [`Device::resource`].
+pub const RESOURCE_ROM: usize = 6;
+
+/// Address range decoded by a device, ranges starting at 0 have not been
+/// assigned.
+#[derive(Clone)]
 pub enum Resource {
     None,
     Pio(Range<u16>),
@@ -29,3 +99,94 @@ pub enum Resource {
     Mem32(Range<u32>),
     Mem64(Range<u64>),
 }
+
+/// Identifies the functions a driver supports, or the interfaces a driver of
+/// a bus behind one supports. IDs of `ANY` and zero bits in the class mask
+/// match everything.
+pub struct Match {
+    pub vendor_id: u16,
+    /// Device ID, or the product ID of USB devices.
+    pub device_id: u16,
+    /// Class, subclass and programming interface, or protocol.
+    pub class: [u8; 3],
+    pub class_mask: [u8; 3],
+}
+
+impl Match {
+    pub const ANY: u16 = 0xFFFF;
+
+    pub const fn device(vendor_id: u16, device_id: u16) -> Self {
+        Self {
+            vendor_id,
+            device_id,
+            class: [0; 3],
+            class_mask: [0; 3],
+        }
+    }
+
+    pub const fn class(class: u8, subclass: u8, prog_if: u8) -> Self {
+        Self::masked([class, subclass, prog_if], [0xFF; 3])
+    }
+
+    pub const fn masked(class: [u8; 3], class_mask: [u8; 3]) -> Self {
+        Self {
+            vendor_id: Self::ANY,
+            device_id: Self::ANY,
+            class,
+            class_mask,
+        }
+    }
+
+    /// Returns how specific the match is, vendor and device IDs outweigh any
+    /// number of class bits.
+    pub fn matches(&self, ids: [u16; 2], class: [u8; 3]) -> Option<u8> {
+        let mut score = 0;
+        for (expected, actual) in [self.vendor_id, self.device_id].into_iter().zip(ids) {
+            if expected != Self::ANY {
+                if expected != actual {
+                    return None;
+                }
+                score += 32;
+            }
+        }
+        for ((expected, mask), actual) in self.class.into_iter().zip(self.class_mask).zip(class) {
+            if expected & mask != actual & mask {
+                return None;
+            }
+            score += mask.count_ones() as u8;
+        }
+        Some(score)
+    }
+}
+
+/// Returns the driver with the most specific match of the IDs and class.
+pub fn best<'a, T>(
+    drivers: &'a [T],
+    matches: impl Fn(&'a T) -> &'a [Match],
+    ids: [u16; 2],
+    class: [u8; 3],
+) -> Option<&'a T> {
+    drivers
+        .iter()
+        .filter_map(|driver| {
+            let score = matches(driver)
+                .iter()
+                .filter_map(|r#match| r#match.matches(ids, class))
+                .max()?;
+            Some((score, driver))
+        })
+        .max_by_key(|(score, _)| *score)
+        .map(|(_, driver)| driver)
+}
+
+pub struct Driver {
+    pub name: &'static str,
+    pub matches: &'static [Match],
+    /// Boot module spawned for each function bound.
+    pub module: &'static str,
+    /// Passed to the driver along with the function, e.g. its options.
+    pub arguments: &'static str,
+    /// Whether the driver is allowed to report input events, e.g. of the
+    /// keyboards behind a USB host controller.
+    pub input: bool,
+}
@@ -15,52 +15,125 @@
 #![no_std]
 #![no_main]
 
-use core::{hint, marker, mem::MaybeUninit, panic};
+use core::{hint, marker, mem::MaybeUninit, panic, str};
 
 use bitflags::bitflags;
-use pio::Port;
+use drv_pci::cfg::{ConfigurationAccessMechanism, ConfigurationSpaceHeaderCommand, CAM, ECAM};
+
+mod acpi;
+mod bar;
+mod bus;
+mod drv;
+mod dump;
+mod hp;
+
+/// Maximum number of PCI segment groups, further ones are ignored.
+const MAX_SEGMENTS: usize = 4;
+
+/// Detail of the function list logged after probing, unless the arguments
+/// name one with `verbosity=`.
+const VERBOSITY: dump::Verbosity = dump::Verbosity::Verbose;
+
+sys::main!(main);
+
+fn main(argument: &[u8]) {
+    sys::Logger::init(log::LevelFilter::Info);
+    let verbosity = verbosity(str::from_utf8(argument).unwrap_or_default());
+
+    let mut segments = [const { None }; MAX_SEGMENTS];
+    for (segment, window) in segments.iter_mut().zip(acpi::mcfg()) {
+        let Some(base) = usize::try_from(window.base_address)
+            .ok()
+            .and_then(|base| sys::memory_map(base, window.buses.len() << 20).ok())
+        else {
+            log::warn!("ECAM of segment {} not mappable", window.segment);
+            continue;
+        };
+        *segment = Some(Segment {
+            number: window.segment,
+            first_bus: *window.buses.start(),
+            am: unsafe { ECAM::new(base, window.buses) },
+        });
+    }
 
-fn main() {}
+    if segments.iter().any(Option::is_some) {
+        run(&segments, verbosity);
+    } else {
+        // CONFIG_ADDRESS and CONFIG_DATA
+        sys::io_permission(0xCF8..=0xCFF).expect("configuration space not accessible");
+        run(
+            &[Some(Segment {
+                number: 0,
+                first_bus: 0,
+                am: unsafe { CAM::new() },
+            })],
+            verbosity,
+        );
+    }
+}
 
-#[panic_handler]
-fn panic(_info: &panic::PanicInfo) -> ! {
-    loop {
-        hint::spin_loop();
+/// Returns the detail of the function list in the arguments, given as
+/// `verbosity=<detail>`.
+fn verbosity(arguments: &str) -> dump::Verbosity {
+    let Some(name) = arguments
+        .split_whitespace()
+        .find_map(|argument| argument.strip_prefix("verbosity="))
+    else {
+        return VERBOSITY;
+    };
+    dump::Verbosity::from_name(name).unwrap_or_else(|| {
+        log::warn!("Unknown verbosity {}", name);
+        VERBOSITY
+    })
+}
+
+fn run<AM: ConfigurationAccessMechanism>(
+    segments: &[Option<Segment<AM>>],
+    verbosity: dump::Verbosity,
+) {
+    let mut tree = bus::Tree::new();
+    for segment in segments.iter().flatten() {
+        tree.enumerate(segment);
+        tree.assign(segment);
     }
+    // signaled by hot-plug slots and drivers exiting
+    let irq = sys::irq_allocate().expect("no interrupt vector");
+    drv::probe(&mut tree, segments);
+    drv::watch(&mut tree, segments, irq.vector);
+    dump::dump(&tree, segments, verbosity);
+    hp::run(&mut tree, segments, irq);
 }
 
-trait ConfigurationAccessMechanism: Sized {
-    fn header(&self, location: u16) -> ConfigurationSpaceHeader<Self>;
+/// Configuration space of a PCI segment group.
+struct Segment<AM: ConfigurationAccessMechanism> {
+    number: u16,
+    first_bus: u8,
+    am: AM,
 }
 
-struct CAM(Port<u32>, Port<u32>);
+fn segment<AM: ConfigurationAccessMechanism>(
+    segments: &[Option<Segment<AM>>],
+    number: u16,
+) -> Option<&Segment<AM>> {
+    segments
+        .iter()
+        .flatten()
+        .find(|segment| segment.number == number)
+}
 
-impl ConfigurationAccessMechanism for CAM {
-    fn header(&self, location: u16) -> ConfigurationSpaceHeader<Self> {
-        let mut header: MaybeUninit<ConfigurationSpaceHeader<Self>> = MaybeUninit::uninit();
-        for register in 0..size_of::<ConfigurationSpaceHeader<Self>>() / size_of::<u32>() {
-            self.0
-                .write(1 << 31 | (location as u32) << 8 | (register * size_of::<u32>()) as u32);
-            let value = self.1.read();
-            unsafe {
-                (header.as_mut_ptr() as *mut u32).add(register).write(value);
-            }
-        }
-        unsafe { header.assume_init() }
+#[panic_handler]
+fn panic(_info: &panic::PanicInfo) -> ! {
+    loop {
+        hint::spin_loop();
     }
 }
 
-struct ECAM(*mut u32);
-
-impl ConfigurationAccessMechanism for ECAM {
+/// Reads a snapshot of the header through any access mechanism.
+trait ConfigurationSpace: ConfigurationAccessMechanism + Sized {
     fn header(&self, location: u16) -> ConfigurationSpaceHeader<Self> {
         let mut header: MaybeUninit<ConfigurationSpaceHeader<Self>> = MaybeUninit::uninit();
-        for register in 0..size_of::<ConfigurationSpaceHeader<Self>>() {
-            let value = unsafe {
-                self.0
-                    .add((location as usize) << 12 | (register * size_of::<u32>()))
-                    .read_volatile()
-            };
+        for register in 0..size_of::<ConfigurationSpaceHeader<Self>>() / size_of::<u32>() {
+            let value = self.read32(location, (register * size_of::<u32>()) as u16);
             unsafe {
                 (header.as_mut_ptr() as *mut u32).add(register).write(value);
             }
@@ -69,6 +142,8 @@ impl ConfigurationAccessMechanism for ECAM {
     }
 }
 
+impl<AM: ConfigurationAccessMechanism> ConfigurationSpace for AM {}
+
 #[repr(C)]
 struct ConfigurationSpaceHeader<AM: ConfigurationAccessMechanism> {
     vendor_id: u16,
@@ -95,31 +170,6 @@ struct ConfigurationSpaceHeader<AM: ConfigurationAccessMechanism> {
 }
 
 bitflags! {
-    struct ConfigurationSpaceHeaderCommand: u16 {
-        /// I/O Space Enable
-        const IOSE = 1 << 0;
-        /// Memory Space Enable
-        const MSE = 1 << 1;
-        /// Bus Master Enable
-        const BME = 1 << 2;
-        /// Special Cycle Enable
-        const SCE = 1 << 3;
-        /// Memory Write and Invalidate
-        const MWI = 1 << 4;
-        /// VGA Palette Snoop
-        const VGAPS = 1 << 5;
-        /// Parity Error Response
-        const PER = 1 << 6;
-        /// IDSEL Stepping/Wait Cycle Control
-        const IDSEL = 1 << 7;
-        /// SERR# Enable
-        const SERRE = 1 << 8;
-        /// Fast Back-to-Back Transactions Enable
-        const FB2BTE = 1 << 9;
-        /// Interrupt Disable
-        const ID = 1 << 10;
-    }
-
     #[derive(Clone, Copy)]
     struct ConfigurationSpaceHeaderStatus: u16 {
         /// Immediate Readiness
@@ -0,0 +1,204 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::ptr;
+
+/// Endpoint types
+pub const ENDPOINT_TYPE_ISOCH_OUT: u32 = 1;
+pub const ENDPOINT_TYPE_BULK_OUT: u32 = 2;
+pub const ENDPOINT_TYPE_INTERRUPT_OUT: u32 = 3;
+pub const ENDPOINT_TYPE_CONTROL: u32 = 4;
+pub const ENDPOINT_TYPE_ISOCH_IN: u32 = 5;
+pub const ENDPOINT_TYPE_BULK_IN: u32 = 6;
+pub const ENDPOINT_TYPE_INTERRUPT_IN: u32 = 7;
+
+/// Input Control Context: the slot context, and the default control endpoint
+pub const ADD_SLOT: u32 = 1 << 0;
+pub const ADD_CONTROL: u32 = 1 << 1;
+
+/// Endpoint state, after an error or stall
+pub const ENDPOINT_STATE_HALTED: u8 = 2;
+
+/// Error count, i.e. retries of a failed transaction
+const ENDPOINT_CERR: u32 = 3;
+
+/// Slot Context, the first 32 bytes of its context.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SlotContext {
+    /// Route String, Speed, MTT, Hub and Context Entries
+    route: u32,
+    /// Max Exit Latency, Root Hub Port Number and Number of Ports
+    port: u32,
+    /// TT Hub Slot ID, TT Port Number, TTT and Interrupter Target
+    tt: u32,
+    /// USB Device Address and Slot State
+    state: u32,
+    _rsvd: [u32; 4],
+}
+
+impl SlotContext {
+    pub fn new(route: u32, speed: u32, port: u8) -> Self {
+        Self {
+            route: route & 0xFFFFF | speed << 20,
+            port: (port as u32) << 16,
+            tt: 0,
+            state: 0,
+            _rsvd: [0; 4],
+        }
+    }
+
+    /// Low- and full-speed devices behind a high-speed hub are reached
+    /// through its transaction translator.
+    pub fn with_tt(mut self, hub_slot: u8, hub_port: u8) -> Self {
+        self.tt = hub_slot as u32 | (hub_port as u32) << 8;
+        self
+    }
+
+    /// `entries` is the last valid device context index.
+    pub fn with_entries(mut self, entries: u8) -> Self {
+        self.route = self.route & !(0x1F << 27) | (entries as u32) << 27;
+        self
+    }
+
+    /// Address the xHC assigned to the device.
+    pub fn address(&self) -> u8 {
+        self.state as u8
+    }
+}
+
+/// Endpoint Context, the first 32 bytes of its context.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct EndpointContext {
+    /// Endpoint State, Mult, Max Primary Streams and Interval
+    interval: u32,
+    /// CErr, EP Type, Max Burst Size and Max Packet Size
+    endpoint_type: u32,
+    /// TR Dequeue Pointer and Dequeue Cycle State
+    dequeue: u64,
+    /// Average TRB Length and Max ESIT Payload
+    payload: u32,
+    _rsvd: [u32; 3],
+}
+
+impl EndpointContext {
+    /// `interval` is in 125 us units as power of 2, `dequeue` the ring's
+    /// dequeue pointer with its cycle state.
+    pub fn new(
+        endpoint_type: u32,
+        max_packet_size: u16,
+        max_burst: u8,
+        interval: u8,
+        dequeue: u64,
+    ) -> Self {
+        let periodic = matches!(
+            endpoint_type,
+            ENDPOINT_TYPE_ISOCH_OUT
+                | ENDPOINT_TYPE_INTERRUPT_OUT
+                | ENDPOINT_TYPE_ISOCH_IN
+                | ENDPOINT_TYPE_INTERRUPT_IN
+        );
+        // bytes transferred per service interval
+        let payload = if periodic {
+            max_packet_size as u32 * (max_burst as u32 + 1)
+        } else {
+            0
+        };
+        let average = match endpoint_type {
+            ENDPOINT_TYPE_CONTROL => 8,
+            ENDPOINT_TYPE_BULK_OUT | ENDPOINT_TYPE_BULK_IN => 3 * 1024,
+            _ => payload,
+        };
+        Self {
+            interval: (interval as u32) << 16 | (payload >> 16) << 24,
+            endpoint_type: if matches!(
+                endpoint_type,
+                ENDPOINT_TYPE_ISOCH_OUT | ENDPOINT_TYPE_ISOCH_IN
+            ) {
+                0
+            } else {
+                ENDPOINT_CERR << 1
+            } | endpoint_type << 3
+                | (max_burst as u32) << 8
+                | (max_packet_size as u32) << 16,
+            dequeue,
+            payload: average & 0xFFFF | (payload & 0xFFFF) << 16,
+            _rsvd: [0; 3],
+        }
+    }
+}
+
+/// Input Context, an input control context followed by a device context.
+pub struct InputContext {
+    base: *mut u8,
+    context_size: usize,
+}
+
+impl InputContext {
+    pub fn new(base: *mut u8, context_size: usize) -> Self {
+        Self { base, context_size }
+    }
+
+    /// Clears all contexts, and adds the ones flagged, bit n standing for
+    /// device context index n.
+    pub fn reset(&mut self, add: u32) {
+        unsafe {
+            ptr::write_bytes(self.base, 0, self.context_size * 33);
+            (self.base as *mut u32).add(1).write_volatile(add);
+        }
+    }
+
+    /// Drops the contexts flagged, before they are added again.
+    pub fn set_drop(&mut self, drop: u32) {
+        unsafe { (self.base as *mut u32).write_volatile(drop) };
+    }
+
+    pub fn set_slot(&mut self, context: SlotContext) {
+        unsafe {
+            (self.base.add(self.context_size) as *mut SlotContext).write_volatile(context);
+        }
+    }
+
+    /// `index` is the device context index, 1 for the default control
+    /// endpoint.
+    pub fn set_endpoint(&mut self, index: u8, context: EndpointContext) {
+        unsafe {
+            (self.base.add(self.context_size * (index as usize + 1)) as *mut EndpointContext)
+                .write_volatile(context);
+        }
+    }
+}
+
+/// Device Context, owned by the xHC once the slot is enabled.
+pub struct DeviceContext {
+    base: *mut u8,
+    context_size: usize,
+}
+
+impl DeviceContext {
+    pub fn new(base: *mut u8, context_size: usize) -> Self {
+        Self { base, context_size }
+    }
+
+    pub fn slot(&self) -> SlotContext {
+        unsafe { (self.base as *const SlotContext).read_volatile() }
+    }
+
+    /// Returns the state of the endpoint, by device context index.
+    pub fn endpoint_state(&self, index: u8) -> u8 {
+        let context = unsafe { self.base.add(self.context_size * index as usize) } as *const u32;
+        (unsafe { context.read_volatile() } & 0x7) as u8
+    }
+}
@@ -0,0 +1,230 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// bmRequestType: device to host
+pub const REQUEST_TYPE_IN: u8 = 1 << 7;
+pub const REQUEST_TYPE_CLASS: u8 = 1 << 5;
+pub const REQUEST_TYPE_INTERFACE: u8 = 1;
+pub const REQUEST_TYPE_ENDPOINT: u8 = 2;
+
+/// Standard requests
+pub const REQUEST_CLEAR_FEATURE: u8 = 1;
+pub const REQUEST_GET_DESCRIPTOR: u8 = 6;
+pub const REQUEST_SET_CONFIGURATION: u8 = 9;
+
+/// Descriptor types
+pub const TYPE_DEVICE: u8 = 1;
+pub const TYPE_CONFIGURATION: u8 = 2;
+pub const TYPE_STRING: u8 = 3;
+pub const TYPE_INTERFACE: u8 = 4;
+pub const TYPE_ENDPOINT: u8 = 5;
+pub const TYPE_SUPERSPEED_ENDPOINT_COMPANION: u8 = 0x30;
+
+/// Feature selectors
+pub const FEATURE_ENDPOINT_HALT: u16 = 0;
+
+/// US English, if the device doesn't list its languages
+pub const LANGUAGE_ID_DEFAULT: u16 = 0x0409;
+
+/// Endpoint transfer types
+pub const TRANSFER_CONTROL: u8 = 0;
+pub const TRANSFER_ISOCHRONOUS: u8 = 1;
+pub const TRANSFER_BULK: u8 = 2;
+pub const TRANSFER_INTERRUPT: u8 = 3;
+
+/// Setup packet of a control transfer.
+#[derive(Clone, Copy)]
+pub struct Setup {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl Setup {
+    pub fn get_descriptor(descriptor_type: u8, index: u8, language_id: u16, length: u16) -> Self {
+        Self {
+            request_type: REQUEST_TYPE_IN,
+            request: REQUEST_GET_DESCRIPTOR,
+            value: (descriptor_type as u16) << 8 | index as u16,
+            index: language_id,
+            length,
+        }
+    }
+
+    pub fn clear_halt(address: u8) -> Self {
+        Self {
+            request_type: REQUEST_TYPE_ENDPOINT,
+            request: REQUEST_CLEAR_FEATURE,
+            value: FEATURE_ENDPOINT_HALT,
+            index: address as u16,
+            length: 0,
+        }
+    }
+
+    pub fn set_configuration(value: u8) -> Self {
+        Self {
+            request_type: 0,
+            request: REQUEST_SET_CONFIGURATION,
+            value: value as u16,
+            index: 0,
+            length: 0,
+        }
+    }
+
+    /// The packet as immediate data of a Setup Stage TRB.
+    pub fn parameter(&self) -> u64 {
+        self.request_type as u64
+            | (self.request as u64) << 8
+            | (self.value as u64) << 16
+            | (self.index as u64) << 32
+            | (self.length as u64) << 48
+    }
+
+    pub fn is_in(&self) -> bool {
+        self.request_type & REQUEST_TYPE_IN != 0
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct DeviceDescriptor {
+    /// USB version, in BCD
+    pub usb: u16,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// String index
+    pub product: u8,
+    pub configurations: u8,
+}
+
+impl DeviceDescriptor {
+    pub const SIZE: usize = 18;
+
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < Self::SIZE || bytes[1] != TYPE_DEVICE {
+            return None;
+        }
+        Some(Self {
+            usb: u16::from_le_bytes([bytes[2], bytes[3]]),
+            vendor_id: u16::from_le_bytes([bytes[8], bytes[9]]),
+            product_id: u16::from_le_bytes([bytes[10], bytes[11]]),
+            product: bytes[15],
+            configurations: bytes[17],
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ConfigurationDescriptor {
+    /// Bytes of the configuration, with its interface and endpoint
+    /// descriptors.
+    pub total_length: u16,
+    /// Argument of SET_CONFIGURATION
+    pub value: u8,
+}
+
+impl ConfigurationDescriptor {
+    pub const SIZE: usize = 9;
+
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < Self::SIZE || bytes[1] != TYPE_CONFIGURATION {
+            return None;
+        }
+        Some(Self {
+            total_length: u16::from_le_bytes([bytes[2], bytes[3]]),
+            value: bytes[5],
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct InterfaceDescriptor {
+    pub number: u8,
+    pub alternate: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+}
+
+impl InterfaceDescriptor {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 9 {
+            return None;
+        }
+        Some(Self {
+            number: bytes[2],
+            alternate: bytes[3],
+            class: bytes[5],
+            subclass: bytes[6],
+            protocol: bytes[7],
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct EndpointDescriptor {
+    /// Endpoint number, and bit 7 set for IN endpoints
+    pub address: u8,
+    pub attributes: u8,
+    /// Bits 12:11 are the additional transactions per microframe of
+    /// high-speed periodic endpoints.
+    pub max_packet_size: u16,
+    pub interval: u8,
+}
+
+impl EndpointDescriptor {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 7 {
+            return None;
+        }
+        Some(Self {
+            address: bytes[2],
+            attributes: bytes[3],
+            max_packet_size: u16::from_le_bytes([bytes[4], bytes[5]]),
+            interval: bytes[6],
+        })
+    }
+
+    pub fn number(&self) -> u8 {
+        self.address & 0xF
+    }
+
+    pub fn is_in(&self) -> bool {
+        self.address & 1 << 7 != 0
+    }
+
+    pub fn transfer_type(&self) -> u8 {
+        self.attributes & 0x3
+    }
+
+    /// Device context index, which is also the doorbell target.
+    pub fn index(&self) -> u8 {
+        self.number() * 2 + self.is_in() as u8
+    }
+}
+
+/// Returns the type and bytes of each descriptor in a configuration, up to
+/// the first truncated one.
+pub fn descriptors(mut bytes: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    core::iter::from_fn(move || {
+        let length = *bytes.first()? as usize;
+        if length < 2 || length > bytes.len() {
+            return None;
+        }
+        let (descriptor, rest) = bytes.split_at(length);
+        bytes = rest;
+        Some((descriptor[1], descriptor))
+    })
+}
@@ -0,0 +1,780 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::{mem, ptr, slice, str};
+
+use crate::{
+    ctx::{self, DeviceContext, EndpointContext, InputContext, SlotContext},
+    desc::{
+        self, ConfigurationDescriptor, DeviceDescriptor, EndpointDescriptor, InterfaceDescriptor,
+        Setup,
+    },
+    drv::{Driver, Function},
+    hc::{self, Controller, Error},
+    port::Speed,
+    ring::{self, Ring, Trb, TRB_COUNT},
+};
+
+/// Interfaces and endpoints of a configuration, further ones are ignored.
+pub const MAX_INTERFACES: usize = 8;
+pub const MAX_ENDPOINTS: usize = 8;
+
+/// Bytes of a control transfer's data stage, longer configurations are
+/// truncated.
+pub const BUFFER_SIZE: usize = 2048;
+
+/// Bytes of an interrupt transfer, i.e. a max packet.
+pub const PACKET_SIZE: usize = 1024;
+
+/// Characters of the product string kept.
+const PRODUCT_SIZE: usize = 32;
+
+/// Memory of a slot, each ring fills a page, which it may not cross.
+#[repr(C, align(4096))]
+pub struct Memory {
+    /// Default control endpoint, and the endpoints of the configuration.
+    rings: [[Trb; TRB_COUNT]; MAX_ENDPOINTS + 1],
+    /// Device context, of up to 32 contexts
+    output: [u8; 32 * 64],
+    /// Input context, i.e. the input control context and a device context
+    input: [u8; 33 * 64],
+    buffer: [u8; BUFFER_SIZE],
+    /// Data of each endpoint's interrupt transfers
+    packets: [[u8; PACKET_SIZE]; MAX_ENDPOINTS],
+}
+
+pub struct Interface {
+    pub descriptor: InterfaceDescriptor,
+    pub driver: Option<&'static Driver>,
+    /// State of the driver bound.
+    pub function: Option<Function>,
+}
+
+pub struct Endpoint {
+    pub descriptor: EndpointDescriptor,
+    /// Index of its interface.
+    pub interface: usize,
+    /// Packets per burst - 1, of SuperSpeed endpoints.
+    pub max_burst: u8,
+    pub ring: Ring,
+    /// Transfer event not passed to the driver yet.
+    pub completion: Option<Trb>,
+    /// Bytes of the transfer queued.
+    requested: usize,
+    packet: *mut u8,
+    packet_phys_addr: u64,
+}
+
+impl Endpoint {
+    /// Returns the data of the completed transfer.
+    pub fn packet(&self, event: &Trb) -> &[u8] {
+        // the residue is left in the transfer length
+        let length = self
+            .requested
+            .saturating_sub((event.status & 0xFFFFFF) as usize);
+        unsafe { slice::from_raw_parts(self.packet, length) }
+    }
+
+    /// Returns the packet buffer, and its physical address, e.g. for
+    /// transfers of the driver's own.
+    pub fn packet_buffer(&mut self) -> (&mut [u8; PACKET_SIZE], u64) {
+        (
+            unsafe { &mut *(self.packet as *mut [u8; PACKET_SIZE]) },
+            self.packet_phys_addr,
+        )
+    }
+
+    fn context(&self, speed: Speed) -> EndpointContext {
+        let descriptor = &self.descriptor;
+        let transfer_type = descriptor.transfer_type();
+        let periodic = matches!(
+            transfer_type,
+            desc::TRANSFER_ISOCHRONOUS | desc::TRANSFER_INTERRUPT
+        );
+        let endpoint_type = match (transfer_type, descriptor.is_in()) {
+            (desc::TRANSFER_ISOCHRONOUS, false) => ctx::ENDPOINT_TYPE_ISOCH_OUT,
+            (desc::TRANSFER_ISOCHRONOUS, true) => ctx::ENDPOINT_TYPE_ISOCH_IN,
+            (desc::TRANSFER_BULK, false) => ctx::ENDPOINT_TYPE_BULK_OUT,
+            (desc::TRANSFER_BULK, true) => ctx::ENDPOINT_TYPE_BULK_IN,
+            (desc::TRANSFER_INTERRUPT, false) => ctx::ENDPOINT_TYPE_INTERRUPT_OUT,
+            (desc::TRANSFER_INTERRUPT, true) => ctx::ENDPOINT_TYPE_INTERRUPT_IN,
+            _ => ctx::ENDPOINT_TYPE_CONTROL,
+        };
+        let max_burst = match speed {
+            Speed::High if periodic => (descriptor.max_packet_size >> 11 & 0x3) as u8,
+            Speed::Super | Speed::SuperPlus => self.max_burst,
+            _ => 0,
+        };
+        // as 2^interval microframes, from frames for low- and full-speed
+        // interrupt endpoints, or as exponent
+        let interval = match (transfer_type, speed) {
+            (desc::TRANSFER_CONTROL | desc::TRANSFER_BULK, _) => 0,
+            (desc::TRANSFER_INTERRUPT, Speed::Low | Speed::Full) => {
+                (descriptor.interval.max(1) as u32 * 8).ilog2().clamp(3, 10) as u8
+            }
+            (_, Speed::Full) => descriptor.interval.clamp(1, 16) + 2,
+            _ => descriptor.interval.clamp(1, 16) - 1,
+        };
+        EndpointContext::new(
+            endpoint_type,
+            descriptor.max_packet_size & 0x7FF,
+            max_burst,
+            interval,
+            self.ring.dequeue_pointer(),
+        )
+    }
+}
+
+/// Node of the USB device tree.
+pub struct Device {
+    pub slot: u8,
+    /// Slot of the hub the device is attached to, 0 for the root hub.
+    pub parent: u8,
+    /// Port of the parent hub.
+    pub port: u8,
+    pub speed: Speed,
+    /// Root hub port, and the ports of the hubs below it, a nibble per tier.
+    pub root_port: u8,
+    pub route: u32,
+    /// Hub slot and port of the transaction translator of low- and
+    /// full-speed devices behind a high-speed hub.
+    pub tt: Option<(u8, u8)>,
+    pub address: u8,
+    pub descriptor: DeviceDescriptor,
+    /// Value of the configuration set.
+    pub configuration: u8,
+    /// Last device context index of the configuration.
+    entries: u8,
+    pub interfaces: [Option<Interface>; MAX_INTERFACES],
+    pub endpoints: [Option<Endpoint>; MAX_ENDPOINTS],
+    product: [u8; PRODUCT_SIZE],
+    product_len: usize,
+    context: SlotContext,
+    control: Ring,
+    memory: *mut Memory,
+    phys_addr: u64,
+}
+
+impl Device {
+    pub fn product(&self) -> &str {
+        str::from_utf8(&self.product[..self.product_len]).unwrap_or_default()
+    }
+
+    /// Returns the transfer ring of the device context index, 1 being the
+    /// default control endpoint.
+    pub fn ring_mut(&mut self, index: u8) -> Option<&mut Ring> {
+        if index == 1 {
+            return Some(&mut self.control);
+        }
+        self.endpoint_mut(index).map(|endpoint| &mut endpoint.ring)
+    }
+
+    /// Returns the endpoint of the device context index.
+    pub fn endpoint_mut(&mut self, index: u8) -> Option<&mut Endpoint> {
+        self.endpoints
+            .iter_mut()
+            .flatten()
+            .find(|endpoint| endpoint.descriptor.index() == index)
+    }
+
+    /// Data of the last control transfer.
+    pub fn buffer(&self) -> &[u8] {
+        unsafe { &(*self.memory).buffer }
+    }
+
+    fn input(&self, context_size: usize) -> (InputContext, u64) {
+        let input = unsafe { ptr::addr_of_mut!((*self.memory).input) } as *mut u8;
+        (InputContext::new(input, context_size), self.phys(input))
+    }
+
+    fn output(&self, context_size: usize) -> (DeviceContext, u64) {
+        let output = unsafe { ptr::addr_of_mut!((*self.memory).output) } as *mut u8;
+        (DeviceContext::new(output, context_size), self.phys(output))
+    }
+
+    fn phys(&self, address: *const u8) -> u64 {
+        self.phys_addr + (address as usize - self.memory as usize) as u64
+    }
+
+    /// Keeps the interfaces of the configuration in the buffer, with their
+    /// default alternate setting, and sets up their endpoints' rings.
+    fn parse(&mut self, length: usize) {
+        // the buffer is left alone until the configuration is parsed
+        let bytes = unsafe {
+            slice::from_raw_parts(ptr::addr_of!((*self.memory).buffer) as *const u8, length)
+        };
+        let mut interface = None;
+        let mut endpoint = None;
+        for (descriptor_type, bytes) in desc::descriptors(bytes) {
+            match descriptor_type {
+                desc::TYPE_INTERFACE => {
+                    interface = None;
+                    endpoint = None;
+                    let Some(descriptor) = InterfaceDescriptor::parse(bytes) else {
+                        continue;
+                    };
+                    if descriptor.alternate != 0 {
+                        continue;
+                    }
+                    interface = self.interfaces.iter().position(Option::is_none);
+                    if let Some(index) = interface {
+                        self.interfaces[index] = Some(Interface {
+                            descriptor,
+                            driver: None,
+                            function: None,
+                        });
+                    }
+                }
+                desc::TYPE_ENDPOINT => {
+                    endpoint = None;
+                    let (Some(interface), Some(descriptor)) =
+                        (interface, EndpointDescriptor::parse(bytes))
+                    else {
+                        continue;
+                    };
+                    endpoint = self.endpoints.iter().position(Option::is_none);
+                    if let Some(index) = endpoint {
+                        let trbs = unsafe { ptr::addr_of_mut!((*self.memory).rings[index + 1]) }
+                            as *mut Trb;
+                        let packet =
+                            unsafe { ptr::addr_of_mut!((*self.memory).packets[index]) } as *mut u8;
+                        self.endpoints[index] = Some(Endpoint {
+                            descriptor,
+                            interface,
+                            max_burst: 0,
+                            ring: Ring::at(trbs, self.phys(trbs as *const u8)),
+                            completion: None,
+                            requested: 0,
+                            packet,
+                            packet_phys_addr: self.phys(packet),
+                        });
+                    }
+                }
+                desc::TYPE_SUPERSPEED_ENDPOINT_COMPANION => {
+                    if let Some(endpoint) =
+                        endpoint.and_then(|index| self.endpoints[index].as_mut())
+                    {
+                        endpoint.max_burst = bytes.get(2).copied().unwrap_or_default();
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Controller {
+    pub fn device(&self, slot: u8) -> Option<&Device> {
+        self.devices.get((slot as usize).checked_sub(1)?)?.as_ref()
+    }
+
+    pub fn device_mut(&mut self, slot: u8) -> Option<&mut Device> {
+        self.devices
+            .get_mut((slot as usize).checked_sub(1)?)?
+            .as_mut()
+    }
+
+    /// Returns the devices attached to the hub, 0 being the root hub.
+    pub fn children(&self, parent: u8) -> impl Iterator<Item = &Device> {
+        self.devices
+            .iter()
+            .flatten()
+            .filter(move |device| device.parent == parent)
+    }
+
+    /// Enumerates the device on the hub's port, 0 being the root hub, and
+    /// binds drivers to its interfaces. Returns its slot.
+    pub fn attach(&mut self, parent: u8, port: u8, speed: Speed) -> Result<u8, Error> {
+        let (root_port, route, tt) = if parent == 0 {
+            (port, 0, None)
+        } else {
+            let hub = self.device(parent).ok_or(Error::NoDevice)?;
+            let tier = (u32::BITS - hub.route.leading_zeros()).div_ceil(4);
+            let tt = match (hub.speed, speed) {
+                (Speed::High, Speed::Low | Speed::Full) => Some((parent, port)),
+                _ => hub.tt,
+            };
+            (
+                hub.root_port,
+                hub.route | (port.min(15) as u32) << (tier * 4),
+                tt,
+            )
+        };
+
+        let slot = self
+            .command(Trb::new(ring::TYPE_ENABLE_SLOT_COMMAND, 0, 0, 0))?
+            .slot();
+        let (memory, phys_addr) = match self.slot_memory(slot) {
+            Ok(memory) => memory,
+            Err(error) => {
+                self.release(slot);
+                return Err(error);
+            }
+        };
+        let control = Ring::at(memory as *mut Trb, phys_addr);
+        let mut context = SlotContext::new(route, speed.id(), root_port);
+        if let Some((hub, hub_port)) = tt {
+            context = context.with_tt(hub, hub_port);
+        }
+        self.devices[slot as usize - 1] = Some(Device {
+            slot,
+            parent,
+            port,
+            speed,
+            root_port,
+            route,
+            tt,
+            address: 0,
+            descriptor: DeviceDescriptor::default(),
+            configuration: 0,
+            entries: 1,
+            interfaces: [const { None }; MAX_INTERFACES],
+            endpoints: [const { None }; MAX_ENDPOINTS],
+            product: [0; PRODUCT_SIZE],
+            product_len: 0,
+            context,
+            control,
+            memory,
+            phys_addr,
+        });
+
+        if let Err(error) = self.enumerate(slot) {
+            self.release(slot);
+            return Err(error);
+        }
+        let Some(device) = self.device(slot) else {
+            return Err(Error::NoDevice);
+        };
+        log::info!(
+            "Slot {}: {:04X}:{:04X} {}, USB {:X}.{:X}, at address {}, port {}",
+            slot,
+            device.descriptor.vendor_id,
+            device.descriptor.product_id,
+            device.product(),
+            device.descriptor.usb >> 8,
+            device.descriptor.usb >> 4 & 0xF,
+            device.address,
+            device.port
+        );
+        for interface in device.interfaces.iter().flatten() {
+            log::info!(
+                "Slot {}: interface {}, class {:02X}:{:02X}:{:02X}",
+                slot,
+                interface.descriptor.number,
+                interface.descriptor.class,
+                interface.descriptor.subclass,
+                interface.descriptor.protocol
+            );
+        }
+        self.bind(slot);
+        self.export(slot);
+        Ok(slot)
+    }
+
+    /// Detaches the device on the hub's port, and the devices below it.
+    pub fn detach(&mut self, parent: u8, port: u8) {
+        let Some(slot) = self
+            .children(parent)
+            .find(|device| device.port == port)
+            .map(|device| device.slot)
+        else {
+            return;
+        };
+        log::info!("Slot {}: detached", slot);
+        self.release(slot);
+    }
+
+    /// Addresses the device, reads its descriptors, and sets its first
+    /// configuration.
+    fn enumerate(&mut self, slot: u8) -> Result<(), Error> {
+        let context_size = self.context_size;
+        let device = self.device_mut(slot).ok_or(Error::NoDevice)?;
+        let speed = device.speed;
+        let (mut input, input_phys_addr) = device.input(context_size);
+        let (output, output_phys_addr) = device.output(context_size);
+        unsafe { ptr::write_bytes(ptr::addr_of_mut!((*device.memory).output), 0, 1) };
+        input.reset(ctx::ADD_SLOT | ctx::ADD_CONTROL);
+        input.set_slot(device.context.with_entries(1));
+        input.set_endpoint(
+            1,
+            EndpointContext::new(
+                ctx::ENDPOINT_TYPE_CONTROL,
+                speed.max_packet_size0(),
+                0,
+                0,
+                device.control.dequeue_pointer(),
+            ),
+        );
+        self.set_device_context(slot, output_phys_addr);
+        let command = (slot as u32) << ring::CONTROL_SLOT_SHIFT;
+        self.command(Trb::new(
+            ring::TYPE_ADDRESS_DEVICE_COMMAND,
+            input_phys_addr,
+            0,
+            command,
+        ))?;
+        let address = output.slot().address();
+
+        // the first 8 bytes hold the max packet size, which is all the default
+        // control endpoint can transfer yet
+        self.control(slot, Setup::get_descriptor(desc::TYPE_DEVICE, 0, 0, 8))?;
+        let device = self.device_mut(slot).ok_or(Error::NoDevice)?;
+        device.address = address;
+        let max_packet_size0 = match device.buffer()[7] {
+            exponent if matches!(speed, Speed::Super | Speed::SuperPlus) => 1 << exponent.min(9),
+            size => size as u16,
+        };
+        if max_packet_size0 != speed.max_packet_size0() && max_packet_size0 != 0 {
+            input.reset(ctx::ADD_CONTROL);
+            input.set_endpoint(
+                1,
+                EndpointContext::new(ctx::ENDPOINT_TYPE_CONTROL, max_packet_size0, 0, 0, 0),
+            );
+            self.command(Trb::new(
+                ring::TYPE_EVALUATE_CONTEXT_COMMAND,
+                input_phys_addr,
+                0,
+                command,
+            ))?;
+        }
+
+        let length = self.control(
+            slot,
+            Setup::get_descriptor(desc::TYPE_DEVICE, 0, 0, DeviceDescriptor::SIZE as u16),
+        )?;
+        let device = self.device_mut(slot).ok_or(Error::NoDevice)?;
+        device.descriptor =
+            DeviceDescriptor::parse(&device.buffer()[..length]).ok_or(Error::Descriptor)?;
+        if device.descriptor.configurations == 0 {
+            return Err(Error::Descriptor);
+        }
+        let product = device.descriptor.product;
+        // strings are optional, and some devices stall on them
+        if let Err(error) = self.product(slot, product) {
+            log::debug!("Slot {}: product string not read: {:?}", slot, error);
+        }
+
+        let length = self.control(
+            slot,
+            Setup::get_descriptor(
+                desc::TYPE_CONFIGURATION,
+                0,
+                0,
+                ConfigurationDescriptor::SIZE as u16,
+            ),
+        )?;
+        let device = self.device(slot).ok_or(Error::NoDevice)?;
+        let configuration =
+            ConfigurationDescriptor::parse(&device.buffer()[..length]).ok_or(Error::Descriptor)?;
+        let length = self.control(
+            slot,
+            Setup::get_descriptor(
+                desc::TYPE_CONFIGURATION,
+                0,
+                0,
+                configuration.total_length.min(BUFFER_SIZE as u16),
+            ),
+        )?;
+        let device = self.device_mut(slot).ok_or(Error::NoDevice)?;
+        device.parse(length);
+        device.configuration = configuration.value;
+        self.configure(slot)
+    }
+
+    /// Adds the endpoints of the configuration to the slot, and sets the
+    /// configuration.
+    fn configure(&mut self, slot: u8) -> Result<(), Error> {
+        let context_size = self.context_size;
+        let device = self.device_mut(slot).ok_or(Error::NoDevice)?;
+        let (mut input, input_phys_addr) = device.input(context_size);
+        let (add, entries) = device.endpoints.iter().flatten().fold(
+            (ctx::ADD_SLOT, 1),
+            |(add, entries), endpoint| {
+                let index = endpoint.descriptor.index();
+                (add | 1 << index, entries.max(index))
+            },
+        );
+        device.entries = entries;
+        input.reset(add);
+        input.set_slot(device.context.with_entries(entries));
+        for endpoint in device.endpoints.iter().flatten() {
+            input.set_endpoint(endpoint.descriptor.index(), endpoint.context(device.speed));
+        }
+        let configuration = device.configuration;
+        if entries > 1 {
+            self.command(Trb::new(
+                ring::TYPE_CONFIGURE_ENDPOINT_COMMAND,
+                input_phys_addr,
+                0,
+                (slot as u32) << ring::CONTROL_SLOT_SHIFT,
+            ))?;
+        }
+        self.control(slot, Setup::set_configuration(configuration))?;
+        Ok(())
+    }
+
+    /// Reads the product string, in the first language the device lists,
+    /// with characters beyond ASCII replaced.
+    fn product(&mut self, slot: u8, index: u8) -> Result<(), Error> {
+        if index == 0 {
+            return Ok(());
+        }
+        let length = self.control(slot, Setup::get_descriptor(desc::TYPE_STRING, 0, 0, 4))?;
+        let device = self.device(slot).ok_or(Error::NoDevice)?;
+        let language_id = match device.buffer()[..length] {
+            [_, desc::TYPE_STRING, low, high, ..] => u16::from_le_bytes([low, high]),
+            _ => desc::LANGUAGE_ID_DEFAULT,
+        };
+        let length = self.control(
+            slot,
+            Setup::get_descriptor(desc::TYPE_STRING, index, language_id, 255),
+        )?;
+        let device = self.device_mut(slot).ok_or(Error::NoDevice)?;
+        let bytes = match device.buffer()[..length] {
+            [size, desc::TYPE_STRING, ref string @ ..] => {
+                &string[..(size as usize).saturating_sub(2).min(string.len())]
+            }
+            _ => return Err(Error::Descriptor),
+        };
+        let mut product = [0; PRODUCT_SIZE];
+        let mut product_len = 0;
+        let units = bytes
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]));
+        for (byte, character) in product.iter_mut().zip(char::decode_utf16(units)) {
+            *byte = match character {
+                Ok(character) if character.is_ascii() && !character.is_ascii_control() => {
+                    character as u8
+                }
+                _ => b'?',
+            };
+            product_len += 1;
+        }
+        device.product = product;
+        device.product_len = product_len;
+        Ok(())
+    }
+
+    /// Issues the request on the default control endpoint, the data stage
+    /// uses the device's buffer. Returns the bytes transferred.
+    pub fn control(&mut self, slot: u8, setup: Setup) -> Result<usize, Error> {
+        let device = self.device_mut(slot).ok_or(Error::NoDevice)?;
+        let length = (setup.length as usize).min(BUFFER_SIZE);
+        let setup = Setup {
+            length: length as u16,
+            ..setup
+        };
+        let buffer = device.phys(device.buffer().as_ptr());
+        // the status stage goes the other way than the data stage, and IN
+        // without one
+        let (transfer_type, data_direction, status_direction) = match (length, setup.is_in()) {
+            (0, _) => (0, 0, ring::CONTROL_DIR_IN),
+            (_, true) => (ring::CONTROL_TRT_IN, ring::CONTROL_DIR_IN, 0),
+            (_, false) => (ring::CONTROL_TRT_OUT, 0, ring::CONTROL_DIR_IN),
+        };
+        device.control.push(Trb::new(
+            ring::TYPE_SETUP_STAGE,
+            setup.parameter(),
+            8,
+            ring::CONTROL_IDT | transfer_type,
+        ));
+        let data = (length != 0).then(|| {
+            device.control.push(Trb::new(
+                ring::TYPE_DATA_STAGE,
+                buffer,
+                length as u32,
+                ring::CONTROL_ISP | data_direction,
+            ))
+        });
+        let status = device.control.push(Trb::new(
+            ring::TYPE_STATUS_STAGE,
+            0,
+            0,
+            ring::CONTROL_IOC | status_direction,
+        ));
+        self.ring(slot, 1);
+
+        let mut transferred = length;
+        loop {
+            let event = self.wait(|event| {
+                event.trb_type() == ring::TYPE_TRANSFER_EVENT
+                    && event.slot() == slot
+                    && event.endpoint() == 1
+            })?;
+            match event.completion_code() {
+                ring::COMPLETION_SUCCESS if event.parameter == status => return Ok(transferred),
+                ring::COMPLETION_SHORT_PACKET if Some(event.parameter) == data => {
+                    // the residue is left in the transfer length
+                    transferred = length.saturating_sub((event.status & 0xFFFFFF) as usize);
+                }
+                ring::COMPLETION_SUCCESS | ring::COMPLETION_SHORT_PACKET => {}
+                code => {
+                    if code == ring::COMPLETION_STALL_ERROR {
+                        self.reset_endpoint(slot, 1)?;
+                    }
+                    return Err(Error::Completion(code));
+                }
+            }
+        }
+    }
+
+    /// Queues a transfer of a max packet on the endpoint, its completion is
+    /// passed to the interface's driver.
+    pub fn receive(&mut self, slot: u8, endpoint: usize) -> Result<(), Error> {
+        let endpoint = self
+            .device_mut(slot)
+            .and_then(|device| device.endpoints.get_mut(endpoint)?.as_mut())
+            .ok_or(Error::NoDevice)?;
+        let length = (endpoint.descriptor.max_packet_size as usize & 0x7FF).min(PACKET_SIZE);
+        endpoint.requested = length;
+        endpoint.ring.push(Trb::new(
+            ring::TYPE_NORMAL,
+            endpoint.packet_phys_addr,
+            length as u32,
+            ring::CONTROL_ISP | ring::CONTROL_IOC,
+        ));
+        let index = endpoint.descriptor.index();
+        self.ring(slot, index);
+        Ok(())
+    }
+
+    /// Transfers the memory on the endpoint, which mustn't cross a 64 KiB
+    /// boundary, and waits for it. Returns the bytes transferred.
+    pub fn bulk(
+        &mut self,
+        slot: u8,
+        endpoint: usize,
+        phys_addr: u64,
+        length: usize,
+    ) -> Result<usize, Error> {
+        let endpoint = self
+            .device_mut(slot)
+            .and_then(|device| device.endpoints.get_mut(endpoint)?.as_mut())
+            .ok_or(Error::NoDevice)?;
+        endpoint.ring.push(Trb::new(
+            ring::TYPE_NORMAL,
+            phys_addr,
+            length as u32,
+            ring::CONTROL_ISP | ring::CONTROL_IOC,
+        ));
+        let index = endpoint.descriptor.index();
+        self.ring(slot, index);
+
+        let event = self.wait(|event| {
+            event.trb_type() == ring::TYPE_TRANSFER_EVENT
+                && event.slot() == slot
+                && event.endpoint() == index
+        })?;
+        match event.completion_code() {
+            ring::COMPLETION_SUCCESS | ring::COMPLETION_SHORT_PACKET => {
+                // the residue is left in the transfer length
+                Ok(length.saturating_sub((event.status & 0xFFFFFF) as usize))
+            }
+            code => Err(Error::Completion(code)),
+        }
+    }
+
+    /// Recovers the halted endpoint on the xHC, and clears the halt on the
+    /// device, which also resets its data toggle to match. Endpoints not
+    /// halted are added again instead, as Reset Endpoint only applies to
+    /// halted ones.
+    pub fn clear_halt(&mut self, slot: u8, endpoint: usize) -> Result<(), Error> {
+        let context_size = self.context_size;
+        let device = self.device(slot).ok_or(Error::NoDevice)?;
+        let endpoint = device
+            .endpoints
+            .get(endpoint)
+            .and_then(Option::as_ref)
+            .ok_or(Error::NoDevice)?;
+        let descriptor = endpoint.descriptor;
+        let index = descriptor.index();
+        if device.output(context_size).0.endpoint_state(index) == ctx::ENDPOINT_STATE_HALTED {
+            self.reset_endpoint(slot, index)?;
+        } else {
+            let (mut input, input_phys_addr) = device.input(context_size);
+            input.reset(ctx::ADD_SLOT | 1 << index);
+            input.set_drop(1 << index);
+            input.set_slot(device.context.with_entries(device.entries));
+            input.set_endpoint(index, endpoint.context(device.speed));
+            self.command(Trb::new(
+                ring::TYPE_CONFIGURE_ENDPOINT_COMMAND,
+                input_phys_addr,
+                0,
+                (slot as u32) << ring::CONTROL_SLOT_SHIFT,
+            ))?;
+        }
+        self.control(slot, Setup::clear_halt(descriptor.address))?;
+        Ok(())
+    }
+
+    /// Recovers the halted endpoint, the xHC continues with the next TRB
+    /// enqueued, the TRBs in between are skipped.
+    pub fn reset_endpoint(&mut self, slot: u8, index: u8) -> Result<(), Error> {
+        let control = (slot as u32) << ring::CONTROL_SLOT_SHIFT
+            | (index as u32) << ring::CONTROL_ENDPOINT_SHIFT;
+        self.command(Trb::new(ring::TYPE_RESET_ENDPOINT_COMMAND, 0, 0, control))?;
+        let dequeue = self
+            .device_mut(slot)
+            .and_then(|device| device.ring_mut(index))
+            .ok_or(Error::NoDevice)?
+            .dequeue_pointer();
+        self.command(Trb::new(
+            ring::TYPE_SET_TR_DEQUEUE_POINTER_COMMAND,
+            dequeue,
+            0,
+            control,
+        ))?;
+        Ok(())
+    }
+
+    /// Unbinds and removes the device, and those below it, and disables its
+    /// slot.
+    fn release(&mut self, slot: u8) {
+        loop {
+            let Some(child) = self.children(slot).next().map(|device| device.slot) else {
+                break;
+            };
+            log::info!("Slot {}: detached", child);
+            self.release(child);
+        }
+        self.unbind(slot);
+        if let Err(error) = self.command(Trb::new(
+            ring::TYPE_DISABLE_SLOT_COMMAND,
+            0,
+            0,
+            (slot as u32) << ring::CONTROL_SLOT_SHIFT,
+        )) {
+            log::warn!("Slot {}: not disabled: {:?}", slot, error);
+        }
+        self.set_device_context(slot, 0);
+        if let Some(device) = self.devices.get_mut(slot as usize - 1) {
+            *device = None;
+        }
+    }
+
+    /// Returns the slot's memory, which is allocated once.
+    fn slot_memory(&mut self, slot: u8) -> Result<(*mut Memory, u64), Error> {
+        let ac64 = self.ac64;
+        let memory = self
+            .memory
+            .get_mut((slot as usize).checked_sub(1).ok_or(Error::NoDevice)?)
+            .ok_or(Error::NoDevice)?;
+        if let Some(memory) = *memory {
+            return Ok(memory);
+        }
+        let allocated = hc::dma_allocate::<Memory>(mem::size_of::<Memory>(), ac64)?;
+        *memory = Some(allocated);
+        Ok(allocated)
+    }
+}
@@ -0,0 +1,178 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use drv_pci::Match;
+
+use crate::{
+    desc::DeviceDescriptor,
+    dev::{Interface, MAX_ENDPOINTS, MAX_INTERFACES},
+    hc::{Controller, Error, MAX_DEVICES},
+    hid::{self, Hid},
+    ring::Trb,
+    storage::{self, Storage},
+};
+
+pub struct Driver {
+    pub name: &'static str,
+    pub matches: &'static [Match],
+    /// Sets up the interface of the slot, and returns the driver's state.
+    pub attach: fn(&mut Controller, u8, usize) -> Result<Function, Error>,
+}
+
+/// State of a driver bound to an interface.
+pub enum Function {
+    Hid(Hid),
+    Storage(Storage),
+}
+
+impl Function {
+    /// Handles the completed transfer of one of the interface's endpoints.
+    fn transfer(&mut self, controller: &mut Controller, slot: u8, endpoint: usize, event: &Trb) {
+        match self {
+            Self::Hid(hid) => hid.transfer(controller, slot, endpoint, event),
+            // bulk transfers are waited for, late events are dropped
+            Self::Storage(_) => {}
+        }
+    }
+}
+
+/// Class drivers, and the interfaces they support.
+static DRIVERS: &[Driver] = &[
+    Driver {
+        name: "hid",
+        matches: &[Match::masked([0x03, 0, 0], [0xFF, 0, 0])],
+        attach: hid::attach,
+    },
+    Driver {
+        name: "usb-storage",
+        // mass storage, SCSI transparent command set, bulk-only transport
+        matches: &[Match::class(0x08, 0x06, 0x50)],
+        attach: storage::attach,
+    },
+];
+
+/// Returns the driver with the most specific match.
+fn best(device: &DeviceDescriptor, interface: &Interface) -> Option<&'static Driver> {
+    drv_pci::best(
+        DRIVERS,
+        |driver| driver.matches,
+        [device.vendor_id, device.product_id],
+        [
+            interface.descriptor.class,
+            interface.descriptor.subclass,
+            interface.descriptor.protocol,
+        ],
+    )
+}
+
+impl Controller {
+    /// Binds the best driver to each interface of the device without one,
+    /// and lets it set the interface up.
+    pub fn bind(&mut self, slot: u8) {
+        for index in 0..MAX_INTERFACES {
+            let Some(device) = self.device_mut(slot) else {
+                return;
+            };
+            let descriptor = device.descriptor;
+            let Some(interface) = device.interfaces[index].as_mut() else {
+                continue;
+            };
+            if interface.driver.is_some() {
+                continue;
+            }
+            let Some(driver) = best(&descriptor, interface) else {
+                continue;
+            };
+            interface.driver = Some(driver);
+            let number = interface.descriptor.number;
+            let attached = (driver.attach)(self, slot, index);
+            let Some(interface) = self
+                .device_mut(slot)
+                .and_then(|device| device.interfaces[index].as_mut())
+            else {
+                return;
+            };
+            match attached {
+                Ok(function) => {
+                    interface.function = Some(function);
+                    log::info!(
+                        "Slot {}: interface {} bound to {}",
+                        slot,
+                        number,
+                        driver.name
+                    );
+                }
+                Err(error) => {
+                    interface.driver = None;
+                    log::warn!(
+                        "Slot {}: interface {} not attached to {}: {:?}",
+                        slot,
+                        number,
+                        driver.name,
+                        error
+                    );
+                }
+            }
+        }
+    }
+
+    /// Detaches the drivers of the device's interfaces, after its disks are
+    /// no longer served.
+    pub fn unbind(&mut self, slot: u8) {
+        self.unexport(slot);
+        let Some(device) = self.device_mut(slot) else {
+            return;
+        };
+        for interface in device.interfaces.iter_mut().flatten() {
+            interface.function = None;
+            if let Some(driver) = interface.driver.take() {
+                log::info!(
+                    "Slot {}: interface {} unbound from {}",
+                    slot,
+                    interface.descriptor.number,
+                    driver.name
+                );
+            }
+        }
+    }
+
+    /// Passes the completed transfers to the drivers of their interfaces.
+    pub fn complete(&mut self) {
+        for slot in 1..=MAX_DEVICES as u8 {
+            for endpoint in 0..MAX_ENDPOINTS {
+                let Some((interface, event)) = self.device_mut(slot).and_then(|device| {
+                    let endpoint = device.endpoints[endpoint].as_mut()?;
+                    Some((endpoint.interface, endpoint.completion.take()?))
+                }) else {
+                    continue;
+                };
+                // the driver may wait for events itself, which must not pass
+                // further transfers to it meanwhile
+                let Some(mut function) = self
+                    .device_mut(slot)
+                    .and_then(|device| device.interfaces[interface].as_mut()?.function.take())
+                else {
+                    continue;
+                };
+                function.transfer(self, slot, endpoint, &event);
+                if let Some(interface) = self
+                    .device_mut(slot)
+                    .and_then(|device| device.interfaces[interface].as_mut())
+                {
+                    interface.function = Some(function);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,438 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::{hint, ptr};
+
+use drv_input::Queue;
+
+use crate::{
+    dev::{Device, Memory},
+    poll,
+    ring::{self, EventRing, Ring, Trb},
+    scsi::Sense,
+    storage::{Export, MAX_EXPORTS},
+    CapabilityParameters1, CapabilityRegisters, InterrupterRegisters, OperationalRegisters,
+    PortRegisters, RuntimeRegisters, StructuralParameters1, StructuralParameters2, USBCommand,
+    USBStatus,
+};
+
+/// Extended capability IDs
+const CAPABILITY_LEGACY_SUPPORT: u8 = 1;
+const CAPABILITY_SUPPORTED_PROTOCOL: u8 = 2;
+
+/// USB Legacy Support Capability: HC BIOS Owned Semaphore
+const USBLEGSUP_BIOS_OWNED: u32 = 1 << 16;
+/// USB Legacy Support Capability: HC OS Owned Semaphore
+const USBLEGSUP_OS_OWNED: u32 = 1 << 24;
+/// USB Legacy Support Control/Status: SMI enables
+const USBLEGCTLSTS_SMI_ENABLE: u32 = 1 << 0 | 1 << 4 | 1 << 13 | 1 << 14 | 1 << 15;
+/// USB Legacy Support Control/Status: SMI events, cleared by writing ones
+const USBLEGCTLSTS_SMI_EVENT: u32 = 1 << 29 | 1 << 30 | 1 << 31;
+
+/// Slots enabled, i.e. devices addressable at once.
+pub const MAX_DEVICES: usize = 32;
+
+/// Event Ring Dequeue Pointer: Event Handler Busy
+const ERDP_EHB: u32 = 1 << 3;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Error {
+    /// The xHC didn't respond in time.
+    Timeout,
+    /// Memory for the xHC is not allocatable, or not accessible by it.
+    Memory,
+    /// The xHC stopped, due to a host system error.
+    Halted,
+    /// A command or transfer completed with the code.
+    Completion(u8),
+    /// The slot has no device, it was detached.
+    NoDevice,
+    /// The device returned a malformed descriptor.
+    Descriptor,
+    /// The driver doesn't support the interface.
+    Unsupported,
+    /// The device failed the SCSI command, with the sense data.
+    Sense(Sense),
+    /// The device broke the bulk-only transport, and was reset.
+    Phase,
+}
+
+pub struct Controller {
+    capability: *mut CapabilityRegisters,
+    operational: *mut OperationalRegisters,
+    interrupter: *mut InterrupterRegisters,
+    doorbell: *mut u32,
+    pub ac64: bool,
+    /// Bytes of each context, 32 or 64.
+    pub context_size: usize,
+    pub ports: u8,
+    /// USB major revision of each port, from the Supported Protocol
+    /// capabilities.
+    revision: [u8; 256],
+    /// Device Context Base Address Array.
+    dcbaa: *mut u64,
+    commands: Ring,
+    events: EventRing,
+    /// Root hub ports with a status change event, handled outside of waits
+    /// for other events.
+    changed: [u64; 4],
+    /// USB device tree, by slot ID - 1.
+    pub devices: [Option<Device>; MAX_DEVICES],
+    /// Memory of each slot, kept for the next device using it, as DMA memory
+    /// is not freed.
+    pub memory: [Option<(*mut Memory, u64)>; MAX_DEVICES],
+    /// Events of the input devices, until they are passed on to the console.
+    pub input: Queue,
+    /// Bounce buffer of mass storage transfers, which are waited for, so
+    /// that all devices share it.
+    pub bounce: Option<(*mut u8, u64)>,
+    /// Disks of mass storage devices served to file system drivers.
+    pub exports: [Option<Export>; MAX_EXPORTS],
+}
+
+impl Controller {
+    /// Takes the xHC over from the BIOS, resets it, sets up the device context
+    /// array, scratchpad buffers, command and event ring, and runs it.
+    pub fn new(base: *mut u8, len: usize) -> Result<Self, Error> {
+        let capability = base as *mut CapabilityRegisters;
+        let caplength = read_volatile!(capability, caplength) as usize;
+        let rtsoff = read_volatile!(capability, rtsoff) as usize & !0x1F;
+        let dboff = read_volatile!(capability, dboff) as usize & !0x3;
+        let operational = unsafe { base.add(caplength) } as *mut OperationalRegisters;
+        let runtime = unsafe { base.add(rtsoff) } as *mut RuntimeRegisters;
+        let doorbell = unsafe { base.add(dboff) } as *mut u32;
+        let hcsparams1 = read_volatile!(capability, hcsparams1);
+        let hccparams1 = read_volatile!(capability, hccparams1);
+        let ac64 = hccparams1.contains(CapabilityParameters1::AC64);
+
+        handoff(base, len, hccparams1);
+        stop(operational)?;
+        write_volatile!(operational, usbcmd, USBCommand::HCRST);
+        if !poll(|| {
+            !read_volatile!(operational, usbcmd).contains(USBCommand::HCRST)
+                && !read_volatile!(operational, usbsts).contains(USBStatus::CNR)
+        }) {
+            return Err(Error::Timeout);
+        }
+
+        let slots =
+            ((hcsparams1 & StructuralParameters1::MAX_SLOTS).bits() as u8).min(MAX_DEVICES as u8);
+        let ports = ((hcsparams1 & StructuralParameters1::MAX_PORTS).bits() >> 24) as u8;
+        let mut controller = Self {
+            capability,
+            operational,
+            interrupter: unsafe { ptr::addr_of_mut!((*runtime).interrupter[0]) },
+            doorbell,
+            ac64,
+            context_size: if hccparams1.contains(CapabilityParameters1::CSZ) {
+                64
+            } else {
+                32
+            },
+            ports,
+            revision: [0; 256],
+            dcbaa: ptr::null_mut(),
+            commands: Ring::new(ac64)?,
+            events: EventRing::new(ac64)?,
+            changed: [0; 4],
+            devices: [const { None }; MAX_DEVICES],
+            memory: [None; MAX_DEVICES],
+            input: Queue::new(),
+            bounce: None,
+            exports: [const { None }; MAX_EXPORTS],
+        };
+        for (capability, offset) in extended_capabilities(base, len, hccparams1) {
+            if capability != CAPABILITY_SUPPORTED_PROTOCOL {
+                continue;
+            }
+            let major = (read(base, offset) >> 24) as u8;
+            let ports = read(base, offset + 8);
+            let first = (ports & 0xFF) as usize;
+            let count = ((ports >> 8) & 0xFF) as usize;
+            for revision in controller
+                .revision
+                .iter_mut()
+                .skip(first.max(1) - 1)
+                .take(count)
+            {
+                *revision = major;
+            }
+        }
+
+        write_volatile!(operational, config, slots as u32);
+        let (dcbaa, dcbaa_phys_addr) = dma_allocate::<u64>((slots as usize + 1) * 8, ac64)?;
+        controller.dcbaa = dcbaa;
+        controller.allocate_scratchpads()?;
+        write_volatile!(operational, dcbaap_lo, dcbaa_phys_addr as u32);
+        write_volatile!(operational, dcbaap_hi, (dcbaa_phys_addr >> 32) as u32);
+
+        // the ring cycle state starts at 1
+        let crcr = controller.commands.phys_addr | 1;
+        write_volatile!(operational, crcr_lo, crcr as u32);
+        write_volatile!(operational, crcr_hi, (crcr >> 32) as u32);
+
+        let interrupter = controller.interrupter;
+        let segment_table = controller.events.segment_table;
+        let dequeue = controller.events.dequeue_pointer();
+        write_volatile!(interrupter, erstsz, 1);
+        write_volatile!(interrupter, erdp_lo, dequeue as u32);
+        write_volatile!(interrupter, erdp_hi, (dequeue >> 32) as u32);
+        // writing the base address enables the event ring
+        write_volatile!(interrupter, erstba_lo, segment_table as u32);
+        write_volatile!(interrupter, erstba_hi, (segment_table >> 32) as u32);
+
+        write_volatile!(operational, usbcmd, USBCommand::RS);
+        if !poll(|| !read_volatile!(operational, usbsts).contains(USBStatus::HCH)) {
+            return Err(Error::Timeout);
+        }
+        let version = read_volatile!(capability, hciversion);
+        log::info!(
+            "xHCI {}.{}, {} ports, {} slots",
+            version >> 8,
+            version & 0xFF,
+            ports,
+            slots
+        );
+
+        controller.power_ports();
+        // checks the command and event ring
+        controller.command(Trb::new(ring::TYPE_NO_OP_COMMAND, 0, 0, 0))?;
+        for port in 1..=ports {
+            controller.port_changed(port);
+        }
+        Ok(controller)
+    }
+
+    /// Handles events as they arrive, the xHC is polled without interrupts.
+    /// Devices are attached and detached, and transfers passed to drivers
+    /// here, as they may wait for events themselves.
+    pub fn run(&mut self) -> ! {
+        loop {
+            while let Some(event) = self.next_event() {
+                self.handle(event);
+            }
+            for port in 1..=self.ports {
+                let (word, bit) = (port as usize / 64, 1 << (port % 64));
+                if self.changed[word] & bit != 0 {
+                    self.changed[word] &= !bit;
+                    self.port_changed(port);
+                }
+            }
+            self.complete();
+            self.serve();
+            self.report_input();
+            hint::spin_loop();
+        }
+    }
+
+    /// Passes the input events on to the console, they are dropped if this
+    /// driver isn't allowed to report them.
+    fn report_input(&mut self) {
+        while let Some(event) = self.input.pop() {
+            if let Err(error) = sys::input_push(event.encode()) {
+                log::warn!("Input events dropped: {:?}", error);
+                self.input.clear();
+            }
+        }
+    }
+
+    /// Issues the command, and waits for its completion event.
+    pub fn command(&mut self, trb: Trb) -> Result<Trb, Error> {
+        let phys_addr = self.commands.push(trb);
+        // doorbell 0 is the xHC's command doorbell
+        self.ring(0, 0);
+
+        let completion = self.wait(|event| {
+            event.trb_type() == ring::TYPE_COMMAND_COMPLETION_EVENT && event.parameter == phys_addr
+        })?;
+        match completion.completion_code() {
+            ring::COMPLETION_SUCCESS => Ok(completion),
+            code => Err(Error::Completion(code)),
+        }
+    }
+
+    /// Waits for the event, others are handled meanwhile.
+    pub fn wait(&mut self, mut matches: impl FnMut(&Trb) -> bool) -> Result<Trb, Error> {
+        let mut found = None;
+        let operational = self.operational;
+        poll(|| {
+            while let Some(event) = self.next_event() {
+                if matches(&event) {
+                    found = Some(event);
+                    break;
+                }
+                self.handle(event);
+            }
+            found.is_some() || read_volatile!(operational, usbsts).contains(USBStatus::HSE)
+        });
+        found.ok_or_else(|| {
+            if read_volatile!(operational, usbsts).contains(USBStatus::HSE) {
+                Error::Halted
+            } else {
+                Error::Timeout
+            }
+        })
+    }
+
+    /// Rings the slot's doorbell for the endpoint, i.e. device context index,
+    /// slot 0 being the xHC's command doorbell.
+    pub fn ring(&self, slot: u8, target: u8) {
+        unsafe {
+            self.doorbell
+                .add(slot as usize)
+                .write_volatile(target as u32)
+        };
+    }
+
+    /// Sets the slot's device context, 0 once the slot is disabled.
+    pub fn set_device_context(&mut self, slot: u8, phys_addr: u64) {
+        unsafe { self.dcbaa.add(slot as usize).write_volatile(phys_addr) };
+    }
+
+    /// Dequeues the next event, and tells the xHC it was consumed.
+    fn next_event(&mut self) -> Option<Trb> {
+        let event = self.events.pop()?;
+        let interrupter = self.interrupter;
+        let dequeue = self.events.dequeue_pointer();
+        write_volatile!(interrupter, erdp_lo, dequeue as u32 | ERDP_EHB);
+        write_volatile!(interrupter, erdp_hi, (dequeue >> 32) as u32);
+        Some(event)
+    }
+
+    fn handle(&mut self, event: Trb) {
+        match event.trb_type() {
+            ring::TYPE_PORT_STATUS_CHANGE_EVENT => {
+                let port = (event.parameter >> 24) as u8;
+                self.changed[port as usize / 64] |= 1 << (port % 64);
+            }
+            ring::TYPE_TRANSFER_EVENT => {
+                match self
+                    .device_mut(event.slot())
+                    .and_then(|device| device.endpoint_mut(event.endpoint()))
+                {
+                    Some(endpoint) => endpoint.completion = Some(event),
+                    None => log::debug!(
+                        "Slot {}: unexpected transfer event on endpoint {}, completion {}",
+                        event.slot(),
+                        event.endpoint(),
+                        event.completion_code()
+                    ),
+                }
+            }
+            trb_type => log::debug!("Unexpected event {}", trb_type),
+        }
+    }
+
+    /// Returns the USB major revision of the port, 2 or 3.
+    pub fn revision(&self, port: u8) -> u8 {
+        self.revision[port as usize - 1]
+    }
+
+    pub fn port(&self, port: u8) -> *mut PortRegisters {
+        unsafe { ptr::addr_of_mut!((*self.operational).port[port as usize - 1]) }
+    }
+
+    /// Allocates the buffers the xHC may use as it wishes, their array is the
+    /// first entry of the device context base address array.
+    fn allocate_scratchpads(&mut self) -> Result<(), Error> {
+        let hcsparams2 = read_volatile!(self.capability, hcsparams2);
+        let count = ((hcsparams2 & StructuralParameters2::MAX_SCRATCHPAD_HI).bits() >> 21) << 5
+            | (hcsparams2 & StructuralParameters2::MAX_SCRATCHPAD_LO).bits() >> 27;
+        if count == 0 {
+            return Ok(());
+        }
+        // bit n stands for pages of 2^(n + 12) bytes
+        let page_size = 1 << (read_volatile!(self.operational, pagesize).trailing_zeros() + 12);
+        let (array, array_phys_addr) = dma_allocate::<u64>(count as usize * 8, self.ac64)?;
+        for index in 0..count as usize {
+            let (_, phys_addr) = dma_allocate::<u8>(page_size, self.ac64)?;
+            unsafe { array.add(index).write_volatile(phys_addr) };
+        }
+        unsafe { self.dcbaa.write_volatile(array_phys_addr) };
+        log::info!("{} scratchpad buffers", count);
+        Ok(())
+    }
+}
+
+/// Requests ownership of the xHC from the BIOS, and disables its SMIs.
+fn handoff(base: *mut u8, len: usize, hccparams1: CapabilityParameters1) {
+    let Some((_, offset)) = extended_capabilities(base, len, hccparams1)
+        .find(|&(capability, _)| capability == CAPABILITY_LEGACY_SUPPORT)
+    else {
+        return;
+    };
+    write(base, offset, read(base, offset) | USBLEGSUP_OS_OWNED);
+    // the BIOS has to release it within 1 s
+    if !poll(|| read(base, offset) & USBLEGSUP_BIOS_OWNED == 0) {
+        log::warn!("BIOS did not release the xHC");
+        write(base, offset, read(base, offset) & !USBLEGSUP_BIOS_OWNED);
+    }
+    let control = read(base, offset + 4);
+    write(
+        base,
+        offset + 4,
+        control & !USBLEGCTLSTS_SMI_ENABLE | USBLEGCTLSTS_SMI_EVENT,
+    );
+}
+
+/// Stops the xHC, which has to be halted before it is reset.
+fn stop(operational: *mut OperationalRegisters) -> Result<(), Error> {
+    if !poll(|| !read_volatile!(operational, usbsts).contains(USBStatus::CNR)) {
+        return Err(Error::Timeout);
+    }
+    let usbcmd = read_volatile!(operational, usbcmd);
+    write_volatile!(operational, usbcmd, usbcmd - USBCommand::RS);
+    if !poll(|| read_volatile!(operational, usbsts).contains(USBStatus::HCH)) {
+        return Err(Error::Timeout);
+    }
+    Ok(())
+}
+
+/// Returns the ID and offset of each extended capability.
+fn extended_capabilities(
+    base: *mut u8,
+    len: usize,
+    hccparams1: CapabilityParameters1,
+) -> impl Iterator<Item = (u8, usize)> {
+    let mut offset = ((hccparams1 & CapabilityParameters1::XECP).bits() >> 16) as usize * 4;
+    core::iter::from_fn(move || {
+        if offset == 0 || offset + 4 > len {
+            return None;
+        }
+        let header = read(base, offset);
+        let capability = (header as u8, offset);
+        // the next pointer is relative, in dwords
+        let next = (header >> 8 & 0xFF) as usize * 4;
+        offset = if next == 0 { 0 } else { offset + next };
+        Some(capability)
+    })
+}
+
+fn read(base: *mut u8, offset: usize) -> u32 {
+    unsafe { (base.add(offset) as *const u32).read_volatile() }
+}
+
+fn write(base: *mut u8, offset: usize, value: u32) {
+    unsafe { (base.add(offset) as *mut u32).write_volatile(value) }
+}
+
+/// Allocates zeroed memory for the xHC, and returns its physical address.
+pub fn dma_allocate<T>(size: usize, ac64: bool) -> Result<(*mut T, u64), Error> {
+    let memory = sys::dma_allocate(size).map_err(|_| Error::Memory)?;
+    let phys_addr = sys::physical_address(memory).map_err(|_| Error::Memory)? as u64;
+    if !ac64 && phys_addr + size as u64 > u32::MAX as u64 {
+        return Err(Error::Memory);
+    }
+    Ok((memory as *mut T, phys_addr))
+}
@@ -0,0 +1,327 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use drv_input::{Buttons, Event, Modifiers, Queue};
+
+use crate::{
+    desc::{self, Setup},
+    dev::BUFFER_SIZE,
+    drv::Function,
+    hc::{Controller, Error},
+    ring::{self, Trb},
+    rpt::Pointer,
+};
+
+/// Class requests
+const REQUEST_SET_IDLE: u8 = 0x0A;
+const REQUEST_SET_PROTOCOL: u8 = 0x0B;
+const PROTOCOL_BOOT: u16 = 0;
+
+/// Class descriptor types
+const TYPE_REPORT: u8 = 0x22;
+
+/// Interface subclass and protocols of boot devices
+const SUBCLASS_BOOT: u8 = 1;
+const PROTOCOL_KEYBOARD: u8 = 1;
+const PROTOCOL_MOUSE: u8 = 2;
+
+/// Keyboard usages, those before the first key report errors
+const USAGE_ERROR_ROLL_OVER: u8 = 0x01;
+const USAGE_FIRST_KEY: u8 = 0x04;
+const USAGE_LEFT_CTRL: u8 = 0xE0;
+
+/// Bytes of a boot keyboard report, the modifiers, a reserved byte and up to
+/// 6 keys held.
+const KEYBOARD_REPORT_SIZE: usize = 8;
+/// Bytes of a report kept, the rest is ignored.
+const REPORT_SIZE: usize = 64;
+
+enum Kind {
+    /// Boot keyboard, with its last report.
+    Keyboard([u8; KEYBOARD_REPORT_SIZE]),
+    /// Boot mouse, buttons and relative motion.
+    Mouse,
+    /// Pointing device with the report protocol, e.g. a tablet.
+    Pointer(Pointer),
+}
+
+pub struct Hid {
+    kind: Kind,
+    /// Buttons held.
+    buttons: Buttons,
+}
+
+/// Switches boot keyboards and mice to the boot protocol, or parses the
+/// report descriptor of other pointing devices, and polls the interrupt IN
+/// endpoint.
+pub fn attach(controller: &mut Controller, slot: u8, interface: usize) -> Result<Function, Error> {
+    let device = controller.device(slot).ok_or(Error::NoDevice)?;
+    let descriptor = device.interfaces[interface]
+        .as_ref()
+        .ok_or(Error::NoDevice)?
+        .descriptor;
+    let endpoint = device
+        .endpoints
+        .iter()
+        .position(|endpoint| {
+            endpoint.as_ref().is_some_and(|endpoint| {
+                endpoint.interface == interface
+                    && endpoint.descriptor.is_in()
+                    && endpoint.descriptor.transfer_type() == desc::TRANSFER_INTERRUPT
+            })
+        })
+        .ok_or(Error::Descriptor)?;
+
+    let request_type = desc::REQUEST_TYPE_CLASS | desc::REQUEST_TYPE_INTERFACE;
+    let index = descriptor.number as u16;
+    let kind = match (descriptor.subclass, descriptor.protocol) {
+        (SUBCLASS_BOOT, protocol @ (PROTOCOL_KEYBOARD | PROTOCOL_MOUSE)) => {
+            controller.control(
+                slot,
+                Setup {
+                    request_type,
+                    request: REQUEST_SET_PROTOCOL,
+                    value: PROTOCOL_BOOT,
+                    index,
+                    length: 0,
+                },
+            )?;
+            if protocol == PROTOCOL_KEYBOARD {
+                Kind::Keyboard([0; KEYBOARD_REPORT_SIZE])
+            } else {
+                Kind::Mouse
+            }
+        }
+        _ => {
+            let length = controller.control(
+                slot,
+                Setup {
+                    request_type: desc::REQUEST_TYPE_IN | desc::REQUEST_TYPE_INTERFACE,
+                    request: desc::REQUEST_GET_DESCRIPTOR,
+                    value: (TYPE_REPORT as u16) << 8,
+                    index,
+                    length: BUFFER_SIZE as u16,
+                },
+            )?;
+            let device = controller.device(slot).ok_or(Error::NoDevice)?;
+            Kind::Pointer(Pointer::parse(&device.buffer()[..length]).ok_or(Error::Unsupported)?)
+        }
+    };
+    // reports only on changes, which mice may not support
+    if let Err(error) = controller.control(
+        slot,
+        Setup {
+            request_type,
+            request: REQUEST_SET_IDLE,
+            value: 0,
+            index,
+            length: 0,
+        },
+    ) {
+        log::debug!("Slot {}: idle rate not set: {:?}", slot, error);
+    }
+    controller.receive(slot, endpoint)?;
+    Ok(Function::Hid(Hid {
+        kind,
+        buttons: Buttons::empty(),
+    }))
+}
+
+impl Hid {
+    /// Translates the report into input events, and polls again.
+    pub fn transfer(
+        &mut self,
+        controller: &mut Controller,
+        slot: u8,
+        endpoint: usize,
+        event: &Trb,
+    ) {
+        match event.completion_code() {
+            ring::COMPLETION_SUCCESS | ring::COMPLETION_SHORT_PACKET => {
+                let Some(packet) = controller
+                    .device(slot)
+                    .and_then(|device| device.endpoints[endpoint].as_ref())
+                    .map(|endpoint| endpoint.packet(event))
+                else {
+                    return;
+                };
+                let mut report = [0; REPORT_SIZE];
+                let length = packet.len().min(REPORT_SIZE);
+                report[..length].copy_from_slice(&packet[..length]);
+                self.report(&report[..length], &mut controller.input);
+            }
+            code => {
+                log::warn!(
+                    "Slot {}: interrupt transfer failed with completion {}",
+                    slot,
+                    code
+                );
+                if let Err(error) = controller.clear_halt(slot, endpoint) {
+                    log::warn!("Slot {}: endpoint not recovered: {:?}", slot, error);
+                    return;
+                }
+            }
+        }
+        if let Err(error) = controller.receive(slot, endpoint) {
+            log::warn!("Slot {}: interrupt transfer not queued: {:?}", slot, error);
+        }
+    }
+
+    fn report(&mut self, report: &[u8], input: &mut Queue) {
+        match &mut self.kind {
+            Kind::Keyboard(previous) => keyboard(previous, report, input),
+            Kind::Mouse => {
+                let [buttons, x, y, ref rest @ ..] = *report else {
+                    return;
+                };
+                press(
+                    &mut self.buttons,
+                    Buttons::from_bits_truncate(buttons),
+                    input,
+                );
+                let wheel = rest.first().map_or(0, |&wheel| wheel as i8);
+                if x != 0 || y != 0 || wheel != 0 {
+                    push(
+                        input,
+                        Event::Motion {
+                            x: x as i8 as i16,
+                            y: y as i8 as i16,
+                            wheel,
+                        },
+                    );
+                }
+            }
+            Kind::Pointer(pointer) => {
+                if !pointer.matches(report) {
+                    return;
+                }
+                let buttons = pointer.buttons.iter().enumerate().fold(
+                    Buttons::empty(),
+                    |buttons, (index, field)| {
+                        if field.and_then(|field| field.read(report)).unwrap_or(0) != 0 {
+                            buttons | Buttons::from_bits_truncate(1 << index)
+                        } else {
+                            buttons
+                        }
+                    },
+                );
+                press(&mut self.buttons, buttons, input);
+                let (Some(x_field), Some(y_field)) = (pointer.x, pointer.y) else {
+                    return;
+                };
+                let (Some(x), Some(y)) = (x_field.read(report), y_field.read(report)) else {
+                    return;
+                };
+                let wheel = pointer
+                    .wheel
+                    .and_then(|field| field.read(report))
+                    .unwrap_or(0)
+                    .clamp(i8::MIN as i32, i8::MAX as i32) as i8;
+                if x_field.relative {
+                    if x != 0 || y != 0 || wheel != 0 {
+                        push(
+                            input,
+                            Event::Motion {
+                                x: x.clamp(i16::MIN as i32, i16::MAX as i32) as i16,
+                                y: y.clamp(i16::MIN as i32, i16::MAX as i32) as i16,
+                                wheel,
+                            },
+                        );
+                    }
+                } else {
+                    push(
+                        input,
+                        Event::Position {
+                            x: x_field.scale(x),
+                            y: y_field.scale(y),
+                        },
+                    );
+                    if wheel != 0 {
+                        push(input, Event::Motion { x: 0, y: 0, wheel });
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Compares the boot keyboard report to the last one, and reports the keys
+/// and modifiers which went down or up.
+fn keyboard(previous: &mut [u8; KEYBOARD_REPORT_SIZE], report: &[u8], input: &mut Queue) {
+    let Some(report) = report.get(..KEYBOARD_REPORT_SIZE) else {
+        return;
+    };
+    // too many keys held to tell which
+    if report[2..].contains(&USAGE_ERROR_ROLL_OVER) {
+        return;
+    }
+    let modifiers = Modifiers::from_bits_truncate(report[0]);
+    let changed = previous[0] ^ report[0];
+    for bit in 0..8 {
+        if changed & 1 << bit != 0 {
+            push(
+                input,
+                Event::Key {
+                    scancode: USAGE_LEFT_CTRL + bit,
+                    pressed: report[0] & 1 << bit != 0,
+                    modifiers,
+                },
+            );
+        }
+    }
+    for &key in &previous[2..] {
+        if key >= USAGE_FIRST_KEY && !report[2..].contains(&key) {
+            push(
+                input,
+                Event::Key {
+                    scancode: key,
+                    pressed: false,
+                    modifiers,
+                },
+            );
+        }
+    }
+    for &key in &report[2..] {
+        if key >= USAGE_FIRST_KEY && !previous[2..].contains(&key) {
+            push(
+                input,
+                Event::Key {
+                    scancode: key,
+                    pressed: true,
+                    modifiers,
+                },
+            );
+        }
+    }
+    previous.copy_from_slice(report);
+}
+
+/// Reports the buttons which went down or up.
+fn press(held: &mut Buttons, buttons: Buttons, input: &mut Queue) {
+    for button in (*held ^ buttons).iter() {
+        push(
+            input,
+            Event::Button {
+                button,
+                pressed: buttons.contains(button),
+            },
+        );
+    }
+    *held = buttons;
+}
+
+fn push(input: &mut Queue, event: Event) {
+    log::debug!("{:?}", event);
+    input.push(event);
+}
@@ -17,9 +17,80 @@
 
 use core::{hint, panic};
 
-use drv_pci::Device;
+use bitflags::bitflags;
+use drv_pci::{Device, Resource};
 
-fn main(_device: Device) {}
+use crate::hc::Controller;
+
+/// Reads a register of a memory-mapped structure.
+macro_rules! read_volatile {
+    ($base:expr, $field:ident) => {{
+        let base = $base;
+        unsafe { core::ptr::addr_of!((*base).$field).read_volatile() }
+    }};
+}
+
+/// Writes a register of a memory-mapped structure.
+macro_rules! write_volatile {
+    ($base:expr, $field:ident, $value:expr) => {{
+        let (base, value) = ($base, $value);
+        unsafe { core::ptr::addr_of_mut!((*base).$field).write_volatile(value) }
+    }};
+}
+
+mod ctx;
+mod desc;
+mod dev;
+mod drv;
+mod hc;
+mod hid;
+mod port;
+mod ring;
+mod rpt;
+mod scsi;
+mod storage;
+
+/// Memory-mapped registers of the xHC.
+const RESOURCE_BAR: usize = 0;
+
+/// Polls of a register until the xHC or device has to respond.
+const ATTEMPTS: usize = 10_000_000;
+
+drv_pci::main!(main);
+
+fn main(device: Device, _arguments: &str) {
+    sys::Logger::init(log::LevelFilter::Info);
+
+    let (phys_addr, len) = match &device.resource[RESOURCE_BAR] {
+        Resource::Mem32(bar) => (bar.start as usize, bar.len()),
+        Resource::Mem64(bar) => (bar.start as usize, (bar.end - bar.start) as usize),
+        _ => {
+            log::error!("BAR not assigned");
+            return;
+        }
+    };
+    let Ok(base) = sys::memory_map(phys_addr, len) else {
+        log::error!("BAR not mappable");
+        return;
+    };
+    let mut controller = match Controller::new(base, len) {
+        Ok(controller) => controller,
+        Err(error) => {
+            log::error!("xHC not initialized: {:?}", error);
+            return;
+        }
+    };
+    controller.run();
+}
+
+/// Polls until the condition holds, returns `false` if it never did.
+fn poll(mut condition: impl FnMut() -> bool) -> bool {
+    (0..ATTEMPTS).any(|_| {
+        let holds = condition();
+        hint::spin_loop();
+        holds
+    })
+}
 
 #[panic_handler]
 fn panic(_info: &panic::PanicInfo) -> ! {
@@ -27,3 +98,259 @@ fn panic(_info: &panic::PanicInfo) -> ! {
         hint::spin_loop();
     }
 }
+
+#[repr(C)]
+struct CapabilityRegisters {
+    /// Capability Register Length
+    caplength: u8,
+    /// Reserved
+    _rsvd: u8,
+    /// Interface Version Number
+    hciversion: u16,
+    /// Structural Parameters 1
+    hcsparams1: StructuralParameters1,
+    /// Structural Parameters 2
+    hcsparams2: StructuralParameters2,
+    /// Structural Parameters 3
+    hcsparams3: u32,
+    /// Capability Parameters 1
+    hccparams1: CapabilityParameters1,
+    /// Doorbell Offset
+    dboff: u32,
+    /// Runtime Register Space Offset
+    rtsoff: u32,
+    /// Capability Parameters 2
+    hccparams2: u32,
+}
+
+bitflags! {
+    #[derive(Clone, Copy)]
+    struct StructuralParameters1: u32 {
+        /// Number of Device Slots
+        const MAX_SLOTS = (1 << 8) - 1;
+        /// Number of Interrupters
+        const MAX_INTRS = ((1 << 11) - 1) << 8;
+        /// Number of Ports
+        const MAX_PORTS = ((1 << 8) - 1) << 24;
+    }
+
+    #[derive(Clone, Copy)]
+    struct StructuralParameters2: u32 {
+        /// Isochronous Scheduling Threshold
+        const IST = (1 << 4) - 1;
+        /// Event Ring Segment Table Max
+        const ERST_MAX = ((1 << 4) - 1) << 4;
+        /// Max Scratchpad Buffers Hi
+        const MAX_SCRATCHPAD_HI = ((1 << 5) - 1) << 21;
+        /// Scratchpad Restore
+        const SPR = 1 << 26;
+        /// Max Scratchpad Buffers Lo
+        const MAX_SCRATCHPAD_LO = ((1 << 5) - 1) << 27;
+    }
+
+    #[derive(Clone, Copy)]
+    struct CapabilityParameters1: u32 {
+        /// 64-bit Addressing Capability
+        const AC64 = 1 << 0;
+        /// BW Negotiation Capability
+        const BNC = 1 << 1;
+        /// Context Size
+        const CSZ = 1 << 2;
+        /// Port Power Control
+        const PPC = 1 << 3;
+        /// Port Indicators
+        const PIND = 1 << 4;
+        /// Light HC Reset Capability
+        const LHRC = 1 << 5;
+        /// Latency Tolerance Messaging Capability
+        const LTC = 1 << 6;
+        /// No Secondary SID Support
+        const NSS = 1 << 7;
+        /// Parse All Event Data
+        const PAE = 1 << 8;
+        /// Stopped - Short Packet Capability
+        const SPC = 1 << 9;
+        /// Stopped EDTLA Capability
+        const SEC = 1 << 10;
+        /// Contiguous Frame ID Capability
+        const CFC = 1 << 11;
+        /// Maximum Primary Stream Array Size
+        const MAX_PSA_SIZE = ((1 << 4) - 1) << 12;
+        /// xHCI Extended Capabilities Pointer
+        const XECP = ((1 << 16) - 1) << 16;
+    }
+}
+
+#[repr(C)]
+struct OperationalRegisters {
+    /// USB Command
+    usbcmd: USBCommand,
+    /// USB Status
+    usbsts: USBStatus,
+    /// Page Size
+    pagesize: u32,
+    /// Reserved
+    _rsvd_0: [u32; 2],
+    /// Device Notification Control
+    dnctrl: u32,
+    /// Command Ring Control
+    crcr_lo: u32,
+    /// Command Ring Control Upper 32-bits
+    crcr_hi: u32,
+    /// Reserved
+    _rsvd_1: [u32; 4],
+    /// Device Context Base Address Array Pointer
+    dcbaap_lo: u32,
+    /// Device Context Base Address Array Pointer Upper 32-bits
+    dcbaap_hi: u32,
+    /// Configure
+    config: u32,
+    /// Reserved
+    _rsvd_2: [u8; 964],
+    port: [PortRegisters; 255],
+}
+
+bitflags! {
+    #[derive(Clone, Copy)]
+    struct USBCommand: u32 {
+        /// Run/Stop
+        const RS = 1 << 0;
+        /// Host Controller Reset
+        const HCRST = 1 << 1;
+        /// Interrupter Enable
+        const INTE = 1 << 2;
+        /// Host System Error Enable
+        const HSEE = 1 << 3;
+        /// Light Host Controller Reset
+        const LHCRST = 1 << 7;
+        /// Controller Save State
+        const CSS = 1 << 8;
+        /// Controller Restore State
+        const CRS = 1 << 9;
+        /// Enable Wrap Event
+        const EWE = 1 << 10;
+        /// Enable U3 MFINDEX Stop
+        const EU3S = 1 << 11;
+        /// CEM Enable
+        const CME = 1 << 13;
+        /// Extended TBC Enable
+        const ETE = 1 << 14;
+        /// Extended TBC TRB Status Enable
+        const TSC_EN = 1 << 15;
+        /// VTIO Enable
+        const VTIOE = 1 << 16;
+    }
+
+    #[derive(Clone, Copy)]
+    struct USBStatus: u32 {
+        /// HCHalted
+        const HCH = 1 << 0;
+        /// Host System Error
+        const HSE = 1 << 2;
+        /// Event Interrupt
+        const EINT = 1 << 3;
+        /// Port Change Detect
+        const PCD = 1 << 4;
+        /// Save State Status
+        const SSS = 1 << 8;
+        /// Restore State Status
+        const RSS = 1 << 9;
+        /// Save/Restore Error
+        const SRE = 1 << 10;
+        /// Controller Not Ready
+        const CNR = 1 << 11;
+        /// Host Controller Error
+        const HCE = 1 << 12;
+    }
+}
+
+#[repr(C)]
+struct PortRegisters {
+    /// Port Status and Control
+    portsc: PortStatus,
+    /// Port Power Management Status and Control
+    portpmsc: u32,
+    /// Port Link Info
+    portli: u32,
+    /// Port Hardware LPM Control
+    porthlpmc: u32,
+}
+
+bitflags! {
+    #[derive(Clone, Copy)]
+    struct PortStatus: u32 {
+        /// Current Connect Status
+        const CCS = 1 << 0;
+        /// Port Enabled/Disabled
+        const PED = 1 << 1;
+        /// Over-current Active
+        const OCA = 1 << 3;
+        /// Port Reset
+        const PR = 1 << 4;
+        /// Port Link State
+        const PLS = ((1 << 4) - 1) << 5;
+        /// Port Power
+        const PP = 1 << 9;
+        /// Port Speed
+        const SPEED = ((1 << 4) - 1) << 10;
+        /// Port Indicator Control
+        const PIC = ((1 << 2) - 1) << 14;
+        /// Port Link State Write Strobe
+        const LWS = 1 << 16;
+        /// Connect Status Change
+        const CSC = 1 << 17;
+        /// Port Enabled/Disabled Change
+        const PEC = 1 << 18;
+        /// Warm Port Reset Change
+        const WRC = 1 << 19;
+        /// Over-current Change
+        const OCC = 1 << 20;
+        /// Port Reset Change
+        const PRC = 1 << 21;
+        /// Port Link State Change
+        const PLC = 1 << 22;
+        /// Port Config Error Change
+        const CEC = 1 << 23;
+        /// Cold Attach Status
+        const CAS = 1 << 24;
+        /// Wake on Connect Enable
+        const WCE = 1 << 25;
+        /// Wake on Disconnect Enable
+        const WDE = 1 << 26;
+        /// Wake on Over-current Enable
+        const WOE = 1 << 27;
+        /// Device Removable
+        const DR = 1 << 30;
+        /// Warm Port Reset
+        const WPR = 1 << 31;
+    }
+}
+
+#[repr(C)]
+struct RuntimeRegisters {
+    /// Microframe Index
+    mfindex: u32,
+    /// Reserved
+    _rsvd: [u32; 7],
+    interrupter: [InterrupterRegisters; 1024],
+}
+
+#[repr(C)]
+struct InterrupterRegisters {
+    /// Interrupter Management
+    iman: u32,
+    /// Interrupter Moderation
+    imod: u32,
+    /// Event Ring Segment Table Size
+    erstsz: u32,
+    /// Reserved
+    _rsvd: u32,
+    /// Event Ring Segment Table Base Address
+    erstba_lo: u32,
+    /// Event Ring Segment Table Base Address Upper 32-bits
+    erstba_hi: u32,
+    /// Event Ring Dequeue Pointer
+    erdp_lo: u32,
+    /// Event Ring Dequeue Pointer Upper 32-bits
+    erdp_hi: u32,
+}
@@ -0,0 +1,178 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{
+    hc::{Controller, Error},
+    poll, PortRegisters, PortStatus,
+};
+
+/// Bits written back unchanged, the others either act when written with ones,
+/// e.g. PED disables the port, or are changes cleared by writing ones.
+const PRESERVE: PortStatus = PortStatus::PP
+    .union(PortStatus::PIC)
+    .union(PortStatus::WCE)
+    .union(PortStatus::WDE)
+    .union(PortStatus::WOE);
+
+const CHANGES: PortStatus = PortStatus::CSC
+    .union(PortStatus::PEC)
+    .union(PortStatus::WRC)
+    .union(PortStatus::OCC)
+    .union(PortStatus::PRC)
+    .union(PortStatus::PLC)
+    .union(PortStatus::CEC);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Speed {
+    Full,
+    Low,
+    High,
+    Super,
+    SuperPlus,
+}
+
+impl Speed {
+    /// Maps the default protocol speed IDs.
+    fn from_id(id: u32) -> Option<Self> {
+        Some(match id {
+            1 => Self::Full,
+            2 => Self::Low,
+            3 => Self::High,
+            4 => Self::Super,
+            5 => Self::SuperPlus,
+            _ => return None,
+        })
+    }
+
+    /// Protocol speed ID, for the slot context.
+    pub fn id(self) -> u32 {
+        match self {
+            Self::Full => 1,
+            Self::Low => 2,
+            Self::High => 3,
+            Self::Super => 4,
+            Self::SuperPlus => 5,
+        }
+    }
+
+    /// Max packet size of the default control endpoint, until the device
+    /// descriptor tells it, which only full-speed devices choose.
+    pub fn max_packet_size0(self) -> u16 {
+        match self {
+            Self::Full | Self::Low => 8,
+            Self::High => 64,
+            Self::Super | Self::SuperPlus => 512,
+        }
+    }
+}
+
+impl Controller {
+    /// Powers the ports, which are off after reset if the xHC controls their
+    /// power.
+    pub fn power_ports(&mut self) {
+        for port in 1..=self.ports {
+            let registers = self.port(port);
+            if !read_volatile!(registers, portsc).contains(PortStatus::PP) {
+                write(registers, PortStatus::PP);
+                if !poll(|| read_volatile!(registers, portsc).contains(PortStatus::PP)) {
+                    log::warn!("Port {}: not powered", port);
+                }
+            }
+        }
+    }
+
+    /// Acknowledges the port's changes, enables the port once a device
+    /// connected, and attaches or detaches the device.
+    pub fn port_changed(&mut self, port: u8) {
+        if port == 0 || port > self.ports {
+            return;
+        }
+        let registers = self.port(port);
+        let portsc = read_volatile!(registers, portsc);
+        let changes = portsc & CHANGES;
+        write(registers, changes);
+
+        let connected = changes.contains(PortStatus::CSC);
+        if connected {
+            self.detach(0, port);
+        }
+        if !portsc.contains(PortStatus::CCS) {
+            if connected {
+                log::info!("Port {}: disconnected", port);
+            }
+            return;
+        }
+        // USB 3 ports enable on their own once the link trained, USB 2 ports
+        // on reset
+        let speed = if portsc.contains(PortStatus::PED) {
+            if !connected {
+                return;
+            }
+            speed(portsc)
+        } else {
+            match reset(registers, self.revision(port) == 3) {
+                Ok(speed) => speed,
+                Err(error) => {
+                    log::warn!("Port {}: reset failed: {:?}", port, error);
+                    return;
+                }
+            }
+        };
+        let Some(speed) = speed else {
+            log::warn!("Port {}: device connected, but not enabled", port);
+            return;
+        };
+        log::info!(
+            "Port {}: USB {} device connected, {:?} speed",
+            port,
+            self.revision(port),
+            speed
+        );
+        if let Err(error) = self.attach(0, port, speed) {
+            log::warn!("Port {}: device not enumerated: {:?}", port, error);
+        }
+    }
+}
+
+/// Resets the port, with a warm reset for USB 3 ports, whose link didn't
+/// train. Returns the speed once the port is enabled.
+fn reset(registers: *mut PortRegisters, warm: bool) -> Result<Option<Speed>, Error> {
+    let (reset, change) = if warm {
+        (PortStatus::WPR, PortStatus::WRC)
+    } else {
+        (PortStatus::PR, PortStatus::PRC)
+    };
+    write(registers, reset);
+    if !poll(|| read_volatile!(registers, portsc).contains(change)) {
+        return Err(Error::Timeout);
+    }
+    write(registers, PortStatus::PRC | PortStatus::WRC);
+
+    let portsc = read_volatile!(registers, portsc);
+    Ok(if portsc.contains(PortStatus::PED) {
+        speed(portsc)
+    } else {
+        None
+    })
+}
+
+fn speed(portsc: PortStatus) -> Option<Speed> {
+    Speed::from_id((portsc & PortStatus::SPEED).bits() >> 10)
+}
+
+/// Writes the bits, besides those preserved.
+fn write(registers: *mut PortRegisters, bits: PortStatus) {
+    let portsc = read_volatile!(registers, portsc);
+    write_volatile!(registers, portsc, portsc & PRESERVE | bits);
+}
@@ -0,0 +1,242 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::{
+    mem, ptr,
+    sync::atomic::{self, Ordering},
+};
+
+use crate::hc::{self, Error};
+
+/// TRBs per ring or segment, which fill a page.
+pub const TRB_COUNT: usize = 256;
+
+/// TRB types
+pub const TYPE_NORMAL: u32 = 1;
+pub const TYPE_SETUP_STAGE: u32 = 2;
+pub const TYPE_DATA_STAGE: u32 = 3;
+pub const TYPE_STATUS_STAGE: u32 = 4;
+pub const TYPE_LINK: u32 = 6;
+pub const TYPE_ENABLE_SLOT_COMMAND: u32 = 9;
+pub const TYPE_DISABLE_SLOT_COMMAND: u32 = 10;
+pub const TYPE_ADDRESS_DEVICE_COMMAND: u32 = 11;
+pub const TYPE_CONFIGURE_ENDPOINT_COMMAND: u32 = 12;
+pub const TYPE_EVALUATE_CONTEXT_COMMAND: u32 = 13;
+pub const TYPE_RESET_ENDPOINT_COMMAND: u32 = 14;
+pub const TYPE_SET_TR_DEQUEUE_POINTER_COMMAND: u32 = 16;
+pub const TYPE_NO_OP_COMMAND: u32 = 23;
+pub const TYPE_TRANSFER_EVENT: u32 = 32;
+pub const TYPE_COMMAND_COMPLETION_EVENT: u32 = 33;
+pub const TYPE_PORT_STATUS_CHANGE_EVENT: u32 = 34;
+
+/// Completion codes
+pub const COMPLETION_SUCCESS: u8 = 1;
+pub const COMPLETION_STALL_ERROR: u8 = 6;
+pub const COMPLETION_SHORT_PACKET: u8 = 13;
+
+/// Interrupt On Short Packet
+pub const CONTROL_ISP: u32 = 1 << 2;
+/// Interrupt On Completion
+pub const CONTROL_IOC: u32 = 1 << 5;
+/// Immediate Data, the parameter holds the data instead of its address
+pub const CONTROL_IDT: u32 = 1 << 6;
+/// Data and Status Stage: direction is IN
+pub const CONTROL_DIR_IN: u32 = 1 << 16;
+/// Setup Stage: Transfer Type
+pub const CONTROL_TRT_OUT: u32 = 2 << 16;
+pub const CONTROL_TRT_IN: u32 = 3 << 16;
+pub const CONTROL_ENDPOINT_SHIFT: u32 = 16;
+pub const CONTROL_SLOT_SHIFT: u32 = 24;
+
+/// Cycle bit, marks the TRBs owned by the consumer
+const CONTROL_CYCLE: u32 = 1 << 0;
+/// Link TRB: toggle the cycle bit
+const CONTROL_TOGGLE_CYCLE: u32 = 1 << 1;
+const CONTROL_TYPE_SHIFT: u32 = 10;
+const CONTROL_TYPE: u32 = ((1 << 6) - 1) << CONTROL_TYPE_SHIFT;
+
+/// Transfer Request Block.
+#[repr(C, align(16))]
+#[derive(Clone, Copy)]
+pub struct Trb {
+    pub parameter: u64,
+    pub status: u32,
+    pub control: u32,
+}
+
+impl Trb {
+    pub fn new(trb_type: u32, parameter: u64, status: u32, control: u32) -> Self {
+        Self {
+            parameter,
+            status,
+            control: control | trb_type << CONTROL_TYPE_SHIFT,
+        }
+    }
+
+    pub fn trb_type(&self) -> u32 {
+        (self.control & CONTROL_TYPE) >> CONTROL_TYPE_SHIFT
+    }
+
+    /// Completion code of an event.
+    pub fn completion_code(&self) -> u8 {
+        (self.status >> 24) as u8
+    }
+
+    /// Slot ID of a command completion or transfer event.
+    pub fn slot(&self) -> u8 {
+        (self.control >> CONTROL_SLOT_SHIFT) as u8
+    }
+
+    /// Endpoint ID, i.e. device context index, of a transfer event.
+    pub fn endpoint(&self) -> u8 {
+        (self.control >> CONTROL_ENDPOINT_SHIFT & 0x1F) as u8
+    }
+}
+
+/// Ring of TRBs produced by software, i.e. the command ring or a transfer
+/// ring. The last TRB links back to the first one.
+pub struct Ring {
+    trbs: *mut Trb,
+    pub phys_addr: u64,
+    enqueue: usize,
+    /// Producer cycle state
+    cycle: bool,
+}
+
+impl Ring {
+    pub fn new(ac64: bool) -> Result<Self, Error> {
+        let (trbs, phys_addr) = hc::dma_allocate::<Trb>(TRB_COUNT * mem::size_of::<Trb>(), ac64)?;
+        Ok(Self::at(trbs, phys_addr))
+    }
+
+    /// Sets up an empty ring in the memory, which has to hold `TRB_COUNT` TRBs.
+    pub fn at(trbs: *mut Trb, phys_addr: u64) -> Self {
+        unsafe {
+            ptr::write_bytes(trbs, 0, TRB_COUNT);
+            trbs.add(TRB_COUNT - 1).write_volatile(Trb::new(
+                TYPE_LINK,
+                phys_addr,
+                0,
+                CONTROL_TOGGLE_CYCLE,
+            ));
+        }
+        Self {
+            trbs,
+            phys_addr,
+            enqueue: 0,
+            cycle: true,
+        }
+    }
+
+    /// Returns the enqueue pointer with the cycle state, as a dequeue pointer
+    /// for the xHC, which continues with the next TRB enqueued.
+    pub fn dequeue_pointer(&self) -> u64 {
+        self.phys_addr
+            + (self.enqueue * mem::size_of::<Trb>()) as u64
+            + if self.cycle { CONTROL_CYCLE as u64 } else { 0 }
+    }
+
+    /// Enqueues the TRB, and returns its physical address, which its
+    /// completion event refers to. The consumer has to keep up, a full ring is
+    /// not detected.
+    pub fn push(&mut self, trb: Trb) -> u64 {
+        let phys_addr = self.phys_addr + (self.enqueue * mem::size_of::<Trb>()) as u64;
+        self.write(self.enqueue, trb);
+        self.enqueue += 1;
+        // hand the link TRB over, and continue at the start
+        if self.enqueue == TRB_COUNT - 1 {
+            let link = unsafe { self.trbs.add(self.enqueue).read_volatile() };
+            self.write(self.enqueue, link);
+            self.enqueue = 0;
+            self.cycle = !self.cycle;
+        }
+        phys_addr
+    }
+
+    /// Writes the TRB with the producer cycle state, the cycle bit is written
+    /// last, as it passes the TRB to the consumer.
+    fn write(&mut self, index: usize, trb: Trb) {
+        let control = trb.control & !CONTROL_CYCLE | if self.cycle { CONTROL_CYCLE } else { 0 };
+        unsafe {
+            let slot = self.trbs.add(index);
+            ptr::addr_of_mut!((*slot).parameter).write_volatile(trb.parameter);
+            ptr::addr_of_mut!((*slot).status).write_volatile(trb.status);
+            atomic::fence(Ordering::SeqCst);
+            ptr::addr_of_mut!((*slot).control).write_volatile(control);
+        }
+    }
+}
+
+/// Entry of the Event Ring Segment Table.
+#[repr(C)]
+pub struct SegmentTableEntry {
+    base: u64,
+    size: u32,
+    _rsvd: u32,
+}
+
+/// Ring of TRBs produced by the xHC, with a single segment.
+pub struct EventRing {
+    trbs: *const Trb,
+    phys_addr: u64,
+    pub segment_table: u64,
+    dequeue: usize,
+    /// Consumer cycle state
+    cycle: bool,
+}
+
+impl EventRing {
+    pub fn new(ac64: bool) -> Result<Self, Error> {
+        let (trbs, phys_addr) = hc::dma_allocate::<Trb>(TRB_COUNT * mem::size_of::<Trb>(), ac64)?;
+        let (entry, segment_table) =
+            hc::dma_allocate::<SegmentTableEntry>(mem::size_of::<SegmentTableEntry>(), ac64)?;
+        unsafe {
+            entry.write_volatile(SegmentTableEntry {
+                base: phys_addr,
+                size: TRB_COUNT as u32,
+                _rsvd: 0,
+            });
+        }
+        Ok(Self {
+            trbs,
+            phys_addr,
+            segment_table,
+            dequeue: 0,
+            cycle: true,
+        })
+    }
+
+    /// Dequeues the next event, if the xHC produced one.
+    pub fn pop(&mut self) -> Option<Trb> {
+        let slot = unsafe { self.trbs.add(self.dequeue) };
+        let control = unsafe { ptr::addr_of!((*slot).control).read_volatile() };
+        if (control & CONTROL_CYCLE != 0) != self.cycle {
+            return None;
+        }
+        // the rest of the TRB is valid once the cycle bit is
+        atomic::fence(Ordering::SeqCst);
+        let trb = unsafe { slot.read_volatile() };
+        self.dequeue += 1;
+        if self.dequeue == TRB_COUNT {
+            self.dequeue = 0;
+            self.cycle = !self.cycle;
+        }
+        Some(trb)
+    }
+
+    /// Returns the address the xHC may produce events up to.
+    pub fn dequeue_pointer(&self) -> u64 {
+        self.phys_addr + (self.dequeue * mem::size_of::<Trb>()) as u64
+    }
+}
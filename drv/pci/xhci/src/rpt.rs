@@ -0,0 +1,253 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Item types
+const TYPE_MAIN: u8 = 0;
+const TYPE_GLOBAL: u8 = 1;
+const TYPE_LOCAL: u8 = 2;
+
+/// Main item tags
+const TAG_INPUT: u8 = 0x8;
+/// Global item tags
+const TAG_USAGE_PAGE: u8 = 0x0;
+const TAG_LOGICAL_MINIMUM: u8 = 0x1;
+const TAG_LOGICAL_MAXIMUM: u8 = 0x2;
+const TAG_REPORT_SIZE: u8 = 0x7;
+const TAG_REPORT_ID: u8 = 0x8;
+const TAG_REPORT_COUNT: u8 = 0x9;
+/// Local item tags
+const TAG_USAGE: u8 = 0x0;
+const TAG_USAGE_MINIMUM: u8 = 0x1;
+const TAG_USAGE_MAXIMUM: u8 = 0x2;
+
+/// Prefix of long items, which are skipped
+const LONG_ITEM: u8 = 0xFE;
+
+/// Input item: Constant, Variable and Relative
+const INPUT_CONSTANT: u32 = 1 << 0;
+const INPUT_VARIABLE: u32 = 1 << 1;
+const INPUT_RELATIVE: u32 = 1 << 2;
+
+/// Usages, with their page in the upper 16 bits
+const USAGE_X: u32 = 0x0001_0030;
+const USAGE_Y: u32 = 0x0001_0031;
+const USAGE_WHEEL: u32 = 0x0001_0038;
+const PAGE_BUTTON: u32 = 0x09;
+
+/// Usages of a main item kept, further ones repeat the last.
+const MAX_USAGES: usize = 16;
+/// Fields of a main item looked at.
+const MAX_FIELDS: usize = 256;
+pub const MAX_BUTTONS: usize = 5;
+
+/// Value in a report.
+#[derive(Clone, Copy)]
+pub struct Field {
+    /// In bits
+    offset: u32,
+    size: u8,
+    minimum: i32,
+    maximum: i32,
+    pub relative: bool,
+}
+
+impl Field {
+    /// Returns the value, sign-extended if the logical minimum is negative.
+    pub fn read(&self, report: &[u8]) -> Option<i32> {
+        let (offset, size) = (self.offset as usize, self.size as usize);
+        if size == 0 || size > 32 || offset + size > report.len() * 8 {
+            return None;
+        }
+        let mut value = 0u32;
+        for bit in 0..size {
+            let position = offset + bit;
+            value |= ((report[position / 8] >> (position % 8) & 1) as u32) << bit;
+        }
+        let shift = 32 - size;
+        Some(if self.minimum < 0 {
+            ((value << shift) as i32) >> shift
+        } else {
+            value as i32
+        })
+    }
+
+    /// Scales the absolute value from the logical range to the whole range.
+    pub fn scale(&self, value: i32) -> u16 {
+        let range = self.maximum as i64 - self.minimum as i64;
+        if range <= 0 {
+            return 0;
+        }
+        let value = (value as i64 - self.minimum as i64).clamp(0, range);
+        (value * u16::MAX as i64 / range) as u16
+    }
+}
+
+/// Fields of a pointing device's report.
+pub struct Pointer {
+    /// Report ID of the first report with them, if the device numbers them.
+    report: Option<Option<u8>>,
+    pub buttons: [Option<Field>; MAX_BUTTONS],
+    pub x: Option<Field>,
+    pub y: Option<Field>,
+    pub wheel: Option<Field>,
+}
+
+impl Pointer {
+    /// Parses the report descriptor, and keeps the first X and Y axis, wheel
+    /// and buttons.
+    pub fn parse(descriptor: &[u8]) -> Option<Self> {
+        let mut pointer = Self {
+            report: None,
+            buttons: [None; MAX_BUTTONS],
+            x: None,
+            y: None,
+            wheel: None,
+        };
+        // global items
+        let mut page = 0;
+        let mut minimum = 0;
+        let mut maximum = (0, 0);
+        let mut report_size = 0;
+        let mut report_count = 0;
+        let mut report_id = None;
+        // local items
+        let mut usages = [0u32; MAX_USAGES];
+        let mut usages_len = 0;
+        let mut usage_minimum: Option<u32> = None;
+        let mut usage_maximum = u32::MAX;
+        // bits of each report so far
+        let mut offsets = [0usize; 256];
+
+        let mut items = descriptor;
+        while let Some(&prefix) = items.first() {
+            if prefix == LONG_ITEM {
+                let size = *items.get(1)? as usize;
+                items = items.get(3 + size..)?;
+                continue;
+            }
+            let size = match prefix & 0x3 {
+                3 => 4,
+                size => size as usize,
+            };
+            let data = items.get(1..1 + size)?;
+            items = &items[1 + size..];
+            let unsigned = data
+                .iter()
+                .rev()
+                .fold(0, |value, &byte| value << 8 | byte as u32);
+            let signed = match size {
+                0 => 0,
+                size => ((unsigned << (32 - size * 8)) as i32) >> (32 - size * 8),
+            };
+            // usages of 4 bytes include their page
+            let usage = if size == 4 {
+                unsigned
+            } else {
+                page << 16 | unsigned
+            };
+            match (prefix >> 2 & 0x3, prefix >> 4) {
+                (TYPE_MAIN, TAG_INPUT) => {
+                    let offset = &mut offsets[report_id.unwrap_or(0) as usize];
+                    if unsigned & (INPUT_CONSTANT | INPUT_VARIABLE) == INPUT_VARIABLE {
+                        // a logical maximum below the minimum is unsigned
+                        let maximum = if maximum.0 < minimum {
+                            maximum.1 as i32
+                        } else {
+                            maximum.0
+                        };
+                        for index in 0..report_count.min(MAX_FIELDS) {
+                            let usage = if usages_len != 0 {
+                                usages[index.min(usages_len - 1)]
+                            } else if let Some(usage_minimum) = usage_minimum {
+                                (usage_minimum + index as u32).min(usage_maximum)
+                            } else {
+                                continue;
+                            };
+                            // the report ID comes first
+                            let bit = offset
+                                .saturating_add(index.saturating_mul(report_size))
+                                .saturating_add(if report_id.is_some() { 8 } else { 0 });
+                            let (Ok(bit), Ok(size)) =
+                                (u32::try_from(bit), u8::try_from(report_size))
+                            else {
+                                continue;
+                            };
+                            let field = Field {
+                                offset: bit,
+                                size,
+                                minimum,
+                                maximum,
+                                relative: unsigned & INPUT_RELATIVE != 0,
+                            };
+                            pointer.add(report_id, usage, field);
+                        }
+                    }
+                    *offset = offset.saturating_add(report_count.saturating_mul(report_size));
+                    usages_len = 0;
+                    usage_minimum = None;
+                    usage_maximum = u32::MAX;
+                }
+                (TYPE_MAIN, _) => {
+                    usages_len = 0;
+                    usage_minimum = None;
+                    usage_maximum = u32::MAX;
+                }
+                (TYPE_GLOBAL, TAG_USAGE_PAGE) => page = unsigned & 0xFFFF,
+                (TYPE_GLOBAL, TAG_LOGICAL_MINIMUM) => minimum = signed,
+                (TYPE_GLOBAL, TAG_LOGICAL_MAXIMUM) => maximum = (signed, unsigned),
+                (TYPE_GLOBAL, TAG_REPORT_SIZE) => report_size = unsigned as usize,
+                (TYPE_GLOBAL, TAG_REPORT_ID) => report_id = Some(unsigned as u8),
+                (TYPE_GLOBAL, TAG_REPORT_COUNT) => report_count = unsigned as usize,
+                (TYPE_LOCAL, TAG_USAGE) => {
+                    if usages_len < MAX_USAGES {
+                        usages[usages_len] = usage;
+                        usages_len += 1;
+                    }
+                }
+                (TYPE_LOCAL, TAG_USAGE_MINIMUM) => usage_minimum = Some(usage),
+                (TYPE_LOCAL, TAG_USAGE_MAXIMUM) => usage_maximum = usage,
+                _ => {}
+            }
+        }
+        (pointer.x.is_some() && pointer.y.is_some()).then_some(pointer)
+    }
+
+    /// Returns if the report is the one with the fields.
+    pub fn matches(&self, report: &[u8]) -> bool {
+        match self.report {
+            Some(Some(report_id)) => report.first() == Some(&report_id),
+            _ => true,
+        }
+    }
+
+    fn add(&mut self, report_id: Option<u8>, usage: u32, field: Field) {
+        let button = (usage >> 16 == PAGE_BUTTON)
+            .then_some((usage & 0xFFFF) as usize)
+            .filter(|button| (1..=MAX_BUTTONS).contains(button));
+        if button.is_none() && !matches!(usage, USAGE_X | USAGE_Y | USAGE_WHEEL) {
+            return;
+        }
+        if self.report.is_some_and(|report| report != report_id) {
+            return;
+        }
+        self.report = Some(report_id);
+        let target = match (button, usage) {
+            (Some(button), _) => &mut self.buttons[button - 1],
+            (_, USAGE_X) => &mut self.x,
+            (_, USAGE_Y) => &mut self.y,
+            _ => &mut self.wheel,
+        };
+        target.get_or_insert(field);
+    }
+}
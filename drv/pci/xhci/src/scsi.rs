@@ -0,0 +1,175 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2A;
+const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+const READ_16: u8 = 0x88;
+const WRITE_16: u8 = 0x8A;
+const SERVICE_ACTION_IN_16: u8 = 0x9E;
+const SERVICE_ACTION_READ_CAPACITY_16: u8 = 0x10;
+
+/// Bytes of the standard INQUIRY data up to the product revision level.
+pub const INQUIRY_LENGTH: usize = 36;
+pub const READ_CAPACITY_10_LENGTH: usize = 8;
+pub const READ_CAPACITY_16_LENGTH: usize = 32;
+/// Bytes of fixed format sense data, up to the additional sense code
+/// qualifier.
+pub const SENSE_LENGTH: usize = 18;
+
+/// Peripheral device type of disks
+pub const TYPE_DIRECT_ACCESS: u8 = 0;
+
+/// Sense keys
+pub const SENSE_NOT_READY: u8 = 0x2;
+pub const SENSE_ILLEGAL_REQUEST: u8 = 0x5;
+pub const SENSE_UNIT_ATTENTION: u8 = 0x6;
+pub const SENSE_DATA_PROTECT: u8 = 0x7;
+
+/// Additional sense codes
+const ASC_NOT_READY: u8 = 0x04;
+const ASC_WRITE_PROTECTED: u8 = 0x27;
+const ASC_MEDIUM_NOT_PRESENT: u8 = 0x3A;
+/// Additional sense code qualifier of [`ASC_NOT_READY`]
+const ASCQ_BECOMING_READY: u8 = 0x01;
+
+/// Capacity of the medium, returned by READ CAPACITY.
+#[derive(Clone, Copy)]
+pub struct Capacity {
+    pub blocks: u64,
+    pub block_size: u32,
+}
+
+impl Capacity {
+    /// Parses READ CAPACITY (10) data, the last block is `u32::MAX` if it
+    /// doesn't fit.
+    pub fn parse_10(data: &[u8]) -> Option<Self> {
+        let data = data.get(..READ_CAPACITY_10_LENGTH)?;
+        Some(Self {
+            blocks: u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as u64 + 1,
+            block_size: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+        })
+    }
+
+    pub fn parse_16(data: &[u8]) -> Option<Self> {
+        let data = data.get(..12)?;
+        let mut last = [0; 8];
+        last.copy_from_slice(&data[..8]);
+        Some(Self {
+            blocks: u64::from_be_bytes(last).checked_add(1)?,
+            block_size: u32::from_be_bytes([data[8], data[9], data[10], data[11]]),
+        })
+    }
+}
+
+/// Fixed format sense data, returned by REQUEST SENSE.
+#[derive(Clone, Copy, Debug)]
+pub struct Sense {
+    pub key: u8,
+    /// Additional sense code and qualifier
+    pub asc: u8,
+    pub ascq: u8,
+}
+
+impl Sense {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        // current or deferred errors in fixed format
+        if data.len() < 14 || !matches!(data[0] & 0x7F, 0x70 | 0x71) {
+            return None;
+        }
+        Some(Self {
+            key: data[2] & 0xF,
+            asc: data[12],
+            ascq: data[13],
+        })
+    }
+
+    /// Whether the command may succeed if issued again, e.g. after a reset
+    /// or medium change was reported, or while the unit spins up.
+    pub fn retry(&self) -> bool {
+        match self.key {
+            SENSE_UNIT_ATTENTION => true,
+            SENSE_NOT_READY => self.asc == ASC_NOT_READY && self.ascq == ASCQ_BECOMING_READY,
+            _ => false,
+        }
+    }
+
+    pub fn no_medium(&self) -> bool {
+        self.key == SENSE_NOT_READY && self.asc == ASC_MEDIUM_NOT_PRESENT
+    }
+
+    pub fn write_protected(&self) -> bool {
+        self.key == SENSE_DATA_PROTECT && self.asc == ASC_WRITE_PROTECTED
+    }
+}
+
+pub fn test_unit_ready() -> [u8; 6] {
+    [TEST_UNIT_READY, 0, 0, 0, 0, 0]
+}
+
+pub fn request_sense() -> [u8; 6] {
+    [REQUEST_SENSE, 0, 0, 0, SENSE_LENGTH as u8, 0]
+}
+
+pub fn inquiry() -> [u8; 6] {
+    [INQUIRY, 0, 0, 0, INQUIRY_LENGTH as u8, 0]
+}
+
+pub fn read_capacity_10() -> [u8; 10] {
+    [READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+}
+
+pub fn read_capacity_16() -> [u8; 16] {
+    let mut cdb = [0; 16];
+    cdb[0] = SERVICE_ACTION_IN_16;
+    cdb[1] = SERVICE_ACTION_READ_CAPACITY_16;
+    cdb[13] = READ_CAPACITY_16_LENGTH as u8;
+    cdb
+}
+
+/// Returns READ (10), or WRITE (10).
+pub fn transfer_10(block: u32, count: u16, write: bool) -> [u8; 10] {
+    let block = block.to_be_bytes();
+    let count = count.to_be_bytes();
+    [
+        if write { WRITE_10 } else { READ_10 },
+        0,
+        block[0],
+        block[1],
+        block[2],
+        block[3],
+        0,
+        count[0],
+        count[1],
+        0,
+    ]
+}
+
+/// Returns READ (16), or WRITE (16), for blocks beyond 32 bits.
+pub fn transfer_16(block: u64, count: u32, write: bool) -> [u8; 16] {
+    let mut cdb = [0; 16];
+    cdb[0] = if write { WRITE_16 } else { READ_16 };
+    cdb[2..10].copy_from_slice(&block.to_be_bytes());
+    cdb[10..14].copy_from_slice(&count.to_be_bytes());
+    cdb
+}
+
+/// Synchronizes the whole cache.
+pub fn synchronize_cache_10() -> [u8; 10] {
+    [SYNCHRONIZE_CACHE_10, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+}
@@ -0,0 +1,734 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::{ops::Range, ptr, str};
+
+use drv_fs::{
+    blk::{self, BlockDevice},
+    ipc::{self, Server},
+    queue::{Queue, Scheduler},
+};
+
+use crate::{
+    desc::{self, Setup},
+    dev::{MAX_INTERFACES, PACKET_SIZE},
+    drv::Function,
+    hc::{self, Controller, Error},
+    ring,
+    scsi::{self, Capacity, Sense},
+};
+
+/// Class requests of the bulk-only transport
+const REQUEST_GET_MAX_LUN: u8 = 0xFE;
+const REQUEST_RESET: u8 = 0xFF;
+
+/// Command Block Wrapper
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CBW_LENGTH: usize = 31;
+const CBW_FLAGS_IN: u8 = 1 << 7;
+const CBW_MAX_COMMAND_LENGTH: usize = 16;
+
+/// Command Status Wrapper
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CSW_LENGTH: usize = 13;
+
+/// CSW status
+const STATUS_PASSED: u8 = 0;
+const STATUS_FAILED: u8 = 1;
+
+/// LUNs probed, GET MAX LUN returns up to 15 but card readers have fewer.
+pub const MAX_LUNS: usize = 8;
+
+/// Bytes transferred per command, the bounce buffer is aligned to its size
+/// so that it doesn't cross a 64 KiB boundary.
+const BOUNCE_SIZE: usize = 64 << 10;
+
+/// Attempts of TEST UNIT READY, after power on and medium changes the
+/// device reports UNIT ATTENTION first.
+const READY_ATTEMPTS: usize = 3;
+
+/// LUNs served to file system drivers at once.
+pub const MAX_EXPORTS: usize = 16;
+
+/// Boot module spawned for each LUN with a disk, to mount it.
+const FILE_SYSTEM_MODULE: &str = "drv_fs_fat";
+
+/// Data phase of a command, in the bounce buffer.
+#[derive(Clone, Copy)]
+enum Data {
+    None,
+    In(usize),
+    Out(usize),
+}
+
+/// Mass storage interface speaking SCSI over the bulk-only transport.
+pub struct Storage {
+    slot: u8,
+    /// Interface number, for class requests.
+    interface: u8,
+    /// Index of the bulk endpoints.
+    bulk_in: usize,
+    bulk_out: usize,
+    tag: u32,
+    bounce: *mut u8,
+    bounce_phys_addr: u64,
+    /// Capacity of the LUNs with a disk inserted.
+    pub luns: [Option<Capacity>; MAX_LUNS],
+}
+
+/// Finds the bulk endpoints of the interface, and probes its LUNs.
+pub fn attach(controller: &mut Controller, slot: u8, interface: usize) -> Result<Function, Error> {
+    let device = controller.device(slot).ok_or(Error::NoDevice)?;
+    let number = device.interfaces[interface]
+        .as_ref()
+        .ok_or(Error::NoDevice)?
+        .descriptor
+        .number;
+    let bulk = |is_in: bool| {
+        device
+            .endpoints
+            .iter()
+            .position(|endpoint| {
+                endpoint.as_ref().is_some_and(|endpoint| {
+                    endpoint.interface == interface
+                        && endpoint.descriptor.is_in() == is_in
+                        && endpoint.descriptor.transfer_type() == desc::TRANSFER_BULK
+                })
+            })
+            .ok_or(Error::Descriptor)
+    };
+    let (bulk_in, bulk_out) = (bulk(true)?, bulk(false)?);
+    let (bounce, bounce_phys_addr) = bounce(controller)?;
+    let mut storage = Storage {
+        slot,
+        interface: number,
+        bulk_in,
+        bulk_out,
+        tag: 0,
+        bounce,
+        bounce_phys_addr,
+        luns: [None; MAX_LUNS],
+    };
+
+    // devices with a single LUN may stall the request
+    let max_lun = match controller.control(
+        slot,
+        Setup {
+            request_type: desc::REQUEST_TYPE_IN
+                | desc::REQUEST_TYPE_CLASS
+                | desc::REQUEST_TYPE_INTERFACE,
+            request: REQUEST_GET_MAX_LUN,
+            value: 0,
+            index: number as u16,
+            length: 1,
+        },
+    ) {
+        Ok(1) => controller
+            .device(slot)
+            .map_or(0, |device| device.buffer()[0]),
+        _ => 0,
+    }
+    .min(MAX_LUNS as u8 - 1);
+
+    for lun in 0..=max_lun {
+        match storage.probe(controller, lun) {
+            Ok(Some(capacity)) => {
+                storage.luns[lun as usize] = Some(capacity);
+                storage.disk(controller, lun).probe();
+            }
+            Ok(None) => {}
+            Err(error) => log::warn!("Slot {}: LUN {} not probed: {:?}", slot, lun, error),
+        }
+    }
+    Ok(Function::Storage(storage))
+}
+
+/// Returns the bounce buffer shared by all devices, allocated on first use.
+fn bounce(controller: &mut Controller) -> Result<(*mut u8, u64), Error> {
+    if let Some(bounce) = controller.bounce {
+        return Ok(bounce);
+    }
+    // twice the size, so that an aligned buffer fits in
+    let (memory, phys_addr) = hc::dma_allocate::<u8>(BOUNCE_SIZE * 2, controller.ac64)?;
+    let offset = phys_addr.next_multiple_of(BOUNCE_SIZE as u64) - phys_addr;
+    let bounce = (unsafe { memory.add(offset as usize) }, phys_addr + offset);
+    controller.bounce = Some(bounce);
+    Ok(bounce)
+}
+
+/// Returns the packet buffer of the endpoint.
+fn packet_buffer(
+    controller: &mut Controller,
+    slot: u8,
+    endpoint: usize,
+) -> Result<(&mut [u8; PACKET_SIZE], u64), Error> {
+    Ok(controller
+        .device_mut(slot)
+        .and_then(|device| device.endpoints.get_mut(endpoint)?.as_mut())
+        .ok_or(Error::NoDevice)?
+        .packet_buffer())
+}
+
+impl Storage {
+    /// Lends the LUN as block device.
+    pub fn disk<'a>(&'a mut self, controller: &'a mut Controller, lun: u8) -> Disk<'a> {
+        Disk {
+            controller,
+            storage: self,
+            lun,
+        }
+    }
+
+    /// Inquires the LUN, and reads its capacity once it's ready. Returns
+    /// `None` for devices other than disks, and without a medium.
+    fn probe(&mut self, controller: &mut Controller, lun: u8) -> Result<Option<Capacity>, Error> {
+        let length = self.command(
+            controller,
+            lun,
+            &scsi::inquiry(),
+            Data::In(scsi::INQUIRY_LENGTH),
+        )?;
+        let data = self.data(length);
+        let text = |range: Range<usize>| {
+            data.get(range)
+                .and_then(|bytes| str::from_utf8(bytes).ok())
+                .map_or("?", str::trim)
+        };
+        let removable = data.get(1).is_some_and(|flags| flags & 1 << 7 != 0);
+        log::info!(
+            "Slot {}: LUN {}: {} {} {}{}",
+            self.slot,
+            lun,
+            text(8..16),
+            text(16..32),
+            text(32..36),
+            if removable { ", removable" } else { "" }
+        );
+        if data.first().map(|byte| byte & 0x1F) != Some(scsi::TYPE_DIRECT_ACCESS) {
+            return Ok(None);
+        }
+
+        let mut attempts = 0;
+        loop {
+            match self.command(controller, lun, &scsi::test_unit_ready(), Data::None) {
+                Ok(_) => break,
+                Err(Error::Sense(sense)) if sense.retry() && attempts < READY_ATTEMPTS => {
+                    attempts += 1
+                }
+                Err(Error::Sense(sense)) if sense.key == scsi::SENSE_NOT_READY => {
+                    log::info!(
+                        "Slot {}: LUN {}: {}",
+                        self.slot,
+                        lun,
+                        if sense.no_medium() {
+                            "no medium"
+                        } else {
+                            "not ready"
+                        }
+                    );
+                    return Ok(None);
+                }
+                Err(error) => return Err(error),
+            }
+        }
+
+        let capacity = self.read_capacity(controller, lun)?;
+        log::info!(
+            "Slot {}: LUN {}: {} blocks of {} bytes, {} MiB",
+            self.slot,
+            lun,
+            capacity.blocks,
+            capacity.block_size,
+            (capacity.blocks * capacity.block_size as u64) >> 20
+        );
+        Ok(Some(capacity))
+    }
+
+    /// Reads the capacity, with READ CAPACITY(16) if it doesn't fit into
+    /// 32 bits.
+    fn read_capacity(&mut self, controller: &mut Controller, lun: u8) -> Result<Capacity, Error> {
+        let length = self.command(
+            controller,
+            lun,
+            &scsi::read_capacity_10(),
+            Data::In(scsi::READ_CAPACITY_10_LENGTH),
+        )?;
+        let capacity = Capacity::parse_10(self.data(length)).ok_or(Error::Descriptor)?;
+        if capacity.blocks <= u32::MAX as u64 {
+            return Ok(capacity);
+        }
+        let length = self.command(
+            controller,
+            lun,
+            &scsi::read_capacity_16(),
+            Data::In(scsi::READ_CAPACITY_16_LENGTH),
+        )?;
+        Capacity::parse_16(self.data(length)).ok_or(Error::Descriptor)
+    }
+
+    /// Returns the data received in the bounce buffer.
+    fn data(&self, length: usize) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.bounce, length.min(BOUNCE_SIZE)) }
+    }
+
+    /// Issues the command, and returns the bytes transferred in the data
+    /// phase. Failed commands return the sense data of the device.
+    fn command(
+        &mut self,
+        controller: &mut Controller,
+        lun: u8,
+        command: &[u8],
+        data: Data,
+    ) -> Result<usize, Error> {
+        let (status, length) = self.transport(controller, lun, command, data)?;
+        if status == STATUS_PASSED {
+            return Ok(length);
+        }
+        let (status, length) = self.transport(
+            controller,
+            lun,
+            &scsi::request_sense(),
+            Data::In(scsi::SENSE_LENGTH),
+        )?;
+        // NO SENSE, if the device can't tell why
+        let sense = (status == STATUS_PASSED)
+            .then(|| Sense::parse(self.data(length)))
+            .flatten()
+            .unwrap_or(Sense {
+                key: 0,
+                asc: 0,
+                ascq: 0,
+            });
+        Err(Error::Sense(sense))
+    }
+
+    /// Sends the CBW, transfers the data, and receives the CSW. Returns its
+    /// status, and the bytes transferred. On transport errors the device is
+    /// reset.
+    fn transport(
+        &mut self,
+        controller: &mut Controller,
+        lun: u8,
+        command: &[u8],
+        data: Data,
+    ) -> Result<(u8, usize), Error> {
+        let (length, flags, endpoint) = match data {
+            Data::None => (0, 0, self.bulk_out),
+            Data::In(length) => (length.min(BOUNCE_SIZE), CBW_FLAGS_IN, self.bulk_in),
+            Data::Out(length) => (length.min(BOUNCE_SIZE), 0, self.bulk_out),
+        };
+        let command = &command[..command.len().min(CBW_MAX_COMMAND_LENGTH)];
+        self.tag = self.tag.wrapping_add(1);
+
+        let (cbw, phys_addr) = packet_buffer(controller, self.slot, self.bulk_out)?;
+        cbw[..CBW_LENGTH].fill(0);
+        cbw[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        cbw[4..8].copy_from_slice(&self.tag.to_le_bytes());
+        cbw[8..12].copy_from_slice(&(length as u32).to_le_bytes());
+        cbw[12] = flags;
+        cbw[13] = lun;
+        cbw[14] = command.len() as u8;
+        cbw[15..15 + command.len()].copy_from_slice(command);
+        if let Err(error) = controller.bulk(self.slot, self.bulk_out, phys_addr, CBW_LENGTH) {
+            self.reset(controller);
+            return Err(error);
+        }
+
+        let mut transferred = 0;
+        if length != 0 {
+            match controller.bulk(self.slot, endpoint, self.bounce_phys_addr, length) {
+                Ok(length) => transferred = length,
+                // the device refuses further data, the CSW tells why
+                Err(Error::Completion(ring::COMPLETION_STALL_ERROR)) => {
+                    controller.clear_halt(self.slot, endpoint)?
+                }
+                Err(error) => {
+                    self.reset(controller);
+                    return Err(error);
+                }
+            }
+        }
+
+        let csw = match self.status(controller) {
+            Err(Error::Completion(ring::COMPLETION_STALL_ERROR)) => {
+                controller.clear_halt(self.slot, self.bulk_in)?;
+                self.status(controller)
+            }
+            csw => csw,
+        };
+        let csw = match csw {
+            Ok(csw) => csw,
+            Err(error) => {
+                self.reset(controller);
+                return Err(error);
+            }
+        };
+        // phase errors, and invalid CSWs require reset recovery
+        let valid = csw[0..4] == CSW_SIGNATURE.to_le_bytes()
+            && csw[4..8] == self.tag.to_le_bytes()
+            && csw[12] <= STATUS_FAILED;
+        if !valid {
+            self.reset(controller);
+            return Err(Error::Phase);
+        }
+        Ok((csw[12], transferred))
+    }
+
+    /// Receives the CSW.
+    fn status(&mut self, controller: &mut Controller) -> Result<[u8; CSW_LENGTH], Error> {
+        let (_, phys_addr) = packet_buffer(controller, self.slot, self.bulk_in)?;
+        if controller.bulk(self.slot, self.bulk_in, phys_addr, CSW_LENGTH)? != CSW_LENGTH {
+            return Err(Error::Phase);
+        }
+        let (packet, _) = packet_buffer(controller, self.slot, self.bulk_in)?;
+        let mut csw = [0; CSW_LENGTH];
+        csw.copy_from_slice(&packet[..CSW_LENGTH]);
+        Ok(csw)
+    }
+
+    /// Reset recovery: resets the transport of the interface, and clears
+    /// both bulk endpoints.
+    fn reset(&mut self, controller: &mut Controller) {
+        log::warn!("Slot {}: resetting the bulk-only transport", self.slot);
+        let result = controller
+            .control(
+                self.slot,
+                Setup {
+                    request_type: desc::REQUEST_TYPE_CLASS | desc::REQUEST_TYPE_INTERFACE,
+                    request: REQUEST_RESET,
+                    value: 0,
+                    index: self.interface as u16,
+                    length: 0,
+                },
+            )
+            .and_then(|_| controller.clear_halt(self.slot, self.bulk_in))
+            .and_then(|_| controller.clear_halt(self.slot, self.bulk_out));
+        if let Err(error) = result {
+            log::warn!("Slot {}: reset recovery failed: {:?}", self.slot, error);
+        }
+    }
+}
+
+/// LUN of a mass storage device, as block device.
+pub struct Disk<'a> {
+    controller: &'a mut Controller,
+    storage: &'a mut Storage,
+    lun: u8,
+}
+
+impl Disk<'_> {
+    fn capacity(&self) -> Capacity {
+        self.storage.luns[self.lun as usize].unwrap_or(Capacity {
+            blocks: 0,
+            block_size: 0,
+        })
+    }
+
+    /// Reads block 0, like a filesystem mounting the disk would.
+    fn probe(&mut self) {
+        let mut block = [0; 4096];
+        let Some(block) = block.get_mut(..self.block_size()) else {
+            return;
+        };
+        if self.read(0, block).is_ok() {
+            if block.len() >= 512 && block[510] == 0x55 && block[511] == 0xAA {
+                log::info!(
+                    "Slot {}: LUN {}: partition table found",
+                    self.storage.slot,
+                    self.lun
+                );
+            } else {
+                log::info!(
+                    "Slot {}: LUN {}: no partition table",
+                    self.storage.slot,
+                    self.lun
+                );
+            }
+        }
+    }
+
+    /// Transfers the blocks in commands of at most the bounce buffer's size.
+    fn transfer(
+        &mut self,
+        block: u64,
+        buffer: *mut u8,
+        length: usize,
+        write: bool,
+    ) -> Result<(), blk::Error> {
+        let capacity = self.capacity();
+        let block_size = capacity.block_size as usize;
+        let count = length / block_size.max(1);
+        if block_size == 0
+            || count * block_size != length
+            || block
+                .checked_add(count as u64)
+                .is_none_or(|end| end > capacity.blocks)
+        {
+            return Err(blk::Error::InvalidRequest);
+        }
+        let per_command = (BOUNCE_SIZE / block_size).min(u16::MAX as usize);
+        let mut done = 0;
+        while done < count {
+            let chunk = (count - done).min(per_command);
+            let start = block + done as u64;
+            let bytes = chunk * block_size;
+            let data = unsafe { buffer.add(done * block_size) };
+            // the 16-byte commands only for blocks beyond 32 bits, as not all
+            // devices support them
+            let (command_10, command_16);
+            let command: &[u8] = if start + chunk as u64 <= 1 << 32 {
+                command_10 = scsi::transfer_10(start as u32, chunk as u16, write);
+                &command_10
+            } else {
+                command_16 = scsi::transfer_16(start, chunk as u32, write);
+                &command_16
+            };
+            if write {
+                unsafe { ptr::copy_nonoverlapping(data, self.storage.bounce, bytes) };
+            }
+            let phase = if write {
+                Data::Out(bytes)
+            } else {
+                Data::In(bytes)
+            };
+            let mut attempts = 0;
+            let result = loop {
+                match self
+                    .storage
+                    .command(self.controller, self.lun, command, phase)
+                {
+                    Err(Error::Sense(sense)) if sense.retry() && attempts < READY_ATTEMPTS => {
+                        attempts += 1
+                    }
+                    result => break result,
+                }
+            };
+            match result {
+                Ok(transferred) if transferred == bytes => {}
+                Ok(_) => {
+                    return Err(self.failure(if write { "write" } else { "read" }, Error::Phase))
+                }
+                Err(error) => return Err(self.failure(if write { "write" } else { "read" }, error)),
+            }
+            if !write {
+                unsafe { ptr::copy_nonoverlapping(self.storage.bounce, data, bytes) };
+            }
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Logs the failure, and maps it to a block device error.
+    fn failure(&self, name: &str, error: Error) -> blk::Error {
+        log::warn!(
+            "Slot {}: LUN {}: {} failed: {:?}",
+            self.storage.slot,
+            self.lun,
+            name,
+            error
+        );
+        match error {
+            Error::Timeout => blk::Error::Timeout,
+            Error::Sense(sense) if sense.write_protected() => blk::Error::ReadOnly,
+            // still not ready after retrying, the file system may try again later
+            Error::Sense(sense) if sense.retry() => blk::Error::Busy,
+            Error::Sense(sense) if sense.key == scsi::SENSE_DATA_PROTECT => blk::Error::ReadOnly,
+            _ => blk::Error::Device,
+        }
+    }
+}
+
+impl BlockDevice for Disk<'_> {
+    fn block_size(&self) -> usize {
+        self.capacity().block_size as usize
+    }
+
+    fn block_count(&self) -> u64 {
+        self.capacity().blocks
+    }
+
+    fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<(), blk::Error> {
+        self.transfer(block, buffer.as_mut_ptr(), buffer.len(), false)
+    }
+
+    fn write(&mut self, block: u64, buffer: &[u8]) -> Result<(), blk::Error> {
+        self.transfer(block, buffer.as_ptr() as *mut u8, buffer.len(), true)
+    }
+
+    fn flush(&mut self) -> Result<(), blk::Error> {
+        match self.storage.command(
+            self.controller,
+            self.lun,
+            &scsi::synchronize_cache_10(),
+            Data::None,
+        ) {
+            Ok(_) => Ok(()),
+            // devices without a write cache
+            Err(Error::Sense(sense)) if sense.key == scsi::SENSE_ILLEGAL_REQUEST => Ok(()),
+            Err(error) => Err(self.failure("flush", error)),
+        }
+    }
+}
+
+/// LUN served to the file system driver spawned for it.
+pub struct Export {
+    slot: u8,
+    interface: usize,
+    lun: u8,
+    endpoint: sys::Endpoint,
+    task: sys::Task,
+    server: Server,
+}
+
+impl Controller {
+    /// Serves the disks of the device's mass storage interfaces, each to a
+    /// file system driver spawned for it, which finds the endpoint as its
+    /// first capability.
+    pub fn export(&mut self, slot: u8) {
+        for interface in 0..MAX_INTERFACES {
+            let Some(Function::Storage(storage)) = self
+                .device(slot)
+                .and_then(|device| device.interfaces[interface].as_ref()?.function.as_ref())
+            else {
+                continue;
+            };
+            let luns = storage.luns;
+            for (lun, _) in luns
+                .iter()
+                .enumerate()
+                .filter(|(_, capacity)| capacity.is_some())
+            {
+                let Some(export) = self.exports.iter_mut().find(|export| export.is_none()) else {
+                    log::warn!("Slot {}: LUN {} not served, too many", slot, lun);
+                    return;
+                };
+                match spawn() {
+                    Ok((endpoint, task)) => {
+                        *export = Some(Export {
+                            slot,
+                            interface,
+                            lun: lun as u8,
+                            endpoint,
+                            task,
+                            server: Server::new(),
+                        })
+                    }
+                    Err(error) => log::warn!(
+                        "Slot {}: LUN {}: {} not spawned: {:?}",
+                        slot,
+                        lun,
+                        FILE_SYSTEM_MODULE,
+                        error
+                    ),
+                }
+            }
+        }
+    }
+
+    /// Stops serving the disks of the device, and kills their file system
+    /// drivers.
+    pub fn unexport(&mut self, slot: u8) {
+        for export in &mut self.exports {
+            let Some(Export { endpoint, task, .. }) = export.take_if(|export| export.slot == slot)
+            else {
+                continue;
+            };
+            if let Err(error) = sys::kill(task).and_then(|_| sys::close(endpoint)) {
+                log::warn!(
+                    "Slot {}: {} not killed: {:?}",
+                    slot,
+                    FILE_SYSTEM_MODULE,
+                    error
+                );
+            }
+        }
+    }
+
+    /// Handles the requests of the file system drivers waiting, through a
+    /// queue in front of the LUN. Requests to a LUN which has gone fail.
+    pub fn serve(&mut self) {
+        for index in 0..MAX_EXPORTS {
+            let Some(mut export) = self.exports[index].take() else {
+                continue;
+            };
+            let (server, endpoint) = (&mut export.server, export.endpoint);
+            let result = self
+                .with_disk(export.slot, export.interface, export.lun, |disk| {
+                    server.serve(&mut Queue::new(disk, Scheduler::Fifo), endpoint)
+                })
+                .unwrap_or_else(|| refuse(endpoint));
+            if let Err(error) = result {
+                log::warn!("Slot {}: not served: {:?}", export.slot, error);
+            }
+            self.exports[index] = Some(export);
+        }
+    }
+
+    /// Lends the LUN of the mass storage interface as block device.
+    pub fn with_disk<R>(
+        &mut self,
+        slot: u8,
+        interface: usize,
+        lun: u8,
+        f: impl FnOnce(&mut Disk) -> R,
+    ) -> Option<R> {
+        let mut function = self.device_mut(slot).and_then(|device| {
+            device
+                .interfaces
+                .get_mut(interface)?
+                .as_mut()?
+                .function
+                .take()
+        })?;
+        let result = match &mut function {
+            Function::Storage(storage)
+                if storage.luns.get(lun as usize).is_some_and(Option::is_some) =>
+            {
+                Some(f(&mut storage.disk(self, lun)))
+            }
+            _ => None,
+        };
+        if let Some(interface) = self
+            .device_mut(slot)
+            .and_then(|device| device.interfaces[interface].as_mut())
+        {
+            interface.function = Some(function);
+        }
+        result
+    }
+}
+
+/// Fails the requests waiting on the endpoint.
+fn refuse(endpoint: sys::Endpoint) -> Result<(), sys::Error> {
+    while let Some((_, reply)) = sys::try_receive(endpoint)? {
+        sys::reply(reply, &ipc::failure(blk::Error::Device))?;
+    }
+    Ok(())
+}
+
+/// Spawns a file system driver, which may call the endpoint created for it.
+fn spawn() -> Result<(sys::Endpoint, sys::Task), sys::Error> {
+    let endpoint = sys::endpoint_create()?;
+    match sys::spawn(
+        FILE_SYSTEM_MODULE,
+        &[],
+        &[sys::Grant::endpoint(endpoint), sys::Grant::dma()],
+    ) {
+        Ok(task) => Ok((endpoint, task)),
+        Err(error) => {
+            let _ = sys::close(endpoint);
+            Err(error)
+        }
+    }
+}
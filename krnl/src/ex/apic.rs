@@ -0,0 +1,94 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::{arch, hint};
+
+use spin::Once;
+
+use super::int;
+use crate::mm;
+
+const MSR_APIC_BASE: u32 = 0x1B;
+
+const REGISTER_ID: usize = 0x020;
+const REGISTER_EOI: usize = 0x0B0;
+const REGISTER_SPURIOUS_INTERRUPT_VECTOR: usize = 0x0F0;
+const REGISTER_INTERRUPT_COMMAND_LOW: usize = 0x300;
+const REGISTER_INTERRUPT_COMMAND_HIGH: usize = 0x310;
+const REGISTER_LVT_TIMER: usize = 0x320;
+const REGISTER_TIMER_INITIAL_COUNT: usize = 0x380;
+const REGISTER_TIMER_DIVIDE_CONFIGURATION: usize = 0x3E0;
+
+/// Ticks of the timer between interrupts, at the bus frequency divided by 16.
+/// Not calibrated, it only has to preempt tasks every now and then.
+const TIMER_COUNT: u32 = 0x10000;
+
+/// The local APIC is at the same physical address on every processor, and
+/// therefore only mapped once.
+static BASE: Once<usize> = Once::new();
+
+/// Enables the local APIC of the current processor and its periodic timer,
+/// and returns its ID.
+pub fn init() -> u32 {
+    BASE.call_once(|| {
+        let (base_0_31, base_32_63): (u32, u32);
+        unsafe {
+            arch::asm!(
+                "rdmsr",
+                in("ecx") MSR_APIC_BASE,
+                out("eax") base_0_31,
+                out("edx") base_32_63,
+                options(nomem, nostack, preserves_flags)
+            )
+        };
+        let base = (base_32_63 as u64) << 32 | (base_0_31 & !0xFFF) as u64;
+        mm::VIRT_MEM
+            .map_mmio(base as usize, 0x400)
+            .expect("local APIC not mappable") as usize
+    });
+
+    write(
+        REGISTER_SPURIOUS_INTERRUPT_VECTOR,
+        1 << 8 | int::VECTOR_SPURIOUS as u32,
+    );
+    write(REGISTER_TIMER_DIVIDE_CONFIGURATION, 0b0011);
+    write(REGISTER_LVT_TIMER, 1 << 17 | int::VECTOR_TIMER as u32);
+    write(REGISTER_TIMER_INITIAL_COUNT, TIMER_COUNT);
+    read(REGISTER_ID) >> 24
+}
+
+/// Signals the end of the interrupt currently being handled.
+pub fn eoi() {
+    write(REGISTER_EOI, 0);
+}
+
+/// Sends a fixed inter-processor interrupt to the processor with the given
+/// local APIC ID.
+pub fn ipi(apic_id: u32, vector: u8) {
+    write(REGISTER_INTERRUPT_COMMAND_HIGH, apic_id << 24);
+    // fixed delivery, physical destination, assert
+    write(REGISTER_INTERRUPT_COMMAND_LOW, 1 << 14 | vector as u32);
+    // delivery status
+    while read(REGISTER_INTERRUPT_COMMAND_LOW) & 1 << 12 != 0 {
+        hint::spin_loop();
+    }
+}
+
+fn read(register: usize) -> u32 {
+    unsafe { ((BASE.get().unwrap() + register) as *const u32).read_volatile() }
+}
+
+fn write(register: usize, value: u32) {
+    unsafe { ((BASE.get().unwrap() + register) as *mut u32).write_volatile(value) }
+}
@@ -12,19 +12,22 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use core::{arch, hint, ptr, slice};
+use core::{arch, hint, ops::Range, ptr, slice};
 
 use alloc::boxed::Box;
 
 pub struct Context {
-    _stack: Box<[u8]>,
+    stack: Box<[u8]>,
     stack_ptr: *mut u8,
 }
 
+// SAFETY: the stack is owned, and can be resumed on any processor
+unsafe impl Send for Context {}
+
 impl Context {
     pub unsafe fn empty() -> Self {
         Self {
-            _stack: Box::new([]),
+            stack: Box::new([]),
             stack_ptr: ptr::null_mut(),
         }
     }
@@ -42,7 +45,8 @@ impl Context {
 
         // SAFETY: stack is valid, large enough to encompass all element
         let stack_ptr = unsafe {
-            let mut stack_ptr = stack.as_mut_ptr() as *mut usize;
+            // the stack grows down from its end
+            let mut stack_ptr = stack.as_mut_ptr().add(stack.len()) as *mut usize;
             stack_ptr = stack_ptr.sub(1); // eip/rip
             stack_ptr.write(entry_point as usize);
             #[cfg(target_arch = "x86")]
@@ -56,10 +60,12 @@ impl Context {
             stack_ptr as *mut u8
         };
 
-        Self {
-            _stack: stack,
-            stack_ptr,
-        }
+        Self { stack, stack_ptr }
+    }
+
+    pub fn stack(&self) -> Range<usize> {
+        let stack = self.stack.as_ptr_range();
+        stack.start as usize..stack.end as usize
     }
 
     /// The context is swapped by using the stack pointer specified by `self`.
@@ -12,10 +12,30 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use core::{arch, cell, mem};
+use core::{arch, cell, mem, ops::Range};
 
-static DESCRIPTOR_TABLE: cell::SyncUnsafeCell<[Descriptor; 32 + 16]> =
-    cell::SyncUnsafeCell::new([Descriptor::zeroed(); 32 + 16]);
+use pio::Port;
+use spin::Mutex;
+
+use super::{apic, sys, Scheduler, Waiter};
+use crate::mm;
+
+/// The masked legacy PIC, its lines only raise spurious or stray IRQs.
+const VECTORS_PIC: Range<u8> = 0x30..0x40;
+/// Handed out to devices, e.g. as the target of MSI.
+pub const VECTORS_DEVICE: Range<u8> = 0x40..0x50;
+/// Local APIC timer, which preempts tasks running in user mode.
+pub const VECTOR_TIMER: u8 = 0xFD;
+/// Wakes an idle processor, so that it picks up newly enqueued runnables.
+pub const VECTOR_WAKE: u8 = 0xFE;
+/// Local APIC spurious interrupt, the lower 4 bits have to be set.
+pub const VECTOR_SPURIOUS: u8 = 0xFF;
+
+static DEVICE_IRQS: [Mutex<DeviceIrq>; (VECTORS_DEVICE.end - VECTORS_DEVICE.start) as usize] =
+    [const { Mutex::new(DeviceIrq::new()) }; (VECTORS_DEVICE.end - VECTORS_DEVICE.start) as usize];
+
+static DESCRIPTOR_TABLE: cell::SyncUnsafeCell<[Descriptor; 256]> =
+    cell::SyncUnsafeCell::new([Descriptor::zeroed(); 256]);
 
 #[repr(C, packed(2))]
 struct DescriptorTableRegister {
@@ -64,6 +84,22 @@ impl Descriptor {
     }
 }
 
+/// Pushed by the processor on interrupts, followed by the stack on x86-64 or
+/// when coming from user mode.
+#[repr(C)]
+struct InterruptStackFrame {
+    ip: usize,
+    cs: usize,
+    flags: usize,
+}
+
+impl InterruptStackFrame {
+    /// Whether the interrupt arrived while running in user mode.
+    fn user(&self) -> bool {
+        self.cs & 3 == 3
+    }
+}
+
 #[repr(u8)]
 enum DescriptorGateType {
     Interrupt = 0xE,
@@ -72,6 +108,10 @@ enum DescriptorGateType {
 
 pub fn init() {
     init_ivt();
+    init_irq();
+    init_irq_pic();
+    init_irq_device();
+    init_pic();
 
     let idtr = DescriptorTableRegister {
         size: (mem::size_of_val(&DESCRIPTOR_TABLE) - 1) as u16,
@@ -84,50 +124,307 @@ pub fn init() {
     };
 }
 
+fn init_irq() {
+    (unsafe { &mut *DESCRIPTOR_TABLE.get() })[VECTOR_SPURIOUS as usize] = Descriptor::new(
+        irq_spurious as usize,
+        1 << 3,
+        DescriptorGateType::Interrupt,
+        0,
+        0,
+    );
+    (unsafe { &mut *DESCRIPTOR_TABLE.get() })[VECTOR_TIMER as usize] = Descriptor::new(
+        irq_timer as usize,
+        1 << 3,
+        DescriptorGateType::Interrupt,
+        0,
+        0,
+    );
+    (unsafe { &mut *DESCRIPTOR_TABLE.get() })[VECTOR_WAKE as usize] = Descriptor::new(
+        irq_wake as usize,
+        1 << 3,
+        DescriptorGateType::Interrupt,
+        0,
+        0,
+    );
+    (unsafe { &mut *DESCRIPTOR_TABLE.get() })[::sys::VECTOR as usize] = Descriptor::new(
+        sys::entry as usize,
+        1 << 3,
+        DescriptorGateType::Interrupt,
+        0,
+        3,
+    );
+}
+
+/// Remaps the legacy PIC to vectors of its own and masks all of its lines, so
+/// that enabling interrupts doesn't deliver IRQs as exceptions.
+fn init_pic() {
+    let (master_command, master_data): (Port<u8>, Port<u8>) =
+        unsafe { (Port::new(0x20), Port::new(0x21)) };
+    let (slave_command, slave_data): (Port<u8>, Port<u8>) =
+        unsafe { (Port::new(0xA0), Port::new(0xA1)) };
+    master_command.write(0x11); // ICW1: init, ICW4 needed
+    slave_command.write(0x11);
+    master_data.write(VECTORS_PIC.start); // ICW2: vector offset
+    slave_data.write(VECTORS_PIC.start + 8);
+    master_data.write(1 << 2); // ICW3: slave on IRQ2
+    slave_data.write(2);
+    master_data.write(0x01); // ICW4: 8086 mode
+    slave_data.write(0x01);
+    master_data.write(0xFF); // OCW1: mask all
+    slave_data.write(0xFF);
+}
+
+/// Not acknowledged, as the local APIC doesn't set it in service.
+extern "x86-interrupt" fn irq_spurious() {}
+
+/// Masked PIC lines can still raise spurious IRQs on IRQ7 and IRQ15, which
+/// aren't in service and must not be acknowledged, except for the cascade on
+/// the master. Stray IRQs on other lines are acknowledged.
+fn irq_pic(line: u8) {
+    let (master_command, slave_command): (Port<u8>, Port<u8>) =
+        unsafe { (Port::new(0x20), Port::new(0xA0)) };
+    let command = if line < 8 {
+        master_command
+    } else {
+        slave_command
+    };
+    command.write(0x0B); // OCW3: read the in-service register
+    let in_service = command.read() & 1 << (line & 7) != 0;
+    if line >= 8 && in_service {
+        slave_command.write(0x20); // OCW2: non-specific EOI
+    }
+    if line >= 8 || in_service {
+        master_command.write(0x20);
+    }
+}
+
+/// Lets another runnable run if the interrupt arrived in user mode, or exits
+/// the running one if it has been killed. Otherwise it arrived while idle.
+extern "x86-interrupt" fn irq_timer(frame: InterruptStackFrame) {
+    apic::eoi();
+    if !frame.user() {
+        return;
+    }
+    let scheduler = Scheduler::get();
+    if !scheduler.killed() {
+        scheduler.r#yield();
+    }
+    // possibly resumed on another processor
+    let scheduler = Scheduler::get();
+    if scheduler.killed() {
+        scheduler.exit();
+    }
+}
+
+/// Nothing to do, as the interrupt itself already woke the processor.
+extern "x86-interrupt" fn irq_wake() {
+    apic::eoi();
+}
+
+struct DeviceIrq {
+    allocated: bool,
+    /// Arrived while nobody was waiting.
+    pending: bool,
+    waiter: Option<Waiter>,
+}
+
+impl DeviceIrq {
+    const fn new() -> Self {
+        Self {
+            allocated: false,
+            pending: false,
+            waiter: None,
+        }
+    }
+}
+
+/// Allocates a device vector, which is not shared with other devices.
+pub fn allocate() -> Option<u8> {
+    VECTORS_DEVICE
+        .zip(DEVICE_IRQS.iter())
+        .find_map(|(vector, irq)| {
+            let mut irq = irq.lock();
+            (!irq.allocated).then(|| {
+                irq.allocated = true;
+                irq.pending = false;
+                vector
+            })
+        })
+}
+
+/// Frees a device vector, its waiter is resumed. The device has to be stopped
+/// from signaling it before.
+pub fn free(vector: u8) {
+    let mut irq = DEVICE_IRQS[(vector - VECTORS_DEVICE.start) as usize].lock();
+    irq.allocated = false;
+    irq.pending = false;
+    let waiter = irq.waiter.take();
+    drop(irq);
+    drop(waiter);
+}
+
+/// Blocks the running runnable until the device vector arrived, unless it
+/// already did since the last call.
+pub fn wait(vector: u8) {
+    let mut irq = DEVICE_IRQS[(vector - VECTORS_DEVICE.start) as usize].lock();
+    if mem::take(&mut irq.pending) {
+        return;
+    }
+
+    let mut irq = Some(irq);
+    Scheduler::get().block(&mut |runnable| {
+        irq.take().unwrap().waiter = Some(Waiter::new(runnable));
+    });
+}
+
+fn irq_device(vector: u8) {
+    apic::eoi();
+    signal(vector);
+}
+
+/// Resumes the waiter of the device vector, as if it arrived.
+pub fn signal(vector: u8) {
+    let mut irq = DEVICE_IRQS[(vector - VECTORS_DEVICE.start) as usize].lock();
+    match irq.waiter.take().and_then(|waiter| waiter.take()) {
+        Some(runnable) => super::wake(runnable),
+        None => irq.pending = true,
+    }
+}
+
+macro_rules! irq_device {
+    ($($vector:tt $name:ident),*$(,)?) => {
+        fn init_irq_device() {
+            $((unsafe { &mut *DESCRIPTOR_TABLE.get() })[$vector] = Descriptor::new($name as usize, 1 << 3, DescriptorGateType::Interrupt, 0, 0);)*
+        }
+
+        $(extern "x86-interrupt" fn $name() {
+            irq_device($vector)
+        })*
+    };
+}
+
+irq_device!(
+    0x40 irq_40,
+    0x41 irq_41,
+    0x42 irq_42,
+    0x43 irq_43,
+    0x44 irq_44,
+    0x45 irq_45,
+    0x46 irq_46,
+    0x47 irq_47,
+    0x48 irq_48,
+    0x49 irq_49,
+    0x4A irq_4a,
+    0x4B irq_4b,
+    0x4C irq_4c,
+    0x4D irq_4d,
+    0x4E irq_4e,
+    0x4F irq_4f,
+);
+
+macro_rules! irq_pic {
+    ($($vector:tt $name:ident),*$(,)?) => {
+        fn init_irq_pic() {
+            $((unsafe { &mut *DESCRIPTOR_TABLE.get() })[$vector] = Descriptor::new($name as usize, 1 << 3, DescriptorGateType::Interrupt, 0, 0);)*
+        }
+
+        $(extern "x86-interrupt" fn $name() {
+            irq_pic($vector - VECTORS_PIC.start)
+        })*
+    };
+}
+
+irq_pic!(
+    0x30 irq_30,
+    0x31 irq_31,
+    0x32 irq_32,
+    0x33 irq_33,
+    0x34 irq_34,
+    0x35 irq_35,
+    0x36 irq_36,
+    0x37 irq_37,
+    0x38 irq_38,
+    0x39 irq_39,
+    0x3A irq_3a,
+    0x3B irq_3b,
+    0x3C irq_3c,
+    0x3D irq_3d,
+    0x3E irq_3e,
+    0x3F irq_3f,
+);
+
+/// Exceptions from user mode exit the running task, the kernel itself must
+/// not cause any.
+fn exception(frame: &InterruptStackFrame, vector: u8, description: &str, error_code: &[usize]) {
+    if vector == VECTOR_PAGE_FAULT && frame.user() {
+        let address: usize;
+        unsafe { arch::asm!("mov {}, cr2", out(reg) address, options(nomem, nostack)) };
+        // access might have been allowed after another processor cached the page,
+        // the fault already invalidated it
+        if mm::VIRT_MEM.user(address) {
+            return;
+        }
+    }
+    if !frame.user() {
+        panic!("{} at {:#x} {:x?}", description, frame.ip, error_code);
+    }
+
+    log::warn!(
+        "{} at {:#x} {:x?}, exiting task",
+        description,
+        frame.ip,
+        error_code
+    );
+    // trap gates leave interrupts enabled
+    unsafe { arch::asm!("cli", options(nomem, nostack)) };
+    Scheduler::get().exit()
+}
+
+const VECTOR_PAGE_FAULT: u8 = 0x0E;
+
 macro_rules! ivt {
-    ($($vector:tt $name:ident $description:tt $function:stmt),*$(,)?) => {
+    ($($vector:tt $name:ident $description:tt $($error_code:ident)?),*$(,)?) => {
         fn init_ivt() {
             $((unsafe { &mut *DESCRIPTOR_TABLE.get() })[$vector] = Descriptor::new($name as usize, 1 << 3, DescriptorGateType::Trap, 0, 0);)*
         }
 
-        $(extern "x86-interrupt" fn $name() {
-            $function
-            panic!($description)
+        $(extern "x86-interrupt" fn $name(frame: InterruptStackFrame $(, $error_code: usize)?) {
+            exception(&frame, $vector, $description, &[$($error_code)?])
         })*
     };
 }
 
 ivt!(
-    0x00 exc_de "Division Error" {},
-    0x01 exc_db "Debug" {},
-    0x02 exc_02 "Exception 2" {},
-    0x03 exc_bp "Breakpoint" {},
-    0x04 exc_of "Overflow" {},
-    0x05 exc_br "Bound Range Exceeded" {},
-    0x06 exc_ud "Invalid Opcode" {},
-    0x07 exc_nm "Device Not Available" {},
-    0x08 exc_df "Double Fault" {},
-    0x09 exc_09 "Exception 9" {},
-    0x0A exc_ts "Invalid TSS" {},
-    0x0B exc_np "Segment Not Present" {},
-    0x0C exc_ss "Stack-Segment Fault" {},
-    0x0D exc_gp "General Protection Fault" {},
-    0x0E exc_pf "Page Fault" {},
-    0x0F exc_15 "Exception 15" {},
-    0x10 exc_mf "x87 Floating-Point Exception" {},
-    0x11 exc_ac "Alignment Check" {},
-    0x12 exc_mc "Machine Check" {},
-    0x13 exc_xf "SIMD Floating-Point Exception" {},
-    0x14 exc_ve "Virtualization Exception" {},
-    0x15 exc_cp "Control Protection Exception" {},
-    0x16 exc_22 "Exception 22" {},
-    0x17 exc_23 "Exception 23" {},
-    0x18 exc_24 "Exception 24" {},
-    0x19 exc_25 "Exception 25" {},
-    0x1A exc_26 "Exception 26" {},
-    0x1B exc_27 "Exception 27" {},
-    0x1C exc_hv "Hypervisor Injection Exception" {},
-    0x1D exc_vc "VMM Communication Exception" {},
-    0x1E exc_sx "Security Exception" {},
-    0x1F exc_31 "Exception 31" {}
+    0x00 exc_de "Division Error",
+    0x01 exc_db "Debug",
+    0x02 exc_02 "Exception 2",
+    0x03 exc_bp "Breakpoint",
+    0x04 exc_of "Overflow",
+    0x05 exc_br "Bound Range Exceeded",
+    0x06 exc_ud "Invalid Opcode",
+    0x07 exc_nm "Device Not Available",
+    0x08 exc_df "Double Fault" error_code,
+    0x09 exc_09 "Exception 9",
+    0x0A exc_ts "Invalid TSS" error_code,
+    0x0B exc_np "Segment Not Present" error_code,
+    0x0C exc_ss "Stack-Segment Fault" error_code,
+    0x0D exc_gp "General Protection Fault" error_code,
+    0x0E exc_pf "Page Fault" error_code,
+    0x0F exc_15 "Exception 15",
+    0x10 exc_mf "x87 Floating-Point Exception",
+    0x11 exc_ac "Alignment Check" error_code,
+    0x12 exc_mc "Machine Check",
+    0x13 exc_xf "SIMD Floating-Point Exception",
+    0x14 exc_ve "Virtualization Exception",
+    0x15 exc_cp "Control Protection Exception" error_code,
+    0x16 exc_22 "Exception 22",
+    0x17 exc_23 "Exception 23",
+    0x18 exc_24 "Exception 24",
+    0x19 exc_25 "Exception 25",
+    0x1A exc_26 "Exception 26",
+    0x1B exc_27 "Exception 27",
+    0x1C exc_hv "Hypervisor Injection Exception",
+    0x1D exc_vc "VMM Communication Exception" error_code,
+    0x1E exc_sx "Security Exception" error_code,
+    0x1F exc_31 "Exception 31"
 );
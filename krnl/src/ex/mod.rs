@@ -12,37 +12,81 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use core::{arch, ptr};
+use core::{
+    arch, mem,
+    ops::{Range, RangeInclusive},
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
-use alloc::{boxed::Box, collections::vec_deque::VecDeque};
+use alloc::{
+    boxed::Box,
+    collections::vec_deque::VecDeque,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
 use ctx::Context;
+use spin::{Mutex, RwLock};
 
-use crate::mm;
+use crate::{mm, ob};
 
+mod apic;
 mod ctx;
+mod sys;
 
 pub mod int;
 
+/// Flags tasks run with in user mode: interrupts are enabled, and I/O
+/// privilege level 0 leaves port access to the I/O permission bitmap.
+const FLAGS_USER: usize = 1 << 9 | 1 << 1;
+
+/// Run queues of all processors, indexed by processor number.
+static RUN_QUEUES: RwLock<Vec<&'static RunQueue>> = RwLock::new(Vec::new());
+
+/// Spawns the first runnables, called by the first scheduler started.
+static INIT: Mutex<Option<fn()>> = Mutex::new(None);
+
 pub struct Scheduler {
     context: Context,
-    runnables: VecDeque<Runnable>,
+    idle: Context,
+    mwait: bool,
+    run_queue: &'static RunQueue,
     running: Option<Runnable>,
+    switch: Switch,
 
     tss: Box<mm::sm::TaskStateSegment>,
+    /// Version of the I/O permission bitmap in the TSS.
+    io_permission_bitmap: Option<u64>,
 }
 
-impl Default for Scheduler {
-    fn default() -> Self {
+impl Scheduler {
+    fn new() -> Self {
+        let apic_id = apic::init();
+        let mut run_queues = RUN_QUEUES.write();
+        let run_queue = Box::leak(Box::new(RunQueue {
+            cpu: run_queues.len(),
+            apic_id,
+            idle: AtomicBool::new(false),
+            runnables: Default::default(),
+        }));
+        run_queues.push(run_queue);
+
         Self {
             context: unsafe { Context::empty() },
-            runnables: Default::default(),
+            idle: Context::new(4 * 1024, Scheduler::idle_entry),
+            mwait: has_mwait(),
+            run_queue,
             running: Default::default(),
-            tss: Default::default(),
+            switch: Switch::Yield,
+            tss: mm::sm::TaskStateSegment::new(),
+            io_permission_bitmap: None,
         }
     }
-}
 
-impl Scheduler {
+    /// Returns the scheduler of the current processor.
+    ///
+    /// Note that the running runnable can be migrated to another processor
+    /// whenever it yields, so the returned reference should not be kept across.
     pub fn get() -> &'static mut Self {
         unsafe {
             let ctx: *mut Self;
@@ -52,13 +96,34 @@ impl Scheduler {
     }
 
     fn scheduler_entry() -> ! {
-        let mut scheduler: Box<Self> = Box::default();
+        let mut scheduler = Box::new(Self::new());
         unsafe {
             scheduler.tss.load();
             mm::sm::GS::set(ptr::addr_of!(scheduler) as usize, size_of_val(&scheduler));
         }
+        let init = INIT.lock().take();
+        if let Some(init) = init {
+            init();
+        }
 
-        while let Some(runnable) = scheduler.runnables.pop_front() {
+        loop {
+            let Some(runnable) = scheduler.run_queue.pop().or_else(|| scheduler.steal()) else {
+                scheduler.idle.swap(&mut scheduler.context);
+                continue;
+            };
+            // the bitmap is only copied, if the TSS holds another one
+            let version = runnable
+                .io_permission_bitmap
+                .as_ref()
+                .map(mm::sm::IoPermissionBitmap::version);
+            if version != scheduler.io_permission_bitmap {
+                scheduler
+                    .tss
+                    .set_io_permission_bitmap(runnable.io_permission_bitmap.as_ref());
+                scheduler.io_permission_bitmap = version;
+            }
+            scheduler.tss.set_kernel_stack(runnable.context.stack().end);
+            mm::VIRT_MEM.flush();
             scheduler.running = Some(runnable);
             scheduler
                 .running
@@ -66,43 +131,532 @@ impl Scheduler {
                 .unwrap()
                 .context
                 .swap(&mut scheduler.context);
+
+            // the previous runnable is only handed over after its context has been saved,
+            // as another processor could otherwise resume it too early
+            let runnable = scheduler.running.take().unwrap();
+            match mem::replace(&mut scheduler.switch, Switch::Yield) {
+                Switch::Yield => enqueue(runnable),
+                Switch::Block(park) => unsafe { (*park)(runnable) },
+                Switch::Exit => {
+                    let task = runnable.task.clone();
+                    drop(runnable);
+                    task.exit();
+                }
+            }
+        }
+    }
+
+    fn idle_entry() -> ! {
+        let scheduler = Scheduler::get();
+        let run_queue = scheduler.run_queue;
+        loop {
+            // has to be set before checking the run queue, otherwise a runnable enqueued in
+            // between would not wake this processor
+            run_queue.idle.store(true, Ordering::SeqCst);
+            if run_queue.len() == 0 {
+                if scheduler.mwait {
+                    // clearing the idle flag also wakes the processor
+                    monitor(ptr::addr_of!(run_queue.idle) as *const u8);
+                    if run_queue.idle.load(Ordering::SeqCst) {
+                        mwait();
+                    }
+                } else {
+                    hlt();
+                }
+            }
+            run_queue.idle.store(false, Ordering::SeqCst);
+            scheduler.context.swap(&mut scheduler.idle);
         }
-        panic!("nothing left to do");
     }
 
     fn runnable_entry() -> ! {
         let scheduler = Scheduler::get();
-        let runnable = scheduler.running.as_mut().unwrap();
-        (runnable.closure.take().unwrap())();
-        scheduler.running = None;
-        scheduler.context.load();
+        if scheduler.killed() {
+            scheduler.exit();
+        }
+        enter_user(scheduler.running.as_ref().unwrap().entry)
+    }
+
+    /// Takes a runnable from the back of the longest run queue of another
+    /// processor, if it is allowed to run on this one.
+    fn steal(&self) -> Option<Runnable> {
+        RUN_QUEUES
+            .read()
+            .iter()
+            .filter(|run_queue| run_queue.cpu != self.run_queue.cpu)
+            .max_by_key(|run_queue| run_queue.len())
+            .and_then(|run_queue| run_queue.steal(self.run_queue.cpu))
     }
 
     pub fn r#yield(&mut self) {
-        self.runnables.push_back(self.running.take().unwrap());
+        self.switch = Switch::Yield;
+        self.context
+            .swap(&mut self.running.as_mut().unwrap().context);
+    }
+
+    /// Blocks the running runnable, which is handed to `park` once its context
+    /// has been saved. `park` has to keep it somewhere, usually as a
+    /// [`Waiter`], until it's passed to [`wake`].
+    ///
+    /// Locks held when calling this function can be released by `park`, which
+    /// makes checking a condition and blocking on it atomic.
+    pub fn block(&mut self, park: &mut dyn FnMut(Runnable)) {
+        // SAFETY: park lives on the stack of the blocked runnable, which can't be
+        // resumed before park has been called
+        self.switch = Switch::Block(unsafe { mem::transmute(park) });
         self.context
-            .swap(&mut self.runnables.back_mut().unwrap().context);
+            .swap(&mut self.running.as_mut().unwrap().context);
+    }
+
+    /// Whether the running runnable has been killed, which it has to exit for
+    /// before returning to user mode.
+    pub fn killed(&self) -> bool {
+        self.running.as_ref().unwrap().task.killed()
+    }
+
+    /// Exits the running runnable. Its stack is still in use, and therefore
+    /// dropped by the scheduler.
+    pub fn exit(&mut self) -> ! {
+        self.switch = Switch::Exit;
+        self.context.load();
+    }
+
+    /// Restricts the running runnable to the given processors, and migrates it
+    /// if the current processor is not one of them.
+    pub fn set_affinity(&mut self, affinity: Affinity) {
+        self.running.as_mut().unwrap().affinity = affinity;
+        if !affinity.contains(self.run_queue.cpu) {
+            self.r#yield();
+        }
+    }
+
+    /// Hands the memory over to the running runnable, see [`Memory`].
+    pub fn own(&mut self, memory: Memory) {
+        memory.allow_user();
+        self.running.as_mut().unwrap().memory.push(memory);
+    }
+
+    /// Takes the memory back from the running runnable, if it owns exactly the
+    /// range, e.g. to transfer it to another.
+    pub fn disown(&mut self, range: Range<usize>) -> Option<Memory> {
+        let memory = &mut self.running.as_mut().unwrap().memory;
+        let index = memory.iter().position(|memory| memory.range == range)?;
+        let memory = memory.swap_remove(index);
+        mm::VIRT_MEM.deny_user(memory.range.start, memory.range.len());
+        Some(memory)
+    }
+
+    /// Whether the running runnable owns all of the range.
+    pub fn owns(&self, range: Range<usize>) -> bool {
+        self.running
+            .as_ref()
+            .unwrap()
+            .memory
+            .iter()
+            .any(|memory| memory.range.start <= range.start && range.end <= memory.range.end)
+    }
+
+    /// Returns the handle table of the running runnable.
+    pub fn handles(&mut self) -> &mut ob::HandleTable {
+        &mut self.running.as_mut().unwrap().handles
+    }
+
+    /// Allows the running runnable to access the I/O ports, if it has a
+    /// capability for them.
+    pub fn allow_io_ports(&mut self, ports: RangeInclusive<u16>) -> Result<(), ob::Error> {
+        let runnable = self.running.as_mut().unwrap();
+        runnable
+            .handles
+            .find(
+                ob::Rights::READ | ob::Rights::WRITE,
+                |object| match object {
+                    ob::Object::Pio(range) => {
+                        range.start() <= ports.start() && ports.end() <= range.end()
+                    }
+                    _ => false,
+                },
+            )
+            .ok_or(ob::Error::AccessDenied)?;
+
+        let io_permission_bitmap = runnable.io_permission_bitmap.get_or_insert_default();
+        io_permission_bitmap.allow(ports);
+        self.tss
+            .set_io_permission_bitmap(Some(io_permission_bitmap));
+        self.io_permission_bitmap = Some(io_permission_bitmap.version());
+        Ok(())
+    }
+
+    /// Spawns a new runnable, which can only access the objects in `handles`,
+    /// and owns `memory`, e.g. its image and stack.
+    pub fn spawn(
+        &mut self,
+        entry: Entry,
+        handles: ob::HandleTable,
+        memory: Vec<Memory>,
+    ) -> Arc<Task> {
+        memory.iter().for_each(Memory::allow_user);
+        let mut runnable = Runnable::new(entry, handles);
+        runnable.memory = memory;
+        let task = runnable.task.clone();
+        enqueue(runnable);
+        task
+    }
+}
+
+/// What happens to the previously running runnable, once switched back to the
+/// scheduler.
+enum Switch {
+    Yield,
+    Block(*mut dyn FnMut(Runnable)),
+    Exit,
+}
+
+struct RunQueue {
+    cpu: usize,
+    apic_id: u32,
+    /// Set while the processor is waiting for work, and has to be woken up.
+    idle: AtomicBool,
+    runnables: Mutex<VecDeque<Runnable>>,
+}
+
+impl RunQueue {
+    fn len(&self) -> usize {
+        self.runnables.lock().len()
+    }
+
+    fn pop(&self) -> Option<Runnable> {
+        self.runnables.lock().pop_front()
+    }
+
+    fn push(&self, runnable: Runnable) {
+        self.runnables.lock().push_back(runnable);
+        if self.idle.swap(false, Ordering::SeqCst) && self.cpu != Scheduler::get().run_queue.cpu {
+            apic::ipi(self.apic_id, int::VECTOR_WAKE);
+        }
     }
 
-    pub fn spawn(&mut self, closure: Box<dyn FnOnce()>) {
-        self.runnables.push_back(Runnable::new(closure));
+    fn steal(&self, cpu: usize) -> Option<Runnable> {
+        let mut runnables = self.runnables.lock();
+        let index = runnables
+            .iter()
+            .rposition(|runnable| runnable.affinity.contains(cpu))?;
+        runnables.remove(index)
     }
 }
 
 pub struct Runnable {
+    /// Kernel stack, used by interrupts and system calls.
     context: Context,
-    closure: Option<Box<dyn FnOnce()>>,
+    entry: Entry,
+    affinity: Affinity,
+    handles: ob::HandleTable,
+    io_permission_bitmap: Option<mm::sm::IoPermissionBitmap>,
+    memory: Vec<Memory>,
+    task: Arc<Task>,
 }
 
 impl Runnable {
-    fn new(closure: Box<dyn FnOnce()>) -> Self {
+    fn new(entry: Entry, handles: ob::HandleTable) -> Self {
         Self {
             context: Context::new(8 * 1024, Scheduler::runnable_entry),
-            closure: Some(closure),
+            entry,
+            affinity: Affinity::ALL,
+            handles,
+            io_permission_bitmap: None,
+            memory: Vec::new(),
+            task: Arc::new(Task {
+                killed: AtomicBool::new(false),
+                exited: AtomicBool::new(false),
+                watcher: Mutex::new(None),
+                waiting: Mutex::new(Weak::new()),
+            }),
         }
     }
 }
 
-pub fn run() -> ! {
+/// Where a runnable starts running in user mode, which is like calling a C
+/// function with the arguments.
+#[derive(Clone, Copy)]
+pub struct Entry {
+    pub ip: usize,
+    /// End of the user stack.
+    pub stack: usize,
+    pub arguments: [usize; 2],
+}
+
+/// Memory a runnable owns, which it can access from user mode and pass to
+/// system calls by address, e.g. its image, stack and what it mapped or
+/// allocated.
+pub struct Memory {
+    range: Range<usize>,
+    /// Frees the memory once dropped, i.e. when the runnable exits.
+    _owner: Box<dyn Send>,
+}
+
+impl Memory {
+    pub fn new(range: Range<usize>, owner: Box<dyn Send>) -> Self {
+        Self {
+            range,
+            _owner: owner,
+        }
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.range.clone()
+    }
+
+    fn allow_user(&self) {
+        mm::VIRT_MEM.allow_user(self.range.start, self.range.len());
+    }
+}
+
+/// State of a runnable shared with others, e.g. the one that spawned it.
+pub struct Task {
+    killed: AtomicBool,
+    exited: AtomicBool,
+    /// Device vector signaled once exited, see [`Task::watch`].
+    watcher: Mutex<Option<u8>>,
+    /// Where the runnable is parked while blocked, see [`Waiter`].
+    waiting: Mutex<Weak<Mutex<Option<Runnable>>>>,
+}
+
+impl Task {
+    /// Stops the runnable, it exits instead of returning to user mode, which
+    /// happens at the next interrupt if it's running there. A blocked one is
+    /// resumed, and fails the system call it blocked in.
+    pub fn kill(&self) {
+        self.killed.store(true, Ordering::Release);
+        let waiter = self.waiting.lock().upgrade();
+        if let Some(runnable) = waiter.and_then(|waiter| waiter.lock().take()) {
+            wake(runnable);
+        }
+    }
+
+    pub fn killed(&self) -> bool {
+        self.killed.load(Ordering::Acquire)
+    }
+
+    /// Signals the device vector once the runnable exited, and returns whether
+    /// it already has.
+    pub fn watch(&self, vector: u8) -> bool {
+        *self.watcher.lock() = Some(vector);
+        self.exited.load(Ordering::Acquire)
+    }
+
+    /// Marks the runnable as exited, after everything it owned was released.
+    fn exit(&self) {
+        self.exited.store(true, Ordering::Release);
+        if let Some(vector) = *self.watcher.lock() {
+            int::signal(vector);
+        }
+    }
+}
+
+/// Runnable blocked in a wait queue. Whoever wakes it has to [`Waiter::take`]
+/// it first, which fails if it was killed in the meantime, e.g. before writing
+/// a result onto its stack. Dropping the waiter resumes the runnable without
+/// a result.
+pub struct Waiter(Arc<Mutex<Option<Runnable>>>);
+
+impl Waiter {
+    pub fn new(runnable: Runnable) -> Self {
+        let task = runnable.task.clone();
+        let waiter = Arc::new(Mutex::new(Some(runnable)));
+        *task.waiting.lock() = Arc::downgrade(&waiter);
+        let waiter = Self(waiter);
+        // killed before it could be found here
+        if task.killed() {
+            if let Some(runnable) = waiter.take() {
+                wake(runnable);
+            }
+        }
+        waiter
+    }
+
+    pub fn take(&self) -> Option<Runnable> {
+        self.0.lock().take()
+    }
+
+    /// Whether the runnable is still blocked.
+    pub fn blocked(&self) -> bool {
+        self.0.lock().is_some()
+    }
+
+    /// Resumes the runnable, unless it was killed.
+    pub fn wake(self) {
+        if let Some(runnable) = self.take() {
+            wake(runnable);
+        }
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        if let Some(runnable) = self.take() {
+            wake(runnable);
+        }
+    }
+}
+
+/// Set of processors a runnable is allowed to run on, limited to the first 64.
+#[derive(Clone, Copy)]
+pub struct Affinity(u64);
+
+impl Affinity {
+    pub const ALL: Self = Self(u64::MAX);
+
+    pub const fn cpu(cpu: usize) -> Self {
+        Self(1 << cpu)
+    }
+
+    pub const fn contains(&self, cpu: usize) -> bool {
+        cpu < u64::BITS as usize && self.0 & 1 << cpu != 0
+    }
+}
+
+/// Makes a runnable, previously blocked by [`Scheduler::block`], runnable
+/// again.
+pub fn wake(runnable: Runnable) {
+    enqueue(runnable);
+}
+
+/// Places the runnable on the least loaded processor it is allowed to run on.
+fn enqueue(runnable: Runnable) {
+    let cpu = Scheduler::get().run_queue.cpu;
+    let run_queues = RUN_QUEUES.read();
+    let run_queue = run_queues
+        .iter()
+        .filter(|run_queue| runnable.affinity.contains(run_queue.cpu))
+        // prefer the current processor when equally loaded
+        .min_by_key(|run_queue| (run_queue.len(), run_queue.cpu != cpu))
+        .expect("no processor in affinity");
+    run_queue.push(runnable);
+}
+
+/// Starts scheduling on this processor, the first one to do so calls `init`.
+pub fn run(init: Option<fn()>) -> ! {
+    if init.is_some() {
+        *INIT.lock() = init;
+    }
     Context::new(8 * 1024, Scheduler::scheduler_entry).load();
 }
+
+/// Leaves the kernel for the entry point. The return address is null, so that
+/// returning faults.
+fn enter_user(entry: Entry) -> ! {
+    let [argument_0, argument_1] = entry.arguments;
+    // the arguments are passed on the stack, which is aligned to 16 bytes before
+    // the call
+    #[cfg(target_arch = "x86")]
+    unsafe {
+        let stack = ((entry.stack & !0xF) - 5 * size_of::<usize>()) as *mut usize;
+        stack.write(0);
+        stack.add(1).write(argument_0);
+        stack.add(2).write(argument_1);
+        arch::asm!(
+            "mov ds, {data:e}",
+            "mov es, {data:e}",
+            "push {data:e}",
+            "push {stack:e}",
+            "push {flags:e}",
+            "push {code:e}",
+            "push {ip:e}",
+            "iretd",
+            data = in(reg) mm::sm::SELECTOR_UDATA,
+            stack = in(reg) stack,
+            flags = in(reg) FLAGS_USER,
+            code = in(reg) mm::sm::SELECTOR_UCODE,
+            ip = in(reg) entry.ip,
+            options(noreturn)
+        )
+    }
+    // the arguments are passed in registers, the stack is aligned to 16 bytes
+    // before the call
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        let stack = ((entry.stack & !0xF) - size_of::<usize>()) as *mut usize;
+        stack.write(0);
+        arch::asm!(
+            "push {data}",
+            "push {stack}",
+            "push {flags}",
+            "push {code}",
+            "push {ip}",
+            "iretq",
+            data = in(reg) mm::sm::SELECTOR_UDATA,
+            stack = in(reg) stack,
+            flags = in(reg) FLAGS_USER,
+            code = in(reg) mm::sm::SELECTOR_UCODE,
+            ip = in(reg) entry.ip,
+            in("rdi") argument_0,
+            in("rsi") argument_1,
+            options(noreturn)
+        )
+    }
+}
+
+/// Stops the processor for good, used when there is nothing sensible left to
+/// do.
+pub fn halt() -> ! {
+    loop {
+        unsafe { arch::asm!("cli", "hlt", options(nomem, nostack)) }
+    }
+}
+
+/// Enables interrupts and halts until the next one arrived.
+///
+/// `sti` only takes effect after the next instruction, therefore an interrupt
+/// can't slip in between and be missed.
+fn hlt() {
+    unsafe { arch::asm!("sti", "hlt", "cli", options(nostack)) }
+}
+
+fn monitor(addr: *const u8) {
+    #[cfg(target_arch = "x86")]
+    unsafe {
+        arch::asm!("monitor", in("eax") addr, in("ecx") 0, in("edx") 0, options(nostack))
+    }
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        arch::asm!("monitor", in("rax") addr, in("ecx") 0, in("edx") 0, options(nostack))
+    }
+}
+
+/// Enables interrupts and waits until either the monitored address has been
+/// written to or the next interrupt arrived.
+fn mwait() {
+    unsafe { arch::asm!("sti", "mwait", "cli", in("eax") 0, in("ecx") 0, options(nostack)) }
+}
+
+fn has_mwait() -> bool {
+    let ecx: u32;
+    // SAFETY: cpuid is always available, ebx/rbx is reserved by LLVM and restored
+    #[cfg(target_arch = "x86")]
+    unsafe {
+        arch::asm!(
+            "xchg {0:e}, ebx",
+            "cpuid",
+            "xchg {0:e}, ebx",
+            out(reg) _,
+            inout("eax") 1 => _,
+            out("ecx") ecx,
+            out("edx") _,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        arch::asm!(
+            "xchg {0:r}, rbx",
+            "cpuid",
+            "xchg {0:r}, rbx",
+            out(reg) _,
+            inout("eax") 1 => _,
+            out("ecx") ecx,
+            out("edx") _,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+    ecx & 1 << 3 != 0
+}
@@ -0,0 +1,596 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::{arch, mem::MaybeUninit, ptr, slice, str};
+
+use alloc::{boxed::Box, sync::Arc, vec};
+use spin::Mutex;
+
+use super::{int, Memory, Scheduler};
+use crate::{input, ipc, ld, mm, ob};
+
+/// Bytes of a single DMA allocation, larger ones are rejected.
+const DMA_SIZE: usize = 4 * 1024 * 1024;
+
+/// Bytes of a log message, longer ones are rejected.
+const LOG_SIZE: usize = 1024;
+
+/// Bytes of the name of a boot module.
+const MODULE_NAME_SIZE: usize = 64;
+
+/// Bytes of the argument passed to a task spawned.
+const ARGUMENT_SIZE: usize = 4096;
+
+/// Capabilities granted to a task spawned.
+const GRANTS: usize = 16;
+
+/// Registers saved on entry, see [`sys::VECTOR`] for the calling convention.
+#[cfg(target_arch = "x86")]
+#[repr(C)]
+struct Frame {
+    edi: usize,
+    esi: usize,
+    ebp: usize,
+    esp: usize,
+    ebx: usize,
+    edx: usize,
+    ecx: usize,
+    eax: usize,
+}
+
+#[cfg(target_arch = "x86")]
+impl Frame {
+    fn number(&self) -> usize {
+        self.eax
+    }
+
+    fn args(&self) -> [usize; 3] {
+        [self.ecx, self.edx, self.edi]
+    }
+
+    fn set_result(&mut self, result: [usize; 2]) {
+        [self.eax, self.edx] = result;
+    }
+}
+
+/// Registers saved on entry, see [`sys::VECTOR`] for the calling convention.
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+struct Frame {
+    rax: usize,
+    rcx: usize,
+    rdx: usize,
+    rsi: usize,
+    rdi: usize,
+    r8: usize,
+    r9: usize,
+    r10: usize,
+    r11: usize,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Frame {
+    fn number(&self) -> usize {
+        self.rax
+    }
+
+    fn args(&self) -> [usize; 3] {
+        [self.rdi, self.rsi, self.rdx]
+    }
+
+    fn set_result(&mut self, result: [usize; 2]) {
+        [self.rax, self.rdx] = result;
+    }
+}
+
+#[naked]
+pub unsafe extern "C" fn entry() {
+    // cdecl, the stack has to be aligned to 16 bytes
+    #[cfg(target_arch = "x86")]
+    arch::naked_asm!(
+        r#"
+        pushad
+        mov ebx, esp
+        and esp, -16
+        sub esp, 12
+        push ebx
+        call {dispatch}
+        mov esp, ebx
+        popad
+        iretd
+        "#,
+        dispatch = sym dispatch
+    );
+
+    // System V ABI for x86-64, the stack is aligned to 16 bytes on interrupt entry
+    // and stays aligned after pushing an odd number of registers
+    #[cfg(target_arch = "x86_64")]
+    arch::naked_asm!(
+        r#"
+        push r11
+        push r10
+        push r9
+        push r8
+        push rdi
+        push rsi
+        push rdx
+        push rcx
+        push rax
+        mov rdi, rsp
+        call {dispatch}
+        pop rax
+        pop rcx
+        pop rdx
+        pop rsi
+        pop rdi
+        pop r8
+        pop r9
+        pop r10
+        pop r11
+        iretq
+        "#,
+        dispatch = sym dispatch
+    );
+}
+
+extern "C" fn dispatch(frame: &mut Frame) {
+    let [arg_0, arg_1, arg_2] = frame.args();
+    let result = match frame.number() {
+        sys::IO_PERMISSION => io_permission(arg_0, arg_1),
+        sys::IRQ_ALLOCATE => irq_allocate(),
+        sys::IRQ_WAIT => irq_wait(arg_0 as u8),
+        sys::MEMORY_MAP => memory_map(arg_0, arg_1),
+        sys::LOG => log(arg_0, arg_1, arg_2),
+        sys::DMA_ALLOCATE => dma_allocate(arg_0),
+        sys::PHYSICAL_ADDRESS => physical_address(arg_0),
+        sys::SPAWN => spawn(arg_0),
+        sys::KILL => kill(arg_0),
+        sys::INPUT_PUSH => input_push(arg_0, arg_1),
+        sys::INPUT_READ => input_read(arg_0, arg_1),
+        sys::ENDPOINT_CREATE => endpoint_create(),
+        sys::CALL => call(arg_0, arg_1),
+        sys::RECEIVE => receive(arg_0, arg_1, arg_2),
+        sys::REPLY => reply(arg_0, arg_1),
+        sys::CLOSE => close(arg_0),
+        sys::EXIT => Scheduler::get().exit(),
+        sys::IRQ_FREE => irq_free(arg_0 as u8),
+        sys::WATCH => watch(arg_0, arg_1 as u8),
+        _ => Err(sys::Error::InvalidNumber),
+    };
+    frame.set_result(match result {
+        Ok(value) => [0, value],
+        Err(error) => [error as usize, 0],
+    });
+
+    // everything the system call held has been released
+    let scheduler = Scheduler::get();
+    if scheduler.killed() {
+        scheduler.exit();
+    }
+}
+
+fn io_permission(start: usize, end: usize) -> Result<usize, sys::Error> {
+    let (Ok(start), Ok(end)) = (u16::try_from(start), u16::try_from(end)) else {
+        return Err(sys::Error::InvalidArgument);
+    };
+    Scheduler::get()
+        .allow_io_ports(start..=end)
+        .map(|_| 0)
+        .map_err(sys::Error::from)
+}
+
+/// Returns the vector and the local APIC ID of the current processor, where
+/// interrupts are to be sent to.
+fn irq_allocate() -> Result<usize, sys::Error> {
+    let vector = int::allocate().ok_or(sys::Error::Exhausted)?;
+    let scheduler = Scheduler::get();
+    scheduler.handles().insert(ob::Capability::new(
+        ob::Object::Irq(vector),
+        ob::Rights::READ | ob::Rights::GRANT,
+    ));
+    Ok(vector as usize | (scheduler.run_queue.apic_id as usize) << 8)
+}
+
+fn irq_wait(vector: u8) -> Result<usize, sys::Error> {
+    if !int::VECTORS_DEVICE.contains(&vector) {
+        return Err(sys::Error::AccessDenied);
+    }
+    let irq = |object: &ob::Object| matches!(object, ob::Object::Irq(irq) if *irq == vector);
+    Scheduler::get()
+        .handles()
+        .find(ob::Rights::READ, irq)
+        .ok_or(sys::Error::AccessDenied)?;
+    int::wait(vector);
+    // freed meanwhile
+    Scheduler::get()
+        .handles()
+        .find(ob::Rights::READ, irq)
+        .ok_or(sys::Error::Closed)?;
+    Ok(0)
+}
+
+/// Frees a vector allocated by the running task, and revokes all capabilities
+/// granted for it. Their holders waiting for it fail with
+/// [`sys::Error::Closed`].
+fn irq_free(vector: u8) -> Result<usize, sys::Error> {
+    let handles = Scheduler::get().handles();
+    let handle = handles
+        .position(
+            ob::Rights::READ | ob::Rights::GRANT,
+            |object| matches!(object, ob::Object::Irq(irq) if *irq == vector),
+        )
+        .ok_or(sys::Error::AccessDenied)?;
+    handles.revoke(handle)?;
+    handles.close(handle)?;
+    int::free(vector);
+    Ok(0)
+}
+
+fn memory_map(phys_addr: usize, size: usize) -> Result<usize, sys::Error> {
+    let start = phys_addr as u64;
+    let end = start
+        .checked_add(size as u64)
+        .ok_or(sys::Error::AccessDenied)?;
+    Scheduler::get()
+        .handles()
+        .find(ob::Rights::MAP, |object| {
+            matches!(object, ob::Object::Memory(range) if range.start <= start && end <= range.end)
+        })
+        .ok_or(sys::Error::AccessDenied)?;
+    let pages = mm::Pages::map_mmio(phys_addr, size).ok_or(sys::Error::Exhausted)?;
+    let range = pages.range();
+    Scheduler::get().own(Memory::new(range.clone(), Box::new(pages)));
+    Ok(range.start + phys_addr % mm::BYTES_PER_PAGE)
+}
+
+fn dma_allocate(size: usize) -> Result<usize, sys::Error> {
+    Scheduler::get()
+        .handles()
+        .find(ob::Rights::MAP, |object| matches!(object, ob::Object::Dma))
+        .ok_or(sys::Error::AccessDenied)?;
+    if size == 0 || size > DMA_SIZE {
+        return Err(sys::Error::InvalidArgument);
+    }
+    let pages = mm::Pages::allocate_dma(size).ok_or(sys::Error::Exhausted)?;
+    let virt_addr = pages.range().start;
+    let scheduler = Scheduler::get();
+    scheduler.own(Memory::new(pages.range(), Box::new(pages)));
+    // the memory is contiguous, and can be shared with another task
+    let phys_addr = mm::VIRT_MEM
+        .translate(virt_addr)
+        .ok_or(sys::Error::Exhausted)? as u64;
+    scheduler.handles().insert(ob::Capability::new(
+        ob::Object::Memory(phys_addr..phys_addr + size as u64),
+        ob::Rights::MAP | ob::Rights::GRANT,
+    ));
+    Ok(virt_addr)
+}
+
+fn physical_address(virt_addr: usize) -> Result<usize, sys::Error> {
+    if !Scheduler::get().owns(virt_addr..virt_addr.saturating_add(1)) {
+        return Err(sys::Error::AccessDenied);
+    }
+    mm::VIRT_MEM
+        .translate(virt_addr)
+        .ok_or(sys::Error::InvalidArgument)
+}
+
+fn log(level: usize, message: usize, len: usize) -> Result<usize, sys::Error> {
+    let level = [
+        log::Level::Error,
+        log::Level::Warn,
+        log::Level::Info,
+        log::Level::Debug,
+        log::Level::Trace,
+    ]
+    .into_iter()
+    .find(|&candidate| candidate as usize == level)
+    .ok_or(sys::Error::InvalidArgument)?;
+    if len > LOG_SIZE {
+        return Err(sys::Error::InvalidArgument);
+    }
+    let mut buffer = [0; LOG_SIZE];
+    copy_in(message, &mut buffer[..len])?;
+    let message = str::from_utf8(&buffer[..len]).map_err(|_| sys::Error::InvalidArgument)?;
+    log::log!(level, "{}", message);
+    Ok(0)
+}
+
+fn spawn(spawn: usize) -> Result<usize, sys::Error> {
+    let spawn = unsafe { read_in::<sys::Spawn>(spawn)? };
+    if spawn.module_len > MODULE_NAME_SIZE
+        || spawn.argument_len > ARGUMENT_SIZE
+        || spawn.grant_count > GRANTS
+    {
+        return Err(sys::Error::InvalidArgument);
+    }
+    let mut module = [0; MODULE_NAME_SIZE];
+    let module = &mut module[..spawn.module_len];
+    copy_in(spawn.module as usize, module)?;
+    let module = str::from_utf8(module).map_err(|_| sys::Error::InvalidArgument)?;
+    let mut argument = vec![0; spawn.argument_len];
+    copy_in(spawn.argument as usize, &mut argument)?;
+
+    let mut handles = ob::HandleTable::default();
+    for index in 0..spawn.grant_count {
+        let grant = spawn.grants.wrapping_add(index) as usize;
+        handles.insert(derive(unsafe { &read_in::<sys::Grant>(grant)? })?);
+    }
+
+    let task = ld::spawn(module, &argument, handles).ok_or(sys::Error::InvalidArgument)?;
+    let handle = Scheduler::get().handles().insert(ob::Capability::new(
+        ob::Object::Task(task),
+        ob::Rights::READ | ob::Rights::WRITE,
+    ));
+    Ok(handle.0 as usize)
+}
+
+fn kill(handle: usize) -> Result<usize, sys::Error> {
+    let handle = ob::Handle(u32::try_from(handle).map_err(|_| sys::Error::InvalidHandle)?);
+    let handles = Scheduler::get().handles();
+    let ob::Object::Task(task) = handles.get(handle, ob::Rights::WRITE)?.object() else {
+        return Err(sys::Error::InvalidHandle);
+    };
+    task.kill();
+    handles.close(handle)?;
+    Ok(0)
+}
+
+/// Signals a device vector of the running task once the task exits, and
+/// returns whether it already has.
+fn watch(handle: usize, vector: u8) -> Result<usize, sys::Error> {
+    let handle = ob::Handle(u32::try_from(handle).map_err(|_| sys::Error::InvalidHandle)?);
+    let handles = Scheduler::get().handles();
+    let ob::Object::Task(task) = handles.get(handle, ob::Rights::READ)?.object() else {
+        return Err(sys::Error::InvalidHandle);
+    };
+    if !int::VECTORS_DEVICE.contains(&vector) {
+        return Err(sys::Error::AccessDenied);
+    }
+    handles
+        .find(
+            ob::Rights::READ,
+            |object| matches!(object, ob::Object::Irq(irq) if *irq == vector),
+        )
+        .ok_or(sys::Error::AccessDenied)?;
+    Ok(task.watch(vector) as usize)
+}
+
+fn input_push(low: usize, high: usize) -> Result<usize, sys::Error> {
+    Scheduler::get()
+        .handles()
+        .find(ob::Rights::WRITE, |object| {
+            matches!(object, ob::Object::Input)
+        })
+        .ok_or(sys::Error::AccessDenied)?;
+    let mut event = [0; sys::INPUT_EVENT_SIZE];
+    event[..4].copy_from_slice(&(low as u32).to_le_bytes());
+    event[4..].copy_from_slice(&(high as u32).to_le_bytes());
+    input::push(event);
+    Ok(0)
+}
+
+fn input_read(buffer: usize, count: usize) -> Result<usize, sys::Error> {
+    if count == 0 {
+        return Err(sys::Error::InvalidArgument);
+    }
+    Scheduler::get()
+        .handles()
+        .find(ob::Rights::READ, |object| {
+            matches!(object, ob::Object::Input)
+        })
+        .ok_or(sys::Error::AccessDenied)?;
+    let count = count.min(input::QUEUE_SIZE);
+    let end = buffer
+        .checked_add(count * sys::INPUT_EVENT_SIZE)
+        .ok_or(sys::Error::InvalidArgument)?;
+    // checked before the events are taken, so that they aren't lost
+    if !Scheduler::get().owns(buffer..end) {
+        return Err(sys::Error::AccessDenied);
+    }
+    let mut events = [[0; sys::INPUT_EVENT_SIZE]; input::QUEUE_SIZE];
+    let count = input::read(&mut events[..count]);
+    copy_out(buffer, events[..count].as_flattened())?;
+    Ok(count)
+}
+
+fn endpoint_create() -> Result<usize, sys::Error> {
+    let handle = Scheduler::get().handles().insert(ob::Capability::new(
+        ob::Object::Endpoint(Arc::new(ipc::Endpoint::default())),
+        ob::Rights::READ | ob::Rights::WRITE | ob::Rights::GRANT | ob::Rights::DUPLICATE,
+    ));
+    Ok(handle.0 as usize)
+}
+
+fn call(handle: usize, message: usize) -> Result<usize, sys::Error> {
+    let endpoint = endpoint(handle, ob::Rights::WRITE)?;
+    let request = message_in(message)?;
+    // the caller owns the memory, as the message was just read from there
+    let reply = endpoint.call(request).ok_or(sys::Error::Closed)?;
+    write_out(message, &message_out(reply))?;
+    Ok(0)
+}
+
+fn receive(handle: usize, message: usize, flags: usize) -> Result<usize, sys::Error> {
+    let endpoint = endpoint(handle, ob::Rights::READ)?;
+    let end = message
+        .checked_add(size_of::<sys::Message>())
+        .ok_or(sys::Error::InvalidArgument)?;
+    // checked before the message is taken, so that it isn't lost
+    if !Scheduler::get().owns(message..end) {
+        return Err(sys::Error::AccessDenied);
+    }
+    let (received, reply) = if flags & sys::RECEIVE_NONBLOCKING != 0 {
+        endpoint.try_receive().ok_or(sys::Error::WouldBlock)?
+    } else {
+        endpoint.receive().ok_or(sys::Error::Closed)?
+    };
+    write_out(message, &message_out(received))?;
+    let handle = Scheduler::get().handles().insert(ob::Capability::new(
+        ob::Object::Reply(Arc::new(Mutex::new(reply))),
+        ob::Rights::WRITE,
+    ));
+    Ok(handle.0 as usize)
+}
+
+fn reply(handle: usize, message: usize) -> Result<usize, sys::Error> {
+    let handle = ob::Handle(u32::try_from(handle).map_err(|_| sys::Error::InvalidHandle)?);
+    let ob::Object::Reply(reply) = Scheduler::get()
+        .handles()
+        .get(handle, ob::Rights::WRITE)?
+        .object()
+        .clone()
+    else {
+        return Err(sys::Error::InvalidHandle);
+    };
+    // consumed even if the message is invalid, the call fails then
+    Scheduler::get().handles().close(handle)?;
+    let reply = reply.lock().take();
+    let message = message_in(message)?;
+    if let Some(reply) = reply {
+        reply.reply(message);
+    }
+    Ok(0)
+}
+
+fn close(handle: usize) -> Result<usize, sys::Error> {
+    let handle = ob::Handle(u32::try_from(handle).map_err(|_| sys::Error::InvalidHandle)?);
+    Scheduler::get().handles().close(handle)?;
+    Ok(0)
+}
+
+/// Returns the endpoint the handle refers to, if it has all of the rights.
+fn endpoint(handle: usize, rights: ob::Rights) -> Result<Arc<ipc::Endpoint>, sys::Error> {
+    let handle = ob::Handle(u32::try_from(handle).map_err(|_| sys::Error::InvalidHandle)?);
+    match Scheduler::get().handles().get(handle, rights)?.object() {
+        ob::Object::Endpoint(endpoint) => Ok(endpoint.clone()),
+        _ => Err(sys::Error::InvalidHandle),
+    }
+}
+
+/// Derives the capability the running task grants, for a part of one it
+/// holds. Endpoints are granted by handle, and only for calling them.
+fn derive(grant: &sys::Grant) -> Result<ob::Capability, sys::Error> {
+    let handles = Scheduler::get().handles();
+    let invalid = |_| sys::Error::InvalidArgument;
+    let object = match grant.kind {
+        sys::Grant::MEMORY if grant.start < grant.end => ob::Object::Memory(grant.start..grant.end),
+        sys::Grant::PIO => ob::Object::Pio(
+            u16::try_from(grant.start).map_err(invalid)?
+                ..=u16::try_from(grant.end).map_err(invalid)?,
+        ),
+        sys::Grant::IRQ => ob::Object::Irq(u8::try_from(grant.start).map_err(invalid)?),
+        sys::Grant::DMA => ob::Object::Dma,
+        sys::Grant::INPUT => ob::Object::Input,
+        sys::Grant::ENDPOINT => {
+            let handle = u32::try_from(grant.start).map_err(|_| sys::Error::InvalidHandle)?;
+            let capability = handles.get(ob::Handle(handle), ob::Rights::empty())?;
+            if !matches!(capability.object(), ob::Object::Endpoint(_)) {
+                return Err(sys::Error::InvalidHandle);
+            }
+            return Ok(handles.grant(ob::Handle(handle), ob::Rights::WRITE)?);
+        }
+        _ => return Err(sys::Error::InvalidArgument),
+    };
+    Ok(handles.grant_part(object, ob::Rights::all())?)
+}
+
+/// Reads a message the running task passed by address, derives the
+/// capabilities it grants, and takes the memory it transfers.
+fn message_in(address: usize) -> Result<ipc::Message, sys::Error> {
+    let message = unsafe { read_in::<sys::Message>(address)? };
+    let grants = message
+        .grants
+        .get(..message.grant_count)
+        .ok_or(sys::Error::InvalidArgument)?;
+    let mut ipc_message = ipc::Message::new(message.label, message.words, None);
+    for grant in grants {
+        ipc_message.grant_capability(derive(grant)?)?;
+    }
+    // taken last, as it's freed if the message is dropped
+    if message.pages_size != 0 {
+        let start = message.pages as usize;
+        let end = message
+            .pages_size
+            .checked_next_multiple_of(mm::BYTES_PER_PAGE)
+            .and_then(|size| start.checked_add(size))
+            .ok_or(sys::Error::InvalidArgument)?;
+        ipc_message.pages = Some(
+            Scheduler::get()
+                .disown(start..end)
+                .ok_or(sys::Error::AccessDenied)?,
+        );
+    }
+    Ok(ipc_message)
+}
+
+/// Returns the message as passed to the receiving task, which found the
+/// handles of the capabilities granted in the words already, and owns the
+/// memory transferred.
+fn message_out(message: ipc::Message) -> sys::Message {
+    let mut sys_message = sys::Message::new(message.label, message.words);
+    if let Some(pages) = message.pages {
+        let range = pages.range();
+        sys_message.transfer(range.start as *mut u8, range.len());
+        Scheduler::get().own(pages);
+    }
+    sys_message
+}
+
+/// Copies a value the running task passed by address, see [`copy_in`].
+///
+/// # Safety
+///
+/// Any bytes have to be a valid `T`.
+unsafe fn read_in<T>(address: usize) -> Result<T, sys::Error> {
+    let mut value = MaybeUninit::<T>::uninit();
+    copy_in(address, unsafe {
+        slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>())
+    })?;
+    Ok(unsafe { value.assume_init() })
+}
+
+/// Copies memory the running task passed by address, which it has to own, see
+/// [`Memory`].
+fn copy_in(address: usize, buffer: &mut [u8]) -> Result<(), sys::Error> {
+    let end = address
+        .checked_add(buffer.len())
+        .ok_or(sys::Error::InvalidArgument)?;
+    if !Scheduler::get().owns(address..end) {
+        return Err(sys::Error::AccessDenied);
+    }
+    unsafe { ptr::copy_nonoverlapping(address as *const u8, buffer.as_mut_ptr(), buffer.len()) };
+    Ok(())
+}
+
+/// Copies a value to where the running task passed by address, see
+/// [`copy_out`].
+fn write_out<T>(address: usize, value: &T) -> Result<(), sys::Error> {
+    copy_out(address, unsafe {
+        slice::from_raw_parts(value as *const T as *const u8, size_of::<T>())
+    })
+}
+
+/// Copies into memory the running task passed by address, which it has to
+/// own, see [`Memory`].
+fn copy_out(address: usize, buffer: &[u8]) -> Result<(), sys::Error> {
+    let end = address
+        .checked_add(buffer.len())
+        .ok_or(sys::Error::InvalidArgument)?;
+    if !Scheduler::get().owns(address..end) {
+        return Err(sys::Error::AccessDenied);
+    }
+    unsafe { ptr::copy_nonoverlapping(buffer.as_ptr(), address as *mut u8, buffer.len()) };
+    Ok(())
+}
@@ -0,0 +1,98 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::collections::vec_deque::VecDeque;
+use spin::Mutex;
+
+use crate::ex::{self, Runnable, Scheduler, Waiter};
+
+/// Events kept until they are read, the oldest are dropped beyond that.
+pub const QUEUE_SIZE: usize = 64;
+
+/// Input event as reported by a driver, its encoding is up to drivers and the
+/// console.
+pub type Event = [u8; sys::INPUT_EVENT_SIZE];
+
+/// Events of all input devices in the order they were reported, and the
+/// runnables waiting for them.
+static INPUT: Mutex<Input> = Mutex::new(Input {
+    events: VecDeque::new(),
+    readers: VecDeque::new(),
+});
+
+struct Input {
+    events: VecDeque<Event>,
+    readers: VecDeque<Waiter>,
+}
+
+impl Input {
+    /// Takes the first reader that is still blocked.
+    fn reader(&mut self) -> Option<Runnable> {
+        while let Some(reader) = self.readers.pop_front() {
+            if let Some(runnable) = reader.take() {
+                return Some(runnable);
+            }
+        }
+        None
+    }
+}
+
+/// Queues the event, and wakes a reader.
+pub fn push(event: Event) {
+    let mut input = INPUT.lock();
+    if input.events.len() == QUEUE_SIZE {
+        input.events.pop_front();
+    }
+    input.events.push_back(event);
+    let reader = input.reader();
+    drop(input);
+    if let Some(reader) = reader {
+        ex::wake(reader);
+    }
+}
+
+/// Moves the oldest events into the buffer, and returns how many. Blocks until
+/// there is at least one, or the reader was killed.
+pub fn read(buffer: &mut [Event]) -> usize {
+    loop {
+        if Scheduler::get().killed() {
+            return 0;
+        }
+
+        let mut input = INPUT.lock();
+        if !input.events.is_empty() {
+            let count = buffer.len().min(input.events.len());
+            for (slot, event) in buffer.iter_mut().zip(input.events.drain(..count)) {
+                *slot = event;
+            }
+            // the events left over are for the next reader
+            if !input.events.is_empty() {
+                if let Some(reader) = input.reader() {
+                    drop(input);
+                    ex::wake(reader);
+                }
+            }
+            return count;
+        }
+
+        let mut input = Some(input);
+        Scheduler::get().block(&mut |runnable| {
+            input
+                .take()
+                .unwrap()
+                .readers
+                .push_back(Waiter::new(runnable))
+        });
+    }
+}
@@ -0,0 +1,293 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::{mem, ptr};
+
+use alloc::{collections::vec_deque::VecDeque, vec::Vec};
+use spin::Mutex;
+
+use crate::{
+    ex::{self, Runnable, Scheduler, Waiter},
+    ob,
+};
+
+/// Number of words a message carries inline.
+pub const MESSAGE_WORDS: usize = sys::MESSAGE_WORDS;
+
+/// Number of capabilities a message carries, their handles take up the last
+/// words.
+pub const MESSAGE_CAPABILITIES: usize = sys::MESSAGE_GRANTS;
+
+pub struct Message {
+    pub label: usize,
+    pub words: [usize; MESSAGE_WORDS],
+    /// Larger buffers are transferred page-granular, the pages are owned by
+    /// the receiver afterwards.
+    pub pages: Option<ex::Memory>,
+    /// Capabilities granted to the receiver, see [`Message::grant`].
+    capabilities: Vec<ob::Capability>,
+}
+
+impl Message {
+    pub fn new(label: usize, words: [usize; MESSAGE_WORDS], pages: Option<ex::Memory>) -> Self {
+        Self {
+            label,
+            words,
+            pages,
+            capabilities: Vec::new(),
+        }
+    }
+
+    /// Grants a capability of the running task to the receiver, which requires
+    /// [`ob::Rights::GRANT`]. The receiver finds its handle in the words, the
+    /// first capability granted in word `MESSAGE_WORDS - n` of `n`.
+    pub fn grant(&mut self, handle: ob::Handle, rights: ob::Rights) -> Result<(), ob::Error> {
+        let capability = Scheduler::get().handles().grant(handle, rights)?;
+        self.grant_capability(capability)
+    }
+
+    /// Passes a capability already derived for the receiver on, e.g. by
+    /// [`ob::HandleTable::grant_part`].
+    pub fn grant_capability(&mut self, capability: ob::Capability) -> Result<(), ob::Error> {
+        if self.capabilities.len() == MESSAGE_CAPABILITIES {
+            return Err(ob::Error::Exhausted);
+        }
+        self.capabilities.push(capability);
+        Ok(())
+    }
+
+    /// Inserts the capabilities into the handle table of the running task, the
+    /// receiver, and passes their handles in the last words.
+    fn accept(mut self) -> Self {
+        let handles = Scheduler::get().handles();
+        let first = MESSAGE_WORDS - self.capabilities.len();
+        for (word, capability) in self.words[first..]
+            .iter_mut()
+            .zip(self.capabilities.drain(..))
+        {
+            *word = handles.insert(capability).0 as usize;
+        }
+        self
+    }
+}
+
+/// Rendezvous point for synchronous message passing, senders block until a
+/// receiver took their message, and receivers block until there is one.
+#[derive(Default)]
+pub struct Endpoint(Mutex<EndpointQueue>);
+
+#[derive(Default)]
+struct EndpointQueue {
+    senders: VecDeque<Sender>,
+    receivers: VecDeque<Receiver>,
+    /// Capabilities that can receive, see [`Endpoint::open`].
+    servers: usize,
+}
+
+// SAFETY: the pointers point into the stacks of blocked runnables, which are
+// only written to after taking them from their waiter
+unsafe impl Send for EndpointQueue {}
+
+impl EndpointQueue {
+    /// Takes the first receiver that is still blocked.
+    fn receiver(&mut self) -> Option<(Runnable, *mut Option<Received>)> {
+        while let Some(receiver) = self.receivers.pop_front() {
+            if let Some(runnable) = receiver.waiter.take() {
+                return Some((runnable, receiver.received));
+            }
+        }
+        None
+    }
+
+    /// Takes the first sender that is still blocked, the messages of others are
+    /// dropped.
+    fn sender(&mut self) -> Option<Sender> {
+        while let Some(sender) = self.senders.pop_front() {
+            if sender.waiter.blocked() {
+                return Some(sender);
+            }
+        }
+        None
+    }
+}
+
+type Received = (Message, Option<Reply>);
+
+struct Sender {
+    waiter: Waiter,
+    message: Message,
+    /// Where to store the reply, in case of a call.
+    reply: Option<*mut Option<Message>>,
+}
+
+struct Receiver {
+    waiter: Waiter,
+    received: *mut Option<Received>,
+}
+
+/// Writes the message onto the stack of the receiver taken, and resumes it.
+fn deliver(receiver: (Runnable, *mut Option<Received>), message: Message, reply: Option<Reply>) {
+    let (runnable, received) = receiver;
+    unsafe { *received = Some((message, reply)) };
+    ex::wake(runnable);
+}
+
+impl Endpoint {
+    /// Sends a message, and blocks until it has been received.
+    pub fn send(&self, message: Message) {
+        let mut queue = self.0.lock();
+        if let Some(receiver) = queue.receiver() {
+            drop(queue);
+            deliver(receiver, message, None);
+            return;
+        }
+
+        let mut queue = Some(queue);
+        let mut message = Some(message);
+        Scheduler::get().block(&mut |runnable| {
+            queue.take().unwrap().senders.push_back(Sender {
+                waiter: Waiter::new(runnable),
+                message: message.take().unwrap(),
+                reply: None,
+            })
+        });
+    }
+
+    /// Sends a message, and blocks until it has been received and replied to.
+    /// Returns `None` if the [`Reply`] was dropped instead, nobody can receive
+    /// it anymore, or the caller was killed.
+    pub fn call(&self, message: Message) -> Option<Message> {
+        let mut reply = None;
+        let reply_ptr = ptr::addr_of_mut!(reply);
+
+        let queue = self.0.lock();
+        if queue.servers == 0 {
+            return None;
+        }
+
+        // the caller has to be blocked in any case, as it's passed on with the reply
+        let mut queue = Some(queue);
+        let mut message = Some(message);
+        Scheduler::get().block(&mut |runnable| {
+            let mut queue = queue.take().unwrap();
+            let message = message.take().unwrap();
+            let waiter = Waiter::new(runnable);
+            match queue.receiver() {
+                Some(receiver) => {
+                    drop(queue);
+                    deliver(
+                        receiver,
+                        message,
+                        Some(Reply {
+                            caller: waiter,
+                            reply: reply_ptr,
+                        }),
+                    );
+                }
+                None => queue.senders.push_back(Sender {
+                    waiter,
+                    message,
+                    reply: Some(reply_ptr),
+                }),
+            }
+        });
+        reply.map(Message::accept)
+    }
+
+    /// Blocks until a message has been sent, calls also return the
+    /// [`Reply`] to answer them with. Returns `None` if the receiver was
+    /// killed meanwhile.
+    pub fn receive(&self) -> Option<Received> {
+        let mut queue = self.0.lock();
+        if let Some(sender) = queue.sender() {
+            drop(queue);
+            return Some(sender.take());
+        }
+
+        let mut received = None;
+        let received_ptr = ptr::addr_of_mut!(received);
+        let mut queue = Some(queue);
+        Scheduler::get().block(&mut |runnable| {
+            queue.take().unwrap().receivers.push_back(Receiver {
+                waiter: Waiter::new(runnable),
+                received: received_ptr,
+            })
+        });
+        let (message, reply) = received?;
+        Some((message.accept(), reply))
+    }
+
+    /// Like [`Endpoint::receive`], but returns `None` instead of blocking if
+    /// no message has been sent.
+    pub fn try_receive(&self) -> Option<Received> {
+        let sender = self.0.lock().sender()?;
+        Some(sender.take())
+    }
+
+    /// Counts a capability that can receive from the endpoint, i.e. a server.
+    pub fn open(&self) {
+        self.0.lock().servers += 1;
+    }
+
+    /// Uncounts a capability counted by [`Endpoint::open`]. Once the last one
+    /// is gone, blocked senders are resumed, and calls return `None`.
+    pub fn close(&self) {
+        let mut queue = self.0.lock();
+        queue.servers -= 1;
+        if queue.servers == 0 {
+            let senders = mem::take(&mut queue.senders);
+            drop(queue);
+            drop(senders);
+        }
+    }
+}
+
+impl Sender {
+    /// Takes the message, and resumes the sender unless it waits for a reply.
+    fn take(self) -> Received {
+        match self.reply {
+            Some(reply) => (
+                self.message.accept(),
+                Some(Reply {
+                    caller: self.waiter,
+                    reply,
+                }),
+            ),
+            None => {
+                self.waiter.wake();
+                (self.message.accept(), None)
+            }
+        }
+    }
+}
+
+/// Blocked caller waiting for a reply, dropping it without replying resumes
+/// the caller without one.
+pub struct Reply {
+    caller: Waiter,
+    reply: *mut Option<Message>,
+}
+
+// SAFETY: see EndpointQueue
+unsafe impl Send for Reply {}
+
+impl Reply {
+    /// Answers the call, which resumes the caller unless it was killed.
+    pub fn reply(self, message: Message) {
+        if let Some(caller) = self.caller.take() {
+            unsafe { *self.reply = Some(message) };
+            ex::wake(caller);
+        }
+    }
+}
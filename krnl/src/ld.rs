@@ -0,0 +1,273 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::{mem, ops::Range, ptr, slice};
+
+use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
+use spin::Mutex;
+
+use crate::{
+    ex::{self, Scheduler},
+    mm, ob,
+};
+
+/// Bytes of the command line of a boot module searched for its name.
+const CMDLINE_SIZE: usize = 256;
+
+/// Bytes of memory an image may occupy.
+const MAX_IMAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Pages of the stack of a task, its argument is copied to the top.
+const STACK_PAGES: usize = 16;
+
+const ELF_MAGIC: [u8; 4] = *b"\x7FELF";
+/// Shared object file, which position-independent executables are
+const ET_DYN: u16 = 3;
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const DT_NULL: usize = 0;
+
+/// Layout of the ELF structures of this architecture, as offsets.
+#[cfg(target_arch = "x86")]
+mod elf {
+    pub const CLASS: u8 = 1;
+    pub const MACHINE: u16 = 3;
+    pub const E_ENTRY: usize = 24;
+    pub const E_PHOFF: usize = 28;
+    pub const E_PHENTSIZE: usize = 42;
+    pub const E_PHNUM: usize = 44;
+    pub const P_OFFSET: usize = 4;
+    pub const P_VADDR: usize = 8;
+    pub const P_FILESZ: usize = 16;
+    pub const P_MEMSZ: usize = 20;
+    /// DT_REL and DT_RELSZ, the addend is stored at the location
+    pub const DT_RELOCATIONS: usize = 17;
+    pub const DT_RELOCATIONS_SIZE: usize = 18;
+    pub const RELOCATION_SIZE: usize = 8;
+    pub const RELOCATION_ADDEND: Option<usize> = None;
+    pub const R_TYPE_MASK: usize = 0xFF;
+    /// R_386_RELATIVE
+    pub const R_RELATIVE: usize = 8;
+}
+
+#[cfg(target_arch = "x86_64")]
+mod elf {
+    pub const CLASS: u8 = 2;
+    pub const MACHINE: u16 = 62;
+    pub const E_ENTRY: usize = 24;
+    pub const E_PHOFF: usize = 32;
+    pub const E_PHENTSIZE: usize = 54;
+    pub const E_PHNUM: usize = 56;
+    pub const P_OFFSET: usize = 8;
+    pub const P_VADDR: usize = 16;
+    pub const P_FILESZ: usize = 32;
+    pub const P_MEMSZ: usize = 40;
+    /// DT_RELA and DT_RELASZ
+    pub const DT_RELOCATIONS: usize = 7;
+    pub const DT_RELOCATIONS_SIZE: usize = 8;
+    pub const RELOCATION_SIZE: usize = 24;
+    pub const RELOCATION_ADDEND: Option<usize> = Some(16);
+    pub const R_TYPE_MASK: usize = 0xFFFFFFFF;
+    /// R_X86_64_RELATIVE
+    pub const R_RELATIVE: usize = 8;
+}
+
+/// Boot modules, by the name of the file they were loaded from.
+static MODULES: Mutex<Vec<Module>> = Mutex::new(Vec::new());
+
+struct Module {
+    name: String,
+    /// Rest of the command line, passed to the task spawned first.
+    arguments: String,
+    image: &'static [u8],
+}
+
+#[repr(C, align(4096))]
+struct Page([u8; 4096]);
+
+/// Executable loaded into memory, which is freed once dropped.
+pub struct Image {
+    pages: Vec<Page>,
+    entry: usize,
+}
+
+impl Image {
+    pub fn memory(&self) -> Range<usize> {
+        let pages = self.pages.as_ptr_range();
+        pages.start as usize..pages.end as usize
+    }
+
+    /// Returns the entry point, which is passed the argument of the task.
+    pub fn entry(&self) -> usize {
+        self.entry
+    }
+}
+
+/// Registers the boot modules. Only modules within the memory mapped since
+/// boot are accessible, and their memory isn't reused.
+pub fn init(modules: &[multiboot::multiboot_module_t]) {
+    let mut registered = MODULES.lock();
+    for module in modules {
+        let name = (module.cmdline as usize..)
+            .take(CMDLINE_SIZE)
+            .map_while(|phys_addr| mm::boot_memory(phys_addr, 1))
+            .flatten()
+            .copied()
+            .take_while(|&byte| byte != 0)
+            .collect::<Vec<_>>();
+        // the file name, without its path or arguments
+        let cmdline = String::from_utf8_lossy(&name);
+        let (path, arguments) = cmdline.split_once(' ').unwrap_or((&cmdline, ""));
+        let name = path.rsplit('/').next().unwrap_or_default();
+        let size = module.mod_end.saturating_sub(module.mod_start) as usize;
+        let Some(image) = mm::boot_memory(module.mod_start as usize, size) else {
+            log::warn!("Boot module {} not accessible", name);
+            continue;
+        };
+        log::info!("Boot module {}, {} KiB", name, size >> 10);
+        registered.push(Module {
+            name: name.into(),
+            arguments: arguments.trim().into(),
+            image,
+        });
+    }
+}
+
+/// Returns the arguments on the command line of the boot module.
+pub fn arguments(name: &str) -> Option<String> {
+    MODULES
+        .lock()
+        .iter()
+        .find(|module| module.name == name)
+        .map(|module| module.arguments.clone())
+}
+
+/// Loads the boot module, and applies its relocations.
+pub fn load(name: &str) -> Option<Image> {
+    let image = MODULES
+        .lock()
+        .iter()
+        .find(|module| module.name == name)?
+        .image;
+    if image.get(..4)? != ELF_MAGIC
+        || *image.get(4)? != elf::CLASS
+        || read::<u16>(image, 16)? != ET_DYN
+        || read::<u16>(image, 18)? != elf::MACHINE
+    {
+        log::warn!("Boot module {} is no position-independent executable", name);
+        return None;
+    }
+
+    let program_headers = || {
+        let offset = read::<usize>(image, elf::E_PHOFF);
+        let size = read::<u16>(image, elf::E_PHENTSIZE);
+        let count = read::<u16>(image, elf::E_PHNUM).unwrap_or(0);
+        (0..count as usize).filter_map(move |index| image.get(offset? + index * size? as usize..))
+    };
+    let size = program_headers()
+        .filter(|header| read::<u32>(header, 0) == Some(PT_LOAD))
+        .map(|header| {
+            Some(read::<usize>(header, elf::P_VADDR)? + read::<usize>(header, elf::P_MEMSZ)?)
+        })
+        .try_fold(0, |size: usize, end| Some(size.max(end?)))?;
+    if size > MAX_IMAGE_SIZE {
+        return None;
+    }
+
+    let mut pages = Vec::new();
+    pages.resize_with(size.div_ceil(mem::size_of::<Page>()), || Page([0; 4096]));
+    let base = pages.as_mut_ptr() as usize;
+    let memory = unsafe { slice::from_raw_parts_mut(base as *mut u8, size) };
+    let mut dynamic = None;
+    for header in program_headers() {
+        let vaddr = read::<usize>(header, elf::P_VADDR)?;
+        match read::<u32>(header, 0)? {
+            PT_LOAD => {
+                let offset = read::<usize>(header, elf::P_OFFSET)?;
+                let len = read::<usize>(header, elf::P_FILESZ)?;
+                memory
+                    .get_mut(vaddr..vaddr.checked_add(len)?)?
+                    .copy_from_slice(image.get(offset..offset.checked_add(len)?)?);
+            }
+            PT_DYNAMIC => dynamic = Some(vaddr),
+            _ => {}
+        }
+    }
+
+    // only relative relocations are expected, the image doesn't import anything
+    let (mut relocations, mut relocations_size) = (0, 0);
+    let word = mem::size_of::<usize>();
+    if let Some(mut offset) = dynamic {
+        loop {
+            let value = read::<usize>(memory, offset + word)?;
+            match read::<usize>(memory, offset)? {
+                DT_NULL => break,
+                elf::DT_RELOCATIONS => relocations = value,
+                elf::DT_RELOCATIONS_SIZE => relocations_size = value,
+                _ => {}
+            }
+            offset += 2 * word;
+        }
+    }
+    let relocations = relocations..relocations.saturating_add(relocations_size);
+    for relocation in relocations.step_by(elf::RELOCATION_SIZE) {
+        let offset = read::<usize>(memory, relocation)?;
+        if read::<usize>(memory, relocation + word)? & elf::R_TYPE_MASK != elf::R_RELATIVE {
+            log::warn!("Boot module {} has unsupported relocations", name);
+            return None;
+        }
+        let addend = match elf::RELOCATION_ADDEND {
+            Some(addend) => read::<usize>(memory, relocation + addend)?,
+            None => read::<usize>(memory, offset)?,
+        };
+        memory
+            .get_mut(offset..offset + word)?
+            .copy_from_slice(&base.wrapping_add(addend).to_ne_bytes());
+    }
+
+    let entry = read::<usize>(image, elf::E_ENTRY)?;
+    if entry >= size {
+        return None;
+    }
+    Some(Image {
+        pages,
+        entry: base + entry,
+    })
+}
+
+/// Reads a value at the offset, if it's within the bytes.
+fn read<T: Copy>(bytes: &[u8], offset: usize) -> Option<T> {
+    let bytes = bytes.get(offset..offset.checked_add(mem::size_of::<T>())?)?;
+    Some(unsafe { ptr::read_unaligned(bytes.as_ptr() as *const T) })
+}
+
+/// Spawns a task running the boot module, which is passed the argument. It
+/// owns its image and stack, see [`ex::Memory`].
+pub fn spawn(name: &str, argument: &[u8], handles: ob::HandleTable) -> Option<Arc<ex::Task>> {
+    let image = load(name)?;
+    let mut stack = mm::Pages::allocate(STACK_PAGES)?;
+    let offset = stack.len().checked_sub(argument.len())? & !(mem::size_of::<usize>() - 1);
+    stack[offset..offset + argument.len()].copy_from_slice(argument);
+    let range = stack.range();
+    let entry = ex::Entry {
+        ip: image.entry(),
+        stack: range.start + offset,
+        arguments: [range.start + offset, argument.len()],
+    };
+    let memory = vec![
+        ex::Memory::new(image.memory(), Box::new(image)),
+        ex::Memory::new(range, Box::new(stack)),
+    ];
+    Some(Scheduler::get().spawn(entry, handles, memory))
+}
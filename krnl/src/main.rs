@@ -16,7 +16,7 @@
 #![no_main]
 #![feature(abi_x86_interrupt, naked_functions, sync_unsafe_cell)]
 
-use core::{arch, hint, panic, slice};
+use core::{arch, panic, slice};
 
 use log::error;
 
@@ -24,9 +24,16 @@ use log::error;
 extern crate alloc;
 
 mod ex;
+mod input;
+mod ipc;
+mod ld;
 mod mm;
+mod ob;
 mod tty;
 
+/// Boot module spawned first.
+const INIT_MODULE: &str = "drv_pci";
+
 #[cfg(target_arch = "x86")]
 arch::global_asm!(include_str!("x86.S"));
 #[cfg(target_arch = "x86_64")]
@@ -35,18 +42,14 @@ arch::global_asm!(include_str!("x86_64.S"));
 #[no_mangle]
 extern "C" fn main(multiboot_magic: u32, multiboot_info: u32) -> ! {
     if multiboot_magic != multiboot::MULTIBOOT_BOOTLOADER_MAGIC {
-        loop {
-            hint::spin_loop();
-        }
+        ex::halt();
     }
     let multiboot_info = unsafe {
         &*((multiboot_info as usize + (&mm::KERNEL_VMA as *const u8 as usize))
             as *const multiboot::multiboot_info)
     };
     if multiboot_info.flags & multiboot::MULTIBOOT_INFO_MEM_MAP == 0 {
-        loop {
-            hint::spin_loop();
-        }
+        ex::halt();
     }
 
     mm::init_virt_mem();
@@ -62,20 +65,45 @@ extern "C" fn main(multiboot_magic: u32, multiboot_info: u32) -> ! {
             multiboot_info.mmap_length as usize / size_of::<multiboot::multiboot_mmap_entry>(),
         )
     });
+    if multiboot_info.flags & multiboot::MULTIBOOT_INFO_MODS != 0 {
+        ld::init(unsafe {
+            slice::from_raw_parts(
+                (multiboot_info.mods_addr as usize + (&mm::KERNEL_VMA as *const u8 as usize))
+                    as *const multiboot::multiboot_module_t,
+                multiboot_info.mods_count as usize,
+            )
+        });
+    }
 
-    ex::run();
+    ex::run(Some(init));
 }
 
 #[no_mangle]
 extern "C" fn main_ap() {
-    ex::run();
+    ex::run(None);
+}
+
+/// Spawns the bus driver, which has access to all devices, and spawns their
+/// drivers in turn.
+fn init() {
+    let mut handles = ob::HandleTable::default();
+    for object in [
+        ob::Object::Memory(0..u64::MAX),
+        ob::Object::Pio(0..=u16::MAX),
+        ob::Object::Dma,
+        ob::Object::Input,
+    ] {
+        handles.insert(ob::Capability::new(object, ob::Rights::all()));
+    }
+    let arguments = ld::arguments(INIT_MODULE).unwrap_or_default();
+    if ld::spawn(INIT_MODULE, arguments.as_bytes(), handles).is_none() {
+        error!("{} not spawned", INIT_MODULE);
+    }
 }
 
 #[panic_handler]
 fn panic(info: &panic::PanicInfo) -> ! {
     error!("{}", info.message());
 
-    loop {
-        hint::spin_loop();
-    }
+    ex::halt();
 }
@@ -12,10 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use core::slice;
+
 mod pg;
 mod pm;
 mod vm;
 
+pub use pg::BYTES_PER_PAGE;
 pub use pm::*;
 pub use vm::*;
 
@@ -25,3 +28,13 @@ extern "C" {
     pub static KERNEL_LMA: u8;
     pub static KERNEL_VMA: u8;
 }
+
+/// Returns physical memory mapped since boot at [`KERNEL_VMA`], e.g. boot
+/// modules, or `None` if the range is beyond it.
+pub fn boot_memory(phys_addr: usize, size: usize) -> Option<&'static [u8]> {
+    let end = phys_addr.checked_add(size)?;
+    if end > pg::PAGES_PER_TABLE * pg::BYTES_PER_PAGE {
+        return None;
+    }
+    Some(unsafe { slice::from_raw_parts((&KERNEL_VMA as *const u8).add(phys_addr), size) })
+}
@@ -117,6 +117,9 @@ impl PageTableEntry {
     const FREE: usize = 0;
     const PRESENT: usize = 1 << 0;
     const WRITEABLE: usize = 1 << 1;
+    const USER: usize = 1 << 2;
+    const WRITE_THROUGH: usize = 1 << 3;
+    const CACHE_DISABLE: usize = 1 << 4;
 
     #[inline(always)]
     pub fn used(&self) -> bool {
@@ -128,6 +131,37 @@ impl PageTableEntry {
         self.0 = Self::PRESENT | Self::WRITEABLE | frame << 12;
     }
 
+    #[inline(always)]
+    pub fn map_uncached(&mut self, frame: usize) {
+        self.0 = Self::PRESENT
+            | Self::WRITEABLE
+            | Self::WRITE_THROUGH
+            | Self::CACHE_DISABLE
+            | frame << 12;
+    }
+
+    /// Allows access from user mode, which the entries of the tables above
+    /// have to allow as well.
+    #[inline(always)]
+    pub fn allow_user(&mut self) {
+        self.0 |= Self::USER;
+    }
+
+    #[inline(always)]
+    pub fn deny_user(&mut self) {
+        self.0 &= !Self::USER;
+    }
+
+    #[inline(always)]
+    pub fn user(&self) -> bool {
+        self.0 & (Self::PRESENT | Self::USER) == Self::PRESENT | Self::USER
+    }
+
+    #[inline(always)]
+    pub fn frame(&self) -> usize {
+        self.0 >> 12
+    }
+
     #[inline(always)]
     pub fn unmap(&mut self) -> usize {
         let frame = self.0 >> 12;
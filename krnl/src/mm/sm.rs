@@ -12,7 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use core::{arch, cell, mem, ptr};
+use core::{
+    arch, cell, mem,
+    ops::RangeInclusive,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::boxed::Box;
 
 const DESCRIPTOR_NULL: usize = 0;
 const DESCRIPTOR_KCODE: usize = 1;
@@ -25,6 +31,10 @@ const DESCRIPTOR_GS: usize = 6;
 #[cfg(target_arch = "x86_64")]
 const DESCRIPTOR_TSS64: usize = 6;
 
+/// Selectors tasks run with in user mode.
+pub const SELECTOR_UCODE: usize = DESCRIPTOR_UCODE << 3 | 3;
+pub const SELECTOR_UDATA: usize = DESCRIPTOR_UDATA << 3 | 3;
+
 #[no_mangle]
 static DESCRIPTOR_TABLE: cell::SyncUnsafeCell<[Descriptor; 7]> = cell::SyncUnsafeCell::new([
     // NULL
@@ -146,7 +156,6 @@ bitflags::bitflags! {
 
 #[cfg(target_arch = "x86")]
 #[repr(C)]
-#[derive(Default)]
 pub struct TaskStateSegment {
     link: u16,
     _reserved_0: u16,
@@ -186,11 +195,11 @@ pub struct TaskStateSegment {
     _reserved_10: u16,
     _reserved_11: u16,
     iopb: u16,
+    io_permission_bitmap: [u8; IO_PERMISSION_BITMAP_SIZE + 1],
 }
 
 #[cfg(target_arch = "x86_64")]
 #[repr(C, packed(4))]
-#[derive(Default)]
 pub struct TaskStateSegment {
     _reserved_0: u32,
     privilege_stack_table: [u64; 3],
@@ -199,11 +208,56 @@ pub struct TaskStateSegment {
     _reserved_2: u64,
     _reserved_3: u16,
     iopb: u16,
+    io_permission_bitmap: [u8; IO_PERMISSION_BITMAP_SIZE + 1],
 }
 
 impl TaskStateSegment {
+    pub fn new() -> Box<Self> {
+        // SAFETY: all zero is valid, and the bitmap is too large to be built on the
+        // stack
+        let mut tss: Box<Self> = unsafe { Box::new_zeroed().assume_init() };
+        // the byte following the bitmap has to be all ones
+        tss.io_permission_bitmap.fill(0xFF);
+        tss.set_io_permission_bitmap(None);
+        #[cfg(target_arch = "x86")]
+        {
+            tss.ss0 = (DESCRIPTOR_KDATA << 3) as u16;
+        }
+        tss
+    }
+
+    /// Sets the stack interrupts from user mode switch to, i.e. the kernel
+    /// stack of the task about to run.
+    #[cfg(target_arch = "x86")]
+    pub fn set_kernel_stack(&mut self, stack: usize) {
+        self.esp0 = stack as u32;
+    }
+
+    /// Sets the stack interrupts from user mode switch to, i.e. the kernel
+    /// stack of the task about to run.
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_kernel_stack(&mut self, stack: usize) {
+        let mut privilege_stack_table = self.privilege_stack_table;
+        privilege_stack_table[0] = stack as u64;
+        self.privilege_stack_table = privilege_stack_table;
+    }
+
+    /// Installs the I/O permission bitmap of the task about to run, without
+    /// one all ports are denied.
+    pub fn set_io_permission_bitmap(&mut self, bitmap: Option<&IoPermissionBitmap>) {
+        match bitmap {
+            Some(bitmap) => {
+                self.io_permission_bitmap[..IO_PERMISSION_BITMAP_SIZE]
+                    .copy_from_slice(&bitmap.bits);
+                self.iopb = mem::offset_of!(Self, io_permission_bitmap) as u16;
+            }
+            // beyond the limit
+            None => self.iopb = size_of::<Self>() as u16,
+        }
+    }
+
     pub unsafe fn load(&self) {
-        let base = ptr::addr_of!(self) as usize;
+        let base = self as *const _ as usize;
         let limit = size_of_val(self);
         (&mut *DESCRIPTOR_TABLE.get())[DESCRIPTOR_TSS] = Descriptor::new(
             base as u32,
@@ -225,9 +279,45 @@ impl TaskStateSegment {
     }
 }
 
+const IO_PERMISSION_BITMAP_SIZE: usize = 65536 / 8;
+
+/// Source of [`IoPermissionBitmap::version`].
+static IO_PERMISSION_BITMAP_VERSION: AtomicU64 = AtomicU64::new(0);
+
+/// One bit for each port, access is only allowed if it is cleared.
+pub struct IoPermissionBitmap {
+    bits: Box<[u8]>,
+    version: u64,
+}
+
+impl Default for IoPermissionBitmap {
+    fn default() -> Self {
+        Self {
+            bits: vec![0xFF; IO_PERMISSION_BITMAP_SIZE].into_boxed_slice(),
+            version: IO_PERMISSION_BITMAP_VERSION.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+}
+
+impl IoPermissionBitmap {
+    pub fn allow(&mut self, ports: RangeInclusive<u16>) {
+        for port in ports {
+            self.bits[port as usize / 8] &= !(1 << (port % 8));
+        }
+        self.version = IO_PERMISSION_BITMAP_VERSION.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Unique across all bitmaps, and changed by every modification.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+}
+
 pub struct GS;
 
 impl GS {
+    /// The descriptor is accessible from user mode, as returning there would
+    /// otherwise clear GS.
     #[cfg(target_arch = "x86")]
     pub unsafe fn set(base: usize, limit: usize) {
         (&mut *DESCRIPTOR_TABLE.get())[DESCRIPTOR_GS] = Descriptor::new(
@@ -238,10 +328,10 @@ impl GS {
                 .union(DescriptorAccess::E)
                 .union(DescriptorAccess::S)
                 .union(DescriptorAccess::P),
-            0,
+            3,
             DescriptorFlags::DB.union(DescriptorFlags::G),
         );
-        arch::asm!("mov gs, {0:x}", in(reg) DESCRIPTOR_GS << 3);
+        arch::asm!("mov gs, {0:x}", in(reg) DESCRIPTOR_GS << 3 | 3);
     }
 
     #[cfg(target_arch = "x86_64")]
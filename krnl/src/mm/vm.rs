@@ -12,10 +12,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use core::{alloc, ptr};
+use core::{alloc, arch, ops, ptr, slice};
 
 use super::{
-    pg::{self, Page, BYTES_PER_PAGE, PAGES_PER_TABLE, PAGES_TOTAL, PAGE_TABLE},
+    pg::{self, Page, PageTableEntry, BYTES_PER_PAGE, PAGES_PER_TABLE, PAGES_TOTAL, PAGE_TABLE},
     KERNEL_VMA, PHYS_MEM,
 };
 
@@ -26,6 +26,29 @@ pub struct VirtualMemory;
 
 impl VirtualMemory {
     pub fn map(&self, page_start: Page, frame_start: usize, count: usize) -> Option<Page> {
+        self.map_with(page_start, frame_start, count, PageTableEntry::map)
+    }
+
+    /// Maps the physical range uncached into the kernel address space, as
+    /// required for memory-mapped I/O.
+    pub fn map_mmio(&self, phys_addr: usize, size: usize) -> Option<*mut u8> {
+        let offset = phys_addr % BYTES_PER_PAGE;
+        let page_start = self.map_with(
+            kernel_page(),
+            phys_addr / BYTES_PER_PAGE,
+            (offset + size).div_ceil(BYTES_PER_PAGE),
+            PageTableEntry::map_uncached,
+        )?;
+        Some(unsafe { (page_start.ptr() as *mut u8).add(offset) })
+    }
+
+    fn map_with(
+        &self,
+        page_start: Page,
+        frame_start: usize,
+        count: usize,
+        map: fn(&mut PageTableEntry, usize),
+    ) -> Option<Page> {
         let page_start = self.find_free(page_start, count)?;
         for (page, frame) in
             (page_start.0..page_start.0 + count).zip(frame_start..frame_start + count)
@@ -42,7 +65,7 @@ impl VirtualMemory {
                 panic!("non-contiguous");
             }
 
-            page_table_entry.map(frame);
+            map(page_table_entry, frame);
         }
 
         Some(page_start)
@@ -65,9 +88,122 @@ impl VirtualMemory {
         Some((page_start, frame_start))
     }
 
+    /// Allocates physically contiguous and zeroed memory, as required for
+    /// DMA.
+    pub fn allocate_dma(&self, size: usize) -> Option<*mut u8> {
+        let count = size.div_ceil(BYTES_PER_PAGE);
+        let (page_start, _) = self.allocate_contiguous(kernel_page(), count)?;
+        let virt_addr = page_start.ptr() as *mut u8;
+        unsafe { virt_addr.write_bytes(0, count * BYTES_PER_PAGE) };
+        Some(virt_addr)
+    }
+
+    /// Returns the physical address the virtual address is mapped to.
+    pub fn translate(&self, virt_addr: usize) -> Option<usize> {
+        let page = Page((virt_addr / BYTES_PER_PAGE) & PAGES_TOTAL);
+        let page_table = unsafe { &mut *PAGE_TABLE };
+        #[cfg(target_arch = "x86_64")]
+        let page_table = page_table.table(page)?;
+        #[cfg(target_arch = "x86_64")]
+        let page_table = page_table.table(page)?;
+        let page_table = page_table.table(page)?;
+        let page_table_entry = &page_table[page];
+        page_table_entry
+            .used()
+            .then(|| page_table_entry.frame() * BYTES_PER_PAGE + virt_addr % BYTES_PER_PAGE)
+    }
+
+    /// Makes the mapped range accessible from user mode.
+    pub fn allow_user(&self, virt_addr: usize, size: usize) {
+        let page_start = (virt_addr / BYTES_PER_PAGE) & PAGES_TOTAL;
+        let page_end = page_start + (virt_addr % BYTES_PER_PAGE + size).div_ceil(BYTES_PER_PAGE);
+        for page in page_start..page_end {
+            let page = Page(page);
+            let page_table = unsafe { &mut *PAGE_TABLE };
+            #[cfg(target_arch = "x86_64")]
+            let page_table = {
+                page_table[page].allow_user();
+                page_table.table(page).expect("not mapped")
+            };
+            #[cfg(target_arch = "x86_64")]
+            let page_table = {
+                page_table[page].allow_user();
+                page_table.table(page).expect("not mapped")
+            };
+            page_table[page].allow_user();
+            let page_table = page_table.table(page).expect("not mapped");
+            page_table[page].allow_user();
+            invalidate(page);
+        }
+    }
+
+    /// Makes the mapped range inaccessible from user mode again. Other
+    /// processors may have cached it, see [`VirtualMemory::flush`].
+    pub fn deny_user(&self, virt_addr: usize, size: usize) {
+        let page_start = (virt_addr / BYTES_PER_PAGE) & PAGES_TOTAL;
+        let page_end = page_start + (virt_addr % BYTES_PER_PAGE + size).div_ceil(BYTES_PER_PAGE);
+        for page in page_start..page_end {
+            let page = Page(page);
+            let page_table = unsafe { &mut *PAGE_TABLE };
+            #[cfg(target_arch = "x86_64")]
+            let page_table = page_table.table(page).expect("not mapped");
+            #[cfg(target_arch = "x86_64")]
+            let page_table = page_table.table(page).expect("not mapped");
+            let page_table = page_table.table(page).expect("not mapped");
+            page_table[page].deny_user();
+            invalidate(page);
+        }
+    }
+
+    /// Invalidates all pages this processor cached, which is done before
+    /// running another task, so that it can't access memory through pages
+    /// cached while a previous one ran.
+    pub fn flush(&self) {
+        unsafe {
+            arch::asm!(
+                "mov {0}, cr3",
+                "mov cr3, {0}",
+                out(reg) _,
+                options(nostack, preserves_flags)
+            );
+        }
+    }
+
+    /// Returns whether the virtual address is accessible from user mode.
+    pub fn user(&self, virt_addr: usize) -> bool {
+        let page = Page((virt_addr / BYTES_PER_PAGE) & PAGES_TOTAL);
+        let page_table = unsafe { &mut *PAGE_TABLE };
+        #[cfg(target_arch = "x86_64")]
+        let page_table = match page_table[page].user() {
+            true => page_table.table(page).unwrap(),
+            false => return false,
+        };
+        #[cfg(target_arch = "x86_64")]
+        let page_table = match page_table[page].user() {
+            true => page_table.table(page).unwrap(),
+            false => return false,
+        };
+        let page_table = match page_table[page].user() {
+            true => page_table.table(page).unwrap(),
+            false => return false,
+        };
+        page_table[page].user()
+    }
+
     pub fn free(&self, page_start: Page, count: usize) {
         let mut phys_mem = PHYS_MEM.lock();
-        for page in page_start.0..page_start.0 + count {
+        for frame in self.unmap_with(page_start, count) {
+            phys_mem.mark_free(frame, 1);
+        }
+    }
+
+    /// Unmaps the pages without freeing the frames, e.g. memory-mapped I/O.
+    pub fn unmap(&self, page_start: Page, count: usize) {
+        self.unmap_with(page_start, count).for_each(drop);
+    }
+
+    fn unmap_with(&self, page_start: Page, count: usize) -> impl Iterator<Item = usize> {
+        (page_start.0..page_start.0 + count).map(|page| {
             let page = Page(page);
             let page_table = unsafe { &mut *PAGE_TABLE };
             #[cfg(target_arch = "x86_64")]
@@ -81,8 +217,9 @@ impl VirtualMemory {
             }
 
             let frame = page_table_entry.unmap();
-            phys_mem.mark_free(frame, 1);
-        }
+            invalidate(page);
+            frame
+        })
     }
 
     fn find_free(&self, page_start: Page, count: usize) -> Option<Page> {
@@ -126,11 +263,8 @@ impl VirtualMemory {
 unsafe impl alloc::GlobalAlloc for VirtualMemory {
     unsafe fn alloc(&self, layout: alloc::Layout) -> *mut u8 {
         let pages = layout.size().div_ceil(BYTES_PER_PAGE);
-        self.allocate(
-            Page(((&KERNEL_VMA as *const u8 as usize) / BYTES_PER_PAGE) & PAGES_TOTAL),
-            pages,
-        )
-        .map_or(ptr::null_mut(), |page_start| page_start.ptr() as *mut u8)
+        self.allocate(kernel_page(), pages)
+            .map_or(ptr::null_mut(), |page_start| page_start.ptr() as *mut u8)
     }
 
     unsafe fn dealloc(&self, virt_addr: *mut u8, layout: alloc::Layout) {
@@ -140,6 +274,96 @@ unsafe impl alloc::GlobalAlloc for VirtualMemory {
     }
 }
 
+/// Owned range of kernel pages, which are freed on drop, along with their
+/// frames unless mapped from elsewhere.
+pub struct Pages {
+    page_start: Page,
+    count: usize,
+    mmio: bool,
+}
+
+impl Pages {
+    pub fn allocate(count: usize) -> Option<Self> {
+        VIRT_MEM
+            .allocate(kernel_page(), count)
+            .map(|page_start| Self {
+                page_start,
+                count,
+                mmio: false,
+            })
+    }
+
+    /// See [`VirtualMemory::allocate_dma`].
+    pub fn allocate_dma(size: usize) -> Option<Self> {
+        let virt_addr = VIRT_MEM.allocate_dma(size)?;
+        Some(Self {
+            page_start: Page((virt_addr as usize / BYTES_PER_PAGE) & PAGES_TOTAL),
+            count: size.div_ceil(BYTES_PER_PAGE),
+            mmio: false,
+        })
+    }
+
+    /// See [`VirtualMemory::map_mmio`], the physical range starts at the
+    /// same offset into the first page.
+    pub fn map_mmio(phys_addr: usize, size: usize) -> Option<Self> {
+        let virt_addr = VIRT_MEM.map_mmio(phys_addr, size)?;
+        Some(Self {
+            page_start: Page((virt_addr as usize / BYTES_PER_PAGE) & PAGES_TOTAL),
+            count: (phys_addr % BYTES_PER_PAGE + size).div_ceil(BYTES_PER_PAGE),
+            mmio: true,
+        })
+    }
+
+    pub fn range(&self) -> ops::Range<usize> {
+        let start = self.page_start.ptr() as usize;
+        start..start + self.count * BYTES_PER_PAGE
+    }
+}
+
+impl ops::Deref for Pages {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        unsafe {
+            slice::from_raw_parts(
+                self.page_start.ptr() as *const u8,
+                self.count * BYTES_PER_PAGE,
+            )
+        }
+    }
+}
+
+impl ops::DerefMut for Pages {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe {
+            slice::from_raw_parts_mut(
+                self.page_start.ptr() as *mut u8,
+                self.count * BYTES_PER_PAGE,
+            )
+        }
+    }
+}
+
+impl Drop for Pages {
+    fn drop(&mut self) {
+        if self.mmio {
+            VIRT_MEM.unmap(self.page_start, self.count);
+        } else {
+            VIRT_MEM.free(self.page_start, self.count);
+        }
+    }
+}
+
+fn invalidate(page: Page) {
+    unsafe {
+        arch::asm!("invlpg [{}]", in(reg) page.ptr(), options(nostack, preserves_flags));
+    }
+}
+
+fn kernel_page() -> Page {
+    Page((unsafe { &KERNEL_VMA as *const u8 as usize } / BYTES_PER_PAGE) & PAGES_TOTAL)
+}
+
 pub fn init_virt_mem() {
     (unsafe { &mut *(pg::PAGE_TABLE) })[pg::Page(0)].unmap();
 }
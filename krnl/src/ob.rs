@@ -0,0 +1,292 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::{
+    ops::{Range, RangeInclusive},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use alloc::{sync::Arc, vec::Vec};
+use spin::Mutex;
+
+use crate::{ex, ipc};
+
+bitflags::bitflags! {
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    pub struct Rights: u32 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const MAP = 1 << 2;
+        /// Allows passing derived capabilities on, e.g. over IPC
+        const GRANT = 1 << 3;
+        const DUPLICATE = 1 << 4;
+    }
+}
+
+#[derive(Clone)]
+pub enum Object {
+    Endpoint(Arc<ipc::Endpoint>),
+    /// Physical memory, e.g. the BARs of a device.
+    Memory(Range<u64>),
+    /// Interrupt vector.
+    Irq(u8),
+    /// I/O ports, as accessed through `pio::Port`.
+    Pio(RangeInclusive<u16>),
+    /// Allocating memory for DMA.
+    Dma,
+    /// Task spawned from a boot module, which can be killed.
+    Task(Arc<ex::Task>),
+    /// Reporting input events, or reading them for the console.
+    Input,
+    /// Caller waiting for the reply to a message received, taken once
+    /// replied.
+    Reply(Arc<Mutex<Option<ipc::Reply>>>),
+}
+
+impl Object {
+    /// Whether this object includes all of the other, e.g. a memory range a
+    /// smaller one.
+    pub fn covers(&self, other: &Object) -> bool {
+        match (self, other) {
+            (Self::Memory(range), Self::Memory(other)) => {
+                range.start <= other.start && other.end <= range.end
+            }
+            (Self::Pio(range), Self::Pio(other)) => {
+                range.start() <= other.start() && other.end() <= range.end()
+            }
+            (Self::Irq(vector), Self::Irq(other)) => vector == other,
+            (Self::Dma, Self::Dma) | (Self::Input, Self::Input) => true,
+            (Self::Endpoint(endpoint), Self::Endpoint(other)) => Arc::ptr_eq(endpoint, other),
+            (Self::Task(task), Self::Task(other)) => Arc::ptr_eq(task, other),
+            (Self::Reply(reply), Self::Reply(other)) => Arc::ptr_eq(reply, other),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Handle(pub u32);
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidHandle,
+    AccessDenied,
+    Revoked,
+    Exhausted,
+}
+
+impl From<Error> for sys::Error {
+    fn from(value: Error) -> Self {
+        match value {
+            Error::InvalidHandle => Self::InvalidHandle,
+            Error::AccessDenied => Self::AccessDenied,
+            Error::Revoked => Self::Revoked,
+            Error::Exhausted => Self::Exhausted,
+        }
+    }
+}
+
+pub struct Capability {
+    object: Object,
+    rights: Rights,
+    derivation: Arc<Derivation>,
+}
+
+impl Capability {
+    pub fn new(object: Object, rights: Rights) -> Self {
+        open(&object, rights);
+        Self {
+            object,
+            rights,
+            derivation: Arc::new(Derivation {
+                revoked: AtomicBool::new(false),
+                parent: None,
+            }),
+        }
+    }
+
+    pub fn object(&self) -> &Object {
+        &self.object
+    }
+
+    pub fn rights(&self) -> Rights {
+        self.rights
+    }
+
+    /// Derives a capability with a subset of the rights, which is revoked
+    /// together with this one.
+    fn derive(&self, rights: Rights) -> Self {
+        self.derive_part(self.object.clone(), rights)
+    }
+
+    /// Like [`Capability::derive`], for a part of the object.
+    fn derive_part(&self, object: Object, rights: Rights) -> Self {
+        let rights = self.rights & rights;
+        open(&object, rights);
+        Self {
+            object,
+            rights,
+            derivation: Arc::new(Derivation {
+                revoked: AtomicBool::new(false),
+                parent: Some(self.derivation.clone()),
+            }),
+        }
+    }
+}
+
+impl Drop for Capability {
+    fn drop(&mut self) {
+        if let Object::Endpoint(endpoint) = &self.object {
+            if self.rights.contains(Rights::READ) {
+                endpoint.close();
+            }
+        }
+    }
+}
+
+/// Counts capabilities that can receive from an endpoint, see
+/// [`ipc::Endpoint::open`].
+fn open(object: &Object, rights: Rights) {
+    if let Object::Endpoint(endpoint) = object {
+        if rights.contains(Rights::READ) {
+            endpoint.open();
+        }
+    }
+}
+
+/// Node in the derivation tree, a capability is valid as long as neither it
+/// nor any of its ancestors have been revoked.
+struct Derivation {
+    revoked: AtomicBool,
+    parent: Option<Arc<Derivation>>,
+}
+
+impl Derivation {
+    fn revoked(&self) -> bool {
+        let mut derivation = self;
+        loop {
+            if derivation.revoked.load(Ordering::Acquire) {
+                return true;
+            }
+            match &derivation.parent {
+                Some(parent) => derivation = parent,
+                None => return false,
+            }
+        }
+    }
+}
+
+/// Per-task table of capabilities, which are referred to by handles.
+#[derive(Default)]
+pub struct HandleTable(Vec<Option<Capability>>);
+
+impl HandleTable {
+    pub fn insert(&mut self, capability: Capability) -> Handle {
+        let index = match self.0.iter().position(Option::is_none) {
+            Some(index) => {
+                self.0[index] = Some(capability);
+                index
+            }
+            None => {
+                self.0.push(Some(capability));
+                self.0.len() - 1
+            }
+        };
+        Handle(index as u32)
+    }
+
+    /// Returns the capability, if it is valid and has all of the given rights.
+    pub fn get(&self, handle: Handle, rights: Rights) -> Result<&Capability, Error> {
+        let capability = self
+            .0
+            .get(handle.0 as usize)
+            .and_then(Option::as_ref)
+            .ok_or(Error::InvalidHandle)?;
+        if capability.derivation.revoked() {
+            return Err(Error::Revoked);
+        }
+        if !capability.rights.contains(rights) {
+            return Err(Error::AccessDenied);
+        }
+        Ok(capability)
+    }
+
+    /// Returns the first valid capability with all of the given rights that
+    /// matches, used where the object rather than the handle is known.
+    pub fn find(&self, rights: Rights, f: impl Fn(&Object) -> bool) -> Option<&Capability> {
+        self.0.iter().flatten().find(|capability| {
+            capability.rights.contains(rights)
+                && f(&capability.object)
+                && !capability.derivation.revoked()
+        })
+    }
+
+    /// Creates a new handle in this table with a subset of the rights.
+    pub fn duplicate(&mut self, handle: Handle, rights: Rights) -> Result<Handle, Error> {
+        let capability = self.get(handle, Rights::DUPLICATE)?.derive(rights);
+        Ok(self.insert(capability))
+    }
+
+    /// Derives a capability with a subset of the rights to be passed on to
+    /// another task, e.g. as part of an [`ipc::Message`].
+    pub fn grant(&self, handle: Handle, rights: Rights) -> Result<Capability, Error> {
+        Ok(self.get(handle, Rights::GRANT)?.derive(rights))
+    }
+
+    /// Derives a capability for a part of an object to be passed on to another
+    /// task, e.g. a single BAR out of a range of physical memory. Requires a
+    /// capability covering it with [`Rights::GRANT`].
+    pub fn grant_part(&self, object: Object, rights: Rights) -> Result<Capability, Error> {
+        let capability = self
+            .find(Rights::GRANT, |other| other.covers(&object))
+            .ok_or(Error::AccessDenied)?;
+        Ok(capability.derive_part(object, rights))
+    }
+
+    /// Returns the handle of the first valid capability with all of the given
+    /// rights that matches, see [`HandleTable::find`].
+    pub fn position(&self, rights: Rights, f: impl Fn(&Object) -> bool) -> Option<Handle> {
+        self.0
+            .iter()
+            .position(|capability| {
+                capability.as_ref().is_some_and(|capability| {
+                    capability.rights.contains(rights)
+                        && f(&capability.object)
+                        && !capability.derivation.revoked()
+                })
+            })
+            .map(|index| Handle(index as u32))
+    }
+
+    pub fn close(&mut self, handle: Handle) -> Result<Capability, Error> {
+        self.0
+            .get_mut(handle.0 as usize)
+            .and_then(Option::take)
+            .ok_or(Error::InvalidHandle)
+    }
+
+    /// Revokes all capabilities that have been derived from this one, be it
+    /// through duplication or granting, while the handle itself stays valid.
+    pub fn revoke(&mut self, handle: Handle) -> Result<(), Error> {
+        self.get(handle, Rights::empty())?;
+        let capability = self.0[handle.0 as usize].as_mut().unwrap();
+        let derivation = Arc::new(Derivation {
+            revoked: AtomicBool::new(false),
+            parent: capability.derivation.parent.clone(),
+        });
+        capability.derivation.revoked.store(true, Ordering::Release);
+        capability.derivation = derivation;
+        Ok(())
+    }
+}
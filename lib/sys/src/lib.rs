@@ -0,0 +1,558 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![no_std]
+
+use core::{
+    arch, fmt,
+    fmt::Write,
+    mem,
+    ops::{Range, RangeInclusive},
+    ptr,
+};
+
+/// Interrupt vector used to enter the kernel.
+///
+/// The number is passed in `eax`/`rax`, the arguments in `ecx`, `edx`, `edi`
+/// on x86 and `rdi`, `rsi`, `rdx` on x86-64. Results are returned in
+/// `eax`/`rax` and `edx`/`rdx`, all other registers are preserved.
+pub const VECTOR: u8 = 0x2E;
+
+pub const IO_PERMISSION: usize = 0;
+pub const IRQ_ALLOCATE: usize = 1;
+pub const IRQ_WAIT: usize = 2;
+pub const MEMORY_MAP: usize = 3;
+pub const LOG: usize = 4;
+pub const DMA_ALLOCATE: usize = 5;
+pub const PHYSICAL_ADDRESS: usize = 6;
+pub const SPAWN: usize = 7;
+pub const KILL: usize = 8;
+pub const INPUT_PUSH: usize = 9;
+pub const INPUT_READ: usize = 10;
+pub const ENDPOINT_CREATE: usize = 11;
+pub const CALL: usize = 12;
+pub const RECEIVE: usize = 13;
+pub const REPLY: usize = 14;
+pub const CLOSE: usize = 15;
+pub const EXIT: usize = 16;
+pub const IRQ_FREE: usize = 17;
+pub const WATCH: usize = 18;
+
+/// Bytes of an input event, see [`input_push`].
+pub const INPUT_EVENT_SIZE: usize = 8;
+
+/// Number of words a message carries inline.
+pub const MESSAGE_WORDS: usize = 8;
+
+/// Number of capabilities a message grants, see [`Message::grant`].
+pub const MESSAGE_GRANTS: usize = 4;
+
+/// Flag of [`RECEIVE`], fails with [`Error::WouldBlock`] instead of blocking.
+pub const RECEIVE_NONBLOCKING: usize = 1 << 0;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(usize)]
+pub enum Error {
+    InvalidNumber = 1,
+    InvalidHandle,
+    AccessDenied,
+    Revoked,
+    Exhausted,
+    InvalidArgument,
+    WouldBlock,
+    /// The call was dropped without a reply, or the object is gone.
+    Closed,
+}
+
+impl Error {
+    pub fn from_raw(raw: usize) -> Option<Self> {
+        Some(match raw {
+            1 => Self::InvalidNumber,
+            2 => Self::InvalidHandle,
+            3 => Self::AccessDenied,
+            4 => Self::Revoked,
+            5 => Self::Exhausted,
+            6 => Self::InvalidArgument,
+            7 => Self::WouldBlock,
+            8 => Self::Closed,
+            _ => return None,
+        })
+    }
+}
+
+/// Allows the calling task to access the I/O ports through `pio::Port`, which
+/// requires a capability for them.
+pub fn io_permission(ports: RangeInclusive<u16>) -> Result<(), Error> {
+    let (status, _) = unsafe {
+        syscall(
+            IO_PERMISSION,
+            [*ports.start() as usize, *ports.end() as usize, 0],
+        )
+    };
+    result(status)
+}
+
+/// Interrupt vector on a specific processor, e.g. the target of MSI.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Irq {
+    pub vector: u8,
+    pub apic_id: u32,
+}
+
+/// Allocates an interrupt vector for the calling task, which can then be
+/// waited on.
+pub fn irq_allocate() -> Result<Irq, Error> {
+    let (status, irq) = unsafe { syscall(IRQ_ALLOCATE, [0, 0, 0]) };
+    result(status)?;
+    Ok(Irq {
+        vector: irq as u8,
+        apic_id: (irq >> 8) as u32,
+    })
+}
+
+/// Blocks until the interrupt arrived, returns immediately if it arrived since
+/// the last call. Fails with [`Error::Closed`] if it was freed meanwhile.
+pub fn irq_wait(vector: u8) -> Result<(), Error> {
+    let (status, _) = unsafe { syscall(IRQ_WAIT, [vector as usize, 0, 0]) };
+    result(status)
+}
+
+/// Frees an interrupt vector allocated by the calling task, the grants of it
+/// are revoked. The device must not signal it anymore.
+pub fn irq_free(vector: u8) -> Result<(), Error> {
+    let (status, _) = unsafe { syscall(IRQ_FREE, [vector as usize, 0, 0]) };
+    result(status)
+}
+
+/// Maps physical memory uncached, e.g. memory-mapped I/O or firmware tables,
+/// which requires a capability for it.
+pub fn memory_map(phys_addr: usize, size: usize) -> Result<*mut u8, Error> {
+    let (status, virt_addr) = unsafe { syscall(MEMORY_MAP, [phys_addr, size, 0]) };
+    result(status)?;
+    Ok(virt_addr as *mut u8)
+}
+
+/// Allocates physically contiguous and zeroed memory, which devices can access
+/// at its [`physical_address`]. Requires a capability for DMA, and is limited
+/// to 4 MiB. The calling task holds a capability for the memory, which lets it
+/// [`Grant::memory`] to another.
+pub fn dma_allocate(size: usize) -> Result<*mut u8, Error> {
+    let (status, virt_addr) = unsafe { syscall(DMA_ALLOCATE, [size, 0, 0]) };
+    result(status)?;
+    Ok(virt_addr as *mut u8)
+}
+
+/// Returns the physical address of memory the caller owns, i.e. its stack or
+/// what it got from [`memory_map`] or [`dma_allocate`], e.g. to build
+/// scatter-gather lists.
+pub fn physical_address(virt_addr: *const u8) -> Result<usize, Error> {
+    let (status, phys_addr) = unsafe { syscall(PHYSICAL_ADDRESS, [virt_addr as usize, 0, 0]) };
+    result(status)?;
+    Ok(phys_addr)
+}
+
+/// Task spawned by the calling one, see [`spawn`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Task(u32);
+
+/// Capability passed on to a task spawned, for a part of one the calling task
+/// holds.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct Grant {
+    pub kind: usize,
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Grant {
+    pub const MEMORY: usize = 0;
+    pub const PIO: usize = 1;
+    pub const IRQ: usize = 2;
+    pub const DMA: usize = 3;
+    pub const INPUT: usize = 4;
+    pub const ENDPOINT: usize = 5;
+
+    /// Physical memory, e.g. a BAR.
+    pub fn memory(range: Range<u64>) -> Self {
+        Self {
+            kind: Self::MEMORY,
+            start: range.start,
+            end: range.end,
+        }
+    }
+
+    pub fn pio(ports: RangeInclusive<u16>) -> Self {
+        Self {
+            kind: Self::PIO,
+            start: *ports.start() as u64,
+            end: *ports.end() as u64,
+        }
+    }
+
+    pub fn irq(vector: u8) -> Self {
+        Self {
+            kind: Self::IRQ,
+            start: vector as u64,
+            end: vector as u64,
+        }
+    }
+
+    /// Allocating memory for DMA, see [`dma_allocate`].
+    pub fn dma() -> Self {
+        Self {
+            kind: Self::DMA,
+            start: 0,
+            end: 0,
+        }
+    }
+
+    /// Reporting input events, see [`input_push`].
+    pub fn input() -> Self {
+        Self {
+            kind: Self::INPUT,
+            start: 0,
+            end: 0,
+        }
+    }
+
+    /// Calling the endpoint, which requires a capability allowing granting
+    /// for it rather than one covering it.
+    pub fn endpoint(endpoint: Endpoint) -> Self {
+        Self {
+            kind: Self::ENDPOINT,
+            start: endpoint.0 as u64,
+            end: endpoint.0 as u64,
+        }
+    }
+}
+
+/// Arguments of [`SPAWN`], which are passed by address.
+#[repr(C)]
+pub struct Spawn {
+    pub module: *const u8,
+    pub module_len: usize,
+    pub argument: *const u8,
+    pub argument_len: usize,
+    pub grants: *const Grant,
+    pub grant_count: usize,
+}
+
+/// Spawns a task running the boot module, which is passed the argument, and
+/// holds the capabilities granted. Those have to be covered by capabilities
+/// of the calling task which allow granting.
+pub fn spawn(module: &str, argument: &[u8], grants: &[Grant]) -> Result<Task, Error> {
+    let spawn = Spawn {
+        module: module.as_ptr(),
+        module_len: module.len(),
+        argument: argument.as_ptr(),
+        argument_len: argument.len(),
+        grants: grants.as_ptr(),
+        grant_count: grants.len(),
+    };
+    let (status, task) = unsafe { syscall(SPAWN, [&spawn as *const Spawn as usize, 0, 0]) };
+    result(status)?;
+    Ok(Task(task as u32))
+}
+
+/// Kills the task, which exits at its next interrupt or system call. Calls it
+/// is blocked in fail, and everything it owns is released.
+pub fn kill(task: Task) -> Result<(), Error> {
+    let (status, _) = unsafe { syscall(KILL, [task.0 as usize, 0, 0]) };
+    result(status)
+}
+
+/// Signals the interrupt vector once the task exited, and everything it owned
+/// was released. Returns whether it already has, which can be polled after
+/// each interrupt.
+pub fn watch(task: Task, vector: u8) -> Result<bool, Error> {
+    let (status, exited) = unsafe { syscall(WATCH, [task.0 as usize, vector as usize, 0]) };
+    result(status)?;
+    Ok(exited != 0)
+}
+
+/// Reports an input event for the console to read, which requires a
+/// capability for input.
+pub fn input_push(event: [u8; INPUT_EVENT_SIZE]) -> Result<(), Error> {
+    let low = u32::from_le_bytes([event[0], event[1], event[2], event[3]]);
+    let high = u32::from_le_bytes([event[4], event[5], event[6], event[7]]);
+    let (status, _) = unsafe { syscall(INPUT_PUSH, [low as usize, high as usize, 0]) };
+    result(status)
+}
+
+/// Moves the oldest input events of all devices into the buffer, and returns
+/// how many. Blocks until there is at least one, and requires a capability
+/// for input.
+pub fn input_read(events: &mut [[u8; INPUT_EVENT_SIZE]]) -> Result<usize, Error> {
+    let (status, count) =
+        unsafe { syscall(INPUT_READ, [events.as_mut_ptr() as usize, events.len(), 0]) };
+    result(status)?;
+    Ok(count)
+}
+
+/// Endpoint to call, or receive messages on. Tasks find the capabilities
+/// granted at spawn as handles 0 and up, in order.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Endpoint(pub u32);
+
+/// Caller of a message received, which waits until it's replied to. Dropping
+/// it fails the call with [`Error::Closed`].
+#[derive(Debug)]
+pub struct Reply(u32);
+
+impl Drop for Reply {
+    fn drop(&mut self) {
+        unsafe { syscall(CLOSE, [self.0 as usize, 0, 0]) };
+    }
+}
+
+/// Message passed through an endpoint, with a label telling its kind.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct Message {
+    pub label: usize,
+    pub words: [usize; MESSAGE_WORDS],
+    pub grants: [Grant; MESSAGE_GRANTS],
+    pub grant_count: usize,
+    /// Memory transferred to the receiver, see [`Message::transfer`].
+    pub pages: *mut u8,
+    pub pages_size: usize,
+}
+
+impl Message {
+    pub fn new(label: usize, words: [usize; MESSAGE_WORDS]) -> Self {
+        Self {
+            label,
+            words,
+            grants: [Grant::dma(); MESSAGE_GRANTS],
+            grant_count: 0,
+            pages: ptr::null_mut(),
+            pages_size: 0,
+        }
+    }
+
+    /// Transfers memory from [`dma_allocate`] or [`memory_map`] to the
+    /// receiver, which owns it afterwards and finds it in the same fields.
+    /// Only whole allocations or mappings can be transferred.
+    pub fn transfer(&mut self, pages: *mut u8, size: usize) {
+        self.pages = pages;
+        self.pages_size = size;
+    }
+
+    /// Grants a capability to the receiver, like [`spawn`] does. The receiver
+    /// finds its handle in the words, the first capability granted in word
+    /// `MESSAGE_WORDS - n` of `n`.
+    pub fn grant(&mut self, grant: Grant) -> Result<(), Error> {
+        *self
+            .grants
+            .get_mut(self.grant_count)
+            .ok_or(Error::Exhausted)? = grant;
+        self.grant_count += 1;
+        Ok(())
+    }
+}
+
+/// Creates an endpoint, the calling task holds the only capability for it.
+pub fn endpoint_create() -> Result<Endpoint, Error> {
+    let (status, endpoint) = unsafe { syscall(ENDPOINT_CREATE, [0, 0, 0]) };
+    result(status)?;
+    Ok(Endpoint(endpoint as u32))
+}
+
+/// Sends the message, and blocks until it has been received and replied to,
+/// the reply replaces it. Fails with [`Error::Closed`] if the receiver dropped
+/// the call instead, or nobody can receive from the endpoint anymore.
+pub fn call(endpoint: Endpoint, message: &mut Message) -> Result<(), Error> {
+    let (status, _) = unsafe {
+        syscall(
+            CALL,
+            [endpoint.0 as usize, message as *mut Message as usize, 0],
+        )
+    };
+    result(status)
+}
+
+/// Blocks until a message has been sent to the endpoint, its caller waits
+/// for the reply.
+pub fn receive(endpoint: Endpoint) -> Result<(Message, Reply), Error> {
+    let mut message = Message::new(0, [0; MESSAGE_WORDS]);
+    let (status, reply) = unsafe {
+        syscall(
+            RECEIVE,
+            [
+                endpoint.0 as usize,
+                &mut message as *mut Message as usize,
+                0,
+            ],
+        )
+    };
+    result(status)?;
+    Ok((message, Reply(reply as u32)))
+}
+
+/// Like [`receive`], but returns `None` instead of blocking.
+pub fn try_receive(endpoint: Endpoint) -> Result<Option<(Message, Reply)>, Error> {
+    let mut message = Message::new(0, [0; MESSAGE_WORDS]);
+    let (status, reply) = unsafe {
+        syscall(
+            RECEIVE,
+            [
+                endpoint.0 as usize,
+                &mut message as *mut Message as usize,
+                RECEIVE_NONBLOCKING,
+            ],
+        )
+    };
+    match result(status) {
+        Ok(()) => Ok(Some((message, Reply(reply as u32)))),
+        Err(Error::WouldBlock) => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+/// Answers the message received, which resumes its caller. The reply is
+/// consumed even if it fails, the call fails with [`Error::Closed`] then.
+pub fn reply(reply: Reply, message: &Message) -> Result<(), Error> {
+    let reply = mem::ManuallyDrop::new(reply);
+    let (status, _) = unsafe {
+        syscall(
+            REPLY,
+            [reply.0 as usize, message as *const Message as usize, 0],
+        )
+    };
+    result(status)
+}
+
+/// Closes the handle of the endpoint. Once no handle that can receive from it
+/// is left, e.g. as its server exited, callers waiting on it fail with
+/// [`Error::Closed`].
+pub fn close(endpoint: Endpoint) -> Result<(), Error> {
+    let (status, _) = unsafe { syscall(CLOSE, [endpoint.0 as usize, 0, 0]) };
+    result(status)
+}
+
+/// Exits the running task, which releases everything it owns.
+pub fn exit() -> ! {
+    unsafe { syscall(EXIT, [0, 0, 0]) };
+    unreachable!()
+}
+
+/// Defines the entry point of a task spawned from a boot module, which calls
+/// `main` with the argument it was spawned with, and exits once it returns.
+#[macro_export]
+macro_rules! main {
+    ($main:path) => {
+        #[no_mangle]
+        extern "C" fn _start(argument: *const u8, len: usize) -> ! {
+            $main(unsafe { core::slice::from_raw_parts(argument, len) });
+            $crate::exit()
+        }
+    };
+}
+
+/// Writes a line to the kernel log.
+pub fn log(level: log::Level, message: &str) -> Result<(), Error> {
+    let (status, _) = unsafe {
+        syscall(
+            LOG,
+            [level as usize, message.as_ptr() as usize, message.len()],
+        )
+    };
+    result(status)
+}
+
+/// Forwards records to the kernel log, each is truncated to
+/// [`Logger::LINE_SIZE`] bytes.
+pub struct Logger;
+
+impl Logger {
+    pub const LINE_SIZE: usize = 256;
+
+    pub fn init(level: log::LevelFilter) {
+        log::set_max_level(level);
+        let _ = log::set_logger(&Logger);
+    }
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        let mut line = Line {
+            bytes: [0; Self::LINE_SIZE],
+            len: 0,
+        };
+        let _ = write!(line, "{}", record.args());
+        // truncation may have split a character
+        let message = match core::str::from_utf8(&line.bytes[..line.len]) {
+            Ok(message) => message,
+            Err(error) => unsafe {
+                core::str::from_utf8_unchecked(&line.bytes[..error.valid_up_to()])
+            },
+        };
+        let _ = log(record.level(), message);
+    }
+
+    fn flush(&self) {}
+}
+
+struct Line {
+    bytes: [u8; Logger::LINE_SIZE],
+    len: usize,
+}
+
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..][..len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+fn result(status: usize) -> Result<(), Error> {
+    match Error::from_raw(status) {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
+}
+
+unsafe fn syscall(number: usize, args: [usize; 3]) -> (usize, usize) {
+    let (result_0, result_1);
+    #[cfg(target_arch = "x86")]
+    {
+        arch::asm!(
+            "int {vector}",
+            vector = const VECTOR,
+            inlateout("eax") number => result_0,
+            in("ecx") args[0],
+            inlateout("edx") args[1] => result_1,
+            in("edi") args[2],
+        );
+    }
+    #[cfg(target_arch = "x86_64")]
+    {
+        arch::asm!(
+            "int {vector}",
+            vector = const VECTOR,
+            inlateout("rax") number => result_0,
+            in("rdi") args[0],
+            in("rsi") args[1],
+            inlateout("rdx") args[2] => result_1,
+        );
+    }
+    (result_0, result_1)
+}